@@ -3,12 +3,29 @@ use std::sync::mpsc::Receiver;
 use egui::Key;
 use egui::Vec2;
 use egui::Color32;
+use egui::{Pos2, Rect, Stroke};
 use egui_file_dialog::FileDialog;
-use egui_plot::{Text, Line, Plot, PlotPoint, VLine};
+use egui_plot::{Text, Line, Plot, PlotImage, PlotPoint, Polygon, VLine};
 
-use ndarray::Array2;
+use ndarray::{Array1, Array2};
 
-use crate::{RawEEG, EEGInfo,Markers, edfio, bvio, signal};
+use crate::{RawEEG, EEGInfo,Markers, Annotation, ChannelGroup, EpochsData, EvokedData, DropReason, edfio, bvio, signal, epochs, evoked, baseline, rejection, plots, positions, topomap, peaks, batch, groups, reference, csd, badchannels, overview, spectrum, tfr, ica, ssp, denoise};
+use crate::signal::{ArtefactMethod, ArtefactWindow};
+use crate::baseline::BaselineMode;
+use crate::rejection::RejectCriteria;
+use crate::topomap::{Interpolation, Segment, Topomap};
+use crate::peaks::{PeakResult, Polarity, TepComponent};
+use crate::reference::Reference;
+use crate::montage::{Montage, ResolvedMontage};
+use crate::csd::CsdParams;
+use crate::badchannels::{BadChannel, BadChannelCriteria};
+use crate::overview::{Overview, OverviewSignal};
+use crate::spectrum::{Psd, Taper, WelchParams};
+use crate::tfr::{PowerBaseline, PowerKind, Tfr, TfrMethod, TfrParams};
+use crate::ica::{ComponentProperties, Ica, IcaMethod, IcaParams};
+use crate::ssp::Projector;
+use crate::headmodel::SphereModel;
+use crate::denoise::{DenoiseMethod, DenoiseReport, Denoising, SoundParams, SspSirParams};
 
 #[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
 enum DataFormat {
@@ -21,6 +38,61 @@ enum ProcessedDataType {
     EDF(Array2<f32>),
 }
 
+const BAD_CHANNEL_COLOR: Color32 = Color32::from_rgb(200, 60, 60);
+// Traces with the marked ICA components removed, over the data in the viewer
+const ICA_PREVIEW_COLOR: Color32 = Color32::from_rgb(255, 170, 0);
+const ICA_ROW_HEIGHT: f32 = 110.0;
+
+// Vertical distance between channels in plot units; one division of the sensitivity
+const CHANNEL_SPACING: f64 = 10.0;
+// Choices stepped through with the keyboard
+const SENSITIVITY_STEPS: [f64; 15] = [1.0, 2.0, 3.0, 5.0, 7.0, 10.0, 15.0, 20.0, 30.0, 50.0, 70.0, 100.0, 200.0, 500.0, 1000.0];
+const PAGE_DURATIONS: [f64; 9] = [1.0, 2.0, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0, 120.0];
+const OVERVIEW_HEIGHT: f32 = 80.0;
+const SPECTROGRAM_HEIGHT: f32 = 110.0;
+// Shared by the viewer and the spectrograms so their time axes line up
+const VIEWER_Y_AXIS_WIDTH: f32 = 40.0;
+const OVERVIEW_BINS: usize = 2000;
+
+const GROUP_COLORS: [Color32; 4] = [
+    Color32::from_rgb(255, 170, 0),
+    Color32::from_rgb(90, 200, 120),
+    Color32::from_rgb(220, 90, 220),
+    Color32::from_rgb(80, 170, 255),
+];
+
+// A rendered map: (latency, colour limit, epochs version) bits, image and contour lines
+struct TopoTexture {
+    key: (u64, u64, u64),
+    texture: egui::TextureHandle,
+    contours: Vec<Segment>,
+}
+
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+enum MontageChoice {
+    Raw,
+    DoubleBanana,
+    Transverse,
+    Laplacian,
+    Csd,
+    Custom,
+}
+
+// What a click or drag in the continuous viewer does
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum ViewerTool {
+    Navigate,
+    AnnotateSegments,
+    EditMarkers,
+}
+
+// Continuous data or the good epochs, for the spectrum and ICA
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+enum DataSource {
+    Continuous,
+    Epochs,
+}
 
 #[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
 enum ReferenceType {
@@ -28,124 +100,3698 @@ enum ReferenceType {
     AverageReference,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-#[serde(default)]
-pub struct TemplateApp {
-    data_format: DataFormat,
-    #[serde(skip)]
-    file_dialog: FileDialog,
-    edf_file: Option<PathBuf>,
-    #[serde(skip)]
-    raw_eeg: RawEEG,
-    #[serde(skip)]
-    eeg_info: EEGInfo,
-    #[serde(skip)]
-    eeg_markers: Markers,
-    #[serde(skip)]
-    loading_receiver: Option<Receiver<Result<(RawEEG, EEGInfo, Markers), std::io::Error>>>,
-    #[serde(skip)]
-    artifact_receiver: Option<Receiver<Result<ProcessedDataType, std::io::Error>>>,
-    #[serde(skip)]
-    filtering_receiver: Option<Receiver<Result<ProcessedDataType, std::io::Error>>>,
-    #[serde(skip)]
-    show_data: bool,
-    apply_notch_filter: bool,
-    selected_channel: usize,
-    reference_type: ReferenceType,
-    unselected_channels: Vec<usize>,
-    decimation_factor: usize,
-    x_view: f64,
-    y_view_min: f64,
-    y_view_max: f64,
-    plot_zoom_factor: Vec2,
-    gain: f64,
-    tmin_cut: f64,
-    tmax_cut: f64,
-    lfreq: f64,
-    hfreq: f64,
-    channel_colors: Vec<Color32>,
-    global_color: Color32,
-    selected_channel_for_color: usize,
-    ruler_position: Option<(f64, f64)>,  // (x, y) position of the ruler
-    ruler_width: f64,                    // Width of the ruler in seconds
-    ruler_height: f64,                   // Height of the ruler in microvolts
-    ruler_dragging: bool,
-}
+// What a spectrogram image was computed from; limits are None when automatic
+#[derive(PartialEq, Clone, Copy, Debug)]
+struct SpectrogramKey {
+    channel: usize,
+    start: usize,
+    end: usize,
+    params: WelchParams,
+    fmax: f64,
+    limits: Option<(f64, f64)>,
+}
+
+struct SpectrogramImage {
+    key: SpectrogramKey,
+    texture: egui::TextureHandle,
+    // dB range of the colours, time and frequency extent of the image
+    range: (f64, f64),
+    times: (f64, f64),
+    freqs: (f64, f64),
+}
+
+// Channel row, ITC, baseline mode, baseline window (bits) and result version of a time-frequency image
+type TfrTextureKey = (usize, bool, Option<PowerBaseline>, u64, u64, u64);
+// Viewer window samples, the components removed and, in average reference view, the bad channels left out
+type IcaPreviewKey = (usize, usize, Vec<usize>, Option<Vec<usize>>);
+// Viewer window samples, projector version and, in average reference view, the bad channels left out
+type SspViewKey = (usize, usize, u64, Option<Vec<usize>>);
+// Viewer window samples and the bad channels left out of the average
+type AverageViewKey = (usize, usize, Vec<usize>);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct TemplateApp {
+    data_format: DataFormat,
+    #[serde(skip)]
+    file_dialog: FileDialog,
+    edf_file: Option<PathBuf>,
+    #[serde(skip)]
+    raw_eeg: RawEEG,
+    #[serde(skip)]
+    eeg_info: EEGInfo,
+    #[serde(skip)]
+    eeg_markers: Markers,
+    #[serde(skip)]
+    loading_receiver: Option<Receiver<Result<(RawEEG, EEGInfo, Markers), std::io::Error>>>,
+    #[serde(skip)]
+    artifact_receiver: Option<Receiver<Result<ProcessedDataType, std::io::Error>>>,
+    #[serde(skip)]
+    filtering_receiver: Option<Receiver<Result<ProcessedDataType, std::io::Error>>>,
+    #[serde(skip)]
+    epochs_receiver: Option<Receiver<Result<EpochsData, std::io::Error>>>,
+    #[serde(skip)]
+    epochs: Option<EpochsData>,
+    #[serde(skip)]
+    evoked: Option<EvokedData>,
+    #[serde(skip)]
+    epochs_version: u64,
+    #[serde(skip)]
+    show_epochs_browser: bool,
+    #[serde(skip)]
+    epochs_page: usize,
+    #[serde(skip)]
+    erp_texture: Option<((usize, u64), egui::TextureHandle, f64)>,
+    epochs_per_page: usize,
+    erp_channel: usize,
+    #[serde(skip)]
+    show_evoked_window: bool,
+    evoked_channel: usize,
+    evoked_cursor: f64,
+    #[serde(skip)]
+    show_topomaps: bool,
+    #[serde(skip)]
+    topomap: Option<((u64, Interpolation), Topomap, Vec<usize>)>,
+    #[serde(skip)]
+    topo_textures: Vec<TopoTexture>,
+    topo_interpolation: Interpolation,
+    topo_latencies: Vec<f64>,
+    #[serde(skip)]
+    show_components: bool,
+    #[serde(skip)]
+    peaks_receiver: Option<Receiver<(Vec<PeakResult>, Vec<String>)>>,
+    #[serde(skip)]
+    peak_results: Vec<PeakResult>,
+    #[serde(skip)]
+    peaks_status: Vec<String>,
+    tep_components: Vec<TepComponent>,
+    channel_groups: Vec<ChannelGroup>,
+    lmfp_windows: Vec<(f64, f64)>,
+    rereference: Reference,
+    montage_choice: MontageChoice,
+    custom_montage: String,
+    csd_params: CsdParams,
+    bad_criteria: BadChannelCriteria,
+    reject_bad_segments: bool,
+    filter_skip_bad_segments: bool,
+    annotation_label: String,
+    #[serde(skip)]
+    show_events: bool,
+    #[serde(skip)]
+    show_spectrum: bool,
+    welch_params: WelchParams,
+    spectrum_source: DataSource,
+    spectrum_fmax: f64,
+    spectrum_show_channels: bool,
+    psd_csv_path: String,
+    #[serde(skip)]
+    psd_receiver: Option<Receiver<Result<Psd, String>>>,
+    #[serde(skip)]
+    psd: Option<Psd>,
+    #[serde(skip)]
+    psd_status: Option<String>,
+    #[serde(skip)]
+    show_tfr: bool,
+    tfr_params: TfrParams,
+    tfr_all_channels: bool,
+    // Epoch channel shown
+    tfr_channel: usize,
+    tfr_show_itc: bool,
+    tfr_baseline: Option<PowerBaseline>,
+    tfr_bmin: f64,
+    tfr_bmax: f64,
+    #[serde(skip)]
+    tfr_receiver: Option<Receiver<Result<Tfr, String>>>,
+    #[serde(skip)]
+    tfr: Option<Tfr>,
+    #[serde(skip)]
+    tfr_version: u64,
+    #[serde(skip)]
+    tfr_status: Option<String>,
+    // With its colour limit
+    #[serde(skip)]
+    tfr_texture: Option<(TfrTextureKey, egui::TextureHandle, f64)>,
+    ica_params: IcaParams,
+    ica_source: DataSource,
+    #[serde(skip)]
+    ica_receiver: Option<Receiver<Result<Ica, String>>>,
+    #[serde(skip)]
+    ica: Option<Ica>,
+    // Components marked for removal
+    #[serde(skip)]
+    ica_exclude: Vec<usize>,
+    #[serde(skip)]
+    ica_status: Option<String>,
+    #[serde(skip)]
+    show_ica_components: bool,
+    #[serde(skip)]
+    ica_preview: bool,
+    #[serde(skip)]
+    ica_properties_receiver: Option<Receiver<Result<ComponentProperties, String>>>,
+    #[serde(skip)]
+    ica_properties: Option<ComponentProperties>,
+    // Interpolator over the fitted channels with a position, and their rows in the mixing matrix
+    #[serde(skip)]
+    ica_topomap: Option<(Topomap, Vec<usize>)>,
+    #[serde(skip)]
+    ica_topo_textures: Vec<egui::TextureHandle>,
+    // Component time courses of the viewer window, by its first and last sample
+    #[serde(skip)]
+    ica_sources: Option<((usize, usize), Array2<f64>)>,
+    #[serde(skip)]
+    ica_preview_cache: Option<(IcaPreviewKey, Vec<Vec<f64>>)>,
+    ssp_source: DataSource,
+    // Annotation description of the artefact segments
+    ssp_label: String,
+    ssp_tmin: f64,
+    ssp_tmax: f64,
+    ssp_n_vectors: usize,
+    #[serde(skip)]
+    projectors: Vec<Projector>,
+    // Bumped on every change to the projectors or their active flags
+    #[serde(skip)]
+    ssp_version: u64,
+    #[serde(skip)]
+    ssp_status: Option<String>,
+    // The viewer window with the active projectors applied
+    #[serde(skip)]
+    ssp_view: Option<(SspViewKey, Vec<Vec<f64>>)>,
+    head_model: SphereModel,
+    sound_params: SoundParams,
+    ssp_sir_params: SspSirParams,
+    denoise_source: DataSource,
+    #[serde(skip)]
+    denoise_receiver: Option<Receiver<Result<Denoising, String>>>,
+    #[serde(skip)]
+    denoising: Option<Denoising>,
+    #[serde(skip)]
+    denoise_status: Option<String>,
+    event_filter: Option<String>,
+    #[serde(skip)]
+    viewer_tool: ViewerTool,
+    new_marker_type: String,
+    #[serde(skip)]
+    selected_marker: Option<usize>,
+    #[serde(skip)]
+    marker_drag: Option<usize>,
+    #[serde(skip)]
+    marker_export_path: String,
+    #[serde(skip)]
+    marker_status: Option<String>,
+    #[serde(skip)]
+    annotation_drag: Option<f64>,
+    #[serde(skip)]
+    selected_annotation: Option<usize>,
+    #[serde(skip)]
+    bad_detect_receiver: Option<Receiver<Vec<BadChannel>>>,
+    #[serde(skip)]
+    bad_channel_report: Vec<BadChannel>,
+    #[serde(skip)]
+    bad_channel_status: Option<String>,
+    #[serde(skip)]
+    pending_interpolation: Vec<usize>,
+    #[serde(skip)]
+    montage: Option<ResolvedMontage>,
+    #[serde(skip)]
+    montage_error: Option<String>,
+    reference_labels: String,
+    add_reference_channel: bool,
+    reference_channel_name: String,
+    batch_folder: String,
+    // Steps repeated on every file of a batch, with the settings of the panels
+    batch_artefacts: bool,
+    batch_filter: bool,
+    batch_baseline: bool,
+    batch_reject: bool,
+    peaks_csv_path: String,
+    #[serde(skip)]
+    show_data: bool,
+    apply_notch_filter: bool,
+    selected_channel: usize,
+    reference_type: ReferenceType,
+    // The viewer window re-referenced to the average of the good channels, in average reference view
+    #[serde(skip)]
+    average_view: Option<(AverageViewKey, Vec<Vec<f64>>)>,
+    unselected_channels: Vec<usize>,
+    decimation_factor: usize,
+    x_view: f64,
+    // Seconds shown at once
+    page_duration: f64,
+    // 0 shows every channel
+    channels_per_page: usize,
+    channel_page: usize,
+    // µV per division (channel spacing)
+    sensitivity: f64,
+    show_scale_bar: bool,
+    show_overview: bool,
+    overview_signal: OverviewSignal,
+    // Keyed by the signal and the bad channels; cleared when the data changes
+    #[serde(skip)]
+    overview: Option<((OverviewSignal, Vec<usize>), Overview)>,
+    // Pointer minus window start while the overview window is dragged
+    #[serde(skip)]
+    overview_drag: Option<f64>,
+    show_spectrogram: bool,
+    spectrogram_channels: Vec<usize>,
+    spectrogram_params: WelchParams,
+    spectrogram_fmax: f64,
+    // dB colour range; None scales each image between its 5th and 99th percentile
+    spectrogram_limits: Option<(f64, f64)>,
+    // One per shown channel, cleared when the data changes
+    #[serde(skip)]
+    spectrogram_textures: Vec<SpectrogramImage>,
+    plot_zoom_factor: Vec2,
+    tmin_cut: f64,
+    tmax_cut: f64,
+    artefact_windows: Vec<ArtefactWindow>,
+    recharge_search_start: f64,
+    recharge_search_end: f64,
+    #[serde(skip)]
+    recharge_status: Option<String>,
+    #[serde(skip)]
+    recharge_receiver: Option<Receiver<Result<f64, String>>>,
+    selected_event_types: Vec<String>,
+    epoch_tmin: f64,
+    epoch_tmax: f64,
+    baseline_bmin: f64,
+    baseline_bmax: f64,
+    baseline_mode: BaselineMode,
+    detrend_order: usize,
+    detrend_exclude_pulse: bool,
+    reject_criteria: RejectCriteria,
+    lfreq: f64,
+    hfreq: f64,
+    channel_colors: Vec<Color32>,
+    global_color: Color32,
+    selected_channel_for_color: usize,
+    ruler_position: Option<(f64, f64)>,  // (x, y) position of the ruler
+    ruler_width: f64,                    // Width of the ruler in seconds
+    ruler_height: f64,                   // Height of the ruler in µV
+    ruler_dragging: bool,
+}
+
+impl Default for TemplateApp {
+    fn default() -> Self {
+        Self {
+            data_format: DataFormat::EDF,
+            file_dialog: FileDialog::new(),
+            edf_file: None,
+            raw_eeg: RawEEG::default(),
+            eeg_info: EEGInfo::default(),
+            eeg_markers: Markers::default(),
+            loading_receiver: None,
+            filtering_receiver: None,
+            apply_notch_filter: false,
+            artifact_receiver: None,
+            epochs_receiver: None,
+            epochs: None,
+            evoked: None,
+            epochs_version: 0,
+            show_epochs_browser: false,
+            epochs_page: 0,
+            erp_texture: None,
+            epochs_per_page: 5,
+            erp_channel: 0,
+            show_evoked_window: false,
+            evoked_channel: 0,
+            evoked_cursor: 0.03,
+            show_topomaps: false,
+            topomap: None,
+            topo_textures: Vec::new(),
+            topo_interpolation: Interpolation::SphericalSpline,
+            topo_latencies: vec![0.015, 0.030, 0.045, 0.060, 0.100, 0.180],
+            show_components: false,
+            peaks_receiver: None,
+            peak_results: Vec::new(),
+            peaks_status: Vec::new(),
+            tep_components: peaks::default_components(),
+            channel_groups: vec![ChannelGroup::from_labels("Left M1", "C3, C1, C5, FC3, CP3")],
+            lmfp_windows: vec![(0.015, 0.035), (0.035, 0.080), (0.080, 0.140), (0.140, 0.250)],
+            rereference: Reference::Average,
+            montage_choice: MontageChoice::Raw,
+            custom_montage: Montage::double_banana().to_text(),
+            csd_params: CsdParams::default(),
+            bad_criteria: BadChannelCriteria::default(),
+            reject_bad_segments: true,
+            filter_skip_bad_segments: true,
+            annotation_label: "BAD_".to_owned(),
+            show_events: false,
+            show_spectrum: false,
+            welch_params: WelchParams::default(),
+            spectrum_source: DataSource::Continuous,
+            spectrum_fmax: 60.0,
+            spectrum_show_channels: true,
+            psd_csv_path: "psd.csv".to_owned(),
+            psd_receiver: None,
+            psd: None,
+            psd_status: None,
+            show_tfr: false,
+            tfr_params: TfrParams::default(),
+            tfr_all_channels: false,
+            tfr_channel: 0,
+            tfr_show_itc: false,
+            tfr_baseline: Some(PowerBaseline::Decibel),
+            tfr_bmin: -0.5,
+            tfr_bmax: -0.1,
+            tfr_receiver: None,
+            tfr: None,
+            tfr_version: 0,
+            tfr_status: None,
+            tfr_texture: None,
+            ica_params: IcaParams::default(),
+            ica_source: DataSource::Continuous,
+            ica_receiver: None,
+            ica: None,
+            ica_exclude: Vec::new(),
+            ica_status: None,
+            show_ica_components: false,
+            ica_preview: false,
+            ica_properties_receiver: None,
+            ica_properties: None,
+            ica_topomap: None,
+            ica_topo_textures: Vec::new(),
+            ica_sources: None,
+            ica_preview_cache: None,
+            ssp_source: DataSource::Epochs,
+            ssp_label: "BAD_blink".to_owned(),
+            ssp_tmin: 0.01,
+            ssp_tmax: 0.05,
+            ssp_n_vectors: 2,
+            projectors: Vec::new(),
+            ssp_version: 0,
+            ssp_status: None,
+            ssp_view: None,
+            head_model: SphereModel::default(),
+            sound_params: SoundParams::default(),
+            ssp_sir_params: SspSirParams::default(),
+            denoise_source: DataSource::Epochs,
+            denoise_receiver: None,
+            denoising: None,
+            denoise_status: None,
+            event_filter: None,
+            viewer_tool: ViewerTool::Navigate,
+            new_marker_type: "S  1".to_owned(),
+            selected_marker: None,
+            marker_drag: None,
+            marker_export_path: String::new(),
+            marker_status: None,
+            annotation_drag: None,
+            selected_annotation: None,
+            bad_detect_receiver: None,
+            bad_channel_report: Vec::new(),
+            bad_channel_status: None,
+            pending_interpolation: Vec::new(),
+            montage: None,
+            montage_error: None,
+            reference_labels: "TP9, TP10".to_owned(),
+            add_reference_channel: false,
+            reference_channel_name: "FCz".to_owned(),
+            batch_folder: String::new(),
+            batch_artefacts: true,
+            batch_filter: true,
+            batch_baseline: false,
+            batch_reject: false,
+            peaks_csv_path: "tep_peaks.csv".to_owned(),
+            show_data: false,
+            reference_type: ReferenceType::Original,
+            average_view: None,
+            selected_channel_for_color: 0,
+            global_color: Color32::WHITE,
+            selected_channel: 0,
+            decimation_factor: 100,
+            x_view: 0.0,
+            page_duration: 10.0,
+            channels_per_page: 32,
+            channel_page: 0,
+            sensitivity: 100.0,
+            show_scale_bar: true,
+            show_overview: true,
+            overview_signal: OverviewSignal::Gfp,
+            overview: None,
+            overview_drag: None,
+            show_spectrogram: false,
+            spectrogram_channels: vec![0],
+            spectrogram_params: WelchParams { segment_seconds: 1.0, ..WelchParams::default() },
+            spectrogram_fmax: 40.0,
+            spectrogram_limits: None,
+            spectrogram_textures: Vec::new(),
+            plot_zoom_factor: Vec2::new(1.0, 1.0),
+            unselected_channels: Vec::new(),
+            channel_colors: Vec::new(),
+            tmin_cut: 0.002,
+            tmax_cut: 0.005,
+            artefact_windows: Vec::new(),
+            recharge_search_start: 0.010,
+            recharge_search_end: 0.500,
+            recharge_status: None,
+            recharge_receiver: None,
+            selected_event_types: Vec::new(),
+            epoch_tmin: -0.1,
+            epoch_tmax: 0.5,
+            baseline_bmin: -0.1,
+            baseline_bmax: 0.0,
+            baseline_mode: BaselineMode::Mean,
+            detrend_order: 1,
+            detrend_exclude_pulse: true,
+            reject_criteria: RejectCriteria {
+                peak_to_peak: Some(200.0),
+                gradient: None,
+                flat: Some(1.0),
+                kurtosis: None,
+            },
+            lfreq: 1.0,
+            hfreq: 45.0,
+            ruler_position: None,
+            ruler_width: 1.0,  // Default width: 1 second
+            ruler_height: 50.0, // Default height: 50 µV
+            ruler_dragging: false,
+        }
+    }
+}
+
+impl TemplateApp {
+    // `scale` takes data units to plot units, see plot_scale
+    fn min_max_decimate<T>(
+        data: &[T],
+        start_sample: usize,
+        decimation: usize,
+        offset: f64,
+        scale: f64,
+        sampling_frequency: f64
+    ) -> Vec<[f64; 2]>
+    where
+        T: Copy + PartialOrd + Into<f64>,
+    {
+        if decimation <= 1 {
+            return data.iter().enumerate().map(|(i, &sample)| {
+                let x = (start_sample + i) as f64 / sampling_frequency;
+                let y = sample.into() * scale + offset;
+                [x, y]
+            }).collect();
+        }
+
+        let mut points = Vec::new();
+        for chunk in data.chunks(decimation) {
+            let chunk_start = (points.len() / 2) * decimation;
+            let time_base = (start_sample + chunk_start) as f64 / sampling_frequency;
+
+            if let Some(&first) = chunk.first() {
+                let (min_val, max_val) = chunk.iter().fold((first, first), |(min, max), &val| {
+                    (if val < min { val } else { min }, if val > max { val } else { max })
+                });
+
+                points.push([time_base, min_val.into() * scale + offset]);
+                points.push([time_base + (decimation as f64 * 0.5) / sampling_frequency,
+                            max_val.into() * scale + offset]);
+            }
+        }
+        points
+    }
+
+    fn selected_markers(&self) -> Markers {
+        self.eeg_markers.select_types(&self.selected_event_types)
+    }
+
+    // Keep the selection for types present in the new file, default to the TMS pulse marker
+    fn reset_event_selection(&mut self) {
+        let types = self.eeg_markers.event_types();
+        self.selected_event_types.retain(|t| types.contains(t));
+        if self.selected_event_types.is_empty() && types.iter().any(|t| t == "R128") {
+            self.selected_event_types.push("R128".to_owned());
+        }
+    }
+
+    fn event_selection_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Events");
+        ui.label("Used for pulse removal and epoching (none selected: all)");
+        for event_type in self.eeg_markers.event_types() {
+            let count = self.eeg_markers.descriptions.iter().filter(|d| **d == event_type).count();
+            let mut selected = self.selected_event_types.contains(&event_type);
+            if ui.checkbox(&mut selected, format!("{event_type} ({count})")).changed() {
+                if selected {
+                    self.selected_event_types.push(event_type);
+                } else {
+                    self.selected_event_types.retain(|t| *t != event_type);
+                }
+            }
+        }
+    }
+
+    fn epoching_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Epoching");
+        ui.add(egui::Slider::new(&mut self.epoch_tmin, -2.0..=0.0)
+            .text("tmin (s)")
+            .suffix(" s"));
+        ui.add(egui::Slider::new(&mut self.epoch_tmax, 0.01..=3.0)
+            .text("tmax (s)")
+            .suffix(" s"));
+
+        if ui.button("Create epochs").clicked() {
+            self.spawn_epoching();
+        }
+        if self.epochs_receiver.is_some() {
+            ui.label("Epoching...");
+            ui.spinner();
+        }
+
+        ui.horizontal(|ui| {
+            ui.toggle_value(&mut self.show_epochs_browser, "Epochs browser");
+            ui.toggle_value(&mut self.show_evoked_window, "Evoked");
+            ui.toggle_value(&mut self.show_topomaps, "Topomaps");
+            ui.toggle_value(&mut self.show_components, "TEP components");
+            ui.toggle_value(&mut self.show_tfr, "Time-frequency");
+        });
+
+        if let Some(epochs) = &self.epochs {
+            ui.label(format!(
+                "{} epochs, {} dropped outside the recording",
+                epochs.n_epochs(),
+                epochs.drop_log.iter().filter(|d| d.reason == DropReason::OutsideRecording).count()
+            ));
+            if !epochs.drop_log.is_empty() {
+                egui::CollapsingHeader::new("Dropped epochs").show(ui, |ui| {
+                    for dropped in &epochs.drop_log {
+                        ui.label(format!(
+                            "#{} {} at {:.3} s: {:?}",
+                            dropped.event.marker_idx,
+                            dropped.event.description,
+                            dropped.event.sample / epochs.sfreq,
+                            dropped.reason
+                        ));
+                    }
+                });
+            }
+        }
+    }
+
+    fn spawn_epoching(&mut self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.epochs_receiver = Some(receiver);
+
+        let info = self.eeg_info.clone();
+        let markers = self.eeg_markers.clone();
+        let event_types = self.selected_event_types.clone();
+        let (tmin, tmax) = (self.epoch_tmin, self.epoch_tmax);
+
+        match self.data_format {
+            DataFormat::EDF => {
+                if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
+                    std::thread::spawn(move || {
+                        let data = signal::vec_to_ndarray(&data_vec);
+                        let result = epochs::create_epochs_edf(tmin, tmax, &event_types, &info, &data, &markers);
+                        sender.send(result.map_err(|e|
+                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                        )).ok();
+                    });
+                }
+            }
+            DataFormat::BrainVision => {
+                if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
+                    std::thread::spawn(move || {
+                        let data = signal::vec_to_ndarray(&data_vec);
+                        let result = epochs::create_epochs_bv(tmin, tmax, &event_types, &info, &data, &markers);
+                        sender.send(result.map_err(|e|
+                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                        )).ok();
+                    });
+                }
+            }
+        }
+    }
+
+    // Run a transform of the continuous data on a worker thread, the result arrives on `filtering_receiver`
+    fn spawn_continuous<F, G>(&mut self, process_bv: F, process_edf: G)
+    where
+        F: FnOnce(&Array2<i16>) -> Result<Array2<i16>, Box<dyn std::error::Error>> + Send + 'static,
+        G: FnOnce(&Array2<f32>) -> Result<Array2<f32>, Box<dyn std::error::Error>> + Send + 'static,
+    {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.filtering_receiver = Some(receiver);
+
+        match self.data_format {
+            DataFormat::EDF => {
+                if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
+                    std::thread::spawn(move || {
+                        let data = signal::vec_to_ndarray(&data_vec);
+                        let result = process_edf(&data).map(ProcessedDataType::EDF);
+                        sender.send(result.map_err(|e|
+                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                        )).ok();
+                    });
+                }
+            }
+            DataFormat::BrainVision => {
+                if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
+                    std::thread::spawn(move || {
+                        let data = signal::vec_to_ndarray(&data_vec);
+                        let result = process_bv(&data).map(ProcessedDataType::BV);
+                        sender.send(result.map_err(|e|
+                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                        )).ok();
+                    });
+                }
+            }
+        }
+    }
+
+    fn bad_channels_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Bad channels");
+        let n_channels = self.n_data_channels();
+        let names: Vec<String> = self.eeg_info.ch_names.iter().take(n_channels).cloned().collect();
+        ui.label("Click a channel name in the viewer to mark or unmark it");
+        ui.horizontal_wrapped(|ui| {
+            for ch in self.eeg_info.bad_channels.clone() {
+                let name = names.get(ch).map_or_else(|| format!("Ch{}", ch + 1), |name| positions::clean_label(name));
+                if ui.button(format!("{name} ✖")).on_hover_text("Mark as good").clicked() {
+                    self.set_channel_bad(ch, false);
+                }
+            }
+        });
+        egui::CollapsingHeader::new("Automatic detection").show(ui, |ui| {
+            let criteria = &mut self.bad_criteria;
+            ui.add(egui::Slider::new(&mut criteria.flat_std, 0.0..=10.0).text("Flat below std").suffix(" µV"));
+            ui.add(egui::Slider::new(&mut criteria.variance_z, 1.0..=20.0).text("Variance z"));
+            ui.add(egui::Slider::new(&mut criteria.min_correlation, 0.0..=1.0).text("Min neighbour correlation"));
+            ui.add(egui::Slider::new(&mut criteria.n_neighbours, 1..=10).text("Neighbours"));
+            ui.add(egui::Slider::new(&mut criteria.line_noise_z, 1.0..=20.0).text("Line noise z"));
+            ui.add(egui::Slider::new(&mut criteria.line_freq, 40.0..=70.0).text("Line frequency").suffix(" Hz"));
+            if ui.add_enabled(self.bad_detect_receiver.is_none(), egui::Button::new("Detect bad channels")).clicked() {
+                self.spawn_bad_channel_detection();
+            }
+            if self.bad_detect_receiver.is_some() {
+                ui.spinner();
+            }
+            for bad in &self.bad_channel_report {
+                let name = names.get(bad.channel).map_or_else(|| format!("Ch{}", bad.channel + 1), |name| positions::clean_label(name));
+                ui.label(format!("{name}: {:?} ({:.2})", bad.reason, bad.value));
+            }
+        });
+        if let Some(epochs) = self.epochs.as_ref().filter(|epochs| epochs.bad_channels != self.eeg_info.bad_channels) {
+            ui.label(format!("{} bad channels in the epochs", epochs.bad_channels.len()));
+        }
+        ui.horizontal(|ui| {
+            let has_bad = !self.eeg_info.bad_channels.is_empty();
+            if ui.add_enabled(has_bad && self.filtering_receiver.is_none(), egui::Button::new("Interpolate continuous")).clicked() {
+                self.interpolate_bad_channels(true);
+            }
+            let epochs_bad = self.epochs.as_ref().is_some_and(|epochs| !epochs.bad_channels.is_empty());
+            if ui.add_enabled(epochs_bad, egui::Button::new("Interpolate epochs")).clicked() {
+                self.interpolate_bad_channels(false);
+            }
+        });
+        if let Some(status) = &self.bad_channel_status {
+            ui.label(status);
+        }
+    }
+
+    // Marking by hand applies to the continuous data and the epochs alike
+    fn set_channel_bad(&mut self, ch: usize, bad: bool) {
+        let epochs_bads = self.epochs.as_mut().map(|epochs| &mut epochs.bad_channels);
+        for bads in std::iter::once(&mut self.eeg_info.bad_channels).chain(epochs_bads) {
+            if bad && !bads.contains(&ch) {
+                bads.push(ch);
+                bads.sort_unstable();
+            } else if !bad {
+                bads.retain(|&other| other != ch);
+            }
+        }
+        // The CSD montage leaves bad channels out of the spline
+        self.refresh_montage();
+    }
+
+    // Channel whose label was clicked, from the plot offsets of the drawn channels
+    fn clicked_channel_label(plot_ui: &egui_plot::PlotUi<'_>, label_rows: &[(usize, f64)], label_x: f64) -> Option<usize> {
+        if !plot_ui.response().clicked() {
+            return None;
+        }
+        let pointer = plot_ui.response().interact_pointer_pos()?;
+        label_rows.iter().find_map(|&(ch, offset)| {
+            let anchor = plot_ui.screen_from_plot(PlotPoint::new(label_x, offset));
+            ((pointer.x - anchor.x).abs() < 40.0 && (pointer.y - anchor.y).abs() < 8.0).then_some(ch)
+        })
+    }
+
+    fn spawn_bad_channel_detection(&mut self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (criteria, info) = (self.bad_criteria, self.eeg_info.clone());
+        let skip = self.eeg_markers.bad_spans(self.n_samples());
+        let (bv_data, edf_data) = (self.raw_eeg.bv_data.clone(), self.raw_eeg.edf_data.clone());
+        self.bad_detect_receiver = Some(receiver);
+        std::thread::spawn(move || {
+            let report = match (bv_data, edf_data) {
+                (Some(data), _) => badchannels::detect_bad_channels(&criteria, &info, &data, &skip),
+                (None, Some(data)) => badchannels::detect_bad_channels(&criteria, &info, &data, &skip),
+                (None, None) => Vec::new(),
+            };
+            sender.send(report).ok();
+        });
+    }
+
+    fn poll_bad_channels(&mut self) {
+        let Some(receiver) = &self.bad_detect_receiver else { return };
+        match receiver.try_recv() {
+            Ok(report) => {
+                for bad in &report {
+                    self.set_channel_bad(bad.channel, true);
+                }
+                self.bad_channel_status = Some(format!("{} channels flagged", self.eeg_info.bad_channels.len()));
+                self.bad_channel_report = report;
+                self.bad_detect_receiver = None;
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => self.bad_detect_receiver = None,
+        }
+    }
+
+    // Replace bad channels by a spherical spline of the good ones; interpolated channels are no longer bad
+    // in the dataset that was repaired, once the repair has succeeded
+    fn interpolate_bad_channels(&mut self, continuous: bool) {
+        let (info, n_channels) = if continuous {
+            (self.eeg_info.clone(), self.n_data_channels())
+        } else {
+            (self.epochs_info(), self.epochs.as_ref().map_or(0, EpochsData::n_channels))
+        };
+        let (matrix, interpolated) = match badchannels::interpolation_operator(&info, n_channels) {
+            Ok(result) => result,
+            Err(e) => {
+                self.bad_channel_status = Some(e.to_string());
+                return;
+            }
+        };
+        if continuous {
+            let (matrix_bv, matrix_edf) = (matrix.clone(), matrix);
+            self.spawn_continuous(
+                move |data| Ok(reference::apply_matrix(&matrix_bv, data)),
+                move |data| Ok(reference::apply_matrix(&matrix_edf, data)),
+            );
+            self.bad_channel_status = Some(format!("Interpolating {} channels", interpolated.len()));
+            self.pending_interpolation = interpolated;
+        } else {
+            let count = interpolated.len();
+            self.modify_epochs(|epochs| {
+                reference::apply_matrix_epochs(&matrix, epochs);
+                epochs.bad_channels.retain(|ch| !interpolated.contains(ch));
+                Ok(())
+            });
+            self.bad_channel_status = Some(format!("Interpolated {count} channels in the epochs"));
+        }
+    }
+
+    // Recording info with the epochs' own bad channels, for analyses of the epochs and the evoked response
+    fn epochs_info(&self) -> EEGInfo {
+        let mut info = self.eeg_info.clone();
+        if let Some(epochs) = &self.epochs {
+            info.bad_channels = epochs.bad_channels.clone();
+        }
+        info
+    }
+
+    fn annotations_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Bad segments");
+        ui.horizontal(|ui| {
+            ui.label("New segments are labelled");
+            ui.add(egui::TextEdit::singleline(&mut self.annotation_label).desired_width(100.0));
+        });
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        let mut remove = None;
+        egui::ScrollArea::vertical().id_salt("annotations").max_height(200.0).show(ui, |ui| {
+            for (idx, annotation) in self.eeg_markers.annotations.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    if ui.selectable_label(self.selected_annotation == Some(idx), format!("{}", idx + 1)).clicked() {
+                        self.selected_annotation = Some(idx);
+                        self.x_view = (annotation.onset / sfreq - 1.0).max(0.0);
+                    }
+                    let mut onset = annotation.onset / sfreq;
+                    let mut duration = annotation.duration / sfreq;
+                    if ui.add(egui::DragValue::new(&mut onset).speed(0.01).range(0.0..=f64::MAX).suffix(" s")).changed() {
+                        annotation.onset = onset * sfreq;
+                    }
+                    if ui.add(egui::DragValue::new(&mut duration).speed(0.01).range(0.0..=f64::MAX).suffix(" s")).changed() {
+                        annotation.duration = duration * sfreq;
+                    }
+                    ui.add(egui::TextEdit::singleline(&mut annotation.description).desired_width(100.0));
+                    if ui.small_button("✖").clicked() {
+                        remove = Some(idx);
+                    }
+                });
+            }
+        });
+        if let Some(idx) = remove {
+            self.eeg_markers.annotations.remove(idx);
+            self.selected_annotation = None;
+        }
+        if self.eeg_markers.annotations.is_empty() {
+            ui.label("No segments");
+        }
+    }
+
+    // Drag across the viewer to add a segment, click inside one to select it
+    fn annotation_interaction(&mut self, plot_ui: &egui_plot::PlotUi<'_>, sfreq: f64) {
+        let response = plot_ui.response();
+        let pointer_time = plot_ui.pointer_coordinate().map(|point| point.x);
+        if self.viewer_tool == ViewerTool::AnnotateSegments {
+            if response.drag_started() {
+                self.annotation_drag = pointer_time;
+            }
+            if response.drag_stopped() {
+                if let (Some(start), Some(end)) = (self.annotation_drag.take(), pointer_time) {
+                    let (start, end) = (start.min(end).max(0.0), start.max(end));
+                    if end > start {
+                        self.eeg_markers.annotations.push(Annotation {
+                            onset: start * sfreq,
+                            duration: (end - start) * sfreq,
+                            description: self.annotation_label.trim().to_owned(),
+                        });
+                        self.selected_annotation = Some(self.eeg_markers.annotations.len() - 1);
+                    }
+                }
+            }
+        }
+        if response.clicked() && self.viewer_tool != ViewerTool::EditMarkers {
+            if let Some(time) = pointer_time {
+                let sample = time * sfreq;
+                self.selected_annotation = self
+                    .eeg_markers
+                    .annotations
+                    .iter()
+                    .position(|annotation| annotation.onset <= sample && sample <= annotation.end());
+            }
+        }
+    }
+
+    fn draw_annotations(&self, plot_ui: &mut egui_plot::PlotUi<'_>, sfreq: f64, y_min: f64, y_max: f64) {
+        for (idx, annotation) in self.eeg_markers.annotations.iter().enumerate() {
+            let (start, end) = (annotation.onset / sfreq, annotation.end() / sfreq);
+            let alpha = if self.selected_annotation == Some(idx) { 90 } else { 45 };
+            let fill = if annotation.is_bad() {
+                Color32::from_rgba_unmultiplied(220, 60, 60, alpha)
+            } else {
+                Color32::from_rgba_unmultiplied(60, 140, 220, alpha)
+            };
+            let corners = vec![[start, y_min], [end, y_min], [end, y_max], [start, y_max]];
+            plot_ui.polygon(Polygon::new(format!("annotation_{idx}"), corners).fill_color(fill).stroke(Stroke::NONE));
+            plot_ui.text(Text::new(format!("annotation_label_{idx}"), PlotPoint::new((start + end) / 2.0, y_max), annotation.description.clone()));
+        }
+        if let (Some(start), Some(end)) = (self.annotation_drag, plot_ui.pointer_coordinate().map(|point| point.x)) {
+            let corners = vec![[start, y_min], [end, y_min], [end, y_max], [start, y_max]];
+            plot_ui.polygon(Polygon::new("annotation_drag", corners).fill_color(Color32::from_rgba_unmultiplied(220, 60, 60, 30)));
+        }
+    }
+
+    fn events_panel(&mut self, ctx: &egui::Context) {
+        egui::SidePanel::left("events_panel").min_width(260.0).show(ctx, |ui| {
+            ui.heading("Events");
+            egui::ComboBox::from_label("Type")
+                .selected_text(self.event_filter.clone().unwrap_or_else(|| "All".to_owned()))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.event_filter, None, "All");
+                    for event_type in self.eeg_markers.event_types() {
+                        ui.selectable_value(&mut self.event_filter, Some(event_type.clone()), event_type);
+                    }
+                });
+            ui.horizontal(|ui| {
+                if ui.button("◀ Previous (P)").clicked() {
+                    self.jump_to_event(false);
+                }
+                if ui.button("Next (N) ▶").clicked() {
+                    self.jump_to_event(true);
+                }
+            });
+            ui.separator();
+
+            let rows = self.filtered_events();
+            ui.label(format!("{} of {} events", rows.len(), self.eeg_markers.markers.len()));
+            let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+            let row_height = ui.text_style_height(&egui::TextStyle::Body);
+            egui::ScrollArea::vertical().id_salt("events_list").show_rows(ui, row_height, rows.len(), |ui, range| {
+                egui::Grid::new("events_grid").striped(true).show(ui, |ui| {
+                    for &idx in &rows[range] {
+                        let time = self.eeg_markers.markers[idx] / sfreq;
+                        let description = self.eeg_markers.descriptions[idx].clone();
+                        let event_type = match self.data_format {
+                            DataFormat::BrainVision => bvio::marker_type(&description),
+                            DataFormat::EDF => "Annotation",
+                        };
+                        let selected = self.selected_marker == Some(idx);
+                        if ui.selectable_label(selected, format!("{time:.3} s")).clicked() {
+                            self.selected_marker = Some(idx);
+                            self.center_view_on(time);
+                        }
+                        ui.label(event_type);
+                        ui.label(description);
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+    }
+
+    // Indices of the markers passing the type filter
+    fn filtered_events(&self) -> Vec<usize> {
+        self.eeg_markers
+            .descriptions
+            .iter()
+            .enumerate()
+            .filter(|(_, description)| self.event_filter.as_ref().is_none_or(|filter| filter == *description))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    fn center_view_on(&mut self, time: f64) {
+        self.x_view = (time - self.page_duration / 2.0).max(0.0);
+    }
+
+    // Select and centre the next or previous event of the filtered type, relative to the selected
+    // event or else the middle of the view
+    fn jump_to_event(&mut self, forward: bool) {
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        let current = self
+            .selected_marker
+            .and_then(|idx| self.eeg_markers.markers.get(idx).copied())
+            .unwrap_or((self.x_view + self.page_duration / 2.0) * sfreq);
+        let rows = self.filtered_events();
+        let target = if forward {
+            rows.into_iter().find(|&idx| self.eeg_markers.markers[idx] > current)
+        } else {
+            rows.into_iter().rev().find(|&idx| self.eeg_markers.markers[idx] < current)
+        };
+        if let Some(idx) = target {
+            self.selected_marker = Some(idx);
+            self.center_view_on(self.eeg_markers.markers[idx] / sfreq);
+        }
+    }
+
+    fn viewer_tool_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Mouse:");
+            ui.radio_value(&mut self.viewer_tool, ViewerTool::Navigate, "Navigate");
+            ui.radio_value(&mut self.viewer_tool, ViewerTool::AnnotateSegments, "Annotate segments");
+            ui.radio_value(&mut self.viewer_tool, ViewerTool::EditMarkers, "Edit markers");
+            match self.viewer_tool {
+                ViewerTool::Navigate => ui.label("Click a channel name to mark it bad"),
+                ViewerTool::AnnotateSegments => ui.label(format!("Drag to add a {} segment", self.annotation_label)),
+                ViewerTool::EditMarkers => ui.label("Click to add, drag to move, Delete to remove"),
+            };
+        });
+    }
+
+    fn markers_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Markers");
+        ui.horizontal(|ui| {
+            ui.label("New marker type:");
+            ui.add(egui::TextEdit::singleline(&mut self.new_marker_type).desired_width(80.0));
+            egui::ComboBox::from_id_salt("new_marker_type")
+                .selected_text("Existing")
+                .show_ui(ui, |ui| {
+                    for event_type in self.eeg_markers.event_types() {
+                        ui.selectable_value(&mut self.new_marker_type, event_type.clone(), event_type);
+                    }
+                });
+        });
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        if let Some(idx) = self.selected_marker.filter(|&idx| idx < self.eeg_markers.markers.len()) {
+            ui.horizontal(|ui| {
+                ui.label(format!("Marker {}:", idx + 1));
+                ui.add(egui::TextEdit::singleline(&mut self.eeg_markers.descriptions[idx]).desired_width(80.0));
+                let mut time = self.eeg_markers.markers[idx] / sfreq;
+                if ui.add(egui::DragValue::new(&mut time).speed(0.001).range(0.0..=f64::MAX).suffix(" s")).changed() {
+                    self.selected_marker = Some(self.eeg_markers.move_marker(idx, time * sfreq));
+                }
+                if ui.button("Delete").clicked() {
+                    self.eeg_markers.remove_marker(idx);
+                    self.selected_marker = None;
+                }
+            });
+        }
+
+        if self.marker_export_path.is_empty() {
+            self.marker_export_path = self.default_marker_export_path();
+        }
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.marker_export_path).desired_width(200.0));
+            let label = match self.data_format {
+                DataFormat::BrainVision => "Write .vmrk",
+                DataFormat::EDF => "Write EDF+",
+            };
+            if ui.button(label).on_hover_text("EDF+ export writes the data as currently processed").clicked() {
+                self.export_markers();
+            }
+        });
+        if let Some(status) = &self.marker_status {
+            ui.label(status);
+        }
+    }
+
+    // The loaded file's .vmrk (backed up before it is replaced), or <name>_edited.edf
+    fn default_marker_export_path(&self) -> String {
+        let Some(path) = &self.edf_file else { return String::new() };
+        match self.data_format {
+            DataFormat::BrainVision => path.with_extension("vmrk").to_string_lossy().into_owned(),
+            DataFormat::EDF => {
+                let stem = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+                path.with_file_name(format!("{stem}_edited.edf")).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    fn export_markers(&mut self) {
+        let path = self.marker_export_path.trim().to_owned();
+        let result = match self.data_format {
+            DataFormat::BrainVision => {
+                let data_file = self
+                    .edf_file
+                    .as_ref()
+                    .and_then(|file| file.with_extension("eeg").file_name().map(|name| name.to_string_lossy().into_owned()))
+                    .unwrap_or_default();
+                bvio::write_vmrk(&path, &data_file, &self.eeg_markers)
+            }
+            DataFormat::EDF => match &self.raw_eeg.edf_data {
+                Some(data) => edfio::write_edf_plus(&path, &self.eeg_info, data, &self.eeg_markers),
+                None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No EDF data loaded")),
+            },
+        };
+        self.marker_status = Some(match result {
+            Ok(()) => format!("Wrote {} markers and {} segments to {path}", self.eeg_markers.markers.len(), self.eeg_markers.annotations.len()),
+            Err(e) => format!("Error writing {path}: {e}"),
+        });
+    }
+
+    // Marker within a few pixels of the pointer
+    fn marker_near_pointer(&self, plot_ui: &egui_plot::PlotUi<'_>, sfreq: f64) -> Option<usize> {
+        let pointer = plot_ui.response().hover_pos()?;
+        let y = plot_ui.plot_from_screen(pointer).y;
+        self.eeg_markers.markers.iter().position(|&pos| {
+            (plot_ui.screen_from_plot(PlotPoint::new(pos / sfreq, y)).x - pointer.x).abs() < 6.0
+        })
+    }
+
+    // Edit-markers tool: click to add or select, drag to move, Delete removes the selected marker
+    fn marker_interaction(&mut self, plot_ui: &egui_plot::PlotUi<'_>, sfreq: f64) {
+        let response = plot_ui.response();
+        let pointer_sample = plot_ui.pointer_coordinate().map(|point| point.x.max(0.0) * sfreq);
+        if response.drag_started() {
+            self.marker_drag = self.marker_near_pointer(plot_ui, sfreq);
+            self.selected_marker = self.marker_drag;
+        }
+        if response.drag_stopped() {
+            if let (Some(idx), Some(sample)) = (self.marker_drag.take(), pointer_sample) {
+                self.selected_marker = Some(self.eeg_markers.move_marker(idx, sample.round()));
+            }
+        }
+        if response.clicked() {
+            self.selected_marker = match (self.marker_near_pointer(plot_ui, sfreq), pointer_sample) {
+                (Some(idx), _) => Some(idx),
+                (None, Some(sample)) => Some(self.eeg_markers.add_marker(sample.round(), self.new_marker_type.trim())),
+                (None, None) => self.selected_marker,
+            };
+        }
+        let ctx = plot_ui.ctx();
+        if !ctx.wants_keyboard_input() && ctx.input(|i| i.key_pressed(Key::Delete)) {
+            if let Some(idx) = self.selected_marker.take() {
+                self.eeg_markers.remove_marker(idx);
+            }
+        }
+    }
+
+    // Marker lines with their descriptions along the top; the selected or dragged marker is highlighted
+    fn draw_markers(&self, plot_ui: &mut egui_plot::PlotUi<'_>, sfreq: f64, label_y: f64) {
+        let (start, end) = (self.x_view * sfreq, (self.x_view + self.page_duration) * sfreq);
+        for (idx, (&pos, description)) in self.eeg_markers.markers.iter().zip(&self.eeg_markers.descriptions).enumerate() {
+            if pos < start || pos > end {
+                continue;
+            }
+            let time = pos / sfreq;
+            let selected = self.selected_marker == Some(idx);
+            let color = if selected { Color32::YELLOW } else { Color32::from_rgb(120, 160, 255) };
+            plot_ui.vline(VLine::new(format!("marker_{idx}"), time).color(color).width(if selected { 2.0 } else { 1.0 }));
+            plot_ui.text(Text::new(format!("marker_label_{idx}"), PlotPoint::new(time, label_y), description.clone()).color(color));
+        }
+        if let (Some(_), Some(pointer)) = (self.marker_drag, plot_ui.pointer_coordinate()) {
+            plot_ui.vline(VLine::new("marker_drag", pointer.x).color(Color32::YELLOW).style(egui_plot::LineStyle::dashed_loose()));
+        }
+    }
+
+    fn rereference_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Re-referencing");
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.rereference, Reference::Average, "Average");
+            if ui.radio(matches!(self.rereference, Reference::Channels(_)), "Channels").clicked() {
+                self.rereference = Reference::Channels(Vec::new());
+            }
+            ui.radio_value(&mut self.rereference, Reference::Rest, "REST");
+        });
+        match &self.rereference {
+            Reference::Average => {
+                ui.label(format!("{} bad channels left out of the average", self.eeg_info.bad_channels.len()));
+            }
+            Reference::Channels(_) => {
+                ui.horizontal(|ui| {
+                    ui.label("Mean of:");
+                    ui.text_edit_singleline(&mut self.reference_labels);
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Linked mastoids").clicked() {
+                        self.reference_labels = "TP9, TP10".to_owned();
+                    }
+                    let names: Vec<String> = self.eeg_info.ch_names.iter().take(self.n_data_channels()).cloned().collect();
+                    egui::ComboBox::from_id_salt("single_reference_channel")
+                        .selected_text("Single channel")
+                        .show_ui(ui, |ui| {
+                            for name in names {
+                                let label = positions::clean_label(&name);
+                                if ui.selectable_label(self.reference_labels == label, &label).clicked() {
+                                    self.reference_labels = label;
+                                }
+                            }
+                        });
+                });
+                self.rereference = Reference::Channels(ChannelGroup::from_labels("", &self.reference_labels).channels);
+            }
+            Reference::Rest => {
+                ui.label("Equivalent dipole layer in a spherical head; needs positions for all good channels");
+            }
+        }
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.add_reference_channel, "Add original reference as flat channel:");
+            ui.add(egui::TextEdit::singleline(&mut self.reference_channel_name).desired_width(60.0));
+        });
+        if ui.add_enabled(self.filtering_receiver.is_none(), egui::Button::new("Re-reference")).clicked() {
+            self.spawn_rereference();
+        }
+    }
+
+    fn spawn_rereference(&mut self) {
+        if self.add_reference_channel {
+            let name = self.reference_channel_name.trim().to_owned();
+            if let Some(data) = &mut self.raw_eeg.bv_data {
+                reference::add_flat_channel(&name, &mut self.eeg_info, data);
+            } else if let Some(data) = &mut self.raw_eeg.edf_data {
+                reference::add_flat_channel(&name, &mut self.eeg_info, data);
+            }
+            self.raw_eeg.number_of_channels = Some(self.n_data_channels());
+            self.channel_colors.push(self.global_color);
+            // Views built on the old channel count
+            self.average_view = None;
+            self.ssp_view = None;
+            self.ica_preview_cache = None;
+            self.refresh_montage();
+            // Added once; the flat channel now carries the reference
+            self.add_reference_channel = false;
+        }
+        let (scheme_bv, info_bv) = (self.rereference.clone(), self.eeg_info.clone());
+        let (scheme_edf, info_edf) = (self.rereference.clone(), self.eeg_info.clone());
+        self.spawn_continuous(
+            move |data| reference::rereference(&scheme_bv, &info_bv, data),
+            move |data| reference::rereference(&scheme_edf, &info_edf, data),
+        );
+    }
+
+    fn csd_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Current source density");
+        let previous = self.csd_params;
+        ui.add(egui::Slider::new(&mut self.csd_params.stiffness, 2..=10).text("Spline order m"));
+        ui.add(egui::Slider::new(&mut self.csd_params.lambda, 1e-10..=1e-2).logarithmic(true).text("Smoothing lambda"));
+        ui.add(egui::Slider::new(&mut self.csd_params.n_terms, 10..=200).text("Legendre terms"));
+        ui.add(egui::Slider::new(&mut self.csd_params.head_radius, 5.0..=15.0).text("Head radius").suffix(" cm"));
+        if self.csd_params != previous && self.montage_choice == MontageChoice::Csd {
+            self.refresh_montage();
+        }
+        ui.label("Output in data units per cm²; channels without a position and bad channels are left as they are");
+        ui.horizontal(|ui| {
+            let float_data = self.data_format == DataFormat::EDF;
+            if ui
+                .add_enabled(float_data && self.filtering_receiver.is_none(), egui::Button::new("CSD continuous"))
+                .on_disabled_hover_text("BrainVision samples are integers, too coarse for the CSD; use the epochs or the CSD montage")
+                .clicked()
+            {
+                let (params, info_bv, info_edf) = (self.csd_params, self.eeg_info.clone(), self.eeg_info.clone());
+                self.spawn_continuous(
+                    move |data| csd::csd_continuous(&params, &info_bv, data),
+                    move |data| csd::csd_continuous(&params, &info_edf, data),
+                );
+            }
+            if ui.add_enabled(self.epochs.is_some(), egui::Button::new("CSD epochs")).clicked() {
+                let (params, info) = (self.csd_params, self.eeg_info.clone());
+                self.modify_epochs(|epochs| csd::csd_epochs(&params, &info, epochs));
+            }
+        });
+    }
+
+    fn ica_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("ICA");
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.ica_params.method, IcaMethod::FastIca, "FastICA");
+            ui.radio_value(&mut self.ica_params.method, IcaMethod::Infomax, "Extended Infomax");
+        });
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.ica_source, DataSource::Continuous, "Continuous (without BAD segments)");
+            ui.add_enabled_ui(self.epochs.is_some(), |ui| {
+                ui.radio_value(&mut self.ica_source, DataSource::Epochs, "Good epochs");
+            });
+        });
+        ui.horizontal(|ui| {
+            let mut limit = self.ica_params.n_components.is_some();
+            if ui.checkbox(&mut limit, "PCA components").on_hover_text("Otherwise every component above the data rank tolerance").changed() {
+                self.ica_params.n_components = limit.then_some(self.ica_params.n_components.unwrap_or(20));
+            }
+            if let Some(n) = &mut self.ica_params.n_components {
+                ui.add(egui::DragValue::new(n).range(1..=256));
+            }
+            ui.add(egui::DragValue::new(&mut self.ica_params.max_iter).range(10..=5000).prefix("Max iterations: "));
+        });
+        let available = match self.ica_source {
+            DataSource::Continuous => self.n_samples() > 0,
+            DataSource::Epochs => self.epochs.is_some(),
+        };
+        ui.horizontal(|ui| {
+            if ui.add_enabled(available && self.ica_receiver.is_none(), egui::Button::new("Fit ICA")).clicked() {
+                self.spawn_ica();
+            }
+            if self.ica_receiver.is_some() {
+                ui.spinner();
+            }
+        });
+        if let Some(ica) = &self.ica {
+            ui.label(format!(
+                "{} components of {} channels, {} iterations{}; PCA left out {:.2}% of the variance",
+                ica.n_components(),
+                ica.channels.len(),
+                ica.n_iter,
+                if ica.converged { "" } else { " (did not converge)" },
+                100.0 * ica.pca_residual,
+            ));
+            egui::ScrollArea::vertical().id_salt("ica_components").max_height(150.0).show(ui, |ui| {
+                ui.horizontal_wrapped(|ui| {
+                    for (c, variance) in ica.explained_variance.iter().enumerate() {
+                        let mut excluded = self.ica_exclude.contains(&c);
+                        let label = format!("IC{} {:.1}%", c + 1, 100.0 * variance);
+                        if ui.checkbox(&mut excluded, label).changed() {
+                            if excluded {
+                                self.ica_exclude.push(c);
+                                self.ica_exclude.sort_unstable();
+                            } else {
+                                self.ica_exclude.retain(|&other| other != c);
+                            }
+                        }
+                    }
+                });
+            });
+            let any = !self.ica_exclude.is_empty();
+            self.ica_removal_ui(ui, any);
+        }
+        if let Some(status) = &self.ica_status {
+            ui.label(status);
+        }
+    }
+
+    fn ica_removal_ui(&mut self, ui: &mut egui::Ui, any: bool) {
+        ui.horizontal(|ui| {
+            ui.toggle_value(&mut self.show_ica_components, "Browse components");
+            ui.checkbox(&mut self.ica_preview, "Preview in viewer").on_hover_text("Draw the data without the marked components over the traces");
+        });
+        ui.horizontal(|ui| {
+            if ui.add_enabled(any && self.filtering_receiver.is_none(), egui::Button::new("Remove from continuous")).clicked() {
+                self.remove_ica_components(true);
+            }
+            if ui.add_enabled(any && self.epochs.is_some(), egui::Button::new("Remove from epochs")).clicked() {
+                self.remove_ica_components(false);
+            }
+        });
+    }
+
+    fn spawn_ica(&mut self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (params, info) = (self.ica_params, self.eeg_info.clone());
+        match self.ica_source {
+            DataSource::Continuous => {
+                let spans = self.eeg_markers.good_spans(self.n_samples());
+                let (bv_data, edf_data) = (self.raw_eeg.bv_data.clone(), self.raw_eeg.edf_data.clone());
+                std::thread::spawn(move || {
+                    let result = match (bv_data, edf_data) {
+                        (Some(data), _) => ica::fit_continuous(&params, &info, &data, &spans),
+                        (None, Some(data)) => ica::fit_continuous(&params, &info, &data, &spans),
+                        (None, None) => Err("No data loaded".into()),
+                    };
+                    sender.send(result.map_err(|e| e.to_string())).ok();
+                });
+            }
+            DataSource::Epochs => {
+                let Some(epochs) = self.epochs.clone() else { return };
+                std::thread::spawn(move || {
+                    sender.send(ica::fit_epochs(&params, &info, &epochs).map_err(|e| e.to_string())).ok();
+                });
+            }
+        }
+        self.ica_receiver = Some(receiver);
+        self.ica_status = Some("Fitting...".to_owned());
+    }
+
+    fn poll_ica(&mut self) {
+        let Some(receiver) = &self.ica_receiver else { return };
+        match receiver.try_recv() {
+            Ok(Ok(ica)) => {
+                self.ica_status = None;
+                let n_channels = self.n_data_channels();
+                let (picks, electrodes): (Vec<usize>, Vec<positions::Position>) = positions::channel_positions(&self.eeg_info, n_channels)
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(ch, pos)| Some((ica.channels.iter().position(|&fitted| fitted == ch)?, pos?)))
+                    .unzip();
+                self.ica_topomap = Topomap::new(&electrodes, Interpolation::SphericalSpline, 48).ok().map(|topomap| (topomap, picks));
+                self.ica_topo_textures.clear();
+                self.ica_sources = None;
+                self.ica_preview_cache = None;
+                self.ica = Some(ica);
+                self.ica_exclude.clear();
+                self.ica_receiver = None;
+                self.spawn_ica_properties();
+            }
+            Ok(Err(e)) => {
+                self.ica_status = Some(e);
+                self.ica_receiver = None;
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => self.ica_receiver = None,
+        }
+    }
+
+    fn spawn_ica_properties(&mut self) {
+        let Some(ica) = self.ica.clone() else { return };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (params, info, epochs) = (self.welch_params, self.eeg_info.clone(), self.epochs.clone());
+        let spans = self.eeg_markers.good_spans(self.n_samples());
+        let (bv_data, edf_data) = (self.raw_eeg.bv_data.clone(), self.raw_eeg.edf_data.clone());
+        std::thread::spawn(move || {
+            let result = match (bv_data, edf_data) {
+                (Some(data), _) => ica::component_properties(&ica, &params, &info, &data, &spans, epochs.as_ref()),
+                (None, Some(data)) => ica::component_properties(&ica, &params, &info, &data, &spans, epochs.as_ref()),
+                (None, None) => Err("No data loaded".into()),
+            };
+            sender.send(result.map_err(|e| e.to_string())).ok();
+        });
+        self.ica_properties_receiver = Some(receiver);
+    }
+
+    fn poll_ica_properties(&mut self) {
+        let Some(receiver) = &self.ica_properties_receiver else { return };
+        match receiver.try_recv() {
+            Ok(Ok(properties)) => {
+                self.ica_properties = Some(properties);
+                self.ica_properties_receiver = None;
+            }
+            Ok(Err(e)) => {
+                self.ica_status = Some(e);
+                self.ica_properties_receiver = None;
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => self.ica_properties_receiver = None,
+        }
+    }
+
+    // One row per component: topography, time course in the viewer window, spectrum and evoked response
+    fn ica_components_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_ica_components;
+        egui::Window::new("ICA components").open(&mut open).default_size([1000.0, 700.0]).show(ctx, |ui| {
+            let Some(n_components) = self.ica.as_ref().map(Ica::n_components) else {
+                ui.label("Fit ICA first");
+                return;
+            };
+            ui.horizontal(|ui| {
+                ui.label(format!("{} of {n_components} components marked", self.ica_exclude.len()));
+                if ui.add_enabled(self.ica_properties_receiver.is_none(), egui::Button::new("Refresh spectra and evoked")).clicked() {
+                    self.spawn_ica_properties();
+                }
+                if self.ica_properties_receiver.is_some() {
+                    ui.spinner();
+                }
+            });
+            let any = !self.ica_exclude.is_empty();
+            self.ica_removal_ui(ui, any);
+            ui.label("Click a map or its label to mark the component for removal");
+            ui.separator();
+            self.ensure_ica_views(ui.ctx());
+            egui::ScrollArea::vertical().id_salt("ica_component_rows").show_rows(ui, ICA_ROW_HEIGHT, n_components, |ui, rows| {
+                for c in rows {
+                    ui.horizontal(|ui| self.ica_component_row(ui, c));
+                }
+            });
+        });
+        self.show_ica_components = open;
+    }
+
+    // Component maps and the time courses of the viewer window, rebuilt when either is out of date
+    fn ensure_ica_views(&mut self, ctx: &egui::Context) {
+        let Some(ica) = &self.ica else { return };
+        if self.ica_topo_textures.len() != ica.n_components() {
+            self.ica_topo_textures = match &self.ica_topomap {
+                Some((topomap, picks)) => (0..ica.n_components())
+                    .map(|c| {
+                        let values: Vec<f64> = picks.iter().map(|&i| ica.mixing[(i, c)]).collect();
+                        let limit = values.iter().fold(0.0, |limit: f64, v| limit.max(v.abs())).max(f64::EPSILON);
+                        let image = plots::heatmap_image(&topomap.grid(&values), |v| {
+                            if v.is_finite() { plots::diverging_color(v, limit) } else { Color32::TRANSPARENT }
+                        });
+                        ctx.load_texture(format!("ica_topomap_{c}"), image, egui::TextureOptions::LINEAR)
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+        }
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        let window = ((self.x_view.max(0.0) * sfreq) as usize, ((self.x_view + self.page_duration).max(0.0) * sfreq) as usize);
+        if self.ica_sources.as_ref().map(|(key, _)| *key) != Some(window) {
+            let sources = match self.data_format {
+                DataFormat::EDF => self.raw_eeg.edf_data.as_ref().map(|data| ica.sources(&self.eeg_info, data, window.0, window.1)),
+                DataFormat::BrainVision => self.raw_eeg.bv_data.as_ref().map(|data| ica.sources(&self.eeg_info, data, window.0, window.1)),
+            };
+            self.ica_sources = sources.map(|sources| (window, sources));
+        }
+    }
+
+    fn ica_component_row(&mut self, ui: &mut egui::Ui, c: usize) {
+        let Some(ica) = &self.ica else { return };
+        let marked = self.ica_exclude.contains(&c);
+        let mut toggle = false;
+        ui.vertical(|ui| {
+            let text = egui::RichText::new(format!("IC{} {:.1}%", c + 1, 100.0 * ica.explained_variance[c]));
+            let text = if marked { text.color(BAD_CHANNEL_COLOR).strong() } else { text };
+            toggle |= ui.selectable_label(marked, text).clicked();
+            let size = ICA_ROW_HEIGHT - 30.0;
+            if let Some(texture) = self.ica_topo_textures.get(c) {
+                let image = egui::Image::new(egui::load::SizedTexture::new(texture.id(), Vec2::splat(size))).sense(egui::Sense::click());
+                let response = ui.add(image);
+                toggle |= response.clicked();
+                if let Some((topomap, _)) = &self.ica_topomap {
+                    let stroke = Stroke::new(1.0, if marked { BAD_CHANNEL_COLOR } else { Color32::GRAY });
+                    ui.painter().circle_stroke(response.rect.center(), size / (2.0 * topomap.extent) as f32, stroke);
+                }
+            } else {
+                ui.add_space(size);
+            }
+        });
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        let height = ICA_ROW_HEIGHT - 10.0;
+        Plot::new(("ica_time_course", c)).width(420.0).height(height).show_y(false).allow_scroll(false).show(ui, |plot_ui| {
+            if let Some(((start, _), sources)) = &self.ica_sources {
+                if c < sources.nrows() {
+                    let trace = sources.row(c).to_vec();
+                    let points = Self::min_max_decimate(&trace, *start, self.decimation_factor, 0.0, 1.0, sfreq);
+                    plot_ui.line(Line::new("Time course", points).color(self.global_color));
+                }
+            }
+        });
+        let properties = self.ica_properties.as_ref();
+        Plot::new(("ica_spectrum", c)).width(220.0).height(height).allow_scroll(false).show(ui, |plot_ui| {
+            if let Some(psd) = properties.map(|properties| &properties.psd) {
+                let points: Vec<[f64; 2]> = psd
+                    .freqs
+                    .iter()
+                    .zip(psd.power.get(c).into_iter().flatten())
+                    .take_while(|(f, _)| **f <= self.spectrum_fmax)
+                    .map(|(&f, &p)| [f, 10.0 * p.max(f64::MIN_POSITIVE).log10()])
+                    .collect();
+                plot_ui.line(Line::new("Spectrum (dB)", points).color(Color32::from_rgb(100, 150, 255)));
+            }
+        });
+        Plot::new(("ica_evoked", c)).width(220.0).height(height).allow_scroll(false).show(ui, |plot_ui| {
+            if let Some(evoked) = properties.and_then(|properties| properties.evoked.as_ref()) {
+                if c < evoked.evoked.nrows() {
+                    let points: Vec<[f64; 2]> = evoked.evoked.row(c).iter().enumerate().map(|(t, &v)| [evoked.tmin + t as f64 / evoked.sfreq, v]).collect();
+                    plot_ui.line(Line::new("Evoked", points).color(Color32::from_rgb(90, 200, 120)));
+                    plot_ui.vline(VLine::new("TMS", 0.0).color(Color32::GRAY));
+                }
+            }
+        });
+        if toggle {
+            if marked {
+                self.ica_exclude.retain(|&other| other != c);
+            } else {
+                self.ica_exclude.push(c);
+                self.ica_exclude.sort_unstable();
+            }
+        }
+    }
+
+    // The marked components removed from the visible window, drawn over the traces
+    fn draw_ica_preview(
+        &mut self,
+        plot_ui: &mut egui_plot::PlotUi<'_>,
+        label_rows: &[(usize, f64)],
+        rows: std::ops::Range<usize>,
+        window: (usize, usize),
+        sampling_frequency: f64,
+    ) {
+        let Some(ica) = &self.ica else { return };
+        if self.ica_exclude.is_empty() {
+            return;
+        }
+        let key = (window.0, window.1, self.ica_exclude.clone(), self.averaged_view());
+        if self.ica_preview_cache.as_ref().map(|(k, _)| k) != Some(&key) {
+            // Clean the data the decomposition was fitted on, then re-reference like the viewer
+            let (exclude, info) = (&self.ica_exclude, &self.eeg_info);
+            let cleaned = match self.data_format {
+                DataFormat::EDF => self.raw_eeg.edf_data.as_ref().map(|data| ica.cleaned_window(exclude, info, data, window.0, window.1)),
+                DataFormat::BrainVision => self.raw_eeg.bv_data.as_ref().map(|data| ica.cleaned_window(exclude, info, data, window.0, window.1)),
+            };
+            let cleaned = if key.3.is_some() {
+                let average = reference::reference_matrix(&Reference::Average, info, self.n_data_channels()).ok();
+                cleaned
+                    .zip(average)
+                    .filter(|(cleaned, average)| cleaned.len() == average.nrows())
+                    .map(|(cleaned, average)| ssp::project_window(&average, &cleaned, 0, cleaned.first().map_or(0, Vec::len)))
+            } else {
+                cleaned
+            };
+            self.ica_preview_cache = cleaned.map(|cleaned| (key, cleaned));
+        }
+        let Some((_, cleaned)) = &self.ica_preview_cache else { return };
+        let value = |ch: usize, t: usize| cleaned.get(ch).and_then(|trace| trace.get(t)).copied().unwrap_or(0.0);
+        let n_samples = cleaned.first().map_or(0, Vec::len);
+        let traces: Vec<(usize, f64, Vec<f64>)> = match &self.montage {
+            Some(montage) => montage.weights[rows.start.min(montage.weights.len())..rows.end.min(montage.weights.len())]
+                .iter()
+                .enumerate()
+                .map(|(row, weights)| {
+                    let trace = (0..n_samples).map(|t| weights.iter().map(|&(ch, weight)| weight * value(ch, t)).sum()).collect();
+                    (weights.first().map_or(0, |&(ch, _)| ch), row as f64 * CHANNEL_SPACING, trace)
+                })
+                .collect(),
+            None => label_rows.iter().map(|&(ch, offset)| (ch, offset, cleaned.get(ch).cloned().unwrap_or_default())).collect(),
+        };
+        for (ch, offset, trace) in traces {
+            let points = Self::min_max_decimate(&trace, window.0, self.decimation_factor, offset, self.plot_scale(ch), sampling_frequency);
+            plot_ui.line(Line::new("ICA preview", points).color(ICA_PREVIEW_COLOR));
+        }
+    }
+
+    fn ssp_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("SSP projectors");
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.ssp_source, DataSource::Continuous, "Segments labelled");
+            ui.add(egui::TextEdit::singleline(&mut self.ssp_label).desired_width(100.0));
+        });
+        ui.horizontal(|ui| {
+            ui.add_enabled_ui(self.epochs.is_some(), |ui| {
+                ui.radio_value(&mut self.ssp_source, DataSource::Epochs, "Epoch window");
+            });
+            let (mut tmin, mut tmax) = (self.ssp_tmin * 1000.0, self.ssp_tmax * 1000.0);
+            if ui.add(egui::DragValue::new(&mut tmin).speed(1.0).suffix(" ms")).changed() {
+                self.ssp_tmin = tmin / 1000.0;
+            }
+            if ui.add(egui::DragValue::new(&mut tmax).speed(1.0).suffix(" ms")).changed() {
+                self.ssp_tmax = tmax / 1000.0;
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.ssp_n_vectors).range(1..=10).prefix("Vectors: "));
+            let available = match self.ssp_source {
+                DataSource::Continuous => self.n_samples() > 0,
+                DataSource::Epochs => self.epochs.is_some(),
+            };
+            if ui.add_enabled(available, egui::Button::new("Compute")).clicked() {
+                self.compute_projectors();
+            }
+        });
+        let mut remove = None;
+        for (idx, projector) in self.projectors.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                let label = format!("{} ({:.1}%)", projector.name, 100.0 * projector.explained);
+                if ui.checkbox(&mut projector.active, label).on_hover_text("Active projectors apply in the viewer").changed() {
+                    self.ssp_version += 1;
+                }
+                if ui.small_button("✖").clicked() {
+                    remove = Some(idx);
+                }
+            });
+        }
+        if let Some(idx) = remove {
+            self.projectors.remove(idx);
+            self.ssp_version += 1;
+        }
+        let any = self.projectors.iter().any(|projector| projector.active);
+        ui.horizontal(|ui| {
+            if ui.add_enabled(any && self.filtering_receiver.is_none(), egui::Button::new("Apply to continuous")).clicked() {
+                let (projectors, info) = (self.projectors.clone(), self.eeg_info.clone());
+                let (projectors_edf, info_edf) = (projectors.clone(), info.clone());
+                self.spawn_continuous(
+                    move |data| Ok(ssp::apply_projectors(&projectors, &info, data)),
+                    move |data| Ok(ssp::apply_projectors(&projectors_edf, &info_edf, data)),
+                );
+            }
+            if ui.add_enabled(any && self.epochs.is_some(), egui::Button::new("Apply to epochs")).clicked() {
+                let (projectors, info) = (self.projectors.clone(), self.eeg_info.clone());
+                self.modify_epochs(|epochs| {
+                    ssp::apply_projectors_epochs(&projectors, &info, epochs);
+                    Ok(())
+                });
+            }
+        });
+        ui.label("Projecting twice changes nothing, so applied projectors can stay active");
+        if let Some(status) = &self.ssp_status {
+            ui.label(status);
+        }
+    }
+
+    fn compute_projectors(&mut self) {
+        let (name, n_vectors) = (self.ssp_label.trim().to_owned(), self.ssp_n_vectors);
+        let result = match self.ssp_source {
+            DataSource::Continuous => {
+                let spans = self.eeg_markers.labelled_spans(&name, self.n_samples());
+                match (&self.raw_eeg.bv_data, &self.raw_eeg.edf_data) {
+                    (Some(data), _) => ssp::projectors_continuous(&name, n_vectors, &self.eeg_info, data, &spans),
+                    (None, Some(data)) => ssp::projectors_continuous(&name, n_vectors, &self.eeg_info, data, &spans),
+                    (None, None) => Err("No data loaded".into()),
+                }
+            }
+            DataSource::Epochs => match &self.epochs {
+                Some(epochs) => {
+                    let name = format!("{:.0}-{:.0} ms", self.ssp_tmin * 1000.0, self.ssp_tmax * 1000.0);
+                    ssp::projectors_epochs(&name, n_vectors, &self.epochs_info(), epochs, self.ssp_tmin, self.ssp_tmax)
+                }
+                None => Err("Create epochs first".into()),
+            },
+        };
+        match result {
+            Ok(projectors) => {
+                self.ssp_status = Some(format!("Added {} projectors", projectors.len()));
+                self.projectors.extend(projectors);
+                self.ssp_version += 1;
+            }
+            Err(e) => self.ssp_status = Some(e.to_string()),
+        }
+    }
+
+    // Project the viewer window when any projector is active; montages read it instead of the data
+    fn refresh_ssp_view(&mut self, start: usize, end: usize) {
+        if !self.projectors.iter().any(|projector| projector.active) {
+            self.ssp_view = None;
+            return;
+        }
+        let averaged = self.averaged_view();
+        let key = (start, end, self.ssp_version, averaged.clone());
+        if self.ssp_view.as_ref().map(|(k, _)| k) == Some(&key) {
+            return;
+        }
+        let n_channels = self.n_data_channels();
+        let mut matrix = ssp::projection_matrix(&self.projectors, &self.eeg_info, n_channels);
+        if averaged.is_some() {
+            let Ok(average) = reference::reference_matrix(&Reference::Average, &self.eeg_info, n_channels) else {
+                self.ssp_view = None;
+                return;
+            };
+            // Project the data the projectors were computed on, then re-reference like the viewer
+            matrix = average * matrix;
+        }
+        let window = match self.data_format {
+            DataFormat::EDF => self.raw_eeg.edf_data
+                .as_ref()
+                .filter(|data| data.len() == n_channels)
+                .map(|data| ssp::project_window(&matrix, data, start, end)),
+            DataFormat::BrainVision => self.raw_eeg.bv_data
+                .as_ref()
+                .filter(|data| data.len() == n_channels)
+                .map(|data| ssp::project_window(&matrix, data, start, end)),
+        };
+        self.ssp_view = window.map(|window| (key, window));
+    }
+
+    // Bad channels when the viewer shows the average reference, which leaves them out
+    fn averaged_view(&self) -> Option<Vec<usize>> {
+        (self.montage.is_none() && self.reference_type == ReferenceType::AverageReference).then(|| self.eeg_info.bad_channels.clone())
+    }
+
+    // Average-reference the viewer window from the current data, so it follows every processing step
+    fn refresh_average_view(&mut self, start: usize, end: usize) {
+        let Some(bad) = self.averaged_view() else {
+            self.average_view = None;
+            return;
+        };
+        let key = (start, end, bad);
+        if self.average_view.as_ref().map(|(k, _)| k) == Some(&key) {
+            return;
+        }
+        let n_channels = self.n_data_channels();
+        let Ok(matrix) = reference::reference_matrix(&Reference::Average, &self.eeg_info, n_channels) else {
+            self.average_view = None;
+            return;
+        };
+        let window = match self.data_format {
+            DataFormat::EDF => self.raw_eeg.edf_data.as_ref().map(|data| ssp::project_window(&matrix, data, start, end)),
+            DataFormat::BrainVision => self.raw_eeg.bv_data.as_ref().map(|data| ssp::project_window(&matrix, data, start, end)),
+        };
+        self.average_view = window.map(|window| (key, window));
+    }
+
+    // Subtract the marked components; the decomposition is kept so more can be removed later
+    fn remove_ica_components(&mut self, continuous: bool) {
+        let Some(ica) = self.ica.clone() else { return };
+        let (exclude, info) = (self.ica_exclude.clone(), self.eeg_info.clone());
+        if continuous {
+            let (ica_edf, exclude_edf, info_edf) = (ica.clone(), exclude.clone(), info.clone());
+            self.spawn_continuous(
+                move |data| Ok(ica::remove_components(&ica, &exclude, &info, data)),
+                move |data| Ok(ica::remove_components(&ica_edf, &exclude_edf, &info_edf, data)),
+            );
+        } else {
+            self.modify_epochs(|epochs| {
+                ica::remove_components_epochs(&ica, &exclude, &info, epochs);
+                Ok(())
+            });
+        }
+        self.ica_status = Some(format!("Removed {} components", self.ica_exclude.len()));
+    }
+
+    fn denoise_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("SOUND / SSP-SIR");
+        ui.horizontal(|ui| {
+            ui.label("Spherical head:");
+            ui.add(egui::DragValue::new(&mut self.head_model.source_radius).range(0.5..=0.95).speed(0.01).prefix("Source radius: "));
+            ui.add(egui::DragValue::new(&mut self.head_model.n_locations).range(50..=5000).prefix("Locations: "));
+        });
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.denoise_source, DataSource::Continuous, "Continuous (without BAD segments)");
+            ui.add_enabled_ui(self.epochs.is_some(), |ui| {
+                ui.radio_value(&mut self.denoise_source, DataSource::Epochs, "Good epochs");
+            });
+        });
+        let available = self.denoise_receiver.is_none()
+            && match self.denoise_source {
+                DataSource::Continuous => self.n_samples() > 0,
+                DataSource::Epochs => self.epochs.is_some(),
+            };
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.sound_params.lambda).range(0.001..=10.0).speed(0.01).prefix("λ: "));
+            ui.add(egui::DragValue::new(&mut self.sound_params.max_iter).range(1..=100).prefix("Iterations: "));
+            if ui.add_enabled(available, egui::Button::new("Run SOUND")).on_hover_text("Estimate per-channel noise and rebuild every channel from the others").clicked() {
+                self.spawn_denoise(DenoiseMethod::Sound(self.sound_params));
+            }
+        });
+        ui.horizontal(|ui| {
+            let params = &mut self.ssp_sir_params;
+            ui.add(egui::DragValue::new(&mut params.n_components).range(1..=20).prefix("Components: "));
+            ui.add(egui::DragValue::new(&mut params.lambda).range(0.0001..=10.0).speed(0.001).prefix("λ: "));
+            ui.add(egui::DragValue::new(&mut params.highpass).range(1.0..=1000.0).suffix(" Hz high-pass"));
+        });
+        ui.horizontal(|ui| {
+            ui.add_enabled_ui(self.denoise_source == DataSource::Epochs, |ui| {
+                let (mut tmin, mut tmax) = (self.ssp_sir_params.tmin * 1000.0, self.ssp_sir_params.tmax * 1000.0);
+                if ui.add(egui::DragValue::new(&mut tmin).speed(1.0).prefix("Artefact ").suffix(" ms")).changed() {
+                    self.ssp_sir_params.tmin = tmin / 1000.0;
+                }
+                if ui.add(egui::DragValue::new(&mut tmax).speed(1.0).suffix(" ms")).changed() {
+                    self.ssp_sir_params.tmax = tmax / 1000.0;
+                }
+            });
+            if ui.add_enabled(available, egui::Button::new("Run SSP-SIR")).on_hover_text("Project out the leading high-frequency components and reconstruct through the head model").clicked() {
+                self.spawn_denoise(DenoiseMethod::SspSir(self.ssp_sir_params));
+            }
+            if self.denoise_receiver.is_some() {
+                ui.spinner();
+            }
+        });
+        if let Some(denoising) = &self.denoising {
+            let noise = match &denoising.report {
+                DenoiseReport::Sound(report) => {
+                    ui.label(format!(
+                        "SOUND on {} channels, {} iterations{}",
+                        denoising.channels.len(),
+                        report.changes.len(),
+                        if report.converged { "" } else { " (did not converge)" },
+                    ));
+                    Some(&report.noise)
+                }
+                DenoiseReport::SspSir(report) => {
+                    let projected: f64 = report.artefact_variance.iter().take(report.n_projected).sum();
+                    ui.label(format!(
+                        "SSP-SIR on {} channels, {} components holding {:.1}% of the high-passed variance projected",
+                        denoising.channels.len(),
+                        report.n_projected,
+                        100.0 * projected,
+                    ));
+                    None
+                }
+            };
+            egui::ScrollArea::vertical().id_salt("denoise_channels").max_height(150.0).show(ui, |ui| {
+                egui::Grid::new("denoise_grid").striped(true).show(ui, |ui| {
+                    ui.label("Channel");
+                    if noise.is_some() {
+                        ui.label("Noise (µV)");
+                    }
+                    ui.label("Removed");
+                    ui.end_row();
+                    for (i, (name, removed)) in denoising.ch_names.iter().zip(&denoising.removed).enumerate() {
+                        ui.label(name);
+                        if let Some(noise) = noise {
+                            ui.label(format!("{:.2}", noise[i]));
+                        }
+                        ui.label(format!("{:.1}%", 100.0 * removed));
+                        ui.end_row();
+                    }
+                });
+            });
+            ui.horizontal(|ui| {
+                if ui.add_enabled(self.filtering_receiver.is_none(), egui::Button::new("Apply to continuous")).clicked() {
+                    self.apply_denoising(true);
+                }
+                if ui.add_enabled(self.epochs.is_some(), egui::Button::new("Apply to epochs")).clicked() {
+                    self.apply_denoising(false);
+                }
+            });
+        }
+        if let Some(status) = &self.denoise_status {
+            ui.label(status);
+        }
+    }
+
+    fn spawn_denoise(&mut self, method: DenoiseMethod) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (model, info) = (self.head_model, self.eeg_info.clone());
+        match self.denoise_source {
+            DataSource::Continuous => {
+                let spans = self.eeg_markers.good_spans(self.n_samples());
+                let (bv_data, edf_data) = (self.raw_eeg.bv_data.clone(), self.raw_eeg.edf_data.clone());
+                std::thread::spawn(move || {
+                    let result = match (bv_data, edf_data) {
+                        (Some(data), _) => denoise::denoise_continuous(&method, &model, &info, &data, &spans),
+                        (None, Some(data)) => denoise::denoise_continuous(&method, &model, &info, &data, &spans),
+                        (None, None) => Err("No data loaded".into()),
+                    };
+                    sender.send(result.map_err(|e| e.to_string())).ok();
+                });
+            }
+            DataSource::Epochs => {
+                let Some(epochs) = self.epochs.clone() else { return };
+                std::thread::spawn(move || {
+                    sender.send(denoise::denoise_epochs(&method, &model, &info, &epochs).map_err(|e| e.to_string())).ok();
+                });
+            }
+        }
+        self.denoise_receiver = Some(receiver);
+        self.denoise_status = Some("Estimating...".to_owned());
+    }
+
+    fn poll_denoise(&mut self) {
+        let Some(receiver) = &self.denoise_receiver else { return };
+        match receiver.try_recv() {
+            Ok(Ok(denoising)) => {
+                self.denoise_status = None;
+                self.denoising = Some(denoising);
+                self.denoise_receiver = None;
+            }
+            Ok(Err(e)) => {
+                self.denoise_status = Some(e);
+                self.denoise_receiver = None;
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => self.denoise_receiver = None,
+        }
+    }
+
+    // The matrix comes from the data it was estimated on; applying it again would clean twice
+    fn apply_denoising(&mut self, continuous: bool) {
+        let Some(denoising) = self.denoising.take() else { return };
+        if continuous {
+            let denoising_edf = denoising.clone();
+            self.spawn_continuous(
+                move |data| Ok(denoise::apply_denoising(&denoising, data)),
+                move |data| Ok(denoise::apply_denoising(&denoising_edf, data)),
+            );
+        } else {
+            self.modify_epochs(|epochs| {
+                denoise::apply_denoising_epochs(&denoising, epochs);
+                Ok(())
+            });
+        }
+        self.denoise_status = Some("Applied".to_owned());
+    }
+
+    fn montage_ui(&mut self, ui: &mut egui::Ui) {
+        let previous = self.montage_choice;
+        egui::ComboBox::from_label("Montage")
+            .selected_text(format!("{:?}", self.montage_choice))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.montage_choice, MontageChoice::Raw, "Channels as recorded");
+                ui.selectable_value(&mut self.montage_choice, MontageChoice::DoubleBanana, "Double banana");
+                ui.selectable_value(&mut self.montage_choice, MontageChoice::Transverse, "Transverse");
+                ui.selectable_value(&mut self.montage_choice, MontageChoice::Laplacian, "Laplacian (Hjorth)");
+                ui.selectable_value(&mut self.montage_choice, MontageChoice::Csd, "CSD (spherical spline)");
+                ui.selectable_value(&mut self.montage_choice, MontageChoice::Custom, "Custom");
+            });
+        if self.montage_choice != previous {
+            self.refresh_montage();
+        }
+        if self.montage_choice == MontageChoice::Custom {
+            ui.label("One derivation per line: Fp1 - F7, or C3 - 0.25*FC3 - 0.25*CP3 - 0.25*C1 - 0.25*C5");
+            ui.add(egui::TextEdit::multiline(&mut self.custom_montage).desired_rows(6).code_editor());
+            if ui.button("Apply montage").clicked() {
+                self.refresh_montage();
+            }
+        } else if let Some(montage) = self.build_montage() {
+            if ui.button("Edit as custom").clicked() {
+                self.custom_montage = montage.to_text();
+                self.montage_choice = MontageChoice::Custom;
+                self.refresh_montage();
+            }
+        }
+        if let Some(error) = &self.montage_error {
+            ui.colored_label(Color32::RED, error);
+        }
+        if let Some(montage) = self.montage.as_ref().filter(|m| !m.skipped.is_empty()) {
+            ui.label(format!("Missing channels for: {}", montage.skipped.join(", ")));
+        }
+    }
+
+    fn build_montage(&self) -> Option<Montage> {
+        let ch_names: Vec<String> = self.eeg_info.ch_names.iter().take(self.n_data_channels()).cloned().collect();
+        match self.montage_choice {
+            MontageChoice::Raw | MontageChoice::Custom => None,
+            MontageChoice::DoubleBanana => Some(Montage::double_banana()),
+            MontageChoice::Transverse => Some(Montage::transverse()),
+            MontageChoice::Laplacian => {
+                let positions = positions::channel_positions(&self.eeg_info, ch_names.len());
+                Some(Montage::laplacian(&ch_names, &positions, 4))
+            }
+            MontageChoice::Csd => csd::csd_montage(&self.csd_params, &self.eeg_info, &ch_names).ok(),
+        }
+    }
+
+    // Resolve the chosen montage against the loaded channels; None shows the channels as recorded
+    fn refresh_montage(&mut self) {
+        self.montage_error = None;
+        let montage = match self.montage_choice {
+            MontageChoice::Custom => match Montage::parse("Custom", &self.custom_montage) {
+                Ok(montage) => Some(montage),
+                Err(e) => {
+                    self.montage_error = Some(e.to_string());
+                    None
+                }
+            },
+            MontageChoice::Csd => {
+                let ch_names: Vec<String> = self.eeg_info.ch_names.iter().take(self.n_data_channels()).cloned().collect();
+                match csd::csd_montage(&self.csd_params, &self.eeg_info, &ch_names) {
+                    Ok(montage) => Some(montage),
+                    Err(e) => {
+                        self.montage_error = Some(e.to_string());
+                        None
+                    }
+                }
+            }
+            _ => self.build_montage(),
+        };
+        let ch_names: Vec<String> = self.eeg_info.ch_names.iter().take(self.n_data_channels()).cloned().collect();
+        self.montage = montage.map(|montage| montage.resolve(&ch_names));
+    }
+
+    // Decimated, offset traces of the montage derivations in `rows` for the visible samples.
+    // A derivation is scaled with the resolution of its first channel.
+    fn montage_points(&self, rows: std::ops::Range<usize>, start_sample: usize, end_sample: usize, sampling_frequency: f64) -> Vec<(String, Vec<[f64; 2]>)> {
+        let Some(montage) = &self.montage else { return Vec::new() };
+        let rows = rows.start.min(montage.names.len())..rows.end.min(montage.names.len());
+        let page = ResolvedMontage {
+            names: montage.names[rows.clone()].to_vec(),
+            weights: montage.weights[rows].to_vec(),
+            skipped: Vec::new(),
+        };
+        let traces = match (&self.ssp_view, self.data_format) {
+            (Some((_, projected)), _) => Some(page.apply_window(projected, 0, end_sample.saturating_sub(start_sample))),
+            (None, DataFormat::EDF) => self.raw_eeg.edf_data.as_ref().map(|data| page.apply_window(data, start_sample, end_sample)),
+            (None, DataFormat::BrainVision) => self.raw_eeg.bv_data.as_ref().map(|data| page.apply_window(data, start_sample, end_sample)),
+        };
+        page
+            .names
+            .iter()
+            .zip(&page.weights)
+            .zip(traces.unwrap_or_default())
+            .enumerate()
+            .map(|(row, ((name, weights), trace))| {
+                let scale = self.plot_scale(weights.first().map_or(0, |&(ch, _)| ch));
+                let points = Self::min_max_decimate(&trace, start_sample, self.decimation_factor, row as f64 * CHANNEL_SPACING, scale, sampling_frequency);
+                (name.clone(), points)
+            })
+            .collect()
+    }
+
+    // Traces in the continuous view: montage derivations, or the selected channels
+    fn n_viewer_rows(&self) -> usize {
+        match &self.montage {
+            Some(montage) => montage.names.len(),
+            None => (0..self.n_data_channels()).filter(|ch| !self.unselected_channels.contains(ch)).count(),
+        }
+    }
+
+    fn n_channel_pages(&self) -> usize {
+        match self.channels_per_page {
+            0 => 1,
+            per_page => self.n_viewer_rows().div_ceil(per_page).max(1),
+        }
+    }
+
+    // Rows of the current channel page
+    fn page_rows(&self) -> std::ops::Range<usize> {
+        let n_rows = self.n_viewer_rows();
+        if self.channels_per_page == 0 {
+            return 0..n_rows;
+        }
+        let start = (self.channel_page.min(self.n_channel_pages() - 1) * self.channels_per_page).min(n_rows);
+        start..(start + self.channels_per_page).min(n_rows)
+    }
+
+    // The next larger or smaller entry of `steps`, for values between steps too
+    fn step_value(value: f64, steps: &[f64], up: bool) -> f64 {
+        let next = if up {
+            steps.iter().find(|&&step| step > value * 1.0001)
+        } else {
+            steps.iter().rev().find(|&&step| step < value * 0.9999)
+        };
+        next.copied().unwrap_or(value)
+    }
+
+    fn display_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.page_duration).range(0.1..=600.0).speed(0.1).prefix("Page: ").suffix(" s"));
+            ui.add(egui::DragValue::new(&mut self.sensitivity).range(0.1..=10000.0).speed(1.0).suffix(" µV/div"));
+            ui.add(egui::DragValue::new(&mut self.channels_per_page).range(0..=256).prefix("Channels per page: "))
+                .on_hover_text("0 shows every channel");
+            let n_pages = self.n_channel_pages();
+            self.channel_page = self.channel_page.min(n_pages - 1);
+            if ui.button("◀").clicked() {
+                self.channel_page = self.channel_page.saturating_sub(1);
+            }
+            ui.label(format!("Channels {} / {n_pages}", self.channel_page + 1));
+            if ui.button("▶").clicked() && self.channel_page + 1 < n_pages {
+                self.channel_page += 1;
+            }
+            ui.checkbox(&mut self.show_scale_bar, "Scale bar");
+        });
+        ui.label("←/→ or H/L: page   [ / ]: page duration   ↑/↓: sensitivity   PageUp/PageDown or K/J: channel page");
+    }
+
+    // Short-time spectra of the chosen channels under the viewer, following x_view and the page duration
+    fn spectrogram_ui(&mut self, ui: &mut egui::Ui) {
+        let names: Vec<String> = self.eeg_info.ch_names.iter().take(self.n_data_channels()).cloned().collect();
+        self.spectrogram_channels.retain(|&ch| ch < names.len());
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.show_spectrogram, "Spectrogram");
+            if !self.show_spectrogram {
+                return;
+            }
+            egui::ComboBox::from_id_salt("spectrogram_channels")
+                .selected_text(format!("{} channels", self.spectrogram_channels.len()))
+                .show_ui(ui, |ui| {
+                    for (ch, name) in names.iter().enumerate() {
+                        let mut shown = self.spectrogram_channels.contains(&ch);
+                        if ui.checkbox(&mut shown, name).changed() {
+                            if shown {
+                                self.spectrogram_channels.push(ch);
+                                self.spectrogram_channels.sort_unstable();
+                            } else {
+                                self.spectrogram_channels.retain(|&other| other != ch);
+                            }
+                        }
+                    }
+                });
+            let params = &mut self.spectrogram_params;
+            ui.add(egui::DragValue::new(&mut params.segment_seconds).range(0.05..=30.0).speed(0.05).prefix("Window: ").suffix(" s"));
+            ui.add(egui::DragValue::new(&mut params.overlap).range(0.0..=0.95).speed(0.01).prefix("Overlap: "));
+            ui.add(egui::DragValue::new(&mut self.spectrogram_fmax).range(1.0..=10000.0).prefix("Up to ").suffix(" Hz"));
+            let mut manual = self.spectrogram_limits.is_some();
+            if ui.checkbox(&mut manual, "Colour limits").changed() {
+                self.spectrogram_limits = manual.then_some(self.spectrogram_limits.unwrap_or((-20.0, 20.0)));
+            }
+            if let Some((low, high)) = &mut self.spectrogram_limits {
+                ui.add(egui::DragValue::new(low).speed(0.5).suffix(" dB"));
+                ui.add(egui::DragValue::new(high).speed(0.5).suffix(" dB"));
+            }
+        });
+        if !self.show_spectrogram {
+            return;
+        }
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        let start = (self.x_view.max(0.0) * sfreq) as usize;
+        let end = ((self.x_view + self.page_duration).max(0.0) * sfreq) as usize;
+        for ch in self.spectrogram_channels.clone() {
+            let key = SpectrogramKey {
+                channel: ch,
+                start,
+                end,
+                params: self.spectrogram_params,
+                fmax: self.spectrogram_fmax,
+                limits: self.spectrogram_limits,
+            };
+            if !self.spectrogram_textures.iter().any(|image| image.key == key) {
+                let Some(image) = self.spectrogram_image(ui.ctx(), key) else { continue };
+                self.spectrogram_textures.retain(|other| other.key.channel != ch);
+                self.spectrogram_textures.push(image);
+            }
+            let Some(image) = self.spectrogram_textures.iter().find(|image| image.key == key) else { continue };
+            let label = format!("{} ({:.0} to {:.0} dB)", names.get(ch).cloned().unwrap_or_default(), image.range.0, image.range.1);
+            Plot::new(("spectrogram", ch))
+                .height(SPECTROGRAM_HEIGHT)
+                .y_axis_min_width(VIEWER_Y_AXIS_WIDTH)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .allow_scroll(false)
+                .allow_boxed_zoom(false)
+                .show_x(false)
+                .show(ui, |plot_ui| {
+                    plot_ui.set_plot_bounds_x(self.x_view..=(self.x_view + self.page_duration));
+                    plot_ui.set_plot_bounds_y(0.0..=self.spectrogram_fmax);
+                    let ((t0, t1), (f0, f1)) = (image.times, image.freqs);
+                    plot_ui.image(PlotImage::new(
+                        format!("spectrogram_{ch}"),
+                        image.texture.id(),
+                        PlotPoint::new((t0 + t1) / 2.0, (f0 + f1) / 2.0),
+                        [(t1 - t0) as f32, (f1 - f0) as f32],
+                    ));
+                    let label_point = PlotPoint::new(self.x_view + self.page_duration * 0.01, self.spectrogram_fmax * 0.9);
+                    plot_ui.text(Text::new("spectrogram_label", label_point, label).color(Color32::WHITE).anchor(egui::Align2::LEFT_CENTER));
+                });
+        }
+    }
+
+    fn spectrogram_image(&self, ctx: &egui::Context, key: SpectrogramKey) -> Option<SpectrogramImage> {
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        let resolution = self.eeg_info.resolution(key.channel);
+        let result = match self.data_format {
+            DataFormat::EDF => self.raw_eeg.edf_data.as_ref()?.get(key.channel).map(|channel| {
+                spectrum::spectrogram(&key.params, sfreq, resolution, channel, key.start, key.end)
+            }),
+            DataFormat::BrainVision => self.raw_eeg.bv_data.as_ref()?.get(key.channel).map(|channel| {
+                spectrum::spectrogram(&key.params, sfreq, resolution, channel, key.start, key.end)
+            }),
+        }?
+        .ok()?;
+        let n_freqs = result.freqs.iter().take_while(|&&f| f <= key.fmax).count();
+        let n_times = result.times.len();
+        if n_freqs == 0 || n_times == 0 {
+            return None;
+        }
+        // Highest frequency in the top row
+        let db = Array2::from_shape_fn((n_freqs, n_times), |(row, t)| {
+            10.0 * result.power[[n_freqs - 1 - row, t]].max(f64::MIN_POSITIVE).log10()
+        });
+        let range = key.limits.unwrap_or_else(|| {
+            let mut sorted: Vec<f64> = db.iter().copied().collect();
+            sorted.sort_by(f64::total_cmp);
+            let at = |q: f64| sorted[((sorted.len() - 1) as f64 * q).round() as usize];
+            (at(0.05), at(0.99))
+        });
+        let pixels = plots::heatmap_image(&db, |v| plots::sequential_color(v, range.0, range.1));
+        let texture = ctx.load_texture(format!("spectrogram_{}", key.channel), pixels, egui::TextureOptions::LINEAR);
+        let dt = key.params.segment_seconds * (1.0 - key.params.overlap);
+        let df = result.freqs.get(1).copied().unwrap_or(1.0);
+        Some(SpectrogramImage {
+            key,
+            texture,
+            range,
+            times: (result.times[0] - dt / 2.0, result.times[n_times - 1] + dt / 2.0),
+            freqs: (-df / 2.0, result.freqs[n_freqs - 1] + df / 2.0),
+        })
+    }
+
+    fn refresh_overview(&mut self) {
+        let key = (self.overview_signal, self.eeg_info.bad_channels.clone());
+        if self.overview.as_ref().is_some_and(|(cached, _)| *cached == key) {
+            return;
+        }
+        let overview = match self.data_format {
+            DataFormat::EDF => self.raw_eeg.edf_data.as_ref().map(|data| overview::compute_overview(key.0, &self.eeg_info, data, OVERVIEW_BINS)),
+            DataFormat::BrainVision => self.raw_eeg.bv_data.as_ref().map(|data| overview::compute_overview(key.0, &self.eeg_info, data, OVERVIEW_BINS)),
+        };
+        self.overview = overview.map(|overview| (key, overview));
+    }
+
+    // Whole recording under the viewer: signal envelope, marker density, bad segments and the current
+    // window. Click to centre the view, drag the window to scroll.
+    fn overview_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.show_overview, "Overview");
+            if !self.show_overview {
+                return;
+            }
+            ui.radio_value(&mut self.overview_signal, OverviewSignal::Gfp, "GFP");
+            let names: Vec<String> = self.eeg_info.ch_names.iter().take(self.n_data_channels()).cloned().collect();
+            let selected = match self.overview_signal {
+                OverviewSignal::Channel(ch) => names.get(ch).cloned().unwrap_or_default(),
+                OverviewSignal::Gfp => "Channel".to_owned(),
+            };
+            egui::ComboBox::from_id_salt("overview_channel").selected_text(selected).show_ui(ui, |ui| {
+                for (ch, name) in names.iter().enumerate() {
+                    ui.selectable_value(&mut self.overview_signal, OverviewSignal::Channel(ch), name.clone());
+                }
+            });
+            ui.label("Marker density follows the event type filter");
+        });
+        if !self.show_overview {
+            return;
+        }
+        self.refresh_overview();
+        let Some((_, overview)) = &self.overview else { return };
+        let sfreq = f64::from(self.eeg_info.sfreq.max(1));
+        let n_samples = self.n_samples();
+        let duration = n_samples as f64 / sfreq;
+
+        // Envelope scaled to 0..1 between the 1st and 99th percentiles, so single spikes do not flatten it
+        let percentile = |values: &[f64], q: f64| {
+            let mut sorted: Vec<f64> = values.iter().copied().filter(|v| v.is_finite()).collect();
+            sorted.sort_by(f64::total_cmp);
+            sorted.get(((sorted.len() as f64 - 1.0) * q).round().max(0.0) as usize).copied().unwrap_or(0.0)
+        };
+        let (lo, hi) = (percentile(&overview.min, 0.01), percentile(&overview.max, 0.99));
+        let norm = |v: f64| ((v - lo) / (hi - lo).max(f64::EPSILON)).clamp(0.0, 1.0);
+        let bin_time = |bin: usize| (bin as f64 + 0.5) * overview.bin_seconds;
+        let upper: Vec<[f64; 2]> = overview.max.iter().enumerate().map(|(bin, &v)| [bin_time(bin), norm(v)]).collect();
+        let lower: Vec<[f64; 2]> = overview.min.iter().enumerate().map(|(bin, &v)| [bin_time(bin), norm(v)]).collect();
+
+        let positions: Vec<f64> = self.filtered_events().iter().map(|&idx| self.eeg_markers.markers[idx]).collect();
+        let density_bin = (duration / 400.0).max(1.0 / sfreq);
+        let counts = overview::marker_density(&positions, sfreq, duration, density_bin);
+        let max_count = counts.iter().copied().max().unwrap_or(0).max(1) as f64;
+        let density: Vec<[f64; 2]> = counts
+            .iter()
+            .enumerate()
+            .flat_map(|(bin, &count)| {
+                let y = 1.1 + 0.35 * count as f64 / max_count;
+                [[bin as f64 * density_bin, y], [(bin + 1) as f64 * density_bin, y]]
+            })
+            .collect();
+        let bad_spans = self.eeg_markers.bad_spans(n_samples);
+
+        let (window_start, window_end) = (self.x_view, self.x_view + self.page_duration);
+        let (y_min, y_max) = (-0.05, 1.5);
+        Plot::new("overview_plot")
+            .height(OVERVIEW_HEIGHT)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .allow_boxed_zoom(false)
+            .allow_double_click_reset(false)
+            .show_y(false)
+            .show_axes([true, false])
+            .show(ui, |plot_ui| {
+                plot_ui.set_plot_bounds_x(0.0..=duration.max(f64::EPSILON));
+                plot_ui.set_plot_bounds_y(y_min..=y_max);
+                for (idx, &(start, end)) in bad_spans.iter().enumerate() {
+                    let corners = vec![[start as f64 / sfreq, y_min], [end as f64 / sfreq, y_min], [end as f64 / sfreq, 1.05], [start as f64 / sfreq, 1.05]];
+                    plot_ui.polygon(Polygon::new(format!("overview_bad_{idx}"), corners).fill_color(Color32::from_rgba_unmultiplied(220, 60, 60, 70)).stroke(Stroke::NONE));
+                }
+                plot_ui.line(Line::new("overview_max", upper).color(Color32::GRAY));
+                plot_ui.line(Line::new("overview_min", lower).color(Color32::GRAY));
+                plot_ui.line(Line::new("overview_markers", density).color(Color32::from_rgb(120, 160, 255)));
+                let corners = vec![[window_start, y_min], [window_end, y_min], [window_end, y_max], [window_start, y_max]];
+                plot_ui.polygon(
+                    Polygon::new("overview_window", corners)
+                        .fill_color(Color32::from_rgba_unmultiplied(255, 255, 255, 30))
+                        .stroke(Stroke::new(1.5, Color32::YELLOW)),
+                );
+
+                // Pressing inside the window grabs it; pressing elsewhere centres the window there first
+                let down = plot_ui.response().is_pointer_button_down_on();
+                match (down, plot_ui.pointer_coordinate()) {
+                    (true, Some(pointer)) => {
+                        let grab = *self.overview_drag.get_or_insert_with(|| {
+                            if (window_start..=window_end).contains(&pointer.x) { pointer.x - window_start } else { (window_end - window_start) / 2.0 }
+                        });
+                        self.x_view = (pointer.x - grab).clamp(0.0, (duration - (window_end - window_start)).max(0.0));
+                    }
+                    (true, None) => {}
+                    (false, _) => self.overview_drag = None,
+                }
+            });
+    }
+
+    // Vertical bar of one division in the lower right corner, labelled with the sensitivity
+    fn draw_scale_bar(&self, plot_ui: &mut egui_plot::PlotUi<'_>) {
+        let x = self.x_view + self.page_duration * 0.97;
+        let y = -CHANNEL_SPACING * 0.9;
+        let color = Color32::from_rgb(230, 230, 120);
+        plot_ui.line(Line::new("scale_bar", vec![[x, y], [x, y + CHANNEL_SPACING]]).color(color).width(2.0));
+        plot_ui.text(
+            Text::new("scale_bar_label", PlotPoint::new(x, y + CHANNEL_SPACING / 2.0), format!("{} µV", self.sensitivity))
+                .color(color)
+                .anchor(egui::Align2::RIGHT_CENTER),
+        );
+    }
+
+    fn baseline_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Baseline and detrending");
+        ui.add(egui::Slider::new(&mut self.baseline_bmin, -2.0..=0.0)
+            .text("Baseline from (s)")
+            .suffix(" s"));
+        ui.add(egui::Slider::new(&mut self.baseline_bmax, -2.0..=0.0)
+            .text("Baseline to (s)")
+            .suffix(" s"));
+        egui::ComboBox::from_label("Baseline value")
+            .selected_text(format!("{:?}", self.baseline_mode))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.baseline_mode, BaselineMode::Mean, "Mean");
+                ui.selectable_value(&mut self.baseline_mode, BaselineMode::Median, "Median");
+            });
+
+        ui.horizontal(|ui| {
+            if ui.add_enabled(self.epochs.is_some(), egui::Button::new("Baseline epochs")).clicked() {
+                let (bmin, bmax, mode) = (self.baseline_bmin, self.baseline_bmax, self.baseline_mode);
+                self.modify_epochs(|epochs| baseline::baseline_epochs(bmin, bmax, mode, epochs));
+            }
+            if ui.button("Baseline continuous (whole recording)").on_hover_text("Remove the per-channel offset of the whole recording; the baseline interval above is relative to events and only applies to epochs").clicked() {
+                let (info_bv, info_edf) = (self.eeg_info.clone(), self.eeg_info.clone());
+                let mode = self.baseline_mode;
+                self.spawn_continuous(
+                    move |data| baseline::baseline_continuous(None, None, mode, &info_bv, data),
+                    move |data| baseline::baseline_continuous(None, None, mode, &info_edf, data),
+                );
+            }
+        });
+
+        ui.add(egui::Slider::new(&mut self.detrend_order, 1..=5).text("Detrend order"));
+        ui.checkbox(&mut self.detrend_exclude_pulse, "Exclude pulse window from the fit");
+        let exclude = self.detrend_exclude_pulse.then_some((-self.tmin_cut, self.tmax_cut));
+        let order = self.detrend_order;
+        ui.horizontal(|ui| {
+            if ui.add_enabled(self.epochs.is_some(), egui::Button::new("Detrend epochs")).clicked() {
+                self.modify_epochs(|epochs| baseline::detrend_epochs(order, exclude, epochs));
+            }
+            if ui.button("Detrend continuous").clicked() {
+                let (info_bv, info_edf) = (self.eeg_info.clone(), self.eeg_info.clone());
+                let (markers_bv, markers_edf) = (self.selected_markers(), self.selected_markers());
+                self.spawn_continuous(
+                    move |data| baseline::detrend_continuous(order, exclude, &markers_bv, &info_bv, data),
+                    move |data| baseline::detrend_continuous(order, exclude, &markers_edf, &info_edf, data),
+                );
+            }
+        });
+    }
+
+    fn rejection_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Epoch rejection");
+        ui.label("Per-channel thresholds in µV");
+        threshold_ui(ui, "Peak-to-peak above", &mut self.reject_criteria.peak_to_peak, 200.0);
+        threshold_ui(ui, "Gradient above", &mut self.reject_criteria.gradient, 50.0);
+        threshold_ui(ui, "Flat below", &mut self.reject_criteria.flat, 1.0);
+        threshold_ui(ui, "Kurtosis z above", &mut self.reject_criteria.kurtosis, 5.0);
+        ui.checkbox(&mut self.reject_bad_segments, "Reject epochs overlapping BAD segments");
+
+        let Some(epochs) = &mut self.epochs else {
+            ui.label("Create epochs first");
+            return;
+        };
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            if ui.button("Apply criteria").clicked() {
+                if self.reject_bad_segments {
+                    rejection::reject_by_annotation(epochs, &self.eeg_markers);
+                }
+                let info = EEGInfo { bad_channels: epochs.bad_channels.clone(), ..self.eeg_info.clone() };
+                rejection::reject_epochs(&self.reject_criteria, &info, epochs);
+                changed = true;
+            }
+            if ui.button("Drop bad epochs").clicked() {
+                rejection::drop_bad_epochs(epochs);
+                changed = true;
+            }
+        });
+        ui.label(format!(
+            "{} of {} epochs marked bad",
+            epochs.n_epochs() - epochs.good_epochs().len(),
+            epochs.n_epochs()
+        ));
+
+        egui::CollapsingHeader::new("Drop log").show(ui, |ui| {
+            egui::ScrollArea::vertical().max_height(250.0).show(ui, |ui| {
+                for epoch_idx in 0..epochs.n_epochs() {
+                    let marker_idx = epochs.events[epoch_idx].marker_idx;
+                    let reasons: Vec<String> = epochs
+                        .drop_log
+                        .iter()
+                        .filter(|d| d.event.marker_idx == marker_idx)
+                        .map(|d| match &d.channel {
+                            Some(channel) => format!("{:?} ({channel})", d.reason),
+                            None => format!("{:?}", d.reason),
+                        })
+                        .collect();
+                    if reasons.is_empty() {
+                        continue;
+                    }
+                    let mut bad = epochs.bad[epoch_idx];
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut bad, format!("Epoch {epoch_idx}")).changed() {
+                            rejection::set_epoch_bad(epochs, epoch_idx, bad);
+                            changed = true;
+                        }
+                        ui.label(reasons.join(", "));
+                    });
+                }
+            });
+        });
+
+        if changed {
+            self.refresh_evoked();
+        }
+    }
+
+    // Change the epochs in place and refresh the evoked average
+    fn modify_epochs(&mut self, f: impl FnOnce(&mut EpochsData) -> Result<(), Box<dyn std::error::Error>>) {
+        if let Some(epochs) = &mut self.epochs {
+            match f(epochs) {
+                Ok(()) => self.refresh_evoked(),
+                Err(e) => eprintln!("Error processing epochs: {e}"),
+            }
+        }
+    }
+
+    // Call after any change to the epochs so the average and cached plots follow
+    fn refresh_evoked(&mut self) {
+        self.evoked = self.epochs.as_ref().and_then(|epochs| epochs::evoked_eeg(epochs).ok());
+        self.epochs_version += 1;
+    }
+
+    fn poll_epochs(&mut self) {
+        if let Some(receiver) = &self.epochs_receiver {
+            match receiver.try_recv() {
+                Ok(Ok(mut new_epochs)) => {
+                    if self.reject_bad_segments {
+                        rejection::reject_by_annotation(&mut new_epochs, &self.eeg_markers);
+                    }
+                    self.epochs = Some(new_epochs);
+                    self.epochs_receiver = None;
+                    self.refresh_evoked();
+                }
+                Ok(Err(e)) => {
+                    eprintln!("Error epoching data: {e}");
+                    self.epochs_receiver = None;
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => {}
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    eprintln!("Epoching thread disconnected");
+                    self.epochs_receiver = None;
+                }
+            }
+        }
+    }
+
+    // Plot units per data unit of a channel: one channel spacing is `sensitivity` µV
+    fn plot_scale(&self, ch: usize) -> f64 {
+        self.eeg_info.resolution(ch) * CHANNEL_SPACING / self.sensitivity
+    }
+
+    fn scale_sample(&self, ch: usize, value: f64) -> f64 {
+        value * self.plot_scale(ch)
+    }
+
+    // Pulse and artefact windows relative to the event (s), for shading
+    fn shaded_windows(&self) -> Vec<(f64, f64)> {
+        let mut windows = vec![(-self.tmin_cut, self.tmax_cut)];
+        windows.extend(self.artefact_windows.iter().map(|w| (w.offset - w.pre, w.offset + w.post)));
+        windows
+    }
+
+    fn epochs_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_epochs_browser;
+        egui::Window::new("Epochs").open(&mut open).default_size([900.0, 750.0]).show(ctx, |ui| {
+            let Some(epochs) = &self.epochs else {
+                ui.label("Create epochs first");
+                return;
+            };
+            let n_pages = epochs.n_epochs().div_ceil(self.epochs_per_page.max(1)).max(1);
+            self.epochs_page = self.epochs_page.min(n_pages - 1);
+            ui.horizontal(|ui| {
+                if ui.button("◀").clicked() {
+                    self.epochs_page = self.epochs_page.saturating_sub(1);
+                }
+                ui.label(format!("Page {} / {n_pages}", self.epochs_page + 1));
+                if ui.button("▶").clicked() && self.epochs_page + 1 < n_pages {
+                    self.epochs_page += 1;
+                }
+                ui.add(egui::DragValue::new(&mut self.epochs_per_page).range(1..=20).prefix("Epochs per page: "));
+                egui::ComboBox::from_label("ERP image channel")
+                    .selected_text(epochs.ch_names.get(self.erp_channel).cloned().unwrap_or_default())
+                    .show_ui(ui, |ui| {
+                        for (ch, name) in epochs.ch_names.iter().enumerate().take(epochs.n_channels()) {
+                            ui.selectable_value(&mut self.erp_channel, ch, name.clone());
+                        }
+                    });
+            });
+            ui.label("Click an epoch to mark it bad, click again to keep it");
+            self.epochs_page_plot(ui);
+            self.erp_image_plot(ui);
+        });
+        self.show_epochs_browser = open;
+    }
+
+    // The epochs of the current page side by side, channels stacked as in the continuous viewer
+    fn epochs_page_plot(&mut self, ui: &mut egui::Ui) {
+        let Some(epochs) = &self.epochs else { return };
+        let duration = epochs.tmax - epochs.tmin;
+        let span = duration * 1.05;
+        let first = self.epochs_page * self.epochs_per_page;
+        let last = (first + self.epochs_per_page).min(epochs.n_epochs());
+        let channel_offset = CHANNEL_SPACING;
+        let channels: Vec<usize> = (0..epochs.n_channels()).filter(|ch| !self.unselected_channels.contains(ch)).collect();
+        let top = channels.len() as f64 * channel_offset;
+        let times = epochs.times();
+        let shaded = self.shaded_windows();
+
+        let mut clicked_epoch = None;
+        Plot::new("epochs_plot")
+            .height(ui.available_height() * 0.5)
+            .show_y(false)
+            .show(ui, |plot_ui| {
+                for (slot, epoch_idx) in (first..last).enumerate() {
+                    let x_of = |t: f64| slot as f64 * span + (t - epochs.tmin);
+                    let bad = epochs.bad.get(epoch_idx).copied().unwrap_or(false);
+                    for (row, &ch) in channels.iter().enumerate() {
+                        let offset = row as f64 * channel_offset;
+                        let points: Vec<[f64; 2]> = epochs
+                            .trace(epoch_idx, ch)
+                            .iter()
+                            .zip(&times)
+                            .map(|(&v, &t)| [x_of(t), self.scale_sample(ch, v) + offset])
+                            .collect();
+                        let color = if bad {
+                            Color32::from_rgb(200, 60, 60)
+                        } else {
+                            self.channel_colors.get(ch).copied().unwrap_or(Color32::WHITE)
+                        };
+                        plot_ui.line(Line::new(format!("epoch_{epoch_idx}_ch_{ch}"), points).color(color));
+                    }
+                    for &(start, end) in &shaded {
+                        plot_ui.polygon(shade(x_of(start), x_of(end), -channel_offset, top));
+                    }
+                    plot_ui.vline(VLine::new("Event", x_of(0.0)).color(Color32::GRAY));
+                    let label = format!("#{epoch_idx} {}{}", epochs.events[epoch_idx].description, if bad { " (bad)" } else { "" });
+                    plot_ui.text(Text::new(format!("epoch_label_{epoch_idx}"), PlotPoint::new(x_of(0.0), top), label));
+                }
+                for (row, &ch) in channels.iter().enumerate() {
+                    let name = epochs.ch_names.get(ch).cloned().unwrap_or_default();
+                    plot_ui.text(Text::new(format!("epoch_ch_{ch}"), PlotPoint::new(-0.02 * duration, row as f64 * channel_offset), name));
+                }
+                if plot_ui.response().clicked() {
+                    if let Some(pointer) = plot_ui.pointer_coordinate() {
+                        let slot = (pointer.x / span).floor();
+                        if slot >= 0.0 && first + (slot as usize) < last {
+                            clicked_epoch = Some(first + slot as usize);
+                        }
+                    }
+                }
+            });
+
+        if let (Some(epoch_idx), Some(epochs)) = (clicked_epoch, &mut self.epochs) {
+            let bad = epochs.bad.get(epoch_idx).copied().unwrap_or(false);
+            rejection::set_epoch_bad(epochs, epoch_idx, !bad);
+            self.refresh_evoked();
+        }
+    }
+
+    // Trials x time image of one channel with the average of the good epochs beneath
+    fn erp_image_plot(&mut self, ui: &mut egui::Ui) {
+        let Some(epochs) = &self.epochs else { return };
+        if epochs.n_epochs() == 0 || epochs.n_times() == 0 {
+            return;
+        }
+        let ch = self.erp_channel.min(epochs.n_channels().saturating_sub(1));
+        let key = (ch, self.epochs_version);
+        if self.erp_texture.as_ref().map(|(k, _, _)| *k) != Some(key) {
+            let mut values = Array2::zeros((epochs.n_epochs(), epochs.n_times()));
+            for (epoch_idx, mut row) in values.outer_iter_mut().enumerate() {
+                row.assign(&Array1::from(epochs.trace(epoch_idx, ch)));
+            }
+            let limit = plots::robust_abs_limit(values.iter().copied(), 0.98);
+            let image = plots::heatmap_image(&values, |v| plots::diverging_color(v, limit));
+            let texture = ui.ctx().load_texture("erp_image", image, egui::TextureOptions::NEAREST);
+            self.erp_texture = Some((key, texture, limit));
+        }
+        let Some((_, texture, limit)) = &self.erp_texture else { return };
+
+        let n_epochs = epochs.n_epochs() as f64;
+        let shaded = self.shaded_windows();
+        ui.label(format!("ERP image, colour limit ±{limit:.1} (red positive), trial 0 at the top"));
+        Plot::new("erp_image")
+            .height(ui.available_height() * 0.6)
+            .link_axis("epochs_time", [true, false])
+            .show(ui, |plot_ui| {
+                plot_ui.image(PlotImage::new(
+                    "ERP image",
+                    texture.id(),
+                    PlotPoint::new((epochs.tmin + epochs.tmax) / 2.0, n_epochs / 2.0),
+                    [(epochs.tmax - epochs.tmin) as f32, n_epochs as f32],
+                ));
+                for &(start, end) in &shaded {
+                    plot_ui.polygon(shade(start, end, 0.0, n_epochs));
+                }
+            });
+
+        Plot::new("erp_average")
+            .height(ui.available_height())
+            .link_axis("epochs_time", [true, false])
+            .show(ui, |plot_ui| {
+                let Some(evoked) = self.evoked.as_ref().filter(|evoked| ch < evoked.evoked.nrows()) else {
+                    return;
+                };
+                let average = evoked.evoked.row(ch);
+                let (min, max) = average
+                    .iter()
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &v| (min.min(v), max.max(v)));
+                for &(start, end) in &shaded {
+                    plot_ui.polygon(shade(start, end, min, max));
+                }
+                let points: Vec<[f64; 2]> = epochs.times().iter().zip(average).map(|(&t, &v)| [t, v]).collect();
+                plot_ui.line(Line::new(format!("Average (n = {})", evoked.nave), points));
+            });
+    }
+
+    fn evoked_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_evoked_window;
+        egui::Window::new("Evoked").open(&mut open).default_size([900.0, 750.0]).show(ctx, |ui| {
+            let Some(evoked) = &self.evoked else {
+                ui.label("Create epochs first");
+                return;
+            };
+            let n_channels = evoked.evoked.nrows();
+            self.evoked_channel = self.evoked_channel.min(n_channels.saturating_sub(1));
+            ui.horizontal(|ui| {
+                ui.label(format!("Average of {} epochs", evoked.nave));
+                egui::ComboBox::from_label("Highlight channel")
+                    .selected_text(evoked.ch_names.get(self.evoked_channel).cloned().unwrap_or_default())
+                    .show_ui(ui, |ui| {
+                        for (ch, name) in evoked.ch_names.iter().enumerate().take(n_channels) {
+                            ui.selectable_value(&mut self.evoked_channel, ch, name.clone());
+                        }
+                    });
+                ui.add(egui::DragValue::new(&mut self.evoked_cursor)
+                    .range(evoked.tmin..=evoked.tmax)
+                    .speed(0.001)
+                    .prefix("Cursor: ")
+                    .suffix(" s"));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Click or drag in the plots to move the latency cursor");
+                if ui.button("Add cursor latency to topomap series").clicked() {
+                    self.topo_latencies.push(self.evoked_cursor);
+                    self.show_topomaps = true;
+                }
+            });
+            self.butterfly_plot(ui);
+            ui.horizontal_top(|ui| {
+                let cursor = self.evoked_cursor;
+                let limit = self.topomap_limit(&[cursor]);
+                ui.vertical(|ui| {
+                    self.topomap_ui(ui, cursor, limit, 180.0);
+                    colour_bar(ui, limit, 180.0);
+                });
+                ui.vertical(|ui| self.field_power_ui(ui));
+                ui.vertical(|ui| self.evoked_readout(ui));
+            });
+        });
+        self.show_evoked_window = open;
+    }
+
+    // All channels of the evoked response overlaid, with the GMFP beneath on a linked time axis
+    fn butterfly_plot(&mut self, ui: &mut egui::Ui) {
+        let Some(evoked) = &self.evoked else { return };
+        let times = evoked.times();
+        let gfp = evoked::global_field_power(evoked, &self.epochs_info());
+        let (min, max) = evoked
+            .evoked
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &v| (min.min(v), max.max(v)));
+        let lmfps = self.local_field_powers();
+        let gfp_max = gfp.iter().chain(lmfps.iter().flat_map(|(_, lmfp)| lmfp)).copied().fold(0.0, f64::max);
+        let shaded = self.shaded_windows();
+        let mut cursor = None;
+
+        Plot::new("evoked_butterfly")
+            .height(ui.available_height() * 0.4)
+            .allow_drag(false)
+            .link_axis("evoked_time", [true, false])
+            .link_cursor("evoked_time", [true, false])
+            .show(ui, |plot_ui| {
+                for &(start, end) in &shaded {
+                    plot_ui.polygon(shade(start, end, min, max));
+                }
+                for (ch, row) in evoked.evoked.outer_iter().enumerate() {
+                    if ch == self.evoked_channel {
+                        continue;
+                    }
+                    let points: Vec<[f64; 2]> = times.iter().zip(row).map(|(&t, &v)| [t, v]).collect();
+                    plot_ui.line(Line::new(format!("butterfly_ch_{ch}"), points).color(Color32::from_gray(110)));
+                }
+                if let Some(row) = evoked.evoked.outer_iter().nth(self.evoked_channel) {
+                    let points: Vec<[f64; 2]> = times.iter().zip(row).map(|(&t, &v)| [t, v]).collect();
+                    let name = evoked.ch_names.get(self.evoked_channel).cloned().unwrap_or_default();
+                    plot_ui.line(Line::new(name, points).color(Color32::from_rgb(255, 170, 0)).width(2.5));
+                }
+                plot_ui.vline(VLine::new("Event", 0.0).color(Color32::GRAY));
+                plot_ui.vline(VLine::new("Cursor", self.evoked_cursor).color(Color32::LIGHT_BLUE));
+                if plot_ui.response().is_pointer_button_down_on() {
+                    cursor = plot_ui.pointer_coordinate().map(|p| p.x);
+                }
+            });
+
+        Plot::new("evoked_gfp")
+            .height(ui.available_height() * 0.3)
+            .legend(egui_plot::Legend::default())
+            .allow_drag(false)
+            .link_axis("evoked_time", [true, false])
+            .link_cursor("evoked_time", [true, false])
+            .show(ui, |plot_ui| {
+                for &(start, end) in &shaded {
+                    plot_ui.polygon(shade(start, end, 0.0, gfp_max));
+                }
+                let points: Vec<[f64; 2]> = times.iter().zip(&gfp).map(|(&t, &v)| [t, v]).collect();
+                plot_ui.line(Line::new("GMFP", points).color(Color32::WHITE).width(2.0));
+                for (idx, (name, lmfp)) in lmfps.iter().enumerate() {
+                    let points: Vec<[f64; 2]> = times.iter().zip(lmfp).map(|(&t, &v)| [t, v]).collect();
+                    plot_ui.line(Line::new(format!("LMFP {name}"), points).color(GROUP_COLORS[idx % GROUP_COLORS.len()]));
+                }
+                plot_ui.vline(VLine::new("Cursor", self.evoked_cursor).color(Color32::LIGHT_BLUE));
+                if plot_ui.response().is_pointer_button_down_on() {
+                    cursor = plot_ui.pointer_coordinate().map(|p| p.x);
+                }
+            });
+
+        if let Some(x) = cursor {
+            self.evoked_cursor = x.clamp(evoked.tmin, evoked.tmax);
+        }
+    }
+
+    // LMFP of every channel group that matches channels of the evoked response
+    fn local_field_powers(&self) -> Vec<(String, Vec<f64>)> {
+        let Some(evoked) = &self.evoked else { return Vec::new() };
+        let info = self.epochs_info();
+        info.channel_groups
+            .iter()
+            .filter_map(|group| {
+                let channels = groups::channel_indices(&evoked.ch_names, &group.channels);
+                let channels: Vec<usize> = channels.into_iter().filter(|&ch| ch < evoked.evoked.nrows()).collect();
+                (!channels.is_empty()).then(|| (group.name.clone(), evoked::local_field_power(evoked, &info, &channels)))
+            })
+            .collect()
+    }
+
+    // Area under the GMFP and the LMFPs in the chosen windows
+    fn field_power_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(evoked) = &self.evoked else { return };
+        ui.label("Field power AUC (µV x ms)");
+        let mut curves = vec![("GMFP".to_owned(), evoked::global_field_power(evoked, &self.epochs_info()))];
+        curves.extend(self.local_field_powers());
+        egui::Grid::new("field_power_auc").striped(true).show(ui, |ui| {
+            ui.label("");
+            for (start, end) in &self.lmfp_windows {
+                ui.label(format!("{:.0}-{:.0}", start * 1000.0, end * 1000.0));
+            }
+            ui.end_row();
+            for (name, curve) in &curves {
+                ui.label(name);
+                for &(start, end) in &self.lmfp_windows {
+                    ui.label(format!("{:.1}", evoked::area_under_curve(evoked, curve, start, end) * 1000.0));
+                }
+                ui.end_row();
+            }
+        });
+        let mut remove_idx = None;
+        for (idx, (start, end)) in self.lmfp_windows.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                let mut window_ms = [*start * 1000.0, *end * 1000.0];
+                let from = ui.add(egui::DragValue::new(&mut window_ms[0]).speed(1.0).suffix(" ms"));
+                let to = ui.add(egui::DragValue::new(&mut window_ms[1]).speed(1.0).suffix(" ms"));
+                if from.changed() || to.changed() {
+                    (*start, *end) = (window_ms[0] / 1000.0, window_ms[1] / 1000.0);
+                }
+                if ui.small_button("x").clicked() {
+                    remove_idx = Some(idx);
+                }
+            });
+        }
+        if let Some(idx) = remove_idx {
+            self.lmfp_windows.remove(idx);
+        }
+        if ui.button("Add window").clicked() {
+            self.lmfp_windows.push((0.0, 0.05));
+        }
+    }
+
+    // Amplitude of every channel at the cursor latency; clicking a name highlights that channel
+    fn evoked_readout(&mut self, ui: &mut egui::Ui) {
+        let Some(evoked) = &self.evoked else { return };
+        let idx = evoked.time_to_index(self.evoked_cursor);
+        let latency = evoked.tmin + idx as f64 / evoked.sfreq;
+        let gfp = evoked::global_field_power(evoked, &self.epochs_info());
+        ui.label(format!(
+            "Latency {:.1} ms, GMFP {:.2} µV",
+            latency * 1000.0,
+            gfp.get(idx).copied().unwrap_or_default()
+        ));
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("evoked_readout").num_columns(8).striped(true).show(ui, |ui| {
+                for (ch, row) in evoked.evoked.outer_iter().enumerate() {
+                    let name = evoked.ch_names.get(ch).cloned().unwrap_or_else(|| format!("Ch{}", ch + 1));
+                    if ui.selectable_label(ch == self.evoked_channel, name).clicked() {
+                        self.evoked_channel = ch;
+                    }
+                    ui.label(format!("{:.2}", row.get(idx).copied().unwrap_or_default()));
+                    if ch % 4 == 3 {
+                        ui.end_row();
+                    }
+                }
+            });
+        });
+    }
+
+    // Welch PSD of the continuous data without BAD_* segments, or of the good epochs
+    fn spawn_psd(&mut self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (params, info) = (self.welch_params, self.eeg_info.clone());
+        match self.spectrum_source {
+            DataSource::Continuous => {
+                let spans = self.eeg_markers.good_spans(self.n_samples());
+                let (bv_data, edf_data) = (self.raw_eeg.bv_data.clone(), self.raw_eeg.edf_data.clone());
+                std::thread::spawn(move || {
+                    let result = match (bv_data, edf_data) {
+                        (Some(data), _) => spectrum::psd_continuous(&params, &info, &data, &spans),
+                        (None, Some(data)) => spectrum::psd_continuous(&params, &info, &data, &spans),
+                        (None, None) => Err("No data loaded".into()),
+                    };
+                    sender.send(result.map_err(|e| e.to_string())).ok();
+                });
+            }
+            DataSource::Epochs => {
+                let Some(epochs) = self.epochs.clone() else { return };
+                std::thread::spawn(move || {
+                    sender.send(spectrum::psd_epochs(&params, &info, &epochs).map_err(|e| e.to_string())).ok();
+                });
+            }
+        }
+        self.psd_receiver = Some(receiver);
+        self.psd_status = None;
+    }
+
+    fn poll_psd(&mut self) {
+        let Some(receiver) = &self.psd_receiver else { return };
+        match receiver.try_recv() {
+            Ok(Ok(psd)) => {
+                self.psd_status = Some(format!("{} segments averaged", psd.n_segments));
+                self.psd = Some(psd);
+                self.psd_receiver = None;
+            }
+            Ok(Err(e)) => {
+                self.psd_status = Some(e);
+                self.psd_receiver = None;
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => self.psd_receiver = None,
+        }
+    }
+
+    fn spectrum_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_spectrum;
+        egui::Window::new("Spectrum").open(&mut open).default_size([800.0, 550.0]).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.spectrum_source, DataSource::Continuous, "Continuous (without BAD segments)");
+                ui.add_enabled_ui(self.epochs.is_some(), |ui| {
+                    ui.radio_value(&mut self.spectrum_source, DataSource::Epochs, "Good epochs");
+                });
+            });
+            let params = &mut self.welch_params;
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Window")
+                    .selected_text(format!("{:?}", params.taper))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut params.taper, Taper::Hann, "Hann");
+                        ui.selectable_value(&mut params.taper, Taper::Hamming, "Hamming");
+                        ui.selectable_value(&mut params.taper, Taper::Rectangular, "Rectangular");
+                    });
+                ui.add(egui::DragValue::new(&mut params.segment_seconds).range(0.05..=60.0).speed(0.05).prefix("Segment: ").suffix(" s"));
+                ui.add(egui::Slider::new(&mut params.overlap, 0.0..=0.95).text("Overlap"));
+                let mut padded = params.n_fft.is_some();
+                if ui.checkbox(&mut padded, "FFT length").changed() {
+                    params.n_fft = padded.then_some(params.n_fft.unwrap_or(4096));
+                }
+                if let Some(n_fft) = &mut params.n_fft {
+                    ui.add(egui::DragValue::new(n_fft).range(2..=1 << 20));
+                }
+            });
+            ui.horizontal(|ui| {
+                let ready = match self.spectrum_source {
+                    DataSource::Continuous => self.n_samples() > 0,
+                    DataSource::Epochs => self.epochs.is_some(),
+                };
+                if ui.add_enabled(ready && self.psd_receiver.is_none(), egui::Button::new("Compute")).clicked() {
+                    self.spawn_psd();
+                }
+                if self.psd_receiver.is_some() {
+                    ui.spinner();
+                }
+                ui.add(egui::DragValue::new(&mut self.spectrum_fmax).range(1.0..=10000.0).prefix("Up to ").suffix(" Hz"));
+                ui.checkbox(&mut self.spectrum_show_channels, "Channels");
+                ui.label("CSV:");
+                ui.text_edit_singleline(&mut self.psd_csv_path);
+                if ui.add_enabled(self.psd.is_some(), egui::Button::new("Export")).clicked() {
+                    if let Some(psd) = &self.psd {
+                        self.psd_status = Some(match spectrum::write_psd_csv(&self.psd_csv_path, psd) {
+                            Ok(()) => format!("Wrote {} frequencies to {}", psd.freqs.len(), self.psd_csv_path),
+                            Err(e) => format!("Error writing {}: {e}", self.psd_csv_path),
+                        });
+                    }
+                }
+            });
+            if let Some(status) = &self.psd_status {
+                ui.label(status);
+            }
+            self.spectrum_plot(ui);
+        });
+        self.show_spectrum = open;
+    }
+
+    // 10 log10 power per channel with the mean and the 5-95 and 25-75 percentile bands over the good channels
+    fn spectrum_plot(&self, ui: &mut egui::Ui) {
+        let Some(psd) = &self.psd else { return };
+        let n_freqs = psd.freqs.iter().take_while(|&&f| f <= self.spectrum_fmax).count();
+        let good: Vec<usize> = (0..psd.power.len()).filter(|ch| !self.eeg_info.bad_channels.contains(ch)).collect();
+        let summary = psd.summary(&good);
+        let db = |power: f64| 10.0 * power.max(f64::MIN_POSITIVE).log10();
+        let curve = |values: &[f64]| -> Vec<[f64; 2]> { psd.freqs.iter().zip(values).take(n_freqs).map(|(&f, &v)| [f, v]).collect() };
+        Plot::new("spectrum_plot")
+            .x_axis_label("Frequency (Hz)")
+            .y_axis_label("Power (dB µV²/Hz)")
+            .show(ui, |plot_ui| {
+                if self.spectrum_show_channels {
+                    for (ch, power) in psd.power.iter().enumerate() {
+                        let color = if self.eeg_info.bad_channels.contains(&ch) {
+                            BAD_CHANNEL_COLOR
+                        } else {
+                            self.channel_colors.get(ch).copied().unwrap_or(Color32::GRAY).gamma_multiply(0.4)
+                        };
+                        let points: Vec<[f64; 2]> = psd.freqs.iter().zip(power).take(n_freqs).map(|(&f, &p)| [f, db(p)]).collect();
+                        let name = psd.ch_names.get(ch).cloned().unwrap_or_else(|| format!("Ch{}", ch + 1));
+                        plot_ui.line(Line::new(name, points).color(color).width(0.5));
+                    }
+                }
+                // Bands as one quad per frequency step, plot polygons are only filled correctly when convex
+                let bands = [(&summary.p5, &summary.p95, 40), (&summary.p25, &summary.p75, 70)];
+                for (band, (low, high, alpha)) in bands.into_iter().enumerate() {
+                    let fill = Color32::from_rgba_unmultiplied(100, 150, 255, alpha);
+                    for f in 1..n_freqs.min(low.len()) {
+                        let (f0, f1) = (psd.freqs[f - 1], psd.freqs[f]);
+                        let corners = vec![[f0, low[f - 1]], [f1, low[f]], [f1, high[f]], [f0, high[f - 1]]];
+                        plot_ui.polygon(Polygon::new(format!("psd_band_{band}"), corners).fill_color(fill).stroke(Stroke::NONE));
+                    }
+                }
+                plot_ui.line(Line::new("Mean", curve(&summary.mean)).color(Color32::from_rgb(100, 150, 255)).width(2.0));
+            });
+    }
+
+    fn spawn_tfr(&mut self) {
+        let Some(epochs) = self.epochs.clone() else { return };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (params, info) = (self.tfr_params, self.epochs_info());
+        let channels: Vec<usize> = if self.tfr_all_channels { (0..epochs.n_channels()).collect() } else { vec![self.tfr_channel] };
+        std::thread::spawn(move || {
+            sender.send(tfr::tfr_epochs(&params, &info, &epochs, &channels).map_err(|e| e.to_string())).ok();
+        });
+        self.tfr_receiver = Some(receiver);
+        self.tfr_status = None;
+    }
+
+    fn poll_tfr(&mut self) {
+        let Some(receiver) = &self.tfr_receiver else { return };
+        match receiver.try_recv() {
+            Ok(Ok(result)) => {
+                self.tfr_status = Some(format!("{} epochs, {} channels", result.n_epochs, result.channels.len()));
+                self.tfr = Some(result);
+                self.tfr_version += 1;
+                self.tfr_receiver = None;
+            }
+            Ok(Err(e)) => {
+                self.tfr_status = Some(e);
+                self.tfr_receiver = None;
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => self.tfr_receiver = None,
+        }
+    }
+
+    fn tfr_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_tfr;
+        egui::Window::new("Time-frequency").open(&mut open).default_size([800.0, 650.0]).show(ctx, |ui| {
+            let Some(epochs) = &self.epochs else {
+                ui.label("Create epochs first");
+                return;
+            };
+            let ch_names: Vec<String> = epochs.ch_names.iter().take(epochs.n_channels()).cloned().collect();
+            let params = &mut self.tfr_params;
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut params.method, TfrMethod::Morlet, "Morlet");
+                ui.radio_value(&mut params.method, TfrMethod::Multitaper, "Multitaper");
+                ui.add(egui::DragValue::new(&mut params.fmin).range(0.1..=1000.0).speed(0.5).prefix("From ").suffix(" Hz"));
+                ui.add(egui::DragValue::new(&mut params.fmax).range(0.1..=1000.0).speed(0.5).prefix("to ").suffix(" Hz"));
+                ui.add(egui::DragValue::new(&mut params.n_freqs).range(1..=200).prefix("in ").suffix(" steps"));
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut params.n_cycles).range(0.5..=50.0).speed(0.1).suffix(" cycles"));
+                if params.method == TfrMethod::Multitaper {
+                    ui.add(egui::DragValue::new(&mut params.time_bandwidth).range(2.0..=20.0).speed(0.1).prefix("Time-bandwidth: "));
+                }
+                ui.add(egui::DragValue::new(&mut params.decim).range(1..=100).prefix("Keep every ").suffix(" samples"));
+                egui::ComboBox::from_label("Power")
+                    .selected_text(format!("{:?}", params.power))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut params.power, PowerKind::Total, "Total");
+                        ui.selectable_value(&mut params.power, PowerKind::Induced, "Induced");
+                        ui.selectable_value(&mut params.power, PowerKind::Evoked, "Evoked");
+                    });
+            });
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Channel")
+                    .selected_text(ch_names.get(self.tfr_channel).cloned().unwrap_or_default())
+                    .show_ui(ui, |ui| {
+                        for (ch, name) in ch_names.iter().enumerate() {
+                            ui.selectable_value(&mut self.tfr_channel, ch, name.clone());
+                        }
+                    });
+                ui.checkbox(&mut self.tfr_all_channels, "Compute all channels");
+                if ui.add_enabled(self.tfr_receiver.is_none(), egui::Button::new("Compute")).clicked() {
+                    self.spawn_tfr();
+                }
+                if self.tfr_receiver.is_some() {
+                    ui.spinner();
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.tfr_show_itc, false, "Power");
+                ui.radio_value(&mut self.tfr_show_itc, true, "Inter-trial coherence");
+                ui.separator();
+                ui.add_enabled_ui(!self.tfr_show_itc, |ui| {
+                    egui::ComboBox::from_label("Baseline")
+                        .selected_text(match self.tfr_baseline {
+                            None => "None (µV²)",
+                            Some(PowerBaseline::Decibel) => "dB",
+                            Some(PowerBaseline::Percent) => "Percent",
+                            Some(PowerBaseline::ZScore) => "z-score",
+                        })
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut self.tfr_baseline, None, "None (µV²)");
+                            ui.selectable_value(&mut self.tfr_baseline, Some(PowerBaseline::Decibel), "dB");
+                            ui.selectable_value(&mut self.tfr_baseline, Some(PowerBaseline::Percent), "Percent");
+                            ui.selectable_value(&mut self.tfr_baseline, Some(PowerBaseline::ZScore), "z-score");
+                        });
+                    ui.add(egui::DragValue::new(&mut self.tfr_bmin).range(-10.0..=10.0).speed(0.01).prefix("from ").suffix(" s"));
+                    ui.add(egui::DragValue::new(&mut self.tfr_bmax).range(-10.0..=10.0).speed(0.01).prefix("to ").suffix(" s"));
+                });
+            });
+            if let Some(status) = &self.tfr_status {
+                ui.label(status);
+            }
+            self.tfr_heatmap(ui);
+        });
+        self.show_tfr = open;
+    }
+
+    // Time x frequency image of the chosen channel, highest frequency at the top
+    fn tfr_heatmap(&mut self, ui: &mut egui::Ui) {
+        let Some(result) = &self.tfr else { return };
+        let Some(row) = result.channels.iter().position(|&ch| ch == self.tfr_channel) else {
+            ui.label("Not computed for this channel");
+            return;
+        };
+        if result.times.is_empty() || result.freqs.is_empty() {
+            return;
+        }
+        let baseline = if self.tfr_show_itc { None } else { self.tfr_baseline };
+        let key = (row, self.tfr_show_itc, baseline, self.tfr_bmin.to_bits(), self.tfr_bmax.to_bits(), self.tfr_version);
+        if self.tfr_texture.as_ref().map(|(k, _, _)| *k) != Some(key) {
+            let source = if self.tfr_show_itc { &result.itc } else { &result.power };
+            let values = source.index_axis(ndarray::Axis(0), row);
+            let values = match baseline {
+                Some(mode) => match tfr::baseline_power(values, &result.times, self.tfr_bmin, self.tfr_bmax, mode) {
+                    Ok(values) => values,
+                    Err(e) => {
+                        ui.label(e.to_string());
+                        return;
+                    }
+                },
+                None => values.to_owned(),
+            };
+            let flipped = Array2::from_shape_fn(values.dim(), |(f, t)| values[[values.nrows() - 1 - f, t]]);
+            // Normalised power is signed; raw power and ITC are not, and run from white to red
+            let limit = if baseline.is_some() {
+                plots::robust_abs_limit(flipped.iter().copied(), 0.98)
+            } else {
+                flipped.iter().copied().fold(f64::EPSILON, f64::max)
+            };
+            let image = plots::heatmap_image(&flipped, |v| plots::diverging_color(v, limit));
+            let texture = ui.ctx().load_texture("tfr_image", image, egui::TextureOptions::NEAREST);
+            self.tfr_texture = Some((key, texture, limit));
+        }
+        let Some((_, texture, limit)) = &self.tfr_texture else { return };
+
+        let (t0, t1) = (result.times[0], result.times[result.times.len() - 1]);
+        let (f0, f1) = (result.freqs[0], result.freqs[result.freqs.len() - 1]);
+        let dt = (t1 - t0) / (result.times.len() - 1).max(1) as f64;
+        let df = (f1 - f0) / (result.freqs.len() - 1).max(1) as f64;
+        let unit = match baseline {
+            _ if self.tfr_show_itc => "",
+            None => " µV²",
+            Some(PowerBaseline::Decibel) => " dB",
+            Some(PowerBaseline::Percent) => " %",
+            Some(PowerBaseline::ZScore) => " z",
+        };
+        ui.label(format!("{}, colour limit ±{limit:.2}{unit} (red positive)", result.ch_names[row]));
+        let shaded = self.shaded_windows();
+        Plot::new("tfr_plot")
+            .height(ui.available_height() - 40.0)
+            .x_axis_label("Time (s)")
+            .y_axis_label("Frequency (Hz)")
+            .show(ui, |plot_ui| {
+                plot_ui.image(PlotImage::new(
+                    "TFR",
+                    texture.id(),
+                    PlotPoint::new((t0 + t1) / 2.0, (f0 + f1) / 2.0),
+                    [(t1 - t0 + dt) as f32, (f1 - f0 + df) as f32],
+                ));
+                for &(start, end) in &shaded {
+                    plot_ui.polygon(shade(start, end, f0 - df / 2.0, f1 + df / 2.0));
+                }
+                plot_ui.vline(VLine::new("Event", 0.0).color(Color32::GRAY));
+            });
+        colour_bar(ui, *limit, 240.0);
+    }
+
+    fn topomaps_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_topomaps;
+        egui::Window::new("Topomaps").open(&mut open).default_size([900.0, 400.0]).show(ctx, |ui| {
+            if self.evoked.is_none() {
+                ui.label("Create epochs first");
+                return;
+            }
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Interpolation")
+                    .selected_text(format!("{:?}", self.topo_interpolation))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.topo_interpolation, Interpolation::SphericalSpline, "Spherical spline");
+                        ui.selectable_value(&mut self.topo_interpolation, Interpolation::InverseDistance, "Inverse distance");
+                    });
+                if ui.button("Add latency").clicked() {
+                    self.topo_latencies.push(self.evoked_cursor);
+                }
+            });
+            let mut remove_idx = None;
+            ui.horizontal_wrapped(|ui| {
+                for (idx, latency) in self.topo_latencies.iter_mut().enumerate() {
+                    let mut ms = *latency * 1000.0;
+                    if ui.add(egui::DragValue::new(&mut ms).speed(1.0).suffix(" ms")).changed() {
+                        *latency = ms / 1000.0;
+                    }
+                    if ui.small_button("x").clicked() {
+                        remove_idx = Some(idx);
+                    }
+                }
+            });
+            if let Some(idx) = remove_idx {
+                self.topo_latencies.remove(idx);
+            }
+
+            let latencies = self.topo_latencies.clone();
+            let limit = self.topomap_limit(&latencies);
+            ui.horizontal_wrapped(|ui| {
+                for &latency in &latencies {
+                    ui.vertical(|ui| self.topomap_ui(ui, latency, limit, 160.0));
+                }
+            });
+            colour_bar(ui, limit, 240.0);
+        });
+        self.show_topomaps = open;
+    }
+
+    // Interpolator over the evoked channels that have a position, rebuilt when the epochs or method change
+    fn ensure_topomap(&mut self) {
+        let key = (self.epochs_version, self.topo_interpolation);
+        if self.topomap.as_ref().map(|(k, _, _)| *k) != Some(key) {
+            self.topo_textures.clear();
+            self.topomap = self.evoked.as_ref().and_then(|evoked| {
+                let (picks, electrodes): (Vec<usize>, Vec<positions::Position>) =
+                    positions::channel_positions(&self.eeg_info, evoked.evoked.nrows())
+                        .into_iter()
+                        .enumerate()
+                        .filter_map(|(ch, pos)| pos.map(|pos| (ch, pos)))
+                        .unzip();
+                match Topomap::new(&electrodes, self.topo_interpolation, 64) {
+                    Ok(topomap) => Some((key, topomap, picks)),
+                    Err(e) => {
+                        eprintln!("Error building topomap: {e}");
+                        None
+                    }
+                }
+            });
+        }
+    }
+
+    // Largest absolute evoked value over the mapped channels at the given latencies
+    fn topomap_limit(&mut self, latencies: &[f64]) -> f64 {
+        self.ensure_topomap();
+        let (Some((_, _, picks)), Some(evoked)) = (&self.topomap, &self.evoked) else { return 1.0 };
+        let limit = latencies
+            .iter()
+            .flat_map(|&t| {
+                let idx = evoked.time_to_index(t);
+                picks.iter().map(move |&ch| evoked.evoked[[ch, idx]].abs())
+            })
+            .fold(0.0, f64::max);
+        if limit > 0.0 { limit } else { 1.0 }
+    }
+
+    fn topomap_ui(&mut self, ui: &mut egui::Ui, latency: f64, limit: f64, size: f32) {
+        let version = self.epochs_version;
+        self.ensure_topomap();
+        let (Some((_, topomap, picks)), Some(evoked)) = (&self.topomap, &self.evoked) else {
+            ui.label("No electrode positions for these channels");
+            return;
+        };
+        let idx = evoked.time_to_index(latency);
+        let key = (latency.to_bits(), limit.to_bits(), version);
+        if !self.topo_textures.iter().any(|t| t.key == key) {
+            let values: Vec<f64> = picks.iter().map(|&ch| evoked.evoked[[ch, idx]]).collect();
+            let grid = topomap.grid(&values);
+            let image = plots::heatmap_image(&grid, |v| {
+                if v.is_finite() { plots::diverging_color(v, limit) } else { Color32::TRANSPARENT }
+            });
+            let texture = ui.ctx().load_texture(format!("topomap_{idx}"), image, egui::TextureOptions::LINEAR);
+            let contours = topomap::contour_segments(topomap, &grid, &topomap::contour_levels(limit, 4));
+            if self.topo_textures.len() > 32 {
+                self.topo_textures.clear();
+            }
+            self.topo_textures.push(TopoTexture { key, texture, contours });
+        }
+        let Some(rendered) = self.topo_textures.iter().find(|t| t.key == key) else { return };
+
+        let (response, painter) = ui.allocate_painter(Vec2::splat(size), egui::Sense::hover());
+        let rect = response.rect;
+        let scale = size / (2.0 * topomap.extent) as f32;
+        let to_screen = |p: [f64; 2]| rect.center() + Vec2::new(p[0] as f32 * scale, -p[1] as f32 * scale);
+        painter.image(rendered.texture.id(), rect, Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)), Color32::WHITE);
+        let contour_stroke = Stroke::new(0.8, Color32::from_black_alpha(150));
+        for [a, b] in &rendered.contours {
+            painter.line_segment([to_screen(*a), to_screen(*b)], contour_stroke);
+        }
+        let head_stroke = Stroke::new(1.5, Color32::GRAY);
+        painter.circle_stroke(rect.center(), scale, head_stroke);
+        let nose = [[-0.12, 0.99], [0.0, 1.12], [0.12, 0.99]];
+        painter.line(nose.iter().map(|&p| to_screen(p)).collect(), head_stroke);
+        for side in [-1.0, 1.0] {
+            let ear = [[1.0, 0.12], [1.05, 0.14], [1.08, 0.06], [1.08, -0.06], [1.05, -0.14], [1.0, -0.12]];
+            painter.line(ear.iter().map(|&[x, y]| to_screen([side * x, y])).collect(), head_stroke);
+        }
+        for &point in &topomap.points {
+            painter.circle_filled(to_screen(point), 1.5, Color32::BLACK);
+        }
+        ui.label(format!("{:.0} ms", (evoked.tmin + idx as f64 / evoked.sfreq) * 1000.0));
+    }
+
+    fn components_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_components;
+        egui::Window::new("TEP components").open(&mut open).default_size([750.0, 600.0]).show(ctx, |ui| {
+            self.components_editor_ui(ui);
+            ui.separator();
+            ui.horizontal(|ui| {
+                if ui.add_enabled(self.evoked.is_some(), egui::Button::new("Detect in current evoked")).clicked() {
+                    self.detect_current_peaks();
+                }
+                ui.label("Batch folder:");
+                ui.text_edit_singleline(&mut self.batch_folder);
+                if ui.add_enabled(self.peaks_receiver.is_none(), egui::Button::new("Run batch")).clicked() {
+                    self.spawn_batch_peaks();
+                }
+                if self.peaks_receiver.is_some() {
+                    ui.spinner();
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Batch steps:");
+                ui.checkbox(&mut self.batch_artefacts, format!("Artefact windows ({})", self.artefact_windows.len()));
+                ui.checkbox(&mut self.batch_filter, format!("Filter {}-{} Hz{}", self.lfreq, self.hfreq, if self.apply_notch_filter { " + notch" } else { "" }));
+                ui.checkbox(&mut self.batch_baseline, "Baseline");
+                ui.checkbox(&mut self.batch_reject, "Rejection criteria");
+            });
+            ui.label("Every .vhdr/.edf file is epoched after the ticked steps, with the current settings. Bad channels, re-referencing, ICA, SSP and SOUND/SSP-SIR are not repeated, so results can differ from the current recording.");
+            ui.horizontal(|ui| {
+                ui.label("CSV:");
+                ui.text_edit_singleline(&mut self.peaks_csv_path);
+                if ui.add_enabled(!self.peak_results.is_empty(), egui::Button::new("Export")).clicked() {
+                    let status = match peaks::write_peaks_csv(&self.peaks_csv_path, &self.peak_results) {
+                        Ok(()) => format!("Wrote {} rows to {}", self.peak_results.len(), self.peaks_csv_path),
+                        Err(e) => format!("Error writing {}: {e}", self.peaks_csv_path),
+                    };
+                    self.peaks_status = vec![status];
+                }
+            });
+            for status in &self.peaks_status {
+                ui.label(status);
+            }
+            ui.separator();
+            self.peak_results_ui(ui);
+        });
+        self.show_components = open;
+    }
+
+    fn components_editor_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("Search windows (ms), polarity and ROI channels or group names (comma separated, empty: all channels)");
+        let mut remove_idx = None;
+        egui::Grid::new("tep_components").num_columns(6).striped(true).show(ui, |ui| {
+            for (idx, component) in self.tep_components.iter_mut().enumerate() {
+                ui.add(egui::TextEdit::singleline(&mut component.name).desired_width(50.0));
+                let mut window_ms = [component.tmin * 1000.0, component.tmax * 1000.0];
+                let from = ui.add(egui::DragValue::new(&mut window_ms[0]).speed(1.0).suffix(" ms"));
+                let to = ui.add(egui::DragValue::new(&mut window_ms[1]).speed(1.0).suffix(" ms"));
+                if from.changed() || to.changed() {
+                    component.tmin = window_ms[0] / 1000.0;
+                    component.tmax = window_ms[1] / 1000.0;
+                }
+                egui::ComboBox::from_id_salt(("tep_polarity", idx))
+                    .selected_text(format!("{:?}", component.polarity))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut component.polarity, Polarity::Negative, "Negative");
+                        ui.selectable_value(&mut component.polarity, Polarity::Positive, "Positive");
+                    });
+                let mut roi = component.roi.join(", ");
+                if ui.add(egui::TextEdit::singleline(&mut roi).desired_width(200.0)).changed() {
+                    component.roi = roi.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_owned).collect();
+                }
+                if ui.small_button("x").clicked() {
+                    remove_idx = Some(idx);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(idx) = remove_idx {
+            self.tep_components.remove(idx);
+        }
+        ui.horizontal(|ui| {
+            if ui.button("Add component").clicked() {
+                self.tep_components.push(TepComponent::new("C", 0.0, 0.010, Polarity::Positive));
+            }
+            if ui.button("Reset to defaults").clicked() {
+                self.tep_components = peaks::default_components();
+            }
+        });
+    }
+
+    fn peak_results_ui(&self, ui: &mut egui::Ui) {
+        let format_value = |v: f64| if v.is_finite() { format!("{v:.2}") } else { "-".to_owned() };
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("tep_results").num_columns(7).striped(true).show(ui, |ui| {
+                for header in ["Subject", "Component", "Channels", "Latency (ms)", "Peak (µV)", "Mean (µV)", "Local peak"] {
+                    ui.strong(header);
+                }
+                ui.end_row();
+                for r in &self.peak_results {
+                    ui.label(&r.subject);
+                    ui.label(&r.component);
+                    ui.label(r.n_channels.to_string());
+                    ui.label(format_value(r.peak_latency * 1000.0));
+                    ui.label(format_value(r.peak_amplitude));
+                    ui.label(format_value(r.mean_amplitude));
+                    ui.label(if r.local_peak { "yes" } else { "no (window extreme)" });
+                    ui.end_row();
+                }
+            });
+        });
+    }
 
-impl Default for TemplateApp {
-    fn default() -> Self {
-        Self {
-            data_format: DataFormat::EDF,
-            file_dialog: FileDialog::new(),
-            edf_file: None,
-            raw_eeg: RawEEG::default(),
-            eeg_info: EEGInfo::default(),
-            eeg_markers: Markers::default(),
-            loading_receiver: None,
-            filtering_receiver: None,
-            apply_notch_filter: false,
-            artifact_receiver: None,
-            show_data: false,
-            reference_type: ReferenceType::Original,
-            selected_channel_for_color: 0,
-            global_color: Color32::WHITE,
-            selected_channel: 0,
-            decimation_factor: 100,
-            x_view: 0.0,
-            y_view_min: 0.0,
-            y_view_max: 600.0,
-            gain: 1.0,
-            plot_zoom_factor: Vec2::new(1.0, 1.0),
-            unselected_channels: Vec::new(),
-            channel_colors: Vec::new(),
-            tmin_cut: 0.002,
-            tmax_cut: 0.005,
-            lfreq: 1.0,
-            hfreq: 45.0,
-            ruler_position: None,
-            ruler_width: 1.0,  // Default width: 1 second
-            ruler_height: 50.0, // Default height: 50 microvolts
-            ruler_dragging: false,
+    // Components with group names in their ROI replaced by the channels of the group
+    fn expanded_components(&self) -> Vec<TepComponent> {
+        self.tep_components
+            .iter()
+            .map(|component| TepComponent { roi: self.eeg_info.expand_labels(&component.roi), ..component.clone() })
+            .collect()
+    }
+
+    fn detect_current_peaks(&mut self) {
+        let Some(evoked) = &self.evoked else { return };
+        let subject = self
+            .edf_file
+            .as_ref()
+            .and_then(|path| path.to_str())
+            .map_or_else(|| "current".to_owned(), batch::subject_name);
+        self.peak_results = peaks::detect_components(evoked, &self.expanded_components(), &self.epochs_info(), &subject);
+        self.peaks_status.clear();
+    }
+
+    fn spawn_batch_peaks(&mut self) {
+        let paths: Vec<String> = match std::fs::read_dir(&self.batch_folder) {
+            Ok(entries) => {
+                let mut paths: Vec<String> = entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| {
+                        path.extension()
+                            .and_then(|ext| ext.to_str())
+                            .is_some_and(|ext| ext.eq_ignore_ascii_case("vhdr") || ext.eq_ignore_ascii_case("edf"))
+                    })
+                    .filter_map(|path| path.to_str().map(str::to_owned))
+                    .collect();
+                paths.sort();
+                paths
+            }
+            Err(e) => {
+                self.peaks_status = vec![format!("Error reading {}: {e}", self.batch_folder)];
+                return;
+            }
+        };
+        let settings = batch::EvokedSettings {
+            event_types: self.selected_event_types.clone(),
+            artefact_windows: if self.batch_artefacts { self.artefact_windows.clone() } else { Vec::new() },
+            filter: self.batch_filter.then_some((self.lfreq, self.hfreq, self.apply_notch_filter)),
+            tmin: self.epoch_tmin,
+            tmax: self.epoch_tmax,
+            baseline: self.batch_baseline.then_some((self.baseline_bmin, self.baseline_bmax, self.baseline_mode)),
+            reject: self.batch_reject.then_some(self.reject_criteria),
+            reject_by_annotation: self.reject_bad_segments,
+        };
+        let components = self.expanded_components();
+        self.peaks_status = vec![format!("Processing {} files...", paths.len())];
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.peaks_receiver = Some(receiver);
+        std::thread::spawn(move || {
+            sender.send(batch::batch_peaks(&paths, &settings, &components)).ok();
+        });
+    }
+
+    fn poll_peaks(&mut self) {
+        if let Some(receiver) = &self.peaks_receiver {
+            match receiver.try_recv() {
+                Ok((results, errors)) => {
+                    self.peaks_status = vec![format!("Batch done, {} rows", results.len())];
+                    self.peaks_status.extend(errors);
+                    self.peak_results = results;
+                    self.peaks_receiver = None;
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => {}
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    self.peaks_status = vec!["Batch thread disconnected".to_owned()];
+                    self.peaks_receiver = None;
+                }
+            }
         }
     }
-}
 
-impl TemplateApp {
-    fn min_max_decimate<T>(
-        &self,
-        data: &[T],
-        start_sample: usize,
-        decimation: usize,
-        offset: f64,
-        sampling_frequency: f64
-    ) -> Vec<[f64; 2]>
-    where
-        T: Copy + PartialOrd + Into<f64>,
-    {
-        if decimation <= 1 {
-            return data.iter().enumerate().map(|(i, &sample)| {
-                let x = (start_sample + i) as f64 / sampling_frequency;
-                let y = (sample.into() / 100.0) * self.gain + offset;
-                [x, y]
-            }).collect();
+    // Number of data channels; EDF channel names also list the annotations channel
+    fn n_data_channels(&self) -> usize {
+        match (&self.raw_eeg.bv_data, &self.raw_eeg.edf_data) {
+            (Some(data), _) => data.len(),
+            (None, Some(data)) => data.len(),
+            (None, None) => self.eeg_info.ch_names.len(),
         }
+    }
 
-        let mut points = Vec::new();
-        for chunk in data.chunks(decimation) {
-            let chunk_start = (points.len() / 2) * decimation;
-            let time_base = (start_sample + chunk_start) as f64 / sampling_frequency;
+    fn n_samples(&self) -> usize {
+        match (&self.raw_eeg.bv_data, &self.raw_eeg.edf_data) {
+            (Some(data), _) => data.first().map_or(0, Vec::len),
+            (None, Some(data)) => data.first().map_or(0, Vec::len),
+            (None, None) => 0,
+        }
+    }
 
-            if let Some(&first) = chunk.first() {
-                let (min_val, max_val) = chunk.iter().fold((first, first), |(min, max), &val| {
-                    (if val < min { val } else { min }, if val > max { val } else { max })
+    fn channel_groups_ui(&mut self, ui: &mut egui::Ui) {
+        let ch_names: Vec<String> = self.eeg_info.ch_names.iter().take(self.n_data_channels()).cloned().collect();
+        let groups = &mut self.eeg_info.channel_groups;
+        let mut changed = false;
+        egui::CollapsingHeader::new("Channel groups (ROIs)").show(ui, |ui| {
+            let mut remove_idx = None;
+            for (idx, group) in groups.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    changed |= ui.add(egui::TextEdit::singleline(&mut group.name).desired_width(80.0)).changed();
+                    let mut labels = group.channels.join(", ");
+                    if ui.add(egui::TextEdit::singleline(&mut labels).desired_width(140.0)).changed() {
+                        group.channels = ChannelGroup::from_labels(&group.name, &labels).channels;
+                        changed = true;
+                    }
+                    ui.menu_button("Pick", |ui| {
+                        egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                            for name in &ch_names {
+                                let label = positions::clean_label(name);
+                                let mut member = group.channels.iter().any(|c| c.eq_ignore_ascii_case(&label));
+                                if ui.checkbox(&mut member, &label).changed() {
+                                    if member {
+                                        group.channels.push(label);
+                                    } else {
+                                        group.channels.retain(|c| !c.eq_ignore_ascii_case(&label));
+                                    }
+                                    changed = true;
+                                }
+                            }
+                        });
+                    });
+                    if ui.small_button("x").clicked() {
+                        remove_idx = Some(idx);
+                    }
+                });
+            }
+            if let Some(idx) = remove_idx {
+                groups.remove(idx);
+                changed = true;
+            }
+            if ui.button("Add group").clicked() {
+                groups.push(ChannelGroup { name: format!("ROI {}", groups.len() + 1), channels: Vec::new() });
+                changed = true;
+            }
+        });
+        if changed {
+            self.channel_groups = self.eeg_info.channel_groups.clone();
+        }
+    }
+
+    fn artefact_windows_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("Windows per marker: offset, pre and post (s)");
+        let mut remove_idx = None;
+        for (idx, window) in self.artefact_windows.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut window.offset).speed(0.001).range(0.0..=2.0).max_decimals(3));
+                ui.add(egui::DragValue::new(&mut window.pre).speed(0.0005).range(0.0..=0.1).max_decimals(4));
+                ui.add(egui::DragValue::new(&mut window.post).speed(0.0005).range(0.0..=0.2).max_decimals(4));
+                egui::ComboBox::from_id_salt(("artefact_method", idx))
+                    .selected_text(format!("{:?}", window.method))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut window.method, ArtefactMethod::Zero, "Zero");
+                        ui.selectable_value(&mut window.method, ArtefactMethod::Interpolate, "Interpolate");
+                    });
+                if ui.button("✖").clicked() {
+                    remove_idx = Some(idx);
+                }
+            });
+        }
+        if let Some(idx) = remove_idx {
+            self.artefact_windows.remove(idx);
+        }
+        if ui.button("Add pulse window").clicked() {
+            self.artefact_windows.push(ArtefactWindow {
+                offset: 0.0,
+                pre: self.tmin_cut,
+                post: self.tmax_cut,
+                method: ArtefactMethod::Interpolate,
+            });
+        }
+
+        ui.add(egui::Slider::new(&mut self.recharge_search_start, 0.001..=1.0)
+            .text("Recharge search from (s)"));
+        ui.add(egui::Slider::new(&mut self.recharge_search_end, 0.002..=2.0)
+            .text("Recharge search to (s)"));
+        ui.horizontal(|ui| {
+            if ui.add_enabled(self.recharge_receiver.is_none(), egui::Button::new("Estimate recharge delay")).clicked() {
+                self.spawn_recharge_estimate();
+            }
+            if self.recharge_receiver.is_some() {
+                ui.spinner();
+            }
+        });
+        if let Some(status) = &self.recharge_status {
+            ui.label(status);
+        }
+        if ui.button("Remove artefact windows").clicked() {
+            self.spawn_artefact_windows();
+        }
+    }
+
+    fn spawn_recharge_estimate(&mut self) {
+        let (start, end) = (self.recharge_search_start, self.recharge_search_end);
+        let (markers, info) = (self.selected_markers(), self.eeg_info.clone());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        match (self.data_format, self.raw_eeg.edf_data.clone(), self.raw_eeg.bv_data.clone()) {
+            (DataFormat::EDF, Some(data_vec), _) => {
+                std::thread::spawn(move || {
+                    let result = signal::estimate_recharge_delay(start, end, &markers, &info, &signal::vec_to_ndarray(&data_vec));
+                    sender.send(result.map_err(|e| e.to_string())).ok();
+                });
+            }
+            (DataFormat::BrainVision, _, Some(data_vec)) => {
+                std::thread::spawn(move || {
+                    let result = signal::estimate_recharge_delay(start, end, &markers, &info, &signal::vec_to_ndarray(&data_vec));
+                    sender.send(result.map_err(|e| e.to_string())).ok();
                 });
+            }
+            _ => {
+                self.recharge_status = Some("No data loaded".to_owned());
+                return;
+            }
+        }
+        self.recharge_status = None;
+        self.recharge_receiver = Some(receiver);
+    }
 
-                points.push([time_base, (min_val.into() / 100.0) * self.gain + offset]);
-                points.push([time_base + (decimation as f64 * 0.5) / sampling_frequency,
-                            (max_val.into() / 100.0) * self.gain + offset]);
+    fn poll_recharge(&mut self) {
+        let Some(receiver) = &self.recharge_receiver else { return };
+        match receiver.try_recv() {
+            Ok(Ok(delay)) => {
+                self.recharge_status = Some(format!("Estimated recharge delay: {:.1} ms, added as a pulse window", delay * 1000.0));
+                self.artefact_windows.push(ArtefactWindow {
+                    offset: delay,
+                    pre: self.tmin_cut,
+                    post: self.tmax_cut,
+                    method: ArtefactMethod::Interpolate,
+                });
+                self.recharge_receiver = None;
+            }
+            Ok(Err(e)) => {
+                self.recharge_status = Some(format!("Error estimating recharge delay: {e}"));
+                self.recharge_receiver = None;
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => self.recharge_receiver = None,
+        }
+    }
+
+    fn spawn_artefact_windows(&mut self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.artifact_receiver = Some(receiver);
+
+        let info = self.eeg_info.clone();
+        let markers = self.selected_markers();
+        let windows = self.artefact_windows.clone();
+
+        match self.data_format {
+            DataFormat::EDF => {
+                if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
+                    std::thread::spawn(move || {
+                        let data = signal::vec_to_ndarray(&data_vec);
+                        let result = signal::remove_artefact_windows(&windows, &markers, &info, &data)
+                            .map(ProcessedDataType::EDF);
+                        sender.send(result.map_err(|e|
+                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                        )).ok();
+                    });
+                }
+            }
+            DataFormat::BrainVision => {
+                if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
+                    std::thread::spawn(move || {
+                        let data = signal::vec_to_ndarray(&data_vec);
+                        let result = signal::remove_artefact_windows(&windows, &markers, &info, &data)
+                            .map(ProcessedDataType::BV);
+                        sender.send(result.map_err(|e|
+                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                        )).ok();
+                    });
+                }
             }
         }
-        points
     }
 }
 
@@ -183,8 +3829,41 @@ impl eframe::App for TemplateApp {
                 Ok(Ok((new_raw_eeg, new_eeg_info, new_markers))) => {
                     self.raw_eeg = new_raw_eeg;
                     self.eeg_info = new_eeg_info;
+                    self.eeg_info.channel_groups = self.channel_groups.clone();
+                    self.bad_channel_report.clear();
+                    self.bad_channel_status = None;
+                    self.selected_marker = None;
+                    self.selected_annotation = None;
+                    self.marker_export_path.clear();
+                    self.marker_status = None;
+                    self.overview = None;
+                    self.spectrogram_textures.clear();
+                    self.ica_sources = None;
+                    self.ica_preview_cache = None;
+                    self.ssp_view = None;
+                    self.average_view = None;
+                    self.channel_page = 0;
+                    self.psd = None;
+                    self.psd_status = None;
+                    self.tfr = None;
+                    self.tfr_status = None;
+                    self.ica = None;
+                    self.ica_exclude.clear();
+                    self.ica_status = None;
+                    self.ica_properties = None;
+                    self.ica_topomap = None;
+                    self.ica_topo_textures.clear();
+                    self.projectors.clear();
+                    self.ssp_version += 1;
+                    self.ssp_status = None;
+                    self.denoising = None;
+                    self.denoise_status = None;
                     self.eeg_markers = new_markers;
                     self.loading_receiver = None;
+                    self.epochs = None;
+                    self.evoked = None;
+                    self.reset_event_selection();
+                    self.refresh_montage();
                     if let Some(ref data_vec) = self.raw_eeg.edf_data {
                         self.channel_colors = vec![Color32::WHITE; data_vec.len()];
                     } else if let Some(ref data_vec) = self.raw_eeg.bv_data {
@@ -219,11 +3898,26 @@ impl eframe::App for TemplateApp {
                             self.raw_eeg.bv_data = Some(data_vec);
                         }
                     }
+                    self.overview = None;
+                    self.spectrogram_textures.clear();
+                    self.ica_sources = None;
+                    self.ica_preview_cache = None;
+                    self.ssp_view = None;
+                    self.average_view = None;
                     self.filtering_receiver = None;
+                    if !self.pending_interpolation.is_empty() {
+                        let interpolated = std::mem::take(&mut self.pending_interpolation);
+                        self.eeg_info.bad_channels.retain(|ch| !interpolated.contains(ch));
+                        self.bad_channel_status = Some(format!("Interpolated {} channels", interpolated.len()));
+                        self.refresh_montage();
+                    }
                 }
                 Ok(Err(e)) => {
                     eprintln!("Error filtering data: {}", e);
                     self.filtering_receiver = None;
+                    if !std::mem::take(&mut self.pending_interpolation).is_empty() {
+                        self.bad_channel_status = Some(format!("Interpolation failed: {e}"));
+                    }
                 }
                 Err(std::sync::mpsc::TryRecvError::Empty) => {
                     // Still filtering
@@ -231,6 +3925,7 @@ impl eframe::App for TemplateApp {
                 Err(std::sync::mpsc::TryRecvError::Disconnected) => {
                     eprintln!("Filtering thread disconnected");
                     self.filtering_receiver = None;
+                    self.pending_interpolation.clear();
                 }
             }
         }
@@ -250,6 +3945,12 @@ impl eframe::App for TemplateApp {
                             self.raw_eeg.bv_data = None;
                         }
                     }
+                    self.overview = None;
+                    self.spectrogram_textures.clear();
+                    self.ica_sources = None;
+                    self.ica_preview_cache = None;
+                    self.ssp_view = None;
+                    self.average_view = None;
                     self.artifact_receiver = None;
                 }
                 Ok(Err(e)) => {
@@ -264,6 +3965,16 @@ impl eframe::App for TemplateApp {
             }
         }
 
+        self.poll_epochs();
+        self.poll_bad_channels();
+        self.poll_peaks();
+        self.poll_psd();
+        self.poll_tfr();
+        self.poll_ica();
+        self.poll_ica_properties();
+        self.poll_denoise();
+        self.poll_recharge();
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
 
@@ -280,9 +3991,16 @@ impl eframe::App for TemplateApp {
                 }
 
                 egui::widgets::global_theme_preference_buttons(ui);
+                ui.add_space(16.0);
+                ui.toggle_value(&mut self.show_events, "Events");
+                ui.toggle_value(&mut self.show_spectrum, "Spectrum");
             });
         });
 
+        if self.show_events {
+            self.events_panel(ctx);
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
             ui.heading("Dangercat EEG reader");
@@ -422,6 +4140,7 @@ impl eframe::App for TemplateApp {
             ui.separator();
             ui.heading("Filter settings");
             ui.checkbox(&mut self.apply_notch_filter, "Apply 50 Hz notch filter");
+            ui.checkbox(&mut self.filter_skip_bad_segments, "Filter the data between BAD segments separately");
             egui::ComboBox::from_label("Highpass filter lfreq")
                 .selected_text(format!("{:?}", self.lfreq))
                 .show_ui(ui, |ui| {
@@ -454,22 +4173,27 @@ impl eframe::App for TemplateApp {
                 let lfreq = self.lfreq;
                 let hfreq = self.hfreq;
                 let apply_notch = self.apply_notch_filter;
+                // Good stretches shorter than a second are too short for the filters and left alone
+                let skip = if self.filter_skip_bad_segments { self.eeg_markers.bad_spans(self.n_samples()) } else { Vec::new() };
+                let min_len = self.eeg_info.sfreq.max(1) as usize;
 
                 match self.data_format {
                     DataFormat::EDF => {
                         if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
                             std::thread::spawn(move || {
                                 let data = signal::vec_to_ndarray(&data_vec);
-                                let result = signal::edf_hp_filter(lfreq, &info, &data)
-                                    .and_then(|filtered| signal::edf_lp_filter(hfreq, &info, &filtered))
-                                    .and_then(|filtered| {
-                                        if apply_notch {
-                                            signal::edf_notch_filter_50hz(&info, &filtered)
-                                        } else {
-                                            Ok(filtered)
-                                        }
-                                    })
-                                    .map(ProcessedDataType::EDF);
+                                let result = signal::apply_between_spans(&data, &skip, min_len, |segment| {
+                                    signal::edf_hp_filter(lfreq, &info, segment)
+                                        .and_then(|filtered| signal::edf_lp_filter(hfreq, &info, &filtered))
+                                        .and_then(|filtered| {
+                                            if apply_notch {
+                                                signal::edf_notch_filter_50hz(&info, &filtered)
+                                            } else {
+                                                Ok(filtered)
+                                            }
+                                        })
+                                })
+                                .map(ProcessedDataType::EDF);
                                 let _ = sender.send(result.map_err(|e| std::io::Error::new(
                                     std::io::ErrorKind::Other, e.to_string()
                                 )));
@@ -480,16 +4204,18 @@ impl eframe::App for TemplateApp {
                         if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
                             std::thread::spawn(move || {
                                 let data = signal::vec_to_ndarray(&data_vec);
-                                let result = signal::hp_filter(lfreq, &info, &data)
-                                    .and_then(|filtered| signal::lp_filter(hfreq, &info, &filtered))
-                                    .and_then(|filtered| {
-                                        if apply_notch {
-                                            signal::notch_filter_50hz(&info, &filtered)
-                                        } else {
-                                            Ok(filtered)
-                                        }
-                                    })
-                                    .map(ProcessedDataType::BV);
+                                let result = signal::apply_between_spans(&data, &skip, min_len, |segment| {
+                                    signal::hp_filter(lfreq, &info, segment)
+                                        .and_then(|filtered| signal::lp_filter(hfreq, &info, &filtered))
+                                        .and_then(|filtered| {
+                                            if apply_notch {
+                                                signal::notch_filter_50hz(&info, &filtered)
+                                            } else {
+                                                Ok(filtered)
+                                            }
+                                        })
+                                })
+                                .map(ProcessedDataType::BV);
                                 let _ = sender.send(result.map_err(|e| std::io::Error::new(
                                     std::io::ErrorKind::Other, e.to_string()
                                 )));
@@ -508,99 +4234,145 @@ impl eframe::App for TemplateApp {
             if self.show_data {
                 if !self.eeg_info.ch_names.is_empty() && self.eeg_info.sfreq > 0 {
                     // Keyboard controls
-                    if ctx.input(|i|i.key_pressed(Key::K)){
-                        self.y_view_max += 10.0;
-                        self.y_view_min += 10.0
+                    if ctx.input(|i|i.key_pressed(Key::K) || i.key_pressed(Key::PageUp)){
+                        self.channel_page = self.channel_page.saturating_sub(1);
                     }
-                    if ctx.input(|i|i.key_pressed(Key::J)){
-                        self.y_view_max -= 10.0;
-                        self.y_view_min -= 10.0
+                    if ctx.input(|i|i.key_pressed(Key::J) || i.key_pressed(Key::PageDown)){
+                        self.channel_page = (self.channel_page + 1).min(self.n_channel_pages() - 1);
                     }
                     if ctx.input(|i|i.key_pressed(Key::ArrowRight)){
-                        self.x_view += 10.0
+                        self.x_view += self.page_duration;
                     }
                     if ctx.input(|i|i.key_pressed(Key::ArrowLeft)){
-                        self.x_view -= 10.0
+                        self.x_view -= self.page_duration;
                     }
                     if ctx.input(|i|i.key_pressed(Key::L)){
-                        self.x_view += 10.0
+                        self.x_view += self.page_duration;
                     }
                     if ctx.input(|i|i.key_pressed(Key::H)){
-                        self.x_view -= 10.0
+                        self.x_view -= self.page_duration;
+                    }
+                    if !ctx.wants_keyboard_input() && ctx.input(|i| i.key_pressed(Key::OpenBracket)) {
+                        self.page_duration = Self::step_value(self.page_duration, &PAGE_DURATIONS, false);
+                    }
+                    if !ctx.wants_keyboard_input() && ctx.input(|i| i.key_pressed(Key::CloseBracket)) {
+                        self.page_duration = Self::step_value(self.page_duration, &PAGE_DURATIONS, true);
+                    }
+                    if !ctx.wants_keyboard_input() && ctx.input(|i| i.key_pressed(Key::N)) {
+                        self.jump_to_event(true);
                     }
+                    if !ctx.wants_keyboard_input() && ctx.input(|i| i.key_pressed(Key::P)) {
+                        self.jump_to_event(false);
+                    }
+                    // Up enlarges the traces, i.e. fewer µV per division
                     if ctx.input(|i|i.key_pressed(Key::ArrowUp)){
-                        self.gain *= 1.1;
+                        self.sensitivity = Self::step_value(self.sensitivity, &SENSITIVITY_STEPS, false);
                     }
                     if ctx.input(|i|i.key_pressed(Key::ArrowDown)){
-                        self.gain /= 1.1;
+                        self.sensitivity = Self::step_value(self.sensitivity, &SENSITIVITY_STEPS, true);
                     }
 
 
+                    self.display_ui(ui);
+                    self.viewer_tool_ui(ui);
+                    let overview_space = if self.show_overview { OVERVIEW_HEIGHT + 40.0 } else { 0.0 };
+                    let spectrogram_space = if self.show_spectrogram {
+                        self.spectrogram_channels.len() as f32 * (SPECTROGRAM_HEIGHT + 4.0) + 30.0
+                    } else {
+                        30.0
+                    };
                     Plot::new("my_plot")
+                        .height((ui.available_height() - overview_space - spectrogram_space).max(200.0))
+                        .y_axis_min_width(VIEWER_Y_AXIS_WIDTH)
+                        .allow_drag(self.viewer_tool == ViewerTool::Navigate)
                         .show_x(true)
                         .show_y(false)
                         .show(ui, |plot_ui| {
                             let sampling_frequency = self.eeg_info.sfreq as f64;
-                            let channel_names = &self.eeg_info.ch_names;
 
                             let start_time = self.x_view;
-                            let end_time = self.x_view + 10.0;
+                            let end_time = self.x_view + self.page_duration;
                             let start_sample = ((start_time * sampling_frequency) as usize).max(0);
                             let end_sample = (end_time * sampling_frequency) as usize;
+                            let label_x = self.x_view + self.page_duration * 0.01;
 
-                            let mut offset = 0.0;
-                            let channel_offset = 10.0;
+                            self.refresh_average_view(start_sample, end_sample);
+                            self.refresh_ssp_view(start_sample, end_sample);
+                            // Projected or average-referenced window in place of the stored traces
+                            let projected = self.ssp_view.as_ref().map(|(_, window)| window).or_else(|| self.average_view.as_ref().map(|(_, window)| window));
+                            let channel_names = &self.eeg_info.ch_names;
+                            let page_rows = self.page_rows();
+                            let mut row = 0;
+                            let channel_offset = CHANNEL_SPACING;
+                            let mut label_rows = Vec::new();
 
                             match self.data_format {
+                                _ if self.montage.is_some() => {
+                                    let mut offset = 0.0;
+                                    for (name, points) in self.montage_points(page_rows.clone(), start_sample, end_sample, sampling_frequency) {
+                                        plot_ui.line(Line::new(name.clone(), points).color(self.global_color));
+                                        let text_point = PlotPoint::new(label_x, offset);
+                                        plot_ui.text(Text::new(name.clone(), text_point, name));
+                                        offset += channel_offset;
+                                    }
+                                }
                                 DataFormat::EDF => {
-                                    let data_vec = match self.reference_type {
-                                        ReferenceType::Original => &self.raw_eeg.edf_data,
-                                        ReferenceType::AverageReference => &self.raw_eeg.edf_data_avg_ref,
-                                    };
-                                    if let Some(data_vec) = data_vec {
+                                    if let Some(data_vec) = &self.raw_eeg.edf_data {
                                         for ch in 0..data_vec.len() {
                                             if !self.unselected_channels.contains(&ch) {
+                                                let on_page = page_rows.contains(&row);
+                                                let offset = row.saturating_sub(page_rows.start) as f64 * channel_offset;
+                                                row += 1;
                                                 let channel_slice = &data_vec[ch];
-                                                if start_sample < channel_slice.len() {
+                                                if on_page && start_sample < channel_slice.len() {
                                                     let actual_end = end_sample.min(channel_slice.len());
                                                     let visible_data = &channel_slice[start_sample..actual_end];
-                                                    let points = self.min_max_decimate(visible_data, start_sample, self.decimation_factor, offset, sampling_frequency);
-                                                    let line_color = self.channel_colors[ch];
+                                                    let points = match projected.and_then(|window| window.get(ch)) {
+                                                        Some(trace) => Self::min_max_decimate(trace, start_sample, self.decimation_factor, offset, self.plot_scale(ch), sampling_frequency),
+                                                        None => Self::min_max_decimate(visible_data, start_sample, self.decimation_factor, offset, self.plot_scale(ch), sampling_frequency),
+                                                    };
+                                                    let is_bad = self.eeg_info.bad_channels.contains(&ch);
+                                                    let line_color = if is_bad { BAD_CHANNEL_COLOR } else { self.channel_colors[ch] };
                                                     plot_ui.line(Line::new(format!("ch_{}", ch), points).color(line_color));
-                                                    let text_point = PlotPoint::new(self.x_view + 0.1, offset);
+                                                    let text_point = PlotPoint::new(label_x, offset);
+                                                    let label = if is_bad { format!("{} (bad)", channel_names[ch]) } else { channel_names[ch].clone() };
                                                     plot_ui.text(Text::new(
                                                         channel_names[ch].clone(),
                                                         text_point,
-                                                        channel_names[ch].clone(),
+                                                        label,
                                                     ));
-                                                    offset += channel_offset;
+                                                    label_rows.push((ch, offset));
                                                 }
                                             }
                                         }
                                     }
                                 }
                                 DataFormat::BrainVision => {
-                                    let data_vec = match self.reference_type {
-                                        ReferenceType::Original => &self.raw_eeg.bv_data,
-                                        ReferenceType::AverageReference => &self.raw_eeg.bv_data_avg_ref,
-                                    };
-                                    if let Some(data_vec) = data_vec {
+                                    if let Some(data_vec) = &self.raw_eeg.bv_data {
                                         for ch in 0..data_vec.len() {
                                             if !self.unselected_channels.contains(&ch) {
+                                                let on_page = page_rows.contains(&row);
+                                                let offset = row.saturating_sub(page_rows.start) as f64 * channel_offset;
+                                                row += 1;
                                                 let channel_slice = &data_vec[ch];
-                                                if start_sample < channel_slice.len() {
+                                                if on_page && start_sample < channel_slice.len() {
                                                     let actual_end = end_sample.min(channel_slice.len());
                                                     let visible_data = &channel_slice[start_sample..actual_end];
-                                                    let points = self.min_max_decimate(visible_data, start_sample, self.decimation_factor, offset, sampling_frequency);
-                                                    let line_color = self.channel_colors[ch];
+                                                    let points = match projected.and_then(|window| window.get(ch)) {
+                                                        Some(trace) => Self::min_max_decimate(trace, start_sample, self.decimation_factor, offset, self.plot_scale(ch), sampling_frequency),
+                                                        None => Self::min_max_decimate(visible_data, start_sample, self.decimation_factor, offset, self.plot_scale(ch), sampling_frequency),
+                                                    };
+                                                    let is_bad = self.eeg_info.bad_channels.contains(&ch);
+                                                    let line_color = if is_bad { BAD_CHANNEL_COLOR } else { self.channel_colors[ch] };
                                                     plot_ui.line(Line::new(format!("ch_{}", ch), points).color(line_color));
-                                                    let text_point = PlotPoint::new(self.x_view + 0.1, offset);
+                                                    let text_point = PlotPoint::new(label_x, offset);
+                                                    let label = if is_bad { format!("{} (bad)", channel_names[ch]) } else { channel_names[ch].clone() };
                                                     plot_ui.text(Text::new(
                                                         channel_names[ch].clone(),
                                                         text_point,
-                                                        channel_names[ch].clone(),
+                                                        label,
                                                     ));
-                                                    offset += channel_offset;
+                                                    label_rows.push((ch, offset));
                                                 }
                                             }
                                         }
@@ -609,17 +4381,31 @@ impl eframe::App for TemplateApp {
                             }
 
 
-                            let visible_channels = self.eeg_info.num_ch as usize - self.unselected_channels.len();
-                            let total_height = visible_channels as f64 * channel_offset;
+                            if self.ica_preview {
+                                self.draw_ica_preview(plot_ui, &label_rows, page_rows.clone(), (start_sample, end_sample), sampling_frequency);
+                            }
+
+                            let navigating = self.viewer_tool == ViewerTool::Navigate;
+                            if let Some(ch) = Self::clicked_channel_label(plot_ui, &label_rows, label_x).filter(|_| navigating) {
+                                let bad = !self.eeg_info.bad_channels.contains(&ch);
+                                self.set_channel_bad(ch, bad);
+                            }
+
+                            let total_height = page_rows.len() as f64 * channel_offset;
                             plot_ui.set_plot_bounds_y(-channel_offset..=(total_height + channel_offset));
-                            plot_ui.set_plot_bounds_x(self.x_view..=(self.x_view + 10.0));
-                            for marker_pos in &self.eeg_markers.markers {
-                                let marker_time = *marker_pos / sampling_frequency;
-                                plot_ui.vline(VLine::new("Annotation", marker_time));
+                            plot_ui.set_plot_bounds_x(self.x_view..=(self.x_view + self.page_duration));
+                            self.annotation_interaction(plot_ui, sampling_frequency);
+                            self.draw_annotations(plot_ui, sampling_frequency, -channel_offset, total_height + channel_offset);
+                            if self.viewer_tool == ViewerTool::EditMarkers {
+                                self.marker_interaction(plot_ui, sampling_frequency);
+                            }
+                            self.draw_markers(plot_ui, sampling_frequency, total_height + channel_offset / 2.0);
+                            if self.show_scale_bar {
+                                self.draw_scale_bar(plot_ui);
                             }
                             if let Some(ruler_pos_val) = self.ruler_position {
                                 let mut ruler_pos = ruler_pos_val;
-                                let plot_ruler_height = (self.ruler_height / 100.0) * self.gain;
+                                let plot_ruler_height = self.ruler_height * CHANNEL_SPACING / self.sensitivity;
 
                                 let ruler_rect = egui::Rect::from_min_size(
                                     egui::pos2(ruler_pos.0 as f32, ruler_pos.1 as f32),
@@ -655,6 +4441,8 @@ impl eframe::App for TemplateApp {
                                 plot_ui.text(Text::new("ruler_text_w".to_string(), text_pos_h, width_text).color(color));
                             }
                         });
+                    self.spectrogram_ui(ui);
+                    self.overview_ui(ui);
 
                 } else {
                     ui.label("No data available to plot");
@@ -679,161 +4467,242 @@ impl eframe::App for TemplateApp {
         egui::SidePanel::right("analysis_panel")
             .min_width(250.0)
             .show(ctx, |ui| {
-                ui.heading("Artefact removal");
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    self.event_selection_ui(ui);
+                    ui.separator();
+                    self.channel_groups_ui(ui);
+                    ui.separator();
 
-                ui.add(egui::Slider::new(&mut self.tmin_cut, 0.001..=0.020)
-                    .text("Pre-stimulus (s)")
-                    .suffix(" s"));
-                ui.add(egui::Slider::new(&mut self.tmax_cut, 0.001..=0.050)
-                    .text("Post-stimulus (s)")
-                    .suffix(" s"));
+                    ui.heading("Artefact removal");
 
-                ui.separator();
+                    ui.add(egui::Slider::new(&mut self.tmin_cut, 0.001..=0.020)
+                        .text("Pre-stimulus (s)")
+                        .suffix(" s"));
+                    ui.add(egui::Slider::new(&mut self.tmax_cut, 0.001..=0.050)
+                        .text("Post-stimulus (s)")
+                        .suffix(" s"));
+
+                    ui.separator();
 
 
-                if ui.button("Remove TMS pulse (zero)").clicked() {
-                    let (sender, receiver) = std::sync::mpsc::channel();
-                    self.artifact_receiver = Some(receiver);
-
-                    let info = self.eeg_info.clone();
-                    let markers = self.eeg_markers.clone();
-                    let tmin = self.tmin_cut;
-                    let tmax = self.tmax_cut;
-
-                    match self.data_format {
-                        DataFormat::EDF => {
-                            if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
-                                std::thread::spawn(move || {
-                                    let data = signal::vec_to_ndarray(&data_vec);
-                                    let result = signal::remove_tms_pulse_f32(tmin, tmax, &markers, &info, &data)
-                                        .map(ProcessedDataType::EDF);
-                                    let _ = sender.send(result.map_err(|e|
-                                        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                                    ));
-                                });
+                    if ui.button("Remove TMS pulse (zero)").clicked() {
+                        let (sender, receiver) = std::sync::mpsc::channel();
+                        self.artifact_receiver = Some(receiver);
+
+                        let info = self.eeg_info.clone();
+                        let markers = self.selected_markers();
+                        let tmin = self.tmin_cut;
+                        let tmax = self.tmax_cut;
+
+                        match self.data_format {
+                            DataFormat::EDF => {
+                                if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
+                                    std::thread::spawn(move || {
+                                        let data = signal::vec_to_ndarray(&data_vec);
+                                        let result = signal::remove_tms_pulse_f32(tmin, tmax, &markers, &info, &data)
+                                            .map(ProcessedDataType::EDF);
+                                        let _ = sender.send(result.map_err(|e|
+                                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                                        ));
+                                    });
+                                }
                             }
-                        }
-                        DataFormat::BrainVision => {
-                            if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
-                                std::thread::spawn(move || {
-                                    let data = signal::vec_to_ndarray(&data_vec);
-                                    let result = signal::remove_tms_pulse(tmin, tmax, &markers, &info, &data)
-                                        .map(ProcessedDataType::BV);
-                                    let _ = sender.send(result.map_err(|e|
-                                        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                                    ));
-                                });
+                            DataFormat::BrainVision => {
+                                if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
+                                    std::thread::spawn(move || {
+                                        let data = signal::vec_to_ndarray(&data_vec);
+                                        let result = signal::remove_tms_pulse(tmin, tmax, &markers, &info, &data)
+                                            .map(ProcessedDataType::BV);
+                                        let _ = sender.send(result.map_err(|e|
+                                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                                        ));
+                                    });
+                                }
                             }
                         }
                     }
-                }
 
-                if ui.button("Remove and interpolate pulse").clicked() {
-                    let (sender, receiver) = std::sync::mpsc::channel();
-                    self.artifact_receiver = Some(receiver);
+                    if ui.button("Remove and interpolate pulse").clicked() {
+                        let (sender, receiver) = std::sync::mpsc::channel();
+                        self.artifact_receiver = Some(receiver);
 
-                    let info = self.eeg_info.clone();
-                    let markers = self.eeg_markers.clone();
-                    let tmin = self.tmin_cut;
-                    let tmax = self.tmax_cut;
+                        let info = self.eeg_info.clone();
+                        let markers = self.selected_markers();
+                        let tmin = self.tmin_cut;
+                        let tmax = self.tmax_cut;
 
-                    match self.data_format {
-                        DataFormat::EDF => {
-                            if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
-                                std::thread::spawn(move || {
-                                    let data = signal::vec_to_ndarray(&data_vec);
-                                    let result = signal::rm_interp_tms_pulse_f32(tmin, tmax, &markers, &info, &data)
-                                        .map(ProcessedDataType::EDF);
-                                    let _ = sender.send(result.map_err(|e|
-                                        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                                    ));
-                                });
+                        match self.data_format {
+                            DataFormat::EDF => {
+                                if let Some(data_vec) = self.raw_eeg.edf_data.clone() {
+                                    std::thread::spawn(move || {
+                                        let data = signal::vec_to_ndarray(&data_vec);
+                                        let result = signal::rm_interp_tms_pulse_f32(tmin, tmax, &markers, &info, &data)
+                                            .map(ProcessedDataType::EDF);
+                                        let _ = sender.send(result.map_err(|e|
+                                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                                        ));
+                                    });
+                                }
                             }
-                        }
-                        DataFormat::BrainVision => {
-                            if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
-                                std::thread::spawn(move || {
-                                    let data = signal::vec_to_ndarray(&data_vec);
-                                    let result = signal::rm_interp_tms_pulse(tmin, tmax, &markers, &info, &data)
-                                        .map(ProcessedDataType::BV);
-                                    let _ = sender.send(result.map_err(|e|
-                                        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                                    ));
-                                });
+                            DataFormat::BrainVision => {
+                                if let Some(data_vec) = self.raw_eeg.bv_data.clone() {
+                                    std::thread::spawn(move || {
+                                        let data = signal::vec_to_ndarray(&data_vec);
+                                        let result = signal::rm_interp_tms_pulse(tmin, tmax, &markers, &info, &data)
+                                            .map(ProcessedDataType::BV);
+                                        let _ = sender.send(result.map_err(|e|
+                                            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                                        ));
+                                    });
+                                }
                             }
                         }
                     }
-                }
 
-                if self.artifact_receiver.is_some() {
-                    ui.label("Processing artefact...");
-                    ui.spinner();
-                }
+                    ui.separator();
+                    self.artefact_windows_ui(ui);
 
-                ui.separator();
-                ui.heading("Plot tools");
-                ui.add(egui::Slider::new(&mut self.decimation_factor, 1..=500)
-                    .text("Decimation factor for plotting")
-                    );
-                ui.separator();
+                    if self.artifact_receiver.is_some() {
+                        ui.label("Processing artefact...");
+                        ui.spinner();
+                    }
 
-                egui::ComboBox::from_label("Reference")
-                    .selected_text(format!("{:?}", self.reference_type))
-                    .show_ui(ui, |ui| {
-                        ui.selectable_value(&mut self.reference_type, ReferenceType::Original, "As recorded");
-                        ui.selectable_value(&mut self.reference_type, ReferenceType::AverageReference, "Average reference");
-                    });
+                    ui.separator();
+                    self.bad_channels_ui(ui);
+                    ui.separator();
+                    self.annotations_ui(ui);
+                    ui.separator();
+                    self.markers_ui(ui);
+                    ui.separator();
+                    self.rereference_ui(ui);
+                    ui.separator();
+                    self.csd_ui(ui);
+                    ui.separator();
+                    self.ica_ui(ui);
+                    ui.separator();
+                    self.ssp_ui(ui);
+                    ui.separator();
+                    self.denoise_ui(ui);
+                    ui.separator();
+                    self.epoching_ui(ui);
+                    ui.separator();
+                    self.baseline_ui(ui);
+                    ui.separator();
+                    self.rejection_ui(ui);
 
+                    ui.separator();
+                    ui.heading("Plot tools");
+                    ui.add(egui::Slider::new(&mut self.decimation_factor, 1..=500)
+                        .text("Decimation factor for plotting")
+                        );
+                    ui.separator();
+                    self.montage_ui(ui);
+                    ui.separator();
 
-                ui.separator();
-                ui.heading("Channel Colors");
+                    egui::ComboBox::from_label("Reference")
+                        .selected_text(format!("{:?}", self.reference_type))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut self.reference_type, ReferenceType::Original, "As recorded");
+                            ui.selectable_value(&mut self.reference_type, ReferenceType::AverageReference, "Average reference").on_hover_text("Average of the good channels, computed for the visible window");
+                        });
 
-                ui.label("Global color for all channels:");
-                egui::color_picker::color_edit_button_srgba(
-                    ui,
-                    &mut self.global_color,
-                    egui::color_picker::Alpha::Opaque
-                );
-                if ui.button("Apply to all channels").clicked() {
-                    for color in &mut self.channel_colors {
-                        *color = self.global_color;
+
+                    ui.separator();
+                    ui.heading("Channel Colors");
+
+                    ui.label("Global color for all channels:");
+                    egui::color_picker::color_edit_button_srgba(
+                        ui,
+                        &mut self.global_color,
+                        egui::color_picker::Alpha::Opaque
+                    );
+                    if ui.button("Apply to all channels").clicked() {
+                        for color in &mut self.channel_colors {
+                            *color = self.global_color;
+                        }
                     }
-                }
-                ui.separator();
+                    ui.separator();
 
-                if !self.eeg_info.ch_names.is_empty() {
-                    egui::ComboBox::from_label("Select channel")
-                        .selected_text(self.eeg_info.ch_names[self.selected_channel_for_color].clone())
-                        .show_ui(ui, |ui| {
-                            for (ch, name) in self.eeg_info.ch_names.iter().enumerate() {
-                                ui.selectable_value(&mut self.selected_channel_for_color, ch, name.clone());
-                            }
-                        });
+                    if !self.eeg_info.ch_names.is_empty() {
+                        egui::ComboBox::from_label("Select channel")
+                            .selected_text(self.eeg_info.ch_names[self.selected_channel_for_color].clone())
+                            .show_ui(ui, |ui| {
+                                for (ch, name) in self.eeg_info.ch_names.iter().enumerate() {
+                                    ui.selectable_value(&mut self.selected_channel_for_color, ch, name.clone());
+                                }
+                            });
 
-                    if self.selected_channel_for_color < self.channel_colors.len() {
-                        ui.label(format!("Color for {}:", self.eeg_info.ch_names[self.selected_channel_for_color]));
-                        egui::color_picker::color_edit_button_srgba(
-                            ui,
-                            &mut self.channel_colors[self.selected_channel_for_color],
-                            egui::color_picker::Alpha::Opaque
-                        );
+                        if self.selected_channel_for_color < self.channel_colors.len() {
+                            ui.label(format!("Color for {}:", self.eeg_info.ch_names[self.selected_channel_for_color]));
+                            egui::color_picker::color_edit_button_srgba(
+                                ui,
+                                &mut self.channel_colors[self.selected_channel_for_color],
+                                egui::color_picker::Alpha::Opaque
+                            );
+                        }
                     }
-                }
-                ui.separator();
-                ui.heading("Measurement Ruler");
-                ui.add(egui::Slider::new(&mut self.ruler_width, 0.1..=10.0).text("Width (s)"));
-                ui.add(egui::Slider::new(&mut self.ruler_height, 10.0..=200.0).text("Height (µV)"));
-                if ui.button("Place ruler").clicked() {
+                    ui.separator();
+                    ui.heading("Measurement Ruler");
+                    ui.add(egui::Slider::new(&mut self.ruler_width, 0.1..=10.0).text("Width (s)"));
+                    ui.add(egui::Slider::new(&mut self.ruler_height, 1.0..=1000.0).logarithmic(true).text("Height (µV)"));
+                    if ui.button("Place ruler").clicked() {
 
-                    self.ruler_position = Some((self.x_view + 5.0, 0.0));
-                }
+                        self.ruler_position = Some((self.x_view + self.page_duration / 2.0, 0.0));
+                    }
 
 
 
+                });
             });
 
+        self.epochs_window(ctx);
+        self.evoked_window(ctx);
+        self.topomaps_window(ctx);
+        self.components_window(ctx);
+        self.spectrum_window(ctx);
+        self.tfr_window(ctx);
+        self.ica_components_window(ctx);
+    }
+}
+
+// Translucent band between two x positions
+fn shade(x_start: f64, x_end: f64, y_min: f64, y_max: f64) -> Polygon<'static> {
+    let corners = vec![[x_start, y_min], [x_end, y_min], [x_end, y_max], [x_start, y_max]];
+    Polygon::new("Artefact window", corners)
+        .fill_color(Color32::from_rgba_unmultiplied(255, 200, 0, 40))
+        .stroke(egui::Stroke::NONE)
+}
 
+// Checkbox to enable an optional threshold, with its value next to it
+// Diverging colour scale from -limit to limit
+fn colour_bar(ui: &mut egui::Ui, limit: f64, width: f32) {
+    let (response, painter) = ui.allocate_painter(Vec2::new(width, 12.0), egui::Sense::hover());
+    let rect = response.rect;
+    let n_steps = 64;
+    let step = rect.width() / n_steps as f32;
+    for i in 0..n_steps {
+        let value = limit * (2.0 * (i as f64 + 0.5) / n_steps as f64 - 1.0);
+        let x = rect.left() + i as f32 * step;
+        let cell = Rect::from_min_max(Pos2::new(x, rect.top()), Pos2::new(x + step + 0.5, rect.bottom()));
+        painter.rect_filled(cell, 0.0, plots::diverging_color(value, limit));
     }
+    ui.horizontal(|ui| {
+        ui.set_width(width);
+        ui.label(format!("{:.1}", -limit));
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| ui.label(format!("{limit:.1}")));
+    });
+}
+
+fn threshold_ui(ui: &mut egui::Ui, label: &str, threshold: &mut Option<f64>, default: f64) {
+    ui.horizontal(|ui| {
+        let mut enabled = threshold.is_some();
+        if ui.checkbox(&mut enabled, label).changed() {
+            *threshold = enabled.then_some(threshold.unwrap_or(default));
+        }
+        if let Some(value) = threshold {
+            ui.add(egui::DragValue::new(value).speed(0.5).range(0.0..=f64::MAX));
+        }
+    });
 }
 
 fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
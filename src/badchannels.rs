@@ -0,0 +1,192 @@
+use nalgebra::DMatrix;
+use rayon::prelude::*;
+use sci_rs::signal::filter::sosfiltfilt_dyn;
+
+use crate::{EEGInfo, positions, signal};
+use crate::positions::Position;
+use crate::signal::Sample;
+use crate::spherical::SphericalSpline;
+
+// Thresholds for automatic detection; z-scores are robust (median and MAD) across channels
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct BadChannelCriteria {
+    // Standard deviation (µV) below which a channel is flat
+    pub flat_std: f64,
+    pub variance_z: f64,
+    // Mean correlation with the nearest channels below which a channel is bad
+    pub min_correlation: f64,
+    pub n_neighbours: usize,
+    pub line_noise_z: f64,
+    pub line_freq: f64,
+}
+
+impl Default for BadChannelCriteria {
+    fn default() -> Self {
+        Self { flat_std: 0.5, variance_z: 5.0, min_correlation: 0.4, n_neighbours: 4, line_noise_z: 5.0, line_freq: 50.0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadChannelReason {
+    Flat,
+    HighVariance,
+    LowCorrelation,
+    LineNoise,
+}
+
+// One failing criterion of one channel with the value that failed it
+#[derive(Debug, Clone)]
+pub struct BadChannel {
+    pub channel: usize,
+    pub reason: BadChannelReason,
+    pub value: f64,
+}
+
+// Samples used for variance and correlation; line noise is measured on the first 30 s of the longest good stretch
+const MAX_SAMPLES: usize = 200_000;
+const LINE_NOISE_SECONDS: f64 = 30.0;
+
+fn mean_std(values: &[f64]) -> (f64, f64) {
+    let n = values.len().max(1) as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    (mean, (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt())
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    match sorted.len() {
+        0 => f64::NAN,
+        n if n % 2 == 1 => sorted[n / 2],
+        n => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+    }
+}
+
+// (x - median) / (1.4826 MAD) of every value
+fn robust_z(values: &[f64]) -> Vec<f64> {
+    let centre = median(values);
+    let deviations: Vec<f64> = values.iter().map(|v| (v - centre).abs()).collect();
+    let scale = (1.4826 * median(&deviations)).max(f64::EPSILON);
+    values.iter().map(|v| (v - centre) / scale).collect()
+}
+
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let ((mean_a, std_a), (mean_b, std_b)) = (mean_std(a), mean_std(b));
+    if std_a <= f64::EPSILON || std_b <= f64::EPSILON {
+        return 0.0;
+    }
+    let covariance = a.iter().zip(b).map(|(x, y)| (x - mean_a) * (y - mean_b)).sum::<f64>() / a.len().max(1) as f64;
+    covariance / (std_a * std_b)
+}
+
+// Ratio of the power removed by a notch at `line_freq` to the power left
+fn line_noise_ratio<T: Sample>(channel: &[T], line_freq: f64, sfreq: f64) -> f64 {
+    let n_samples = channel.len().min((LINE_NOISE_SECONDS * sfreq) as usize);
+    let samples: Vec<f64> = channel[..n_samples].iter().map(|&x| x.into()).collect();
+    if samples.len() < 2 * sfreq as usize {
+        return 0.0;
+    }
+    let sos = signal::design_notch(line_freq, sfreq);
+    let notched: Vec<f64> = sosfiltfilt_dyn(samples.iter().copied(), &sos);
+    let line: Vec<f64> = samples.iter().zip(&notched).map(|(x, y)| x - y).collect();
+    mean_std(&line).1 / mean_std(&notched).1.max(f64::EPSILON)
+}
+
+// Channels failing any criterion, every failure listed (a channel can appear more than once).
+// Samples inside the `skip` spans (e.g. BAD_* segments) are left out.
+pub fn detect_bad_channels<T: Sample>(criteria: &BadChannelCriteria, eeg_info: &EEGInfo, data: &[Vec<T>], skip: &[(usize, usize)]) -> Vec<BadChannel> {
+    let n_channels = data.len();
+    let n_samples = data.iter().map(Vec::len).min().unwrap_or(0);
+    let mut kept = Vec::new();
+    let mut start = 0;
+    for &(skip_start, skip_end) in skip.iter().chain(std::iter::once(&(n_samples, n_samples))) {
+        if skip_start.min(n_samples) > start {
+            kept.push((start, skip_start.min(n_samples)));
+        }
+        start = start.max(skip_end);
+    }
+    let n_kept: usize = kept.iter().map(|(start, end)| end - start).sum();
+    if n_channels == 0 || n_kept == 0 {
+        return Vec::new();
+    }
+    let step = n_kept.div_ceil(MAX_SAMPLES);
+    let traces: Vec<Vec<f64>> = data
+        .iter()
+        .map(|ch| kept.iter().flat_map(|&(start, end)| ch[start..end].iter().step_by(step)).map(|&x| x.into()).collect())
+        .collect();
+    let stds: Vec<f64> = traces.iter().enumerate().map(|(ch, trace)| mean_std(trace).1 * eeg_info.resolution(ch)).collect();
+
+    let mut bad = Vec::new();
+    let flat: Vec<bool> = stds.iter().map(|&std| std < criteria.flat_std).collect();
+    bad.extend((0..n_channels).filter(|&ch| flat[ch]).map(|ch| BadChannel { channel: ch, reason: BadChannelReason::Flat, value: stds[ch] }));
+    let live: Vec<usize> = (0..n_channels).filter(|&ch| !flat[ch]).collect();
+
+    let live_stds: Vec<f64> = live.iter().map(|&ch| stds[ch]).collect();
+    for (&ch, z) in live.iter().zip(robust_z(&live_stds)) {
+        if z > criteria.variance_z {
+            bad.push(BadChannel { channel: ch, reason: BadChannelReason::HighVariance, value: z });
+        }
+    }
+
+    let all_positions = positions::channel_positions(eeg_info, n_channels);
+    let placed: Vec<(usize, Position)> = live.iter().filter_map(|&ch| all_positions[ch].map(|pos| (ch, pos))).collect();
+    for &(ch, pos) in &placed {
+        let mut others: Vec<&(usize, Position)> = placed.iter().filter(|(other, _)| *other != ch).collect();
+        others.sort_by(|a, b| positions::distance(&pos, &a.1).total_cmp(&positions::distance(&pos, &b.1)));
+        let neighbours: Vec<usize> = others.iter().take(criteria.n_neighbours).map(|(other, _)| *other).collect();
+        if neighbours.is_empty() {
+            continue;
+        }
+        let mean = neighbours.iter().map(|&other| correlation(&traces[ch], &traces[other])).sum::<f64>() / neighbours.len() as f64;
+        if mean < criteria.min_correlation {
+            bad.push(BadChannel { channel: ch, reason: BadChannelReason::LowCorrelation, value: mean });
+        }
+    }
+
+    let sfreq = f64::from(eeg_info.sfreq);
+    let longest = kept.iter().copied().max_by_key(|(start, end)| end - start).unwrap_or((0, 0));
+    if criteria.line_freq + 1.0 < sfreq / 2.0 {
+        let ratios: Vec<f64> = live
+            .par_iter()
+            .map(|&ch| line_noise_ratio(&data[ch][longest.0..longest.1], criteria.line_freq, sfreq))
+            .collect();
+        for (&ch, z) in live.iter().zip(robust_z(&ratios)) {
+            if z > criteria.line_noise_z {
+                bad.push(BadChannel { channel: ch, reason: BadChannelReason::LineNoise, value: z });
+            }
+        }
+    }
+    bad.sort_by_key(|b| b.channel);
+    bad
+}
+
+// channels x channels matrix replacing the bad channels that have a position with a spherical spline
+// through the good positioned channels. Returns the matrix and the channels it interpolates.
+pub fn interpolation_operator(eeg_info: &EEGInfo, n_channels: usize) -> Result<(DMatrix<f64>, Vec<usize>), Box<dyn std::error::Error>> {
+    let all_positions = positions::channel_positions(eeg_info, n_channels);
+    let (mut good, mut targets) = (Vec::new(), Vec::new());
+    for (ch, pos) in all_positions.iter().enumerate() {
+        if let Some(pos) = pos {
+            if eeg_info.bad_channels.contains(&ch) {
+                targets.push((ch, *pos));
+            } else {
+                good.push((ch, *pos));
+            }
+        }
+    }
+    if targets.is_empty() {
+        return Err("No bad channels with a position to interpolate".into());
+    }
+    let electrodes: Vec<Position> = good.iter().map(|(_, pos)| *pos).collect();
+    let spline = SphericalSpline::new(&electrodes, 4, 7, 1e-5)?;
+    let weights = spline.interpolation_matrix(&targets.iter().map(|(_, pos)| *pos).collect::<Vec<_>>());
+
+    let mut matrix = DMatrix::identity(n_channels, n_channels);
+    for (i, &(row, _)) in targets.iter().enumerate() {
+        matrix[(row, row)] = 0.0;
+        for (j, &(ch, _)) in good.iter().enumerate() {
+            matrix[(row, ch)] = weights[(i, j)];
+        }
+    }
+    Ok((matrix, targets.iter().map(|(ch, _)| *ch).collect()))
+}
@@ -0,0 +1,198 @@
+use nalgebra::DMatrix;
+use ndarray::Array2;
+
+use crate::{EEGInfo, Markers, EpochsData};
+use crate::signal::{vec_to_ndarray, Sample};
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum BaselineMode {
+    Mean,
+    Median,
+}
+
+fn baseline_value(values: &[f64], mode: BaselineMode) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    match mode {
+        BaselineMode::Mean => values.iter().sum::<f64>() / values.len() as f64,
+        BaselineMode::Median => {
+            let mut sorted = values.to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let mid = sorted.len() / 2;
+            if sorted.len() % 2 == 0 {
+                (sorted[mid - 1] + sorted[mid]) / 2.0
+            } else {
+                sorted[mid]
+            }
+        }
+    }
+}
+
+// Least-squares polynomial fit on the samples outside the excluded window, subtracted from the whole trace.
+// Legendre polynomials of x scaled to [-1, 1] keep the normal equations well conditioned, and the basis is
+// evaluated per sample so memory does not grow with the recording.
+struct Detrender {
+    inverse_gram: DMatrix<f64>,
+    excluded: Vec<bool>,
+    order: usize,
+}
+
+impl Detrender {
+    fn new(n_times: usize, order: usize, exclude: &[(usize, usize)]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut excluded = vec![false; n_times];
+        for &(start, end) in exclude {
+            if start < end {
+                excluded[start.min(n_times)..end.min(n_times)].fill(true);
+            }
+        }
+        if excluded.iter().filter(|&&e| !e).count() <= order {
+            return Err("Not enough samples outside the excluded window to detrend".into());
+        }
+        let mut detrender = Self { inverse_gram: DMatrix::zeros(order + 1, order + 1), excluded, order };
+        let mut gram = DMatrix::zeros(order + 1, order + 1);
+        let mut basis = vec![0.0; order + 1];
+        for i in (0..n_times).filter(|&i| !detrender.excluded[i]) {
+            detrender.basis(i, &mut basis);
+            for p in 0..=order {
+                for q in 0..=order {
+                    gram[(p, q)] += basis[p] * basis[q];
+                }
+            }
+        }
+        detrender.inverse_gram = gram.try_inverse().ok_or("The detrend fit is singular")?;
+        Ok(detrender)
+    }
+
+    // P_0..P_order at sample i
+    fn basis(&self, i: usize, basis: &mut [f64]) {
+        let scale = (self.excluded.len().max(2) - 1) as f64 / 2.0;
+        let x = (i as f64 - scale) / scale;
+        basis[0] = 1.0;
+        if self.order >= 1 {
+            basis[1] = x;
+        }
+        for n in 1..self.order {
+            let nf = n as f64;
+            basis[n + 1] = ((2.0 * nf + 1.0) * x * basis[n] - nf * basis[n - 1]) / (nf + 1.0);
+        }
+    }
+
+    fn apply(&self, trace: &mut [f64]) {
+        let mut basis = vec![0.0; self.order + 1];
+        let mut projection = nalgebra::DVector::zeros(self.order + 1);
+        for (i, &value) in trace.iter().enumerate().filter(|(i, _)| !self.excluded[*i]) {
+            self.basis(i, &mut basis);
+            for (p, b) in basis.iter().enumerate() {
+                projection[p] += b * value;
+            }
+        }
+        let coefficients = &self.inverse_gram * projection;
+        for (i, sample) in trace.iter_mut().enumerate() {
+            self.basis(i, &mut basis);
+            *sample -= basis.iter().zip(coefficients.iter()).map(|(b, c)| b * c).sum::<f64>();
+        }
+    }
+}
+
+// Per-epoch, per-channel baseline over bmin..bmax (s, relative to the event)
+pub fn baseline_epochs(
+    bmin: f64,
+    bmax: f64,
+    mode: BaselineMode,
+    epochs: &mut EpochsData,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if bmax <= bmin || bmin < epochs.tmin || bmax > epochs.tmax {
+        return Err(format!("Baseline {bmin} to {bmax} s is outside the epoch").into());
+    }
+    let start = epochs.time_to_index(bmin);
+    let end = epochs.time_to_index(bmax) + 1;
+    epochs.map_traces(|trace| {
+        let offset = baseline_value(&trace[start..end], mode);
+        trace.iter_mut().for_each(|sample| *sample -= offset);
+    });
+    Ok(())
+}
+
+// Polynomial detrend of every epoch; `exclude` (s, relative to the event) is left out of the fit
+pub fn detrend_epochs(
+    order: usize,
+    exclude: Option<(f64, f64)>,
+    epochs: &mut EpochsData,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exclude: Vec<(usize, usize)> = exclude
+        .map(|(start, end)| (epochs.time_to_index(start), epochs.time_to_index(end) + 1))
+        .into_iter()
+        .collect();
+    let detrender = Detrender::new(epochs.n_times(), order, &exclude)?;
+    epochs.map_traces(|trace| detrender.apply(trace));
+    Ok(())
+}
+
+// Per-channel baseline of continuous data over tmin..tmax (s from the start, None: whole recording)
+pub fn baseline_continuous<T: Sample>(
+    tmin: Option<f64>,
+    tmax: Option<f64>,
+    mode: BaselineMode,
+    eeg_info: &EEGInfo,
+    eeg_data: &Array2<T>,
+) -> Result<Array2<T>, Box<dyn std::error::Error>> {
+    if eeg_data.is_empty() {
+        return Ok(eeg_data.clone());
+    }
+    let sfreq = eeg_info.sfreq as f64;
+    let n_samples = eeg_data.ncols();
+    let start = tmin.map_or(0, |t| ((t * sfreq).round().max(0.0) as usize).min(n_samples));
+    let end = tmax.map_or(n_samples, |t| ((t * sfreq).round().max(0.0) as usize).min(n_samples));
+    if start >= end {
+        return Err("Empty baseline interval".into());
+    }
+
+    let data_vec_vec: Vec<Vec<T>> = eeg_data
+        .outer_iter()
+        .map(|channel| {
+            let trace: Vec<f64> = channel.iter().map(|&sample| sample.into()).collect();
+            let offset = baseline_value(&trace[start..end], mode);
+            trace.into_iter().map(|sample| T::from_f64(sample - offset)).collect()
+        })
+        .collect();
+    Ok(vec_to_ndarray(&data_vec_vec))
+}
+
+// Polynomial detrend of continuous data, leaving `exclude` (s) around every marker out of the fit
+pub fn detrend_continuous<T: Sample>(
+    order: usize,
+    exclude: Option<(f64, f64)>,
+    markers: &Markers,
+    eeg_info: &EEGInfo,
+    eeg_data: &Array2<T>,
+) -> Result<Array2<T>, Box<dyn std::error::Error>> {
+    if eeg_data.is_empty() {
+        return Ok(eeg_data.clone());
+    }
+    let sfreq = eeg_info.sfreq as f64;
+    let n_samples = eeg_data.ncols() as isize;
+    let windows: Vec<(usize, usize)> = match exclude {
+        Some((start, end)) => markers
+            .markers
+            .iter()
+            .map(|&pos| {
+                let first = (pos.round() as isize + (start * sfreq).round() as isize).clamp(0, n_samples);
+                let last = (pos.round() as isize + (end * sfreq).round() as isize + 1).clamp(0, n_samples);
+                (first as usize, last as usize)
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let detrender = Detrender::new(eeg_data.ncols(), order, &windows)?;
+
+    let data_vec_vec: Vec<Vec<T>> = eeg_data
+        .outer_iter()
+        .map(|channel| {
+            let mut trace: Vec<f64> = channel.iter().map(|&sample| sample.into()).collect();
+            detrender.apply(&mut trace);
+            trace.into_iter().map(T::from_f64).collect()
+        })
+        .collect();
+    Ok(vec_to_ndarray(&data_vec_vec))
+}
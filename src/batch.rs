@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use crate::{RawEEG, EEGInfo, Markers, EvokedData, edfio, bvio, signal, epochs, baseline, rejection};
+use crate::baseline::BaselineMode;
+use crate::peaks::{self, PeakResult, TepComponent};
+use crate::rejection::RejectCriteria;
+use crate::signal::ArtefactWindow;
+
+// Processing applied to every file of a batch, in this order: artefact windows around the selected events,
+// high-pass, low-pass and optional 50 Hz notch over the whole recording, epoching, baseline and rejection.
+// Interactive steps (bad channels, re-referencing, ICA, SSP, SOUND) are not repeated.
+#[derive(Debug, Clone)]
+pub struct EvokedSettings {
+    pub event_types: Vec<String>,
+    // No windows: the pulse is left in
+    pub artefact_windows: Vec<ArtefactWindow>,
+    // High-pass and low-pass cut-offs (Hz) and whether to notch 50 Hz
+    pub filter: Option<(f64, f64, bool)>,
+    pub tmin: f64,
+    pub tmax: f64,
+    pub baseline: Option<(f64, f64, BaselineMode)>,
+    pub reject: Option<RejectCriteria>,
+    // Drop epochs overlapping BAD_* annotations of the file
+    pub reject_by_annotation: bool,
+}
+
+// .vhdr files are read as BrainVision, anything else as EDF
+pub fn load_file(path: &str) -> std::io::Result<(RawEEG, EEGInfo, Markers)> {
+    let mut raw_eeg = RawEEG::default();
+    let mut eeg_info = EEGInfo::default();
+    let mut eeg_markers = Markers::default();
+    if path.to_lowercase().ends_with(".vhdr") {
+        bvio::load_bv_data(path, &mut raw_eeg, &mut eeg_info, &mut eeg_markers)?;
+    } else {
+        edfio::parse_edf_info_load_data(path, &mut raw_eeg, &mut eeg_info, &mut eeg_markers, false, true)?;
+    }
+    Ok((raw_eeg, eeg_info, eeg_markers))
+}
+
+// The evoked response with the recording info it was read with, for its channel resolutions
+pub fn evoked_from_file(path: &str, settings: &EvokedSettings) -> Result<(EvokedData, EEGInfo), Box<dyn std::error::Error>> {
+    let (raw_eeg, eeg_info, markers) = load_file(path)?;
+    let events = markers.select_types(&settings.event_types);
+    let mut epochs = if let Some(data_vec) = &raw_eeg.bv_data {
+        let mut data = signal::remove_artefact_windows(&settings.artefact_windows, &events, &eeg_info, &signal::vec_to_ndarray(data_vec))?;
+        if let Some((lfreq, hfreq, notch)) = settings.filter {
+            data = signal::lp_filter(hfreq, &eeg_info, &signal::hp_filter(lfreq, &eeg_info, &data)?)?;
+            if notch {
+                data = signal::notch_filter_50hz(&eeg_info, &data)?;
+            }
+        }
+        epochs::create_epochs_bv(settings.tmin, settings.tmax, &settings.event_types, &eeg_info, &data, &markers)?
+    } else if let Some(data_vec) = &raw_eeg.edf_data {
+        let mut data = signal::remove_artefact_windows(&settings.artefact_windows, &events, &eeg_info, &signal::vec_to_ndarray(data_vec))?;
+        if let Some((lfreq, hfreq, notch)) = settings.filter {
+            data = signal::edf_lp_filter(hfreq, &eeg_info, &signal::edf_hp_filter(lfreq, &eeg_info, &data)?)?;
+            if notch {
+                data = signal::edf_notch_filter_50hz(&eeg_info, &data)?;
+            }
+        }
+        epochs::create_epochs_edf(settings.tmin, settings.tmax, &settings.event_types, &eeg_info, &data, &markers)?
+    } else {
+        return Err(format!("No data loaded from {path}").into());
+    };
+    if let Some((bmin, bmax, mode)) = settings.baseline {
+        baseline::baseline_epochs(bmin, bmax, mode, &mut epochs)?;
+    }
+    if settings.reject_by_annotation {
+        rejection::reject_by_annotation(&mut epochs, &markers);
+    }
+    if let Some(criteria) = &settings.reject {
+        rejection::reject_epochs(criteria, &eeg_info, &mut epochs);
+    }
+    Ok((epochs::evoked_eeg(&epochs)?, eeg_info))
+}
+
+pub fn subject_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map_or_else(|| path.to_owned(), |stem| stem.to_string_lossy().into_owned())
+}
+
+// Components of every file; files that fail are reported with their error instead
+pub fn batch_peaks(
+    paths: &[String],
+    settings: &EvokedSettings,
+    components: &[TepComponent],
+) -> (Vec<PeakResult>, Vec<String>) {
+    let mut results = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        // The EDF reader panics on malformed headers; one bad file should not end the batch
+        match std::panic::catch_unwind(|| evoked_from_file(path, settings)) {
+            // Freshly loaded files have no bad channels marked
+            Ok(Ok((evoked, eeg_info))) => results.extend(peaks::detect_components(&evoked, components, &eeg_info, &subject_name(path))),
+            Ok(Err(e)) => errors.push(format!("{path}: {e}")),
+            Err(_) => errors.push(format!("{path}: could not be read")),
+        }
+    }
+    (results, errors)
+}
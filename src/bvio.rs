@@ -4,7 +4,7 @@ use std::io::BufReader;
 
 use ndarray::prelude::*;
 
-use crate::{RawEEG, EEGInfo, Markers, reference};
+use crate::{RawEEG, EEGInfo, Markers, Annotation, positions, signal};
 
 //fn type_of<T>(_: T) -> &'static str {
 //    type_name::<T>()
@@ -43,6 +43,10 @@ pub fn parse_header(header: &Option<String>) -> Result<EEGInfo, Box<dyn std::err
         binary_format: Some(String::new()),
         sampling_interval_in: Some(String::new()),
         sampling_interval: Some(0),
+        ch_pos: Vec::new(),
+        channel_groups: Vec::new(),
+        bad_channels: Vec::new(),
+        resolutions: Vec::new(),
     };
     //Prints the whole header
     //header_vec.iter().for_each(|x| println!("Lines {:?}", x));
@@ -111,12 +115,35 @@ pub fn parse_header(header: &Option<String>) -> Result<EEGInfo, Box<dyn std::err
             eeg_info
                 .ch_names
                 .push(x.to_string().replace(",,0.1,µV", "").replace("Ch", ""));
+            eeg_info.resolutions.push(channel_resolution(x));
         }
         if eeg_info.ch_names.len() == eeg_info.num_ch as usize{
             break;
         }
         };
 
+    // [Coordinates] Ch<n>=<radius>,<theta>,<phi>; radius 0 means no position
+    eeg_info.ch_pos = vec![None; eeg_info.num_ch.max(0) as usize];
+    let mut in_coordinates = false;
+    for x in &header_vec {
+        let line = x.trim();
+        if line.starts_with('[') {
+            in_coordinates = line == "[Coordinates]";
+            continue;
+        }
+        if !in_coordinates {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else { continue };
+        let Ok(ch) = key.trim_start_matches("Ch").parse::<usize>() else { continue };
+        let values: Vec<f64> = value.split(',').filter_map(|v| v.trim().parse().ok()).collect();
+        if let (Some(slot), &[radius, theta, phi]) = (eeg_info.ch_pos.get_mut(ch.wrapping_sub(1)), &values[..]) {
+            if radius > 0.0 {
+                *slot = Some(positions::spherical_to_cartesian(theta, phi));
+            }
+        }
+    }
+
     //println!("Header: {:?}", header_str);
     //println!("{:?}", eeg_info);
     Ok(eeg_info)
@@ -146,24 +173,113 @@ pub fn parse_vmrk(vmrk: &Option<String>) -> Result<Markers, Box<dyn std::error::
     let vmrk_vec: Vec<String> = vmrk_content.split("\n").map(|x| x.to_string()).collect();
     let mut markers = Markers {
         n_markers: 0,
-        markers: Vec::new()
+        markers: Vec::new(),
+        descriptions: Vec::new(),
+        annotations: Vec::new(),
+        other: Vec::new(),
     };
     let mut marker_vec: Vec<f64> = Vec::new();
+    let mut description_vec: Vec<String> = Vec::new();
     vmrk_vec.iter().for_each(|x| {
-        if x.contains("R128") {
+        // Mk<n>=<type>,<description>,<position>,<points>,<channel>
+        if x.starts_with("Mk") && (x.contains("=Stimulus,") || x.contains("=Response,")) {
         let chars: Vec<&str> = x.split(",").collect();
         //println!("CHARS: {:?}", chars[2]);
+        if chars.len() > 2 {
         let default: f64 = 0.0;
         marker_vec.push(chars[2].parse::<f64>().unwrap_or(default));
+        description_vec.push(chars[1].to_owned());
+        }
+        } else if let Some(annotation) = parse_annotation(x) {
+            markers.annotations.push(annotation);
+        } else if let Some((_, entry)) = x.strip_prefix("Mk").and_then(|x| x.split_once('=')) {
+            markers.other.push(entry.trim_end().to_owned());
         }
 });
     //println!("MARKERS {:?}", marker_vec);
     markers.markers = marker_vec.clone();
     markers.n_markers = marker_vec.len();
+    markers.descriptions = description_vec;
     Ok(markers)
 }
 
 
+// "Ch1=Fp1,,0.1,µV": resolution times unit in µV, an empty resolution meaning 1
+fn channel_resolution(line: &str) -> f64 {
+    let fields: Vec<&str> = line.trim().split_once('=').map_or("", |(_, value)| value).split(',').collect();
+    let resolution = fields.get(2).and_then(|r| r.trim().parse::<f64>().ok()).unwrap_or(1.0);
+    resolution * fields.get(3).map_or(1.0, |unit| signal::microvolts_per_unit(unit))
+}
+
+// "Mk<n>=Bad Interval,<description>,<position>,<points>,<channel>", or any marker with a BAD_* description
+// spanning more than one point; "Comment" entries spanning more than one point keep their description
+fn parse_annotation(line: &str) -> Option<Annotation> {
+    let (_, entry) = line.strip_prefix("Mk")?.split_once('=')?;
+    let fields: Vec<&str> = entry.split(',').collect();
+    let [marker_type, description, position, points, ..] = fields[..] else { return None };
+    let description = description.trim();
+    let is_bad = marker_type.trim().eq_ignore_ascii_case("Bad Interval") || description.to_uppercase().starts_with("BAD");
+    let is_comment = marker_type.trim().eq_ignore_ascii_case("Comment");
+    let points = points.trim().parse::<f64>().ok()?;
+    if !(is_bad || is_comment) || points <= 1.0 {
+        return None;
+    }
+    let description = if !is_bad || description.to_uppercase().starts_with("BAD") {
+        description.to_owned()
+    } else {
+        format!("BAD_{}", description.replace(' ', "_"))
+    };
+    Some(Annotation { onset: position.trim().parse().ok()?, duration: points, description })
+}
+
+// BrainVision marker type of a description: "R 128" is a response, anything else a stimulus
+pub fn marker_type(description: &str) -> &'static str {
+    if description.starts_with('R') { "Response" } else { "Stimulus" }
+}
+
+// Marker file for `data_file` with the markers and annotations sorted by position, typed by `marker_type`;
+// BAD_* annotations become bad intervals, other annotations comments, and entries of other types are kept as read.
+// An existing file is backed up to <path>.bak once before it is replaced.
+pub fn write_vmrk(path: &str, data_file: &str, markers: &Markers) -> std::io::Result<()> {
+    let mut entries: Vec<(f64, String)> = Vec::new();
+    if !markers.other.iter().any(|entry| entry.starts_with("New Segment,")) {
+        entries.push((1.0, "New Segment,,1,1,0".to_owned()));
+    }
+    entries.extend(markers.other.iter().map(|entry| {
+        let position = entry.split(',').nth(2).and_then(|pos| pos.trim().parse().ok()).unwrap_or(0.0);
+        (position, entry.clone())
+    }));
+    entries.extend(
+        markers
+            .markers
+            .iter()
+            .zip(&markers.descriptions)
+            .map(|(&pos, description)| (pos, format!("{},{description},{},1,0", marker_type(description), pos.round() as i64))),
+    );
+    entries.extend(markers.annotations.iter().map(|annotation| {
+        let marker_type = if annotation.is_bad() { "Bad Interval" } else { "Comment" };
+        let (onset, size) = (annotation.onset.round() as i64, annotation.duration.max(1.0).round() as i64);
+        (annotation.onset, format!("{marker_type},{},{onset},{size},0", annotation.description))
+    }));
+    // Stable, so segment starts stay ahead of the markers at their position
+    entries.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut vmrk = format!(
+        "Brain Vision Data Exchange Marker File, Version 1.0\r\n\r\n[Common Infos]\r\nCodepage=UTF-8\r\nDataFile={data_file}\r\n\r\n\
+         [Marker Infos]\r\n; Each entry: Mk<Marker number>=<Type>,<Description>,<Position in data points>,\r\n\
+         ; <Size in data points>, <Channel number (0 = marker is related to all channels)>\r\n"
+    );
+    for (idx, (_, entry)) in entries.iter().enumerate() {
+        vmrk.push_str(&format!("Mk{}={entry}\r\n", idx + 1));
+    }
+
+    let backup = format!("{path}.bak");
+    if std::path::Path::new(path).exists() && !std::path::Path::new(&backup).exists() {
+        std::fs::copy(path, &backup)?;
+    }
+    std::fs::write(path, vmrk)
+}
+
 pub fn parse_bytes_opt(path: &str, eeg_info: &EEGInfo) -> Result<Vec<Vec<i16>>, Box<dyn std::error::Error>> {
     match eeg_info.binary_format.as_ref().unwrap().as_str() {
         "INT_16" => {
@@ -322,17 +438,8 @@ pub fn load_bv_data(
             std::io::ErrorKind::Other,
             format!("Failed to read .eeg file: {}", e)
         ))?;
-    raw_eeg.bv_data = Some(channels.clone());
+    raw_eeg.bv_data = Some(channels);
     raw_eeg.sampling_frequency = Some(eeg_info.sfreq as u64);
     raw_eeg.number_of_channels = Some(eeg_info.num_ch as usize);
-    match reference::compute_average_reference_i16(&channels) {
-        Ok(avg_ref) => {
-            raw_eeg.bv_data_avg_ref = Some(avg_ref);
-        }
-        Err(e) => {
-            eprintln!("Error computing average reference: {}", e);
-            raw_eeg.bv_data_avg_ref = None;
-        }
-    }
     Ok(())
 }
@@ -0,0 +1,84 @@
+use nalgebra::DMatrix;
+use ndarray::Array2;
+
+use crate::{EEGInfo, EpochsData, positions, reference, spherical};
+use crate::montage::{Derivation, Montage};
+use crate::positions::{clean_label, Position};
+use crate::signal::Sample;
+
+// Spherical-spline surface Laplacian settings; Kayser & Tenke (2006) defaults
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct CsdParams {
+    // Spline order m
+    pub stiffness: i32,
+    // Smoothing added to the spline diagonal
+    pub lambda: f64,
+    // Legendre terms of the series
+    pub n_terms: usize,
+    // Head radius (cm); the output is in data units per cm^2
+    pub head_radius: f64,
+}
+
+impl Default for CsdParams {
+    fn default() -> Self {
+        Self { stiffness: 4, lambda: 1e-5, n_terms: 50, head_radius: 10.0 }
+    }
+}
+
+// Channels entering the transform: those with a position that are not marked bad
+fn csd_channels(eeg_info: &EEGInfo, n_channels: usize) -> Vec<(usize, Position)> {
+    positions::channel_positions(eeg_info, n_channels)
+        .into_iter()
+        .enumerate()
+        .filter(|(ch, _)| !eeg_info.bad_channels.contains(ch))
+        .filter_map(|(ch, pos)| pos.map(|pos| (ch, pos)))
+        .collect()
+}
+
+// channels x channels matrix taking potentials to CSD. Channels without a position and bad channels
+// (e.g. EOG, EMG) pass through unchanged.
+pub fn csd_operator(params: &CsdParams, eeg_info: &EEGInfo, n_channels: usize) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    let channels = csd_channels(eeg_info, n_channels);
+    let electrodes: Vec<Position> = channels.iter().map(|(_, pos)| *pos).collect();
+    let csd = spherical::csd_matrix(&electrodes, params.stiffness, params.n_terms, params.lambda)? / params.head_radius.powi(2);
+    let mut matrix = DMatrix::identity(n_channels, n_channels);
+    for (i, &(row, _)) in channels.iter().enumerate() {
+        matrix[(row, row)] = 0.0;
+        for (j, &(ch, _)) in channels.iter().enumerate() {
+            matrix[(row, ch)] = csd[(i, j)];
+        }
+    }
+    Ok(matrix)
+}
+
+// Float data only: at µV/cm² the CSD of integer samples would round to a few steps
+pub fn csd_continuous<T: Sample>(params: &CsdParams, eeg_info: &EEGInfo, eeg_data: &Array2<T>) -> Result<Array2<T>, Box<dyn std::error::Error>> {
+    if T::STEP > 0.0 {
+        return Err("Integer samples cannot hold the CSD; transform the epochs or use the CSD montage".into());
+    }
+    let matrix = csd_operator(params, eeg_info, eeg_data.nrows())?;
+    Ok(reference::apply_matrix(&matrix, eeg_data))
+}
+
+// BrainVision epochs are moved to floats first
+pub fn csd_epochs(params: &CsdParams, eeg_info: &EEGInfo, epochs: &mut EpochsData) -> Result<(), Box<dyn std::error::Error>> {
+    let matrix = csd_operator(params, eeg_info, epochs.n_channels())?;
+    epochs.to_float();
+    reference::apply_matrix_epochs(&matrix, epochs);
+    Ok(())
+}
+
+// The transform as a viewer montage, one weighted derivation per transformed channel
+pub fn csd_montage(params: &CsdParams, eeg_info: &EEGInfo, ch_names: &[String]) -> Result<Montage, Box<dyn std::error::Error>> {
+    let channels = csd_channels(eeg_info, ch_names.len());
+    let matrix = csd_operator(params, eeg_info, ch_names.len())?;
+    let label = |ch: usize| clean_label(&ch_names[ch]);
+    let derivations = channels
+        .iter()
+        .map(|&(row, _)| Derivation {
+            name: format!("{} (CSD)", label(row)),
+            terms: channels.iter().map(|&(ch, _)| (label(ch), matrix[(row, ch)])).collect(),
+        })
+        .collect();
+    Ok(Montage { name: "CSD".to_owned(), derivations })
+}
@@ -0,0 +1,433 @@
+use nalgebra::{DMatrix, DVector, SymmetricEigen};
+use ndarray::Array2;
+use sci_rs::signal::filter::{design::Sos, sosfiltfilt_dyn};
+
+use crate::{EEGInfo, EpochsData, positions, reference, signal};
+use crate::headmodel::{self, SphereModel};
+use crate::signal::Sample;
+
+// SOUND (Mutanen et al. 2018): per-channel noise levels estimated by predicting every channel from the others
+// through a minimum-norm source estimate, iterated; the data is then replaced by the noise-weighted estimate
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct SoundParams {
+    // Regularisation relative to the mean diagonal of the whitened lead field Gram matrix
+    pub lambda: f64,
+    pub max_iter: usize,
+    // Stop once no noise level changes by more than this fraction
+    pub tol: f64,
+}
+
+impl Default for SoundParams {
+    fn default() -> Self {
+        Self { lambda: 0.1, max_iter: 10, tol: 0.01 }
+    }
+}
+
+// SSP-SIR (Mutanen et al. 2016): project out the leading components of the high-passed data (muscle),
+// then rebuild the channels from a minimum-norm estimate with a lead field projected the same way
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct SspSirParams {
+    pub n_components: usize,
+    pub lambda: f64,
+    // Cut-off (Hz) of the high-pass isolating muscle activity
+    pub highpass: f64,
+    // Window (s) of the epochs the artefact is estimated in; continuous data uses all good samples
+    pub tmin: f64,
+    pub tmax: f64,
+}
+
+impl Default for SspSirParams {
+    fn default() -> Self {
+        Self { n_components: 5, lambda: 0.01, highpass: 100.0, tmin: 0.0, tmax: 0.05 }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SoundReport {
+    // Final noise estimate per fitted channel (µV)
+    pub noise: Vec<f64>,
+    // Largest relative change of the noise levels at each iteration
+    pub changes: Vec<f64>,
+    pub converged: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SspSirReport {
+    // Share of the high-passed variance along each leading component, projected or not
+    pub artefact_variance: Vec<f64>,
+    pub n_projected: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum DenoiseReport {
+    Sound(SoundReport),
+    SspSir(SspSirReport),
+}
+
+// Channels x channels matrix in data units producing the cleaned data. Channels without a position and bad
+// channels pass through; the rest come out average referenced over themselves.
+#[derive(Debug, Clone)]
+pub struct Denoising {
+    pub matrix: DMatrix<f64>,
+    pub channels: Vec<usize>,
+    pub ch_names: Vec<String>,
+    // Fraction of each fitted channel's variance the matrix removes from the estimation data
+    pub removed: Vec<f64>,
+    pub report: DenoiseReport,
+}
+
+// Samples entering the covariance, and the longest stretch high-passed at once
+const MAX_COVARIANCE_SAMPLES: usize = 500_000;
+const FILTER_STRETCH_SECONDS: f64 = 30.0;
+
+// Good channels with a position
+fn fitted_channels(eeg_info: &EEGInfo, n_channels: usize) -> (Vec<usize>, Vec<positions::Position>) {
+    positions::channel_positions(eeg_info, n_channels)
+        .into_iter()
+        .enumerate()
+        .filter(|(ch, _)| !eeg_info.bad_channels.contains(ch))
+        .filter_map(|(ch, pos)| pos.map(|pos| (ch, pos)))
+        .unzip()
+}
+
+// I - 1 1ᵀ / n
+fn average_reference(n: usize) -> DMatrix<f64> {
+    DMatrix::identity(n, n) - DMatrix::from_element(n, n, 1.0 / n as f64)
+}
+
+// Running x xᵀ over stretches of channels x samples, each centred and optionally high-passed first
+struct Covariance {
+    sum: DMatrix<f64>,
+    n_samples: usize,
+}
+
+impl Covariance {
+    fn new(n: usize) -> Self {
+        Self { sum: DMatrix::zeros(n, n), n_samples: 0 }
+    }
+
+    // `traces` are channels x samples in µV; every `step`th sample is kept
+    fn add(&mut self, mut traces: Vec<Vec<f64>>, highpass: Option<&[Sos<f64>]>, step: usize) {
+        for trace in &mut traces {
+            if let Some(sos) = highpass {
+                *trace = sosfiltfilt_dyn(trace.iter().copied(), sos);
+            }
+            let mean = trace.iter().sum::<f64>() / trace.len().max(1) as f64;
+            trace.iter_mut().for_each(|v| *v -= mean);
+        }
+        let n_samples = traces.first().map_or(0, Vec::len);
+        let kept: Vec<usize> = (0..n_samples).step_by(step.max(1)).collect();
+        let x = DMatrix::from_fn(traces.len(), kept.len(), |i, j| traces[i][kept[j]]);
+        self.sum += &x * x.transpose();
+        self.n_samples += kept.len();
+    }
+
+    fn finish(self) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+        if self.n_samples == 0 {
+            return Err("No good data to estimate the covariance from".into());
+        }
+        Ok(self.sum / self.n_samples as f64)
+    }
+}
+
+fn highpass_sos(highpass: Option<f64>, sfreq: f64) -> Result<Option<Vec<Sos<f64>>>, Box<dyn std::error::Error>> {
+    match highpass {
+        Some(cutoff) if cutoff >= sfreq / 2.0 => Err(format!("A {cutoff} Hz high-pass needs a sampling rate above {} Hz", 2.0 * cutoff).into()),
+        Some(cutoff) => Ok(Some(signal::design_butter_hp(4, cutoff, sfreq))),
+        None => Ok(None),
+    }
+}
+
+// Covariance (µV²) of the fitted channels over the `spans` of continuous data
+fn covariance_continuous<T: Sample>(
+    eeg_info: &EEGInfo,
+    data: &[Vec<T>],
+    channels: &[usize],
+    spans: &[(usize, usize)],
+    highpass: Option<f64>,
+) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    let sfreq = f64::from(eeg_info.sfreq);
+    let sos = highpass_sos(highpass, sfreq)?;
+    let n_samples = data.iter().map(Vec::len).min().unwrap_or(0);
+    let n_kept: usize = spans.iter().map(|&(start, end)| end.min(n_samples).saturating_sub(start)).sum();
+    let step = n_kept.div_ceil(MAX_COVARIANCE_SAMPLES).max(1);
+    let stretch = ((FILTER_STRETCH_SECONDS * sfreq) as usize).max(1);
+    let mut covariance = Covariance::new(channels.len());
+    for &(start, end) in spans {
+        let end = end.min(n_samples);
+        for stretch_start in (start..end).step_by(stretch) {
+            let stretch_end = (stretch_start + stretch).min(end);
+            let traces = channels
+                .iter()
+                .map(|&ch| data[ch][stretch_start..stretch_end].iter().map(|&x| x.into() * eeg_info.resolution(ch)).collect())
+                .collect();
+            covariance.add(traces, sos.as_deref(), step);
+        }
+    }
+    covariance.finish()
+}
+
+// Covariance (µV²) of the fitted channels over the good epochs, within `window` (s) when given.
+// Epochs are filtered whole before the window is cut.
+fn covariance_epochs(
+    eeg_info: &EEGInfo,
+    epochs: &EpochsData,
+    channels: &[usize],
+    window: Option<(f64, f64)>,
+    highpass: Option<f64>,
+) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    let sos = highpass_sos(highpass, epochs.sfreq)?;
+    let n_times = epochs.n_times();
+    let (first, last) = match window {
+        Some((tmin, tmax)) => (
+            ((tmin - epochs.tmin) * epochs.sfreq).round().max(0.0) as usize,
+            (((tmax - epochs.tmin) * epochs.sfreq).round().max(0.0) as usize + 1).min(n_times),
+        ),
+        None => (0, n_times),
+    };
+    if last <= first {
+        return Err("The window lies outside the epochs".into());
+    }
+    let good: Vec<usize> = (0..epochs.n_epochs()).filter(|&idx| !epochs.bad.get(idx).copied().unwrap_or(false)).collect();
+    let mut covariance = Covariance::new(channels.len());
+    for &idx in &good {
+        let traces: Vec<Vec<f64>> = channels
+            .iter()
+            .map(|&ch| {
+                let resolution = eeg_info.resolution(ch);
+                let trace: Vec<f64> = epochs.trace(idx, ch).into_iter().map(|x| x * resolution).collect();
+                match &sos {
+                    Some(sos) => sosfiltfilt_dyn(trace.into_iter(), sos),
+                    None => trace,
+                }
+            })
+            .map(|trace| trace[first..last].to_vec())
+            .collect();
+        covariance.add(traces, None, 1);
+    }
+    covariance.finish()
+}
+
+// Minimum-norm operator L Gᵀ (G Gᵀ + λ tr(G Gᵀ)/n I)⁻¹ taking (weighted) data to the lead field `l`'s
+// reconstruction, with G the weighted or projected lead field
+fn reconstruction(l: &DMatrix<f64>, g: &DMatrix<f64>, lambda: f64) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    let gram = g * g.transpose();
+    let n = gram.nrows();
+    let regularised = &gram + DMatrix::identity(n, n) * (lambda * gram.trace() / n as f64).max(f64::EPSILON);
+    let inverse = regularised.try_inverse().ok_or("The regularised lead field is singular")?;
+    Ok(l * g.transpose() * inverse)
+}
+
+// diag((I - M) C (I - M)ᵀ) / diag(C)
+fn removed_fraction(matrix: &DMatrix<f64>, covariance: &DMatrix<f64>) -> Vec<f64> {
+    let residual = matrix - DMatrix::identity(matrix.nrows(), matrix.ncols());
+    let removed = &residual * covariance * residual.transpose();
+    (0..matrix.nrows()).map(|i| removed[(i, i)] / covariance[(i, i)].max(f64::EPSILON)).collect()
+}
+
+fn sound_operator(params: &SoundParams, lead_field: &DMatrix<f64>, covariance: &DMatrix<f64>) -> Result<(DMatrix<f64>, SoundReport), Box<dyn std::error::Error>> {
+    let n = lead_field.nrows();
+    let mut sigmas = vec![1.0; n];
+    let mut report = SoundReport::default();
+    for _ in 0..params.max_iter {
+        let previous = sigmas.clone();
+        for i in 0..n {
+            let others: Vec<usize> = (0..n).filter(|&j| j != i).collect();
+            let weighted = DMatrix::from_fn(n - 1, lead_field.ncols(), |r, c| lead_field[(others[r], c)] / sigmas[others[r]]);
+            // Row predicting channel i from the weighted other channels
+            let prediction = reconstruction(&lead_field.rows(i, 1).into_owned(), &weighted, params.lambda)?;
+            let mut residual = DVector::zeros(n);
+            residual[i] = 1.0;
+            for (r, &j) in others.iter().enumerate() {
+                residual[j] -= prediction[(0, r)] / sigmas[j];
+            }
+            sigmas[i] = (residual.transpose() * covariance * &residual)[(0, 0)].max(0.0).sqrt().max(f64::EPSILON);
+        }
+        let change = sigmas.iter().zip(&previous).map(|(new, old)| (new - old).abs() / old).fold(0.0, f64::max);
+        report.changes.push(change);
+        if change < params.tol {
+            report.converged = true;
+            break;
+        }
+    }
+    let weights = DMatrix::from_diagonal(&DVector::from_iterator(n, sigmas.iter().map(|s| 1.0 / s)));
+    let operator = reconstruction(lead_field, &(&weights * lead_field), params.lambda)? * weights;
+    report.noise = sigmas;
+    Ok((operator, report))
+}
+
+fn ssp_sir_operator(params: &SspSirParams, lead_field: &DMatrix<f64>, artefact: &DMatrix<f64>) -> Result<(DMatrix<f64>, SspSirReport), Box<dyn std::error::Error>> {
+    let n = lead_field.nrows();
+    let eigen = SymmetricEigen::new(artefact.clone());
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a]));
+    let total = eigen.eigenvalues.iter().map(|v| v.max(0.0)).sum::<f64>().max(f64::EPSILON);
+    // Leave at least a few dimensions to rebuild from
+    let n_projected = params.n_components.min(n.saturating_sub(3));
+    let mut projection = DMatrix::identity(n, n);
+    for &k in &order[..n_projected] {
+        let u = eigen.eigenvectors.column(k);
+        projection -= u * u.transpose();
+    }
+    let operator = reconstruction(lead_field, &(&projection * lead_field), params.lambda)? * &projection;
+    let report = SspSirReport {
+        artefact_variance: order.iter().take(n_projected.max(10).min(n)).map(|&k| eigen.eigenvalues[k].max(0.0) / total).collect(),
+        n_projected,
+    };
+    Ok((operator, report))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DenoiseMethod {
+    Sound(SoundParams),
+    SspSir(SspSirParams),
+}
+
+// `covariance` gives the fitted channels' covariance (µV²), optionally high-passed and within an epoch window
+fn denoise(
+    method: &DenoiseMethod,
+    model: &SphereModel,
+    eeg_info: &EEGInfo,
+    n_channels: usize,
+    covariance: impl Fn(&[usize], Option<f64>, Option<(f64, f64)>) -> Result<DMatrix<f64>, Box<dyn std::error::Error>>,
+) -> Result<Denoising, Box<dyn std::error::Error>> {
+    let (channels, electrodes) = fitted_channels(eeg_info, n_channels);
+    if channels.len() < 8 {
+        return Err(format!("{} good channels with a position are too few for a head model", channels.len()).into());
+    }
+    let average = average_reference(channels.len());
+    let lead_field = &average * headmodel::lead_field(model, &electrodes);
+    let broadband = &average * covariance(&channels, None, None)? * &average;
+    let (operator, report) = match method {
+        DenoiseMethod::Sound(params) => {
+            let (operator, report) = sound_operator(params, &lead_field, &broadband)?;
+            (operator, DenoiseReport::Sound(report))
+        }
+        DenoiseMethod::SspSir(params) => {
+            let artefact = &average * covariance(&channels, Some(params.highpass), Some((params.tmin, params.tmax)))? * &average;
+            let (operator, report) = ssp_sir_operator(params, &lead_field, &artefact)?;
+            (operator, DenoiseReport::SspSir(report))
+        }
+    };
+    let operator = operator * &average;
+    let removed = removed_fraction(&operator, &broadband);
+    let mut matrix = DMatrix::identity(n_channels, n_channels);
+    for (i, &row) in channels.iter().enumerate() {
+        for (j, &ch) in channels.iter().enumerate() {
+            // The operator works in µV
+            matrix[(row, ch)] = operator[(i, j)] * eeg_info.resolution(ch) / eeg_info.resolution(row);
+        }
+    }
+    Ok(Denoising {
+        matrix,
+        ch_names: channels.iter().map(|&ch| eeg_info.ch_names.get(ch).cloned().unwrap_or_else(|| format!("Ch{}", ch + 1))).collect(),
+        channels,
+        removed,
+        report,
+    })
+}
+
+// Estimated from the given stretches, e.g. Markers::good_spans; SSP-SIR takes the artefact from all of them
+pub fn denoise_continuous<T: Sample>(
+    method: &DenoiseMethod,
+    model: &SphereModel,
+    eeg_info: &EEGInfo,
+    data: &[Vec<T>],
+    spans: &[(usize, usize)],
+) -> Result<Denoising, Box<dyn std::error::Error>> {
+    denoise(method, model, eeg_info, data.len(), |channels, highpass, _| covariance_continuous(eeg_info, data, channels, spans, highpass))
+}
+
+// Estimated from the good epochs; SSP-SIR takes the artefact from its window only
+pub fn denoise_epochs(method: &DenoiseMethod, model: &SphereModel, eeg_info: &EEGInfo, epochs: &EpochsData) -> Result<Denoising, Box<dyn std::error::Error>> {
+    denoise(method, model, eeg_info, epochs.n_channels(), |channels, highpass, window| covariance_epochs(eeg_info, epochs, channels, window, highpass))
+}
+
+pub fn apply_denoising<T: Sample>(denoising: &Denoising, eeg_data: &Array2<T>) -> Array2<T> {
+    reference::apply_matrix(&denoising.matrix, eeg_data)
+}
+
+pub fn apply_denoising_epochs(denoising: &Denoising, epochs: &mut EpochsData) {
+    reference::apply_matrix_epochs(&denoising.matrix, epochs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: [&str; 19] = ["Fp1", "Fp2", "F7", "F3", "Fz", "F4", "F8", "T7", "C3", "Cz", "C4", "T8", "P7", "P3", "Pz", "P4", "P8", "O1", "O2"];
+    const SFREQ: i32 = 1000;
+    const N_SAMPLES: usize = 5000;
+
+    fn info() -> EEGInfo {
+        EEGInfo { ch_names: LABELS.iter().map(|&label| label.to_owned()).collect(), sfreq: SFREQ, ..Default::default() }
+    }
+
+    // Three slow dipoles of the model, about 10 µV on the channels, plus 0.5 µV of sensor noise.
+    // Sources at half the head radius keep the fields smooth enough for 19 channels to predict each other.
+    fn brain(model: &SphereModel) -> Vec<Vec<f64>> {
+        let electrodes: Vec<positions::Position> = LABELS.iter().filter_map(|&label| positions::standard_position(label)).collect();
+        let lead_field = headmodel::lead_field(model, &electrodes);
+        let scale = 10.0 / lead_field.column(30).amax();
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut noise = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % 2001) as f64 / 1000.0 - 1.0
+        };
+        (0..LABELS.len())
+            .map(|ch| {
+                (0..N_SAMPLES)
+                    .map(|t| {
+                        let time = t as f64 / f64::from(SFREQ);
+                        let sources = [(30, 5.0), (151, 10.0), (272, 13.0)];
+                        let signal: f64 = sources.iter().map(|&(column, freq)| lead_field[(ch, column)] * (2.0 * std::f64::consts::PI * freq * time).sin()).sum();
+                        signal * scale + 0.5 * noise()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Share of `pattern` (average referenced) the cleaning matrix lets through
+    fn passed(denoising: &Denoising, pattern: &[f64]) -> f64 {
+        let mean = pattern.iter().sum::<f64>() / pattern.len() as f64;
+        let pattern = DVector::from_iterator(pattern.len(), pattern.iter().map(|x| x - mean));
+        (&denoising.matrix * &pattern).norm() / pattern.norm()
+    }
+
+    #[test]
+    fn ssp_sir_removes_a_rank_one_artefact() -> Result<(), Box<dyn std::error::Error>> {
+        let model = SphereModel { n_locations: 200, source_radius: 0.5 };
+        let pattern: Vec<f64> = (0..LABELS.len()).map(|ch| if ch % 3 == 0 { 1.0 } else { -0.5 }).collect();
+        let mut data = brain(&model);
+        for (ch, trace) in data.iter_mut().enumerate() {
+            for (t, value) in trace.iter_mut().enumerate() {
+                *value += 40.0 * pattern[ch] * (2.0 * std::f64::consts::PI * 180.0 * t as f64 / f64::from(SFREQ)).sin();
+            }
+        }
+        let params = SspSirParams { n_components: 1, ..Default::default() };
+        let denoising = denoise_continuous(&DenoiseMethod::SspSir(params), &model, &info(), &data, &[(0, N_SAMPLES)])?;
+        assert!(passed(&denoising, &pattern) < 0.05, "artefact passed: {}", passed(&denoising, &pattern));
+        Ok(())
+    }
+
+    #[test]
+    fn sound_suppresses_a_noisy_channel() -> Result<(), Box<dyn std::error::Error>> {
+        let model = SphereModel { n_locations: 200, source_radius: 0.5 };
+        let noisy = LABELS.iter().position(|&label| label == "C3").unwrap_or(0);
+        let mut data = brain(&model);
+        for (t, value) in data[noisy].iter_mut().enumerate() {
+            *value += 50.0 * (t as f64 * 0.7).sin();
+        }
+        let denoising = denoise_continuous(&DenoiseMethod::Sound(SoundParams::default()), &model, &info(), &data, &[(0, N_SAMPLES)])?;
+        let DenoiseReport::Sound(report) = &denoising.report else { return Err("Not a SOUND report".into()) };
+        let loudest = report.noise.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).map(|(ch, _)| ch);
+        assert_eq!(loudest, Some(noisy));
+        let spike: Vec<f64> = (0..LABELS.len()).map(|ch| if ch == noisy { 1.0 } else { 0.0 }).collect();
+        // At least 90 % of the noise power goes
+        assert!(passed(&denoising, &spike) < 0.3, "noise passed: {}", passed(&denoising, &spike));
+        Ok(())
+    }
+}
@@ -8,7 +8,7 @@ use edf_reader::sync_reader::SyncEDFReader;
 use local_edf_reader::LocalFileReader;
 use local_edf_reader::init_sync_reader;
 
-use crate::{RawEEG, EEGInfo, Markers, reference};
+use crate::{RawEEG, EEGInfo, Markers, Annotation, signal};
 
 
 pub fn open_file(file_path: &str, raw_eeg: &mut RawEEG) -> std::io::Result<()> {
@@ -82,6 +82,7 @@ pub fn parse_edf_info_load_data(
 
     eeg_info.num_ch = number_of_channels as i32;
     eeg_info.ch_names = header.channels.iter().map(|c| c.label.clone()).collect();
+    eeg_info.resolutions = header.channels.iter().map(|c| signal::microvolts_per_unit(&c.physical_dimension)).collect();
 
     let start_time_ms = 0;
     let total_duration_ms = header.number_of_blocks * header.block_duration;
@@ -129,17 +130,7 @@ pub fn parse_edf_info_load_data(
                 }
             }
 
-            raw_eeg.edf_data = Some(eeg_data_only.clone());
-
-            match reference::compute_average_reference_f32(&eeg_data_only) {
-                Ok(avg_ref) => {
-                    raw_eeg.edf_data_avg_ref = Some(avg_ref);
-                }
-                Err(e) => {
-                    eprintln!("Error computing average reference: {}", e);
-                    raw_eeg.edf_data_avg_ref = None;
-                }
-            }
+            raw_eeg.edf_data = Some(eeg_data_only);
 
         } else {
             return Err(Error::new(
@@ -219,7 +210,8 @@ fn parse_edf_annotations(
             }
         }
 
-        if parts.len() < 3 {
+        // Time-keeping TALs ("+onset\x14\x14") carry no description
+        if parts.len() < 2 {
             continue;
         }
 
@@ -229,7 +221,26 @@ fn parse_edf_annotations(
                     // Subtract the first block offset
                     let adjusted_time = onset_seconds - first_timestamp.unwrap_or(0.0);
                     let sample_position = adjusted_time * sampling_frequency as f64;
-                    eeg_markers.markers.push(sample_position);
+                    let description = parts[parts.len() - 1].trim().to_owned();
+                    // "+onset\x15duration\x14..." is an annotation spanning the duration rather than an event
+                    let duration = tal
+                        .split('\x14')
+                        .next()
+                        .and_then(|timing| timing.split_once('\x15'))
+                        .and_then(|(_, duration)| duration.trim().parse::<f64>().ok());
+                    match duration {
+                        Some(duration) if duration > 0.0 => {
+                            eeg_markers.annotations.push(Annotation {
+                                onset: sample_position,
+                                duration: duration * sampling_frequency as f64,
+                                description,
+                            });
+                        }
+                        _ => {
+                            eeg_markers.markers.push(sample_position);
+                            eeg_markers.descriptions.push(description);
+                        }
+                    }
                 }
             }
         }
@@ -237,3 +248,116 @@ fn parse_edf_annotations(
 
     eeg_markers.n_markers = eeg_markers.markers.len();
 }
+
+
+// Left-aligned ASCII header field, space padded or cut to `width`
+fn header_field(value: &str, width: usize) -> String {
+    let ascii: String = value.chars().map(|c| if c.is_ascii() { c } else { '_' }).take(width).collect();
+    format!("{ascii:<width$}")
+}
+
+// Shortest decimal form of `value` that fits the 8 characters of an EDF number field
+fn header_number(value: f64) -> String {
+    (0..=6)
+        .rev()
+        .map(|decimals| format!("{value:.decimals$}"))
+        .find(|text| text.len() <= 8)
+        .unwrap_or_else(|| format!("{}", value.round() as i64))
+}
+
+// Annotation signal bytes of every one-second record: the time-keeping TAL, then the events starting in it
+fn annotation_records(markers: &Markers, sfreq: f64, n_records: usize) -> Vec<Vec<u8>> {
+    let mut records: Vec<Vec<u8>> = (0..n_records).map(|record| format!("+{record}\x14\x14\x00").into_bytes()).collect();
+    let mut push = |onset: f64, text: String| {
+        let record = (onset.max(0.0) as usize).min(n_records - 1);
+        records[record].extend(text.into_bytes());
+    };
+    for (&pos, description) in markers.markers.iter().zip(&markers.descriptions) {
+        let onset = pos / sfreq;
+        push(onset, format!("+{onset:.6}\x14{description}\x14\x00"));
+    }
+    for annotation in &markers.annotations {
+        let (onset, duration) = (annotation.onset / sfreq, annotation.duration / sfreq);
+        push(onset, format!("+{onset:.6}\x15{duration:.6}\x14{}\x14\x00", annotation.description));
+    }
+    records
+}
+
+// EDF+ file with the data in µV, via the channel resolutions, as 16-bit samples scaled per channel and the
+// markers and annotations in an "EDF Annotations" signal. Data records last one second; the last one is padded with zeros.
+pub fn write_edf_plus(path: &str, eeg_info: &EEGInfo, data: &[Vec<f32>], markers: &Markers) -> std::io::Result<()> {
+    let (ch_names, sfreq) = (&eeg_info.ch_names, eeg_info.sfreq.max(0) as usize);
+    let n_samples = data.iter().map(Vec::len).min().unwrap_or(0);
+    if sfreq == 0 || n_samples == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "No data to write"));
+    }
+    let n_records = n_samples.div_ceil(sfreq);
+    let annotations = annotation_records(markers, sfreq as f64, n_records);
+    let annotation_samples = annotations.iter().map(Vec::len).max().unwrap_or(0).div_ceil(2).max(32);
+
+    let ranges: Vec<(f64, f64)> = data
+        .iter()
+        .enumerate()
+        .map(|(ch, channel)| {
+            let resolution = eeg_info.resolution(ch);
+            let (min, max) = channel[..n_samples]
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &x| (min.min(f64::from(x) * resolution), max.max(f64::from(x) * resolution)));
+            let (min, max) = if max - min < 1e-6 { (min - 1.0, max + 1.0) } else { (min, max) };
+            // The header values as written, so the scaling matches what readers see
+            let parse = |value: f64| header_number(value).parse::<f64>().unwrap_or(value);
+            (parse(min), parse(max))
+        })
+        .collect();
+
+    let n_signals = data.len() + 1;
+    let mut header = String::new();
+    header.push_str(&header_field("0", 8));
+    header.push_str(&header_field("X X X X", 80));
+    header.push_str(&header_field("Startdate X X X X", 80));
+    header.push_str(&header_field("01.01.85", 8));
+    header.push_str(&header_field("00.00.00", 8));
+    header.push_str(&header_field(&(256 * (n_signals + 1)).to_string(), 8));
+    header.push_str(&header_field("EDF+C", 44));
+    header.push_str(&header_field(&n_records.to_string(), 8));
+    header.push_str(&header_field("1", 8));
+    header.push_str(&header_field(&n_signals.to_string(), 4));
+    let labels: Vec<String> = (0..data.len())
+        .map(|ch| ch_names.get(ch).cloned().unwrap_or_else(|| format!("Ch{}", ch + 1)))
+        .chain(std::iter::once("EDF Annotations".to_owned()))
+        .collect();
+    let signal_fields: [Box<dyn Fn(usize) -> String>; 10] = [
+        Box::new(|s| header_field(&labels[s], 16)),
+        Box::new(|_| header_field("", 80)),
+        Box::new(|s| header_field(if s < data.len() { "uV" } else { "" }, 8)),
+        // Physical equal to digital for the annotations, as `parse_edf_annotations` reads them
+        Box::new(|s| header_field(&ranges.get(s).map_or("-32768".to_owned(), |r| header_number(r.0)), 8)),
+        Box::new(|s| header_field(&ranges.get(s).map_or("32767".to_owned(), |r| header_number(r.1)), 8)),
+        Box::new(|_| header_field("-32768", 8)),
+        Box::new(|_| header_field("32767", 8)),
+        Box::new(|_| header_field("", 80)),
+        Box::new(|s| header_field(&(if s < data.len() { sfreq } else { annotation_samples }).to_string(), 8)),
+        Box::new(|_| header_field("", 32)),
+    ];
+    for field in &signal_fields {
+        for signal in 0..n_signals {
+            header.push_str(&field(signal));
+        }
+    }
+
+    let mut bytes = header.into_bytes();
+    for (record, annotation) in annotations.iter().enumerate() {
+        for (ch, (channel, &(min, max))) in data.iter().zip(&ranges).enumerate() {
+            let resolution = eeg_info.resolution(ch);
+            for t in record * sfreq..(record + 1) * sfreq {
+                let value = channel.get(t).filter(|_| t < n_samples).map_or(0.0, |&x| f64::from(x) * resolution);
+                let digital = ((value - min) / (max - min) * 65535.0 - 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+                bytes.extend_from_slice(&digital.to_le_bytes());
+            }
+        }
+        let mut padded = annotation.clone();
+        padded.resize(annotation_samples * 2, 0);
+        bytes.extend(padded);
+    }
+    std::fs::write(path, bytes)
+}
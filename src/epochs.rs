@@ -1,58 +1,216 @@
 use ndarray::{s, Array2, Array3, Axis};
 
-use crate::io::vec_to_ndarray;
-use crate::{EEGData, EEGInfo, Markers, EpochsData};
-use crate::signal;
+use crate::{EEGInfo, Markers, EpochsData, EvokedData, EpochEvent, DroppedEpoch, DropReason};
+use crate::signal::Sample;
 
+// Epochs x channels x times, the event of each kept epoch, and the dropped epochs
+pub type EpochResult<T> = (Array3<T>, Vec<EpochEvent>, Vec<DroppedEpoch>);
 
-pub fn epoch_eeg(
+// Cut epochs from tmin (negative, pre-stimulus) to tmax (post-stimulus) around the chosen event types.
+// Epochs running past the recording edges are dropped and returned separately.
+pub fn epoch_eeg<T: Sample>(
     tmin: f64,
     tmax: f64,
+    event_types: &[String],
+    eeg_info: &EEGInfo,
+    eeg_data: &Array2<T>,
+    markers: &Markers
+) -> Result<EpochResult<T>, Box<dyn std::error::Error>> {
+    if tmin > 0.0 || tmax <= tmin {
+        return Err(format!("Invalid epoch limits: tmin {tmin} s, tmax {tmax} s").into());
+    }
+    if eeg_info.sfreq <= 0 {
+        return Err("Sampling frequency must be positive".into());
+    }
+
+    let sfreq = eeg_info.sfreq as f64;
+    let n_samples = eeg_data.ncols() as isize;
+    let pre_samples = (tmin * sfreq).round() as isize;
+    let post_samples = (tmax * sfreq).round() as isize;
+    let n_times = (post_samples - pre_samples + 1) as usize;
+
+    let mut kept = Vec::new();
+    let mut drop_log = Vec::new();
+    for (marker_idx, &marker_pos) in markers.markers.iter().enumerate() {
+        let description = markers.descriptions.get(marker_idx).cloned().unwrap_or_default();
+        if !event_types.is_empty() && !event_types.contains(&description) {
+            continue;
+        }
+        let event = EpochEvent { marker_idx, sample: marker_pos, description };
+        let start_cut = marker_pos.round() as isize + pre_samples;
+        let end_cut = marker_pos.round() as isize + post_samples + 1;
+        if start_cut < 0 || end_cut > n_samples {
+            drop_log.push(DroppedEpoch { event, reason: DropReason::OutsideRecording, channel: None });
+            continue;
+        }
+        kept.push((start_cut as usize, event));
+    }
+
+    let mut epochs = Array3::from_elem((kept.len(), eeg_data.nrows(), n_times), T::default());
+    for (epoch_idx, (start_cut, _)) in kept.iter().enumerate() {
+        epochs
+            .slice_mut(s![epoch_idx, .., ..])
+            .assign(&eeg_data.slice(s![.., *start_cut..*start_cut + n_times]));
+    }
+    let events = kept.into_iter().map(|(_, event)| event).collect();
+    Ok((epochs, events, drop_log))
+}
+
+pub fn create_epochs_bv(
+    tmin: f64,
+    tmax: f64,
+    event_types: &[String],
     eeg_info: &EEGInfo,
     eeg_data: &Array2<i16>,
     markers: &Markers
-) -> Result<Array3<i16>, Box<dyn std::error::Error>> {
-    if eeg_data.is_empty() {
-        return Ok(Array3::from_shape_vec((0, 0, 0), Vec::new()).unwrap());
+) -> Result<EpochsData, Box<dyn std::error::Error>> {
+    let (bv_epochs, events, drop_log) = epoch_eeg(tmin, tmax, event_types, eeg_info, eeg_data, markers)?;
+    Ok(EpochsData {
+        bv_epochs,
+        ch_names: eeg_info.ch_names.clone(),
+        tmin,
+        tmax,
+        sfreq: eeg_info.sfreq as f64,
+        bad: vec![false; events.len()],
+        events,
+        drop_log,
+        bad_channels: eeg_info.bad_channels.clone(),
+        ..Default::default()
+    })
+}
+
+pub fn create_epochs_edf(
+    tmin: f64,
+    tmax: f64,
+    event_types: &[String],
+    eeg_info: &EEGInfo,
+    eeg_data: &Array2<f32>,
+    markers: &Markers
+) -> Result<EpochsData, Box<dyn std::error::Error>> {
+    let (edf_epochs_data, events, drop_log) = epoch_eeg(tmin, tmax, event_types, eeg_info, eeg_data, markers)?;
+    Ok(EpochsData {
+        edf_epochs_data,
+        ch_names: eeg_info.ch_names.clone(),
+        tmin,
+        tmax,
+        sfreq: eeg_info.sfreq as f64,
+        bad: vec![false; events.len()],
+        events,
+        drop_log,
+        bad_channels: eeg_info.bad_channels.clone(),
+        ..Default::default()
+    })
+}
+
+impl EpochsData {
+    pub fn n_epochs(&self) -> usize {
+        self.events.len()
     }
 
-    let mut epochs: Vec<Vec<Vec<i16>>> = Vec::new();
+    pub fn good_epochs(&self) -> Vec<usize> {
+        (0..self.n_epochs()).filter(|&i| !self.bad.get(i).copied().unwrap_or(false)).collect()
+    }
 
-    let mut data_copy = eeg_data.clone();
-    let n_samples = data_copy.ncols();
+    pub fn n_channels(&self) -> usize {
+        if self.bv_epochs.is_empty() {
+            self.edf_epochs_data.len_of(Axis(1))
+        } else {
+            self.bv_epochs.len_of(Axis(1))
+        }
+    }
 
-    let min_samples = (tmin * eeg_info.sfreq as f64).round() as usize;
-    let max_samples = (tmax * eeg_info.sfreq as f64).round() as usize;
+    pub fn n_times(&self) -> usize {
+        if self.bv_epochs.is_empty() {
+            self.edf_epochs_data.len_of(Axis(2))
+        } else {
+            self.bv_epochs.len_of(Axis(2))
+        }
+    }
 
-    for &marker_pos in &markers.markers {
-        let mut current_epoch: Vec<Vec<i16>> = Vec::new();
-        let marker_idx = marker_pos.round() as usize;
+    // Epoch time axis in seconds, 0 at the event
+    pub fn times(&self) -> Vec<f64> {
+        (0..self.n_times()).map(|i| self.tmin + i as f64 / self.sfreq).collect()
+    }
 
-        let start_cut = marker_idx.saturating_sub(min_samples);
-        let end_cut = (marker_idx + max_samples).min(n_samples);
-        if start_cut >= end_cut {
-            continue;
+    // Epochs x channels x times in f64, whichever format was epoched
+    pub fn data_f64(&self) -> Array3<f64> {
+        if self.bv_epochs.is_empty() {
+            self.edf_epochs_data.mapv(f64::from)
+        } else {
+            self.bv_epochs.mapv(f64::from)
         }
-        for ch_idx in 0..data_copy.nrows() {
-            let slice = data_copy.slice_mut(s![ch_idx, start_cut..end_cut]).to_vec();
-            current_epoch.push(slice);
+    }
+
+    // One channel of one epoch in f64
+    pub fn trace(&self, epoch_idx: usize, ch_idx: usize) -> Vec<f64> {
+        if self.bv_epochs.is_empty() {
+            self.edf_epochs_data.slice(s![epoch_idx, ch_idx, ..]).iter().map(|&x| f64::from(x)).collect()
+        } else {
+            self.bv_epochs.slice(s![epoch_idx, ch_idx, ..]).iter().map(|&x| f64::from(x)).collect()
+        }
+    }
+
+    // Sample index closest to `time` (s), clamped to the epoch
+    pub fn time_to_index(&self, time: f64) -> usize {
+        let idx = ((time - self.tmin) * self.sfreq).round().max(0.0) as usize;
+        idx.min(self.n_times().saturating_sub(1))
+    }
+
+    // Apply `f` to every single-channel trace of every epoch, in place
+    pub fn map_traces(&mut self, f: impl Fn(&mut [f64])) {
+        map_lanes(&mut self.bv_epochs, &f);
+        map_lanes(&mut self.edf_epochs_data, &f);
+    }
+
+    // Apply `f` to the vector of all channels at every sample of every epoch, in place
+    pub fn map_channel_vectors(&mut self, f: impl Fn(&mut [f64])) {
+        map_lanes_along(&mut self.bv_epochs, Axis(1), &f);
+        map_lanes_along(&mut self.edf_epochs_data, Axis(1), &f);
+    }
+
+    // Move BrainVision integer epochs to the float array, for results the integers would round away
+    pub fn to_float(&mut self) {
+        if !self.bv_epochs.is_empty() {
+            self.edf_epochs_data = std::mem::take(&mut self.bv_epochs).mapv(f32::from);
+        }
+    }
+}
+
+fn map_lanes<T: Sample>(data: &mut Array3<T>, f: &impl Fn(&mut [f64])) {
+    map_lanes_along(data, Axis(2), f);
+}
+
+fn map_lanes_along<T: Sample>(data: &mut Array3<T>, axis: Axis, f: &impl Fn(&mut [f64])) {
+    let mut trace = Vec::with_capacity(data.len_of(axis));
+    for mut lane in data.lanes_mut(axis) {
+        trace.clear();
+        trace.extend(lane.iter().map(|&sample| sample.into()));
+        f(&mut trace);
+        for (sample, &value) in lane.iter_mut().zip(&trace) {
+            *sample = T::from_f64(value);
         }
-        epochs.push(current_epoch);
     }
-    let data = signal::vec_to_ndarray3(epochs);
-    Ok(data)
 }
 
 
 pub fn evoked_eeg(
     epochs: &EpochsData,
-    eeg_info: &EEGInfo,
-) -> Result<Array2<f64>, Box<dyn std::error::Error>> {
-    if epochs.epochs.is_empty() {
-        return Ok(Array2::from_shape_vec((0, 0), Vec::new()).unwrap());
-    }
-    let default_evoked = Array2::from_shape_vec((0, 0), Vec::new()).unwrap();
-    let epochs_f64 = epochs.epochs.mapv(|i| i as f64);
-    let evoked = epochs_f64.mean_axis(Axis(0)).unwrap_or(default_evoked);
-    Ok(evoked)
+) -> Result<EvokedData, Box<dyn std::error::Error>> {
+    let good = epochs.good_epochs();
+    if good.is_empty() {
+        return Err("No good epochs to average".into());
+    }
+    let evoked = epochs
+        .data_f64()
+        .select(Axis(0), &good)
+        .mean_axis(Axis(0))
+        .ok_or("No good epochs to average")?;
+    Ok(EvokedData {
+        evoked,
+        ch_names: epochs.ch_names.clone(),
+        tmin: epochs.tmin,
+        tmax: epochs.tmax,
+        sfreq: epochs.sfreq,
+        nave: good.len(),
+    })
 }
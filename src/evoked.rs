@@ -0,0 +1,73 @@
+use ndarray::Axis;
+
+use crate::{EEGInfo, EvokedData};
+
+impl EvokedData {
+    pub fn n_times(&self) -> usize {
+        self.evoked.ncols()
+    }
+
+    // Time axis in seconds, 0 at the event
+    pub fn times(&self) -> Vec<f64> {
+        (0..self.n_times()).map(|i| self.tmin + i as f64 / self.sfreq).collect()
+    }
+
+    // Sample index closest to `time` (s), clamped to the evoked response
+    pub fn time_to_index(&self, time: f64) -> usize {
+        let idx = ((time - self.tmin) * self.sfreq).round().max(0.0) as usize;
+        idx.min(self.n_times().saturating_sub(1))
+    }
+}
+
+// Global mean field power: spatial standard deviation across the good channels at each time point, in µV
+pub fn global_field_power(evoked: &EvokedData, eeg_info: &EEGInfo) -> Vec<f64> {
+    evoked
+        .evoked
+        .axis_iter(Axis(1))
+        .map(|column| {
+            let values: Vec<f64> = column
+                .iter()
+                .enumerate()
+                .filter(|(ch, _)| !eeg_info.bad_channels.contains(ch))
+                .map(|(ch, v)| v * eeg_info.resolution(ch))
+                .collect();
+            let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+            (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len().max(1) as f64).sqrt()
+        })
+        .collect()
+}
+
+// Local mean field power (Romero Lauro et al.): root mean square over the good channels of a region of
+// interest at each time point, in µV. Not mean-centred, so a response of one polarity under the whole
+// region counts in full.
+pub fn local_field_power(evoked: &EvokedData, eeg_info: &EEGInfo, channels: &[usize]) -> Vec<f64> {
+    let channels: Vec<usize> = channels
+        .iter()
+        .copied()
+        .filter(|&ch| ch < evoked.evoked.nrows() && !eeg_info.bad_channels.contains(&ch))
+        .collect();
+    if channels.is_empty() {
+        return vec![0.0; evoked.n_times()];
+    }
+    evoked
+        .evoked
+        .axis_iter(Axis(1))
+        .map(|column| {
+            let sum: f64 = channels.iter().map(|&ch| (column[ch] * eeg_info.resolution(ch)).powi(2)).sum();
+            (sum / channels.len() as f64).sqrt()
+        })
+        .collect()
+}
+
+// Trapezoidal area under a time course between tmin and tmax (s), in the unit of `values` x s (µV s for field powers)
+pub fn area_under_curve(evoked: &EvokedData, values: &[f64], tmin: f64, tmax: f64) -> f64 {
+    if values.len() < 2 || tmax <= tmin {
+        return 0.0;
+    }
+    let start = evoked.time_to_index(tmin).min(values.len() - 1);
+    let end = evoked.time_to_index(tmax).min(values.len() - 1);
+    values[start..=end]
+        .windows(2)
+        .map(|pair| (pair[0] + pair[1]) / 2.0 / evoked.sfreq)
+        .sum()
+}
@@ -0,0 +1,50 @@
+use crate::{ChannelGroup, EEGInfo};
+use crate::positions::clean_label;
+
+impl ChannelGroup {
+    // Group from a comma separated list of labels, e.g. "C3, FC1, CP1"
+    pub fn from_labels(name: &str, labels: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            channels: labels.split(',').map(str::trim).filter(|l| !l.is_empty()).map(str::to_owned).collect(),
+        }
+    }
+}
+
+// Indices into `ch_names` of the channels matching `labels`, compared without prefixes and case
+pub fn channel_indices(ch_names: &[String], labels: &[String]) -> Vec<usize> {
+    let labels: Vec<String> = labels.iter().map(|label| clean_label(label)).collect();
+    ch_names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| {
+            let name = clean_label(name);
+            labels.iter().any(|label| label.eq_ignore_ascii_case(&name))
+        })
+        .map(|(ch, _)| ch)
+        .collect()
+}
+
+impl EEGInfo {
+    pub fn group(&self, name: &str) -> Option<&ChannelGroup> {
+        self.channel_groups.iter().find(|group| group.name == name)
+    }
+
+    // Channel indices of a named group; empty if the group is unknown
+    pub fn group_channels(&self, name: &str) -> Vec<usize> {
+        self.group(name)
+            .map(|group| channel_indices(&self.ch_names, &group.channels))
+            .unwrap_or_default()
+    }
+
+    // Replace group names in a label list by the labels of their channels
+    pub fn expand_labels(&self, labels: &[String]) -> Vec<String> {
+        labels
+            .iter()
+            .flat_map(|label| match self.group(label) {
+                Some(group) => group.channels.clone(),
+                None => vec![label.clone()],
+            })
+            .collect()
+    }
+}
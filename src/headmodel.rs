@@ -0,0 +1,80 @@
+use std::f64::consts::PI;
+
+use nalgebra::DMatrix;
+
+use crate::positions::Position;
+
+// Homogeneous conducting sphere of unit radius and conductivity with dipoles on a concentric sphere.
+// One shell rather than brain, skull and scalp: SOUND and SSP-SIR only lean on the spatial smoothness
+// of the lead field, not on absolute source amplitudes.
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct SphereModel {
+    // Radius of the source sphere as a fraction of the head radius
+    pub source_radius: f64,
+    // Dipole locations, spread evenly over the sphere above LOWEST_SOURCE_Z
+    pub n_locations: usize,
+}
+
+impl Default for SphereModel {
+    fn default() -> Self {
+        Self { source_radius: 0.8, n_locations: 500 }
+    }
+}
+
+// Sources stop a little below the electrode equator, roughly the base of the temporal lobes
+const LOWEST_SOURCE_Z: f64 = -0.3;
+
+fn dot(a: &Position, b: &Position) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn normalise(pos: &Position) -> Position {
+    let norm = dot(pos, pos).sqrt().max(f64::EPSILON);
+    [pos[0] / norm, pos[1] / norm, pos[2] / norm]
+}
+
+// Unit vectors of a Fibonacci lattice over the cap z >= LOWEST_SOURCE_Z
+pub fn source_locations(n_locations: usize) -> Vec<Position> {
+    let golden = PI * (3.0 - 5f64.sqrt());
+    (0..n_locations)
+        .map(|i| {
+            let z = 1.0 - (1.0 - LOWEST_SOURCE_Z) * (i as f64 + 0.5) / n_locations as f64;
+            let radius = (1.0 - z * z).max(0.0).sqrt();
+            let azimuth = golden * i as f64;
+            [radius * azimuth.cos(), radius * azimuth.sin(), z]
+        })
+        .collect()
+}
+
+// Surface potential at unit vector `electrode` of a dipole `moment` at `eccentricity` along unit vector `location`:
+// 1/(4π) Σ (2n+1)/n f^(n-1) [n q_r P_n(x) + (e·q_t) P_n'(x)] with x = e·u and f the eccentricity
+pub fn dipole_potential(electrode: &Position, location: &Position, eccentricity: f64, moment: &Position) -> f64 {
+    let x = dot(electrode, location).clamp(-1.0, 1.0);
+    let radial = dot(moment, location);
+    let tangential = dot(electrode, moment) - radial * x;
+    // Enough terms for f^n to fall below 1e-10
+    let n_terms = ((-23.0 / eccentricity.clamp(1e-3, 0.999).ln()).ceil() as usize + 10).min(2000);
+    let (mut p_previous, mut p) = (1.0, x);
+    let (mut dp_previous, mut dp) = (0.0, 1.0);
+    let (mut sum, mut power) = (0.0, 1.0);
+    for n in 1..=n_terms {
+        let nf = n as f64;
+        sum += (2.0 * nf + 1.0) / nf * power * (nf * radial * p + tangential * dp);
+        // P_{n+1} by Bonnet's recurrence, P'_{n+1} = P'_{n-1} + (2n + 1) P_n
+        let p_next = ((2.0 * nf + 1.0) * x * p - nf * p_previous) / (nf + 1.0);
+        let dp_next = dp_previous + (2.0 * nf + 1.0) * p;
+        (p_previous, p, dp_previous, dp) = (p, p_next, dp, dp_next);
+        power *= eccentricity;
+    }
+    sum / (4.0 * PI)
+}
+
+// electrodes x (3 * locations) potentials of unit x, y and z dipoles at every source location
+pub fn lead_field(model: &SphereModel, electrodes: &[Position]) -> DMatrix<f64> {
+    let electrodes: Vec<Position> = electrodes.iter().map(normalise).collect();
+    let locations = source_locations(model.n_locations);
+    let axes = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    DMatrix::from_fn(electrodes.len(), 3 * locations.len(), |e, column| {
+        dipole_potential(&electrodes[e], &locations[column / 3], model.source_radius, &axes[column % 3])
+    })
+}
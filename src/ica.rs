@@ -0,0 +1,432 @@
+use nalgebra::{DMatrix, DVector, SymmetricEigen};
+use ndarray::Array2;
+
+use crate::{EEGInfo, EpochsData, EvokedData, epochs, reference, spectrum};
+use crate::signal::Sample;
+use crate::spectrum::{Psd, WelchParams};
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum IcaMethod {
+    // Symmetric FastICA with the log-cosh contrast (Hyvärinen 1999)
+    FastIca,
+    // Extended Infomax, sub- and super-Gaussian sources (Lee, Girolami & Sejnowski 1999)
+    Infomax,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct IcaParams {
+    pub method: IcaMethod,
+    // Principal components kept before ICA; None keeps every one above the rank tolerance
+    pub n_components: Option<usize>,
+    pub max_iter: usize,
+    // FastICA: largest 1 - |cos| between successive unmixing rows; Infomax: 1000 x squared weight change
+    pub tol: f64,
+    pub seed: u64,
+}
+
+impl Default for IcaParams {
+    fn default() -> Self {
+        Self { method: IcaMethod::FastIca, n_components: None, max_iter: 500, tol: 1e-4, seed: 42 }
+    }
+}
+
+// Decomposition of the good channels in µV. `unmixing` takes centred channel data to unit-variance
+// sources (components x channels), `mixing` takes sources back (channels x components).
+// Components are sorted by the share of the fitted variance they explain.
+#[derive(Debug, Clone)]
+pub struct Ica {
+    pub method: IcaMethod,
+    pub channels: Vec<usize>,
+    pub ch_names: Vec<String>,
+    pub mean: Vec<f64>,
+    pub unmixing: DMatrix<f64>,
+    pub mixing: DMatrix<f64>,
+    pub explained_variance: Vec<f64>,
+    // PCA variance left out before the decomposition, as a fraction
+    pub pca_residual: f64,
+    pub n_samples: usize,
+    pub n_iter: usize,
+    pub converged: bool,
+}
+
+// Samples kept for the fit, evenly spaced over the data; ICA wants many times n_components² samples
+const MAX_FIT_SAMPLES: usize = 100_000;
+// Eigenvalues below this fraction of the largest are taken as lost rank (e.g. average reference), as in MNE
+const RANK_TOL: f64 = 1e-6;
+// Rounding to integers leaves lost rank at about step² / 12 rather than zero; eigenvalues within this factor
+// of that are lost rank too
+const ROUNDING_MARGIN: f64 = 4.0;
+// Samples the Infomax source kurtosis is estimated on, once per pass
+const KURTOSIS_SAMPLES: usize = 6000;
+
+// SplitMix64, so fits are repeatable without a random number crate
+struct Rng(u64);
+
+impl Rng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        ((z ^ (z >> 31)) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn normal(&mut self) -> f64 {
+        let u = self.next_f64().max(f64::MIN_POSITIVE);
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * self.next_f64()).cos()
+    }
+
+    fn shuffle(&mut self, values: &mut [usize]) {
+        for i in (1..values.len()).rev() {
+            let j = (self.next_f64() * (i + 1) as f64) as usize;
+            values.swap(i, j.min(i));
+        }
+    }
+}
+
+// (W Wᵀ)^(-1/2) W, the nearest matrix with orthonormal rows
+fn symmetric_decorrelation(w: &DMatrix<f64>) -> DMatrix<f64> {
+    let eigen = SymmetricEigen::new(w * w.transpose());
+    let inv_sqrt = DMatrix::from_diagonal(&eigen.eigenvalues.map(|v| 1.0 / v.max(f64::EPSILON).sqrt()));
+    &eigen.eigenvectors * inv_sqrt * eigen.eigenvectors.transpose() * w
+}
+
+// Unmixing of whitened data (components x components), iterations and convergence
+fn fastica(z: &DMatrix<f64>, params: &IcaParams, rng: &mut Rng) -> (DMatrix<f64>, usize, bool) {
+    let (k, n) = z.shape();
+    let mut w = symmetric_decorrelation(&DMatrix::from_fn(k, k, |_, _| rng.normal()));
+    for iter in 1..=params.max_iter {
+        let g = (&w * z).map(f64::tanh);
+        let g_prime = DVector::from_iterator(k, g.row_iter().map(|row| row.iter().map(|v| 1.0 - v * v).sum::<f64>() / n as f64));
+        let update = &g * z.transpose() / n as f64 - DMatrix::from_diagonal(&g_prime) * &w;
+        let next = symmetric_decorrelation(&update);
+        // Rows are unit vectors, so a fixed point has |<new, old>| = 1 for every one
+        let change = (&next * w.transpose()).diagonal().iter().map(|v| (v.abs() - 1.0).abs()).fold(0.0, f64::max);
+        w = next;
+        if change < params.tol {
+            return (w, iter, true);
+        }
+    }
+    (w, params.max_iter, false)
+}
+
+// Excess kurtosis sign of every source, the extended Infomax switch between sub- and super-Gaussian
+fn kurtosis_signs(u: &DMatrix<f64>) -> Vec<f64> {
+    u.row_iter()
+        .map(|row| {
+            let n = row.len().max(1) as f64;
+            let m2 = row.iter().map(|v| v * v).sum::<f64>() / n;
+            let m4 = row.iter().map(|v| v.powi(4)).sum::<f64>() / n;
+            let kurtosis = m4 / (m2 * m2).max(f64::EPSILON) - 3.0;
+            // Small bias towards super-Gaussian, as in EEGLAB's runica
+            if kurtosis + 0.02 >= 0.0 { 1.0 } else { -1.0 }
+        })
+        .collect()
+}
+
+// Natural-gradient extended Infomax with learning-rate annealing, as in EEGLAB's runica
+fn infomax(z: &DMatrix<f64>, params: &IcaParams, rng: &mut Rng) -> (DMatrix<f64>, usize, bool) {
+    let (k, n) = z.shape();
+    let block = ((n as f64 / 3.0).sqrt().floor() as usize).clamp(1, n);
+    let mut lrate = 0.01 / ((k * k) as f64).ln().max(1.0);
+    let identity = DMatrix::<f64>::identity(k, k);
+    let mut order: Vec<usize> = (0..n).collect();
+    let (mut w, mut previous_delta) = (identity.clone(), None::<DMatrix<f64>>);
+    for iter in 1..=params.max_iter {
+        let start_w = w.clone();
+        rng.shuffle(&mut order);
+        let subset = DMatrix::from_fn(k, n.min(KURTOSIS_SAMPLES), |i, j| z[(i, order[j])]);
+        let signs = kurtosis_signs(&(&w * subset));
+        for chunk in order.chunks(block) {
+            let x = DMatrix::from_fn(k, chunk.len(), |i, j| z[(i, chunk[j])]);
+            let u = &w * &x;
+            let mut y = u.map(f64::tanh);
+            for (i, sign) in signs.iter().enumerate() {
+                y.row_mut(i).scale_mut(*sign);
+            }
+            let gradient = &identity * chunk.len() as f64 - &y * u.transpose() - &u * u.transpose();
+            w += lrate * gradient * &w;
+        }
+        if w.iter().any(|v| !v.is_finite() || v.abs() > 1e8) {
+            // Diverged: start over more slowly
+            w = identity.clone();
+            previous_delta = None;
+            lrate *= 0.8;
+            continue;
+        }
+        let delta = &w - start_w;
+        let change = delta.norm_squared();
+        if let Some(previous) = &previous_delta {
+            let cosine = delta.dot(previous) / (change.sqrt() * previous.norm()).max(f64::EPSILON);
+            if cosine < 0.5 {
+                lrate *= 0.9;
+            }
+        }
+        previous_delta = Some(delta);
+        if change * 1e3 < params.tol {
+            return (w, iter, true);
+        }
+    }
+    (w, params.max_iter, false)
+}
+
+// Fit on fitted channels x samples in µV, stored with a rounding step of `step` µV on the coarsest channel
+fn fit(params: &IcaParams, channels: Vec<usize>, ch_names: Vec<String>, mut x: DMatrix<f64>, step: f64) -> Result<Ica, Box<dyn std::error::Error>> {
+    let (n_channels, n_samples) = x.shape();
+    if n_channels < 2 {
+        return Err("ICA needs at least two good channels".into());
+    }
+    if n_samples < 10 * n_channels {
+        return Err(format!("{n_samples} samples are too few to decompose {n_channels} channels").into());
+    }
+    let mean: Vec<f64> = x.row_iter().map(|row| row.mean()).collect();
+    for (i, &m) in mean.iter().enumerate() {
+        x.row_mut(i).add_scalar_mut(-m);
+    }
+    let covariance = &x * x.transpose() / n_samples as f64;
+    let eigen = SymmetricEigen::new(covariance);
+    let mut order: Vec<usize> = (0..n_channels).collect();
+    order.sort_by(|&a, &b| eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a]));
+    let total: f64 = eigen.eigenvalues.iter().map(|v| v.max(0.0)).sum();
+    let largest = eigen.eigenvalues[order[0]];
+    if largest <= 0.0 {
+        return Err("The data to decompose is flat".into());
+    }
+    let floor = (largest * RANK_TOL).max(ROUNDING_MARGIN * step * step / 12.0);
+    let rank = order.iter().take_while(|&&i| eigen.eigenvalues[i] > floor).count().max(1);
+    let k = params.n_components.unwrap_or(rank).clamp(1, rank);
+    let kept: f64 = order[..k].iter().map(|&i| eigen.eigenvalues[i]).sum();
+    let whitening = DMatrix::from_fn(k, n_channels, |r, c| eigen.eigenvectors[(c, order[r])] / eigen.eigenvalues[order[r]].sqrt());
+    let z = &whitening * &x;
+
+    let mut rng = Rng(params.seed);
+    let (w, n_iter, converged) = match params.method {
+        IcaMethod::FastIca => fastica(&z, params, &mut rng),
+        IcaMethod::Infomax => infomax(&z, params, &mut rng),
+    };
+    let mut unmixing = w * whitening;
+    // Unit-variance sources, so the mixing columns carry the scale
+    let sources = &unmixing * &x;
+    for (i, row) in sources.row_iter().enumerate() {
+        let std = (row.norm_squared() / n_samples as f64).sqrt().max(f64::EPSILON);
+        unmixing.row_mut(i).scale_mut(1.0 / std);
+    }
+    let mixing = unmixing.clone().pseudo_inverse(f64::EPSILON).map_err(|e| e.to_owned())?;
+    let variance: Vec<f64> = mixing.column_iter().map(|column| column.norm_squared() / total).collect();
+    let mut sorted: Vec<usize> = (0..k).collect();
+    sorted.sort_by(|&a, &b| variance[b].total_cmp(&variance[a]));
+    Ok(Ica {
+        method: params.method,
+        channels,
+        ch_names,
+        mean,
+        unmixing: DMatrix::from_fn(k, n_channels, |r, c| unmixing[(sorted[r], c)]),
+        mixing: DMatrix::from_fn(n_channels, k, |r, c| mixing[(r, sorted[c])]),
+        explained_variance: sorted.iter().map(|&i| variance[i]).collect(),
+        pca_residual: 1.0 - kept / total,
+        n_samples,
+        n_iter,
+        converged,
+    })
+}
+
+fn fit_channels(eeg_info: &EEGInfo, n_channels: usize) -> (Vec<usize>, Vec<String>) {
+    let channels: Vec<usize> = (0..n_channels).filter(|ch| !eeg_info.bad_channels.contains(ch)).collect();
+    let names = channels.iter().map(|&ch| eeg_info.ch_names.get(ch).cloned().unwrap_or_else(|| format!("Ch{}", ch + 1))).collect();
+    (channels, names)
+}
+
+// Fit on the good channels over the given stretches of samples, e.g. Markers::good_spans
+pub fn fit_continuous<T: Sample>(
+    params: &IcaParams,
+    eeg_info: &EEGInfo,
+    data: &[Vec<T>],
+    spans: &[(usize, usize)],
+) -> Result<Ica, Box<dyn std::error::Error>> {
+    let n_samples = data.iter().map(Vec::len).min().unwrap_or(0);
+    let spans: Vec<(usize, usize)> = spans.iter().map(|&(start, end)| (start.min(n_samples), end.min(n_samples))).collect();
+    let n_kept: usize = spans.iter().map(|(start, end)| end - start).sum();
+    let step = n_kept.div_ceil(MAX_FIT_SAMPLES).max(1);
+    let times: Vec<usize> = spans.iter().flat_map(|&(start, end)| start..end).step_by(step).collect();
+    let (channels, names) = fit_channels(eeg_info, data.len());
+    let x = DMatrix::from_fn(channels.len(), times.len(), |i, j| {
+        let ch = channels[i];
+        data[ch][times[j]].into() * eeg_info.resolution(ch)
+    });
+    let step = channels.iter().map(|&ch| T::STEP * eeg_info.resolution(ch)).fold(0.0, f64::max);
+    fit(params, channels, names, x, step)
+}
+
+// Fit on the good channels of the epochs not marked bad, concatenated
+pub fn fit_epochs(params: &IcaParams, eeg_info: &EEGInfo, epochs: &EpochsData) -> Result<Ica, Box<dyn std::error::Error>> {
+    let good: Vec<usize> = (0..epochs.n_epochs()).filter(|&idx| !epochs.bad.get(idx).copied().unwrap_or(false)).collect();
+    let n_times = epochs.n_times();
+    let step = (good.len() * n_times).div_ceil(MAX_FIT_SAMPLES).max(1);
+    let (channels, names) = fit_channels(eeg_info, epochs.n_channels());
+    let traces: Vec<Vec<f64>> = channels
+        .iter()
+        .map(|&ch| {
+            let resolution = eeg_info.resolution(ch);
+            good.iter().flat_map(|&idx| epochs.trace(idx, ch)).step_by(step).map(|x| x * resolution).collect()
+        })
+        .collect();
+    let n_samples = traces.first().map_or(0, Vec::len);
+    // BrainVision epochs keep the integer samples
+    let unit = if epochs.bv_epochs.is_empty() { 0.0 } else { 1.0 };
+    let step = channels.iter().map(|&ch| unit * eeg_info.resolution(ch)).fold(0.0, f64::max);
+    fit(params, channels, names, DMatrix::from_fn(traces.len(), n_samples, |i, j| traces[i][j]), step)
+}
+
+// Spectra of the component time courses over the continuous data and their average over the epochs
+#[derive(Debug, Clone)]
+pub struct ComponentProperties {
+    pub psd: Psd,
+    pub evoked: Option<EvokedData>,
+}
+
+impl Ica {
+    pub fn n_components(&self) -> usize {
+        self.unmixing.nrows()
+    }
+
+    pub fn component_names(&self) -> Vec<String> {
+        (1..=self.n_components()).map(|c| format!("IC{c}")).collect()
+    }
+
+    // Fitted channels x samples in µV, without the channel means
+    fn centred_window<T: Sample>(&self, eeg_info: &EEGInfo, data: &[Vec<T>], start: usize, end: usize) -> DMatrix<f64> {
+        let end = self.channels.iter().map(|&ch| data.get(ch).map_or(0, Vec::len)).fold(end, usize::min);
+        let start = start.min(end);
+        DMatrix::from_fn(self.channels.len(), end - start, |i, t| {
+            let ch = self.channels[i];
+            data[ch][start + t].into() * eeg_info.resolution(ch) - self.mean[i]
+        })
+    }
+
+    // Component time courses (components x samples) between `start` and `end`
+    pub fn sources<T: Sample>(&self, eeg_info: &EEGInfo, data: &[Vec<T>], start: usize, end: usize) -> Array2<f64> {
+        let sources = &self.unmixing * self.centred_window(eeg_info, data, start, end);
+        Array2::from_shape_fn(sources.shape(), |(c, t)| sources[(c, t)])
+    }
+
+    // Every channel between `start` and `end` in data units with the `exclude` components removed,
+    // as the removal matrix would leave it
+    pub fn cleaned_window<T: Sample>(&self, exclude: &[usize], eeg_info: &EEGInfo, data: &[Vec<T>], start: usize, end: usize) -> Vec<Vec<f64>> {
+        let mut cleaned: Vec<Vec<f64>> = data
+            .iter()
+            .map(|channel| channel[start.min(channel.len())..end.min(channel.len())].iter().map(|&x| x.into()).collect())
+            .collect();
+        let exclude: Vec<usize> = exclude.iter().copied().filter(|&c| c < self.n_components()).collect();
+        if exclude.is_empty() {
+            return cleaned;
+        }
+        let window = DMatrix::from_fn(self.channels.len(), cleaned.first().map_or(0, Vec::len), |i, t| {
+            let ch = self.channels[i];
+            cleaned[ch].get(t).copied().unwrap_or(0.0) * eeg_info.resolution(ch)
+        });
+        let unmixing = DMatrix::from_fn(exclude.len(), self.channels.len(), |r, c| self.unmixing[(exclude[r], c)]);
+        let mixing = DMatrix::from_fn(self.channels.len(), exclude.len(), |r, c| self.mixing[(r, exclude[c])]);
+        let removed = mixing * (unmixing * window);
+        for (i, &ch) in self.channels.iter().enumerate() {
+            let resolution = eeg_info.resolution(ch);
+            for (t, value) in cleaned[ch].iter_mut().enumerate() {
+                *value -= removed[(i, t)] / resolution;
+            }
+        }
+        cleaned
+    }
+
+    // channels x channels matrix in data units subtracting the `exclude` components' share of the fitted
+    // channels; other channels pass through. The components' share of the channel means goes too.
+    pub fn removal_matrix(&self, exclude: &[usize], eeg_info: &EEGInfo, n_channels: usize) -> DMatrix<f64> {
+        let mut projection = DMatrix::zeros(self.channels.len(), self.channels.len());
+        for &c in exclude.iter().filter(|&&c| c < self.n_components()) {
+            projection += self.mixing.column(c) * self.unmixing.row(c);
+        }
+        let mut matrix = DMatrix::identity(n_channels, n_channels);
+        for (i, &row) in self.channels.iter().enumerate().filter(|(_, row)| **row < n_channels) {
+            for (j, &ch) in self.channels.iter().enumerate().filter(|(_, ch)| **ch < n_channels) {
+                // In µV the operator is I - P; data units scale by the resolutions either side
+                matrix[(row, ch)] -= projection[(i, j)] * eeg_info.resolution(ch) / eeg_info.resolution(row);
+            }
+        }
+        matrix
+    }
+}
+
+pub fn remove_components<T: Sample>(ica: &Ica, exclude: &[usize], eeg_info: &EEGInfo, eeg_data: &Array2<T>) -> Array2<T> {
+    reference::apply_matrix(&ica.removal_matrix(exclude, eeg_info, eeg_data.nrows()), eeg_data)
+}
+
+pub fn remove_components_epochs(ica: &Ica, exclude: &[usize], eeg_info: &EEGInfo, epochs: &mut EpochsData) {
+    let matrix = ica.removal_matrix(exclude, eeg_info, epochs.n_channels());
+    reference::apply_matrix_epochs(&matrix, epochs);
+}
+
+// Component spectra over the `spans` of the continuous data (e.g. Markers::good_spans) and, given epochs,
+// the component time courses averaged over the good epochs
+pub fn component_properties<T: Sample>(
+    ica: &Ica,
+    params: &WelchParams,
+    eeg_info: &EEGInfo,
+    data: &[Vec<T>],
+    spans: &[(usize, usize)],
+    epochs: Option<&EpochsData>,
+) -> Result<ComponentProperties, Box<dyn std::error::Error>> {
+    let sfreq = f64::from(eeg_info.sfreq);
+    let psd = spectrum::psd_streamed(params, sfreq, ica.component_names(), spans, |start, end| ica.sources(eeg_info, data, start, end))?;
+    let evoked = epochs.and_then(|epochs| epochs::evoked_eeg(epochs).ok()).map(|evoked| {
+        // Not centred: the average is usually baseline corrected, and this is the share removal takes out
+        let window = DMatrix::from_fn(ica.channels.len(), evoked.n_times(), |i, t| {
+            let ch = ica.channels[i];
+            evoked.evoked.get([ch, t]).copied().unwrap_or(0.0) * eeg_info.resolution(ch)
+        });
+        let sources = &ica.unmixing * window;
+        EvokedData {
+            evoked: Array2::from_shape_fn(sources.shape(), |(c, t)| sources[(c, t)]),
+            ch_names: ica.component_names(),
+            ..evoked
+        }
+    });
+    Ok(ComponentProperties { psd, evoked })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A sine and a sawtooth mixed into two channels; both methods recover the mixing columns up to order,
+    // sign and scale
+    #[test]
+    fn unmix_two_sources() -> Result<(), Box<dyn std::error::Error>> {
+        let mixing = [[1.0, 0.6], [0.4, 1.0]];
+        let n_samples = 5000;
+        let sources: Vec<[f64; 2]> = (0..n_samples)
+            .map(|t| {
+                let t = t as f64;
+                [(t * 0.05).sin(), (t * 0.013).rem_euclid(1.0) * 2.0 - 1.0]
+            })
+            .collect();
+        let data: Vec<Vec<f64>> = (0..2).map(|ch| sources.iter().map(|s| mixing[ch][0] * s[0] + mixing[ch][1] * s[1]).collect()).collect();
+        let info = EEGInfo { ch_names: vec!["C3".to_owned(), "C4".to_owned()], ..Default::default() };
+        let unit = |column: [f64; 2]| {
+            let norm = column[0].hypot(column[1]);
+            [column[0] / norm, column[1] / norm]
+        };
+        let expected = [unit([mixing[0][0], mixing[1][0]]), unit([mixing[0][1], mixing[1][1]])];
+        for method in [IcaMethod::FastIca, IcaMethod::Infomax] {
+            let params = IcaParams { method, max_iter: 1000, ..Default::default() };
+            let ica = fit_continuous(&params, &info, &data, &[(0, n_samples)])?;
+            assert_eq!(ica.n_components(), 2);
+            let cosine = |component: usize, e: &[f64; 2]| {
+                let found = unit([ica.mixing[(0, component)], ica.mixing[(1, component)]]);
+                (e[0] * found[0] + e[1] * found[1]).abs()
+            };
+            // Either order of the components, each matching its own column
+            let straight = cosine(0, &expected[0]).min(cosine(1, &expected[1]));
+            let swapped = cosine(0, &expected[1]).min(cosine(1, &expected[0]));
+            assert!(straight.max(swapped) > 0.999, "{method:?}: |cos| {straight} or {swapped}");
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod plots;
 pub use app::TemplateApp;
 
 use edf_reader::model::EDFChannel;
@@ -12,6 +13,26 @@ pub mod edfio;
 pub mod signal;
 pub mod bvio;
 pub mod reference;
+pub mod epochs;
+pub mod evoked;
+pub mod baseline;
+pub mod rejection;
+pub mod positions;
+pub mod spherical;
+pub mod topomap;
+pub mod peaks;
+pub mod batch;
+pub mod groups;
+pub mod montage;
+pub mod csd;
+pub mod badchannels;
+pub mod overview;
+pub mod spectrum;
+pub mod tfr;
+pub mod ica;
+pub mod ssp;
+pub mod headmodel;
+pub mod denoise;
 
 #[derive(Debug, Default, Clone)]
 pub struct RawEEG {
@@ -24,8 +45,6 @@ pub struct RawEEG {
     pub total_duration_ms: Option<u64>,
     pub edf_data: Option<Vec<Vec<f32>>>,
     pub bv_data: Option<Vec<Vec<i16>>>,
-    pub edf_data_avg_ref: Option<Vec<Vec<f32>>>,
-    pub bv_data_avg_ref: Option<Vec<Vec<i16>>>,
 }
 
 
@@ -39,28 +58,218 @@ pub struct EEGInfo {
     pub binary_format: Option<String>,
     pub sampling_interval_in: Option<String>,
     pub sampling_interval: Option<i32>,
+    // Electrode positions from the header, see positions::Position
+    pub ch_pos: Vec<Option<[f64; 3]>>,
+    pub channel_groups: Vec<ChannelGroup>,
+    // Indices of channels left out of the average reference
+    pub bad_channels: Vec<usize>,
+    // µV per data unit of each channel, from the header
+    pub resolutions: Vec<f64>,
 }
 
+impl EEGInfo {
+    // 1.0 when the header gave no resolution, i.e. the data is taken to be in µV
+    pub fn resolution(&self, ch: usize) -> f64 {
+        self.resolutions.get(ch).copied().unwrap_or(1.0)
+    }
+}
+
+// Named region of interest; channels are labels so a group carries over between recordings
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChannelGroup {
+    pub name: String,
+    pub channels: Vec<String>,
+}
+
+// `other` keeps marker file entries of types the viewer does not use, e.g. "New Segment,,1,1,0",
+// so they are written back unchanged.
 #[derive(Debug, Default, Clone)]
 pub struct Markers {
     pub n_markers: usize,
-    pub markers: Vec<f64>
+    pub markers: Vec<f64>,
+    pub descriptions: Vec<String>,
+    pub annotations: Vec<Annotation>,
+    pub other: Vec<String>,
+}
+
+// Labelled stretch of data, onset and duration in samples. "BAD_*" annotations mark data to leave out.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Annotation {
+    pub onset: f64,
+    pub duration: f64,
+    pub description: String,
+}
+
+impl Annotation {
+    pub fn is_bad(&self) -> bool {
+        self.description.to_uppercase().starts_with("BAD")
+    }
+
+    pub fn end(&self) -> f64 {
+        self.onset + self.duration
+    }
 }
 
+impl Markers {
+    // Unique marker descriptions, e.g. "R128" or "S  1"
+    pub fn event_types(&self) -> Vec<String> {
+        let mut types = self.descriptions.clone();
+        types.sort();
+        types.dedup();
+        types
+    }
+
+    // Markers whose description is in `types`, all markers if `types` is empty
+    pub fn select_types(&self, types: &[String]) -> Self {
+        if types.is_empty() {
+            return self.clone();
+        }
+        let mut selected = Self::default();
+        for (pos, description) in self.markers.iter().zip(&self.descriptions) {
+            if types.contains(description) {
+                selected.markers.push(*pos);
+                selected.descriptions.push(description.clone());
+            }
+        }
+        selected.n_markers = selected.markers.len();
+        selected.annotations = self.annotations.clone();
+        selected
+    }
+
+    // Insert a marker keeping the markers sorted by position; returns its index
+    pub fn add_marker(&mut self, position: f64, description: &str) -> usize {
+        let idx = self.markers.partition_point(|&pos| pos <= position);
+        self.markers.insert(idx, position);
+        self.descriptions.insert(idx, description.to_owned());
+        self.n_markers = self.markers.len();
+        idx
+    }
+
+    // Move a marker to a new position; returns its new index
+    pub fn move_marker(&mut self, idx: usize, position: f64) -> usize {
+        match self.remove_marker(idx) {
+            Some(description) => self.add_marker(position, &description),
+            None => idx,
+        }
+    }
+
+    // Remove a marker, returning its description
+    pub fn remove_marker(&mut self, idx: usize) -> Option<String> {
+        if idx >= self.markers.len() {
+            return None;
+        }
+        self.markers.remove(idx);
+        let description = self.descriptions.remove(idx);
+        self.n_markers = self.markers.len();
+        Some(description)
+    }
+
+    // Whether the samples from `start` to `end` overlap a BAD_* annotation
+    pub fn overlaps_bad(&self, start: f64, end: f64) -> bool {
+        self.annotations
+            .iter()
+            .any(|annotation| annotation.is_bad() && annotation.onset < end && annotation.end() > start)
+    }
 
-#[derive(Debug)]
+    // Sample ranges start..end covered by BAD_* annotations, sorted and merged, clipped to the recording
+    pub fn bad_spans(&self, n_samples: usize) -> Vec<(usize, usize)> {
+        self.spans_where(n_samples, Annotation::is_bad)
+    }
+
+    // Sample ranges of the annotations with this description, sorted and merged like bad_spans
+    pub fn labelled_spans(&self, description: &str, n_samples: usize) -> Vec<(usize, usize)> {
+        self.spans_where(n_samples, |annotation| annotation.description == description)
+    }
+
+    fn spans_where(&self, n_samples: usize, keep: impl Fn(&Annotation) -> bool) -> Vec<(usize, usize)> {
+        let mut spans: Vec<(usize, usize)> = self
+            .annotations
+            .iter()
+            .filter(|annotation| keep(annotation))
+            .map(|annotation| {
+                let start = (annotation.onset.floor().max(0.0) as usize).min(n_samples);
+                (start, (annotation.end().ceil().max(0.0) as usize).min(n_samples))
+            })
+            .filter(|(start, end)| end > start)
+            .collect();
+        spans.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+        for (start, end) in spans {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    // The complement of `bad_spans`
+    pub fn good_spans(&self, n_samples: usize) -> Vec<(usize, usize)> {
+        let mut good = Vec::new();
+        let mut start = 0;
+        for (bad_start, bad_end) in self.bad_spans(n_samples) {
+            if bad_start > start {
+                good.push((start, bad_start));
+            }
+            start = bad_end;
+        }
+        if start < n_samples {
+            good.push((start, n_samples));
+        }
+        good
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EpochEvent {
+    pub marker_idx: usize,
+    pub sample: f64,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    OutsideRecording,
+    PeakToPeak,
+    Gradient,
+    Flat,
+    Kurtosis,
+    BadSegment,
+    User,
+    // Kept by the user despite the criteria or a BAD segment; later rejection runs leave it alone
+    KeptByUser,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DroppedEpoch {
+    pub event: EpochEvent,
+    pub reason: DropReason,
+    pub channel: Option<String>,
+}
+
+// Only the array matching the source format is filled, the other one is empty.
+// Epochs flagged in `bad` stay in the arrays until dropped, so the decision can be overridden.
+// `bad_channels` starts as a copy of the recording's flags and diverges once either is interpolated.
+#[derive(Debug, Default, Clone)]
 pub struct EpochsData {
     pub bv_epochs: Array3<i16>,
     pub edf_epochs_data: Array3<f32>,
     pub ch_names: Vec<String>,
     pub tmin: f64,
-    pub tmax: f64
+    pub tmax: f64,
+    pub sfreq: f64,
+    pub events: Vec<EpochEvent>,
+    pub bad: Vec<bool>,
+    pub drop_log: Vec<DroppedEpoch>,
+    pub bad_channels: Vec<usize>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 pub struct EvokedData {
     pub evoked: Array2<f64>,
     pub ch_names: Vec<String>,
     pub tmin: f64,
-    pub tmax: f64
+    pub tmax: f64,
+    pub sfreq: f64,
+    pub nave: usize,
 }
@@ -0,0 +1,231 @@
+use crate::groups;
+use crate::positions::{self, clean_label};
+use crate::signal::Sample;
+
+// One displayed trace: a weighted sum of channels, e.g. Fp1 - F7 is [(Fp1, 1), (F7, -1)]
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Debug)]
+pub struct Derivation {
+    pub name: String,
+    pub terms: Vec<(String, f64)>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Debug, Default)]
+pub struct Montage {
+    pub name: String,
+    pub derivations: Vec<Derivation>,
+}
+
+// Derivations resolved against the channels of a recording
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedMontage {
+    pub names: Vec<String>,
+    pub weights: Vec<Vec<(usize, f64)>>,
+    // Derivations left out because a channel is missing
+    pub skipped: Vec<String>,
+}
+
+const DOUBLE_BANANA: &[(&str, &str)] = &[
+    ("Fp1", "F7"), ("F7", "T7"), ("T7", "P7"), ("P7", "O1"),
+    ("Fp1", "F3"), ("F3", "C3"), ("C3", "P3"), ("P3", "O1"),
+    ("Fz", "Cz"), ("Cz", "Pz"),
+    ("Fp2", "F4"), ("F4", "C4"), ("C4", "P4"), ("P4", "O2"),
+    ("Fp2", "F8"), ("F8", "T8"), ("T8", "P8"), ("P8", "O2"),
+];
+
+const TRANSVERSE: &[(&str, &str)] = &[
+    ("F7", "Fp1"), ("Fp1", "Fp2"), ("Fp2", "F8"),
+    ("F7", "F3"), ("F3", "Fz"), ("Fz", "F4"), ("F4", "F8"),
+    ("T7", "C3"), ("C3", "Cz"), ("Cz", "C4"), ("C4", "T8"),
+    ("P7", "P3"), ("P3", "Pz"), ("Pz", "P4"), ("P4", "P8"),
+    ("P7", "O1"), ("O1", "O2"), ("O2", "P8"),
+];
+
+// 10-10 names and their old 10-20 equivalents
+const ALIASES: &[(&str, &str)] = &[("T7", "T3"), ("T8", "T4"), ("P7", "T5"), ("P8", "T6")];
+
+impl Derivation {
+    pub fn bipolar(a: &str, b: &str) -> Self {
+        Self { name: format!("{a}-{b}"), terms: vec![(a.to_owned(), 1.0), (b.to_owned(), -1.0)] }
+    }
+
+    // "Fp1 - F7", "Fp1-F7" or "C3 - 0.25*FC3 - 0.25*CP3"
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut terms = Vec::new();
+        let mut sign = 1.0;
+        for token in text.split_whitespace().flat_map(split_terms) {
+            match token {
+                "+" => sign = 1.0,
+                "-" => sign = -1.0,
+                _ => {
+                    let (token_sign, term) = match token.strip_prefix('-') {
+                        Some(rest) => (-1.0, rest),
+                        None => (1.0, token.strip_prefix('+').unwrap_or(token)),
+                    };
+                    let (weight, label) = match term.split_once('*') {
+                        Some((weight, label)) => (weight.parse::<f64>().map_err(|e| format!("Bad weight in '{term}': {e}"))?, label),
+                        None => (1.0, term),
+                    };
+                    terms.push((label.to_owned(), sign * token_sign * weight));
+                    sign = 1.0;
+                }
+            }
+        }
+        if terms.is_empty() {
+            return Err(format!("Empty derivation '{text}'").into());
+        }
+        Ok(Self { name: text.split_whitespace().collect::<Vec<_>>().join(" "), terms })
+    }
+}
+
+// Split "Fp1-F7" into "Fp1" and "-F7" at every sign after the start of a term, except the exponent of a weight
+fn split_terms(token: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (idx, c) in token.char_indices() {
+        if !matches!(c, '+' | '-') {
+            continue;
+        }
+        let piece = &token[start..idx];
+        let body = piece.trim_start_matches(['+', '-']);
+        let exponent = !piece.contains('*') && body.strip_suffix(['e', 'E']).is_some_and(|mantissa| mantissa.parse::<f64>().is_ok());
+        if !body.is_empty() && !body.ends_with('*') && !exponent {
+            pieces.push(piece);
+            start = idx;
+        }
+    }
+    pieces.push(&token[start..]);
+    pieces
+}
+
+impl Montage {
+    fn from_pairs(name: &str, pairs: &[(&str, &str)]) -> Self {
+        Self { name: name.to_owned(), derivations: pairs.iter().map(|(a, b)| Derivation::bipolar(a, b)).collect() }
+    }
+
+    pub fn double_banana() -> Self {
+        Self::from_pairs("Double banana", DOUBLE_BANANA)
+    }
+
+    pub fn transverse() -> Self {
+        Self::from_pairs("Transverse", TRANSVERSE)
+    }
+
+    // Hjorth Laplacian: every positioned channel minus the mean of its `n_neighbours` closest channels
+    pub fn laplacian(ch_names: &[String], positions: &[Option<positions::Position>], n_neighbours: usize) -> Self {
+        let placed: Vec<(usize, positions::Position)> = positions
+            .iter()
+            .enumerate()
+            .filter_map(|(ch, pos)| pos.map(|pos| (ch, pos)))
+            .collect();
+        let derivations = placed
+            .iter()
+            .map(|(ch, pos)| {
+                let mut others: Vec<&(usize, positions::Position)> = placed.iter().filter(|(other, _)| other != ch).collect();
+                others.sort_by(|a, b| positions::distance(pos, &a.1).total_cmp(&positions::distance(pos, &b.1)));
+                let neighbours: Vec<usize> = others.iter().take(n_neighbours).map(|(other, _)| *other).collect();
+                let label = |idx: usize| clean_label(ch_names.get(idx).map_or("", String::as_str));
+                let mut terms = vec![(label(*ch), 1.0)];
+                let weight = -1.0 / neighbours.len().max(1) as f64;
+                terms.extend(neighbours.iter().map(|&other| (label(other), weight)));
+                Derivation { name: format!("{} (Lap)", label(*ch)), terms }
+            })
+            .collect();
+        Self { name: "Laplacian".to_owned(), derivations }
+    }
+
+    // One derivation per line, see Derivation::parse
+    pub fn parse(name: &str, text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let derivations = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(Derivation::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { name: name.to_owned(), derivations })
+    }
+
+    pub fn to_text(&self) -> String {
+        self.derivations
+            .iter()
+            .map(|derivation| {
+                derivation
+                    .terms
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, (label, weight))| {
+                        let sign = if *weight < 0.0 { "- " } else if idx > 0 { "+ " } else { "" };
+                        if (weight.abs() - 1.0).abs() < 1e-12 {
+                            format!("{sign}{label}")
+                        } else {
+                            format!("{sign}{}*{label}", weight.abs())
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn resolve(&self, ch_names: &[String]) -> ResolvedMontage {
+        let find = |label: &str| {
+            let alias = ALIASES
+                .iter()
+                .find_map(|&(new, old)| if new.eq_ignore_ascii_case(label) { Some(old) } else if old.eq_ignore_ascii_case(label) { Some(new) } else { None });
+            std::iter::once(label)
+                .chain(alias)
+                .find_map(|name| groups::channel_indices(ch_names, &[name.to_owned()]).first().copied())
+        };
+        let mut resolved = ResolvedMontage::default();
+        for derivation in &self.derivations {
+            let weights: Option<Vec<(usize, f64)>> = derivation
+                .terms
+                .iter()
+                .map(|(label, weight)| find(label).map(|ch| (ch, *weight)))
+                .collect();
+            match weights {
+                Some(weights) => {
+                    resolved.names.push(derivation.name.clone());
+                    resolved.weights.push(weights);
+                }
+                None => resolved.skipped.push(derivation.name.clone()),
+            }
+        }
+        resolved
+    }
+}
+
+impl ResolvedMontage {
+    // Derived traces over samples start..end of channel-major data
+    pub fn apply_window<T: Sample>(&self, data: &[Vec<T>], start: usize, end: usize) -> Vec<Vec<f64>> {
+        self.weights
+            .iter()
+            .map(|weights| {
+                if weights.iter().any(|&(ch, _)| ch >= data.len()) {
+                    return Vec::new();
+                }
+                let end = weights.iter().map(|&(ch, _)| data[ch].len()).fold(end, usize::min);
+                (start.min(end)..end)
+                    .map(|t| weights.iter().map(|&(ch, weight)| weight * data[ch][t].into()).sum())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_with_and_without_spaces() -> Result<(), Box<dyn std::error::Error>> {
+        let expected = vec![("Fp1".to_owned(), 1.0), ("F7".to_owned(), -1.0)];
+        assert_eq!(Derivation::parse("Fp1 - F7")?.terms, expected);
+        assert_eq!(Derivation::parse("Fp1-F7")?.terms, expected);
+        let laplacian = vec![("C3".to_owned(), 1.0), ("FC3".to_owned(), -0.25), ("CP3".to_owned(), -0.25)];
+        assert_eq!(Derivation::parse("C3 - 0.25*FC3 - 0.25*CP3")?.terms, laplacian);
+        assert_eq!(Derivation::parse("C3-0.25*FC3-0.25*CP3")?.terms, laplacian);
+        assert_eq!(Derivation::parse("Cz+1e-1*Pz")?.terms, vec![("Cz".to_owned(), 1.0), ("Pz".to_owned(), 0.1)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use rayon::prelude::*;
+
+use crate::EEGInfo;
+use crate::signal::Sample;
+
+// Trace of the recording overview
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OverviewSignal {
+    // Range of one channel
+    Channel(usize),
+    // Global field power: standard deviation across the good channels
+    Gfp,
+}
+
+// Whole-recording summary in fixed bins, values in µV
+#[derive(Debug, Clone, Default)]
+pub struct Overview {
+    pub bin_seconds: f64,
+    pub min: Vec<f64>,
+    pub max: Vec<f64>,
+}
+
+// Samples per bin used for GFP, which otherwise costs a pass over every channel of the recording
+const MAX_GFP_SAMPLES_PER_BIN: usize = 500;
+
+fn gfp<T: Sample>(eeg_info: &EEGInfo, data: &[Vec<T>], channels: &[usize], t: usize) -> f64 {
+    let values: Vec<f64> = channels.iter().map(|&ch| data[ch][t].into() * eeg_info.resolution(ch)).collect();
+    let n = values.len().max(1) as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt()
+}
+
+// Minimum and maximum of the signal in `n_bins` equal stretches of the recording
+pub fn compute_overview<T: Sample>(signal: OverviewSignal, eeg_info: &EEGInfo, data: &[Vec<T>], n_bins: usize) -> Overview {
+    let n_samples = data.iter().map(Vec::len).min().unwrap_or(0);
+    let sfreq = f64::from(eeg_info.sfreq.max(1));
+    if n_samples == 0 || n_bins == 0 {
+        return Overview::default();
+    }
+    let bin_len = n_samples.div_ceil(n_bins);
+    let good: Vec<usize> = (0..data.len()).filter(|ch| !eeg_info.bad_channels.contains(ch)).collect();
+    let (min, max) = (0..n_samples.div_ceil(bin_len))
+        .into_par_iter()
+        .map(|bin| {
+            let (start, end) = (bin * bin_len, ((bin + 1) * bin_len).min(n_samples));
+            let fold = |(lo, hi): (f64, f64), v: f64| (lo.min(v), hi.max(v));
+            match signal {
+                OverviewSignal::Channel(ch) if ch < data.len() => data[ch][start..end]
+                    .iter()
+                    .map(|&x| x.into() * eeg_info.resolution(ch))
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), fold),
+                OverviewSignal::Channel(_) => (0.0, 0.0),
+                OverviewSignal::Gfp => (start..end)
+                    .step_by((end - start).div_ceil(MAX_GFP_SAMPLES_PER_BIN).max(1))
+                    .map(|t| gfp(eeg_info, data, &good, t))
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), fold),
+            }
+        })
+        .unzip();
+    Overview { bin_seconds: bin_len as f64 / sfreq, min, max }
+}
+
+// Marker counts in bins of `bin_seconds`
+pub fn marker_density(positions: &[f64], sfreq: f64, duration: f64, bin_seconds: f64) -> Vec<usize> {
+    let n_bins = (duration / bin_seconds).ceil().max(1.0) as usize;
+    let mut counts = vec![0; n_bins];
+    for &pos in positions {
+        let bin = (pos / sfreq / bin_seconds).max(0.0) as usize;
+        counts[bin.min(n_bins - 1)] += 1;
+    }
+    counts
+}
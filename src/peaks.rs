@@ -0,0 +1,150 @@
+use std::fmt::Write as _;
+
+use crate::{EEGInfo, EvokedData};
+use crate::groups;
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Polarity {
+    Negative,
+    Positive,
+}
+
+// A TMS-evoked component searched for between tmin and tmax (s) in the mean of the ROI channels.
+// An empty ROI uses every good channel.
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Debug)]
+pub struct TepComponent {
+    pub name: String,
+    pub tmin: f64,
+    pub tmax: f64,
+    pub polarity: Polarity,
+    pub roi: Vec<String>,
+}
+
+impl TepComponent {
+    pub fn new(name: &str, tmin: f64, tmax: f64, polarity: Polarity) -> Self {
+        Self { name: name.to_owned(), tmin, tmax, polarity, roi: Vec::new() }
+    }
+}
+
+// Canonical TEP windows
+pub fn default_components() -> Vec<TepComponent> {
+    vec![
+        TepComponent::new("N15", 0.010, 0.020, Polarity::Negative),
+        TepComponent::new("P30", 0.025, 0.040, Polarity::Positive),
+        TepComponent::new("N45", 0.040, 0.055, Polarity::Negative),
+        TepComponent::new("P60", 0.055, 0.080, Polarity::Positive),
+        TepComponent::new("N100", 0.085, 0.140, Polarity::Negative),
+        TepComponent::new("P180", 0.150, 0.250, Polarity::Positive),
+    ]
+}
+
+// Latencies in s, amplitudes in µV; NaN when the window or ROI is empty or the window lies outside the epoch.
+// `local_peak` is false when no local extremum of the right polarity was found and the window extreme is reported.
+#[derive(Debug, Clone)]
+pub struct PeakResult {
+    pub subject: String,
+    pub component: String,
+    pub n_channels: usize,
+    pub peak_latency: f64,
+    pub peak_amplitude: f64,
+    pub mean_amplitude: f64,
+    pub local_peak: bool,
+}
+
+// Channel indices of the evoked response matching the ROI labels, without the bad channels
+pub fn roi_channels(evoked: &EvokedData, roi: &[String], bad_channels: &[usize]) -> Vec<usize> {
+    let n_channels = evoked.evoked.nrows();
+    let channels = if roi.is_empty() { (0..n_channels).collect() } else { groups::channel_indices(&evoked.ch_names, roi) };
+    channels
+        .into_iter()
+        .filter(|ch| *ch < n_channels && !bad_channels.contains(ch))
+        .collect()
+}
+
+// Mean waveform over the given channels in µV
+pub fn roi_waveform(evoked: &EvokedData, eeg_info: &EEGInfo, channels: &[usize]) -> Vec<f64> {
+    if channels.is_empty() {
+        return Vec::new();
+    }
+    (0..evoked.n_times())
+        .map(|t| channels.iter().map(|&ch| evoked.evoked[[ch, t]] * eeg_info.resolution(ch)).sum::<f64>() / channels.len() as f64)
+        .collect()
+}
+
+// The ROI leaves out the bad channels of `eeg_info`
+pub fn detect_component(evoked: &EvokedData, component: &TepComponent, eeg_info: &EEGInfo, subject: &str) -> PeakResult {
+    let channels = roi_channels(evoked, &component.roi, &eeg_info.bad_channels);
+    let waveform = roi_waveform(evoked, eeg_info, &channels);
+    let mut result = PeakResult {
+        subject: subject.to_owned(),
+        component: component.name.clone(),
+        n_channels: channels.len(),
+        peak_latency: f64::NAN,
+        peak_amplitude: f64::NAN,
+        mean_amplitude: f64::NAN,
+        local_peak: false,
+    };
+    // `time_to_index` clamps, so a window outside the epoch would report the edge sample
+    if waveform.is_empty() || component.tmax <= component.tmin || component.tmax < evoked.tmin || component.tmin > evoked.tmax {
+        return result;
+    }
+    let start = evoked.time_to_index(component.tmin);
+    let end = evoked.time_to_index(component.tmax);
+    let window = &waveform[start..=end];
+    result.mean_amplitude = window.iter().sum::<f64>() / window.len() as f64;
+
+    // Sign flip so the search is always for a maximum
+    let sign = match component.polarity {
+        Polarity::Positive => 1.0,
+        Polarity::Negative => -1.0,
+    };
+    let is_local = |t: usize| {
+        t > 0 && t + 1 < waveform.len() && sign * waveform[t] > sign * waveform[t - 1] && sign * waveform[t] >= sign * waveform[t + 1]
+    };
+    let best = |candidates: &mut dyn Iterator<Item = usize>| {
+        candidates.reduce(|best, t| if sign * waveform[t] > sign * waveform[best] { t } else { best })
+    };
+    let peak = match best(&mut (start..=end).filter(|&t| is_local(t))) {
+        Some(t) => {
+            result.local_peak = true;
+            Some(t)
+        }
+        None => best(&mut (start..=end)),
+    };
+    if let Some(t) = peak {
+        result.peak_latency = evoked.tmin + t as f64 / evoked.sfreq;
+        result.peak_amplitude = waveform[t];
+    }
+    result
+}
+
+pub fn detect_components(evoked: &EvokedData, components: &[TepComponent], eeg_info: &EEGInfo, subject: &str) -> Vec<PeakResult> {
+    components
+        .iter()
+        .map(|component| detect_component(evoked, component, eeg_info, subject))
+        .collect()
+}
+
+pub fn peaks_to_csv(results: &[PeakResult]) -> String {
+    let mut csv = String::from("subject,component,n_channels,peak_latency_ms,peak_amplitude_uv,mean_amplitude_uv,local_peak\n");
+    for r in results {
+        writeln!(
+            csv,
+            "{},{},{},{:.1},{:.3},{:.3},{}",
+            r.subject,
+            r.component,
+            r.n_channels,
+            r.peak_latency * 1000.0,
+            r.peak_amplitude,
+            r.mean_amplitude,
+            r.local_peak
+        )
+        .ok();
+    }
+    csv
+}
+
+pub fn write_peaks_csv(path: &str, results: &[PeakResult]) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, peaks_to_csv(results))?;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use egui::{Color32, ColorImage};
+use ndarray::Array2;
+
+// Blue - white - red, symmetric around zero
+pub fn diverging_color(value: f64, limit: f64) -> Color32 {
+    let t = if limit > 0.0 { (value / limit).clamp(-1.0, 1.0) } else { 0.0 };
+    let fade = |x: f64| (255.0 * (1.0 - x.abs())).round() as u8;
+    if t >= 0.0 {
+        Color32::from_rgb(255, fade(t), fade(t))
+    } else {
+        Color32::from_rgb(fade(t), fade(t), 255)
+    }
+}
+
+// Dark blue - green - yellow between min and max
+pub fn sequential_color(value: f64, min: f64, max: f64) -> Color32 {
+    const STOPS: [(u8, u8, u8); 5] = [(68, 1, 84), (59, 82, 139), (33, 145, 140), (94, 201, 98), (253, 231, 37)];
+    let t = if max > min { ((value - min) / (max - min)).clamp(0.0, 1.0) } else { 0.0 };
+    let x = t * (STOPS.len() - 1) as f64;
+    let lo = (x.floor() as usize).min(STOPS.len() - 2);
+    let frac = x - lo as f64;
+    let mix = |a: u8, b: u8| (f64::from(a) + (f64::from(b) - f64::from(a)) * frac).round() as u8;
+    let (a, b) = (STOPS[lo], STOPS[lo + 1]);
+    Color32::from_rgb(mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+}
+
+// Largest absolute value below the given quantile, robust colour limit for diverging maps
+pub fn robust_abs_limit(values: impl Iterator<Item = f64>, quantile: f64) -> f64 {
+    let mut abs: Vec<f64> = values.filter(|v| v.is_finite()).map(f64::abs).collect();
+    if abs.is_empty() {
+        return 1.0;
+    }
+    abs.sort_by(f64::total_cmp);
+    let idx = ((abs.len() - 1) as f64 * quantile.clamp(0.0, 1.0)).round() as usize;
+    abs[idx].max(f64::EPSILON)
+}
+
+// Rows of `values` become image rows from top to bottom
+pub fn heatmap_image(values: &Array2<f64>, color: impl Fn(f64) -> Color32) -> ColorImage {
+    let pixels = values.iter().map(|&v| color(v)).collect();
+    ColorImage::new([values.ncols(), values.nrows()], pixels)
+}
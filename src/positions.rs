@@ -0,0 +1,80 @@
+use std::f64::consts::FRAC_PI_2;
+
+use crate::EEGInfo;
+
+// Electrode positions on the unit sphere: x towards the right ear, y towards the nose, z up
+pub type Position = [f64; 3];
+
+// Idealised 10-10 positions in the spherical (theta, phi) degrees used by BrainVision .vhdr files.
+// theta is the angle from Cz, negative on the left; phi is measured from the T7-T8 axis.
+const STANDARD_1010: &[(&str, f64, f64)] = &[
+    ("Fp1", -90.0, -72.0), ("Fpz", 90.0, 90.0), ("Fp2", 90.0, 72.0),
+    ("AF7", -90.0, -54.0), ("AF3", -74.0, -68.0), ("AFz", 67.0, 90.0), ("AF4", 74.0, 68.0), ("AF8", 90.0, 54.0),
+    ("F7", -90.0, -36.0), ("F5", -74.0, -41.0), ("F3", -60.0, -51.0), ("F1", -49.0, -68.0), ("Fz", 45.0, 90.0),
+    ("F2", 49.0, 68.0), ("F4", 60.0, 51.0), ("F6", 74.0, 41.0), ("F8", 90.0, 36.0),
+    ("FT9", -113.0, -18.0), ("FT7", -90.0, -18.0), ("FC5", -69.0, -21.0), ("FC3", -49.0, -29.0), ("FC1", -31.0, -46.0),
+    ("FCz", 23.0, 90.0), ("FC2", 31.0, 46.0), ("FC4", 49.0, 29.0), ("FC6", 69.0, 21.0), ("FT8", 90.0, 18.0), ("FT10", 113.0, 18.0),
+    ("T7", -90.0, 0.0), ("C5", -68.0, 0.0), ("C3", -45.0, 0.0), ("C1", -23.0, 0.0), ("Cz", 0.0, 0.0),
+    ("C2", 23.0, 0.0), ("C4", 45.0, 0.0), ("C6", 68.0, 0.0), ("T8", 90.0, 0.0),
+    ("TP9", -113.0, 18.0), ("TP7", -90.0, 18.0), ("CP5", -69.0, 21.0), ("CP3", -49.0, 29.0), ("CP1", -31.0, 46.0),
+    ("CPz", 23.0, -90.0), ("CP2", 31.0, -46.0), ("CP4", 49.0, -29.0), ("CP6", 69.0, -21.0), ("TP8", 90.0, -18.0), ("TP10", 113.0, -18.0),
+    ("P7", -90.0, 36.0), ("P5", -74.0, 41.0), ("P3", -60.0, 51.0), ("P1", -49.0, 68.0), ("Pz", 45.0, -90.0),
+    ("P2", 49.0, -68.0), ("P4", 60.0, -51.0), ("P6", 74.0, -41.0), ("P8", 90.0, -36.0),
+    ("PO7", -90.0, 54.0), ("PO3", -74.0, 68.0), ("POz", 67.0, -90.0), ("PO4", 74.0, -68.0), ("PO8", 90.0, -54.0),
+    ("O1", -90.0, 72.0), ("Oz", 90.0, -90.0), ("O2", 90.0, -72.0), ("Iz", 113.0, -90.0),
+    // Old 10-20 names
+    ("T3", -90.0, 0.0), ("T4", 90.0, 0.0), ("T5", -90.0, 36.0), ("T6", 90.0, -36.0),
+];
+
+pub fn spherical_to_cartesian(theta_deg: f64, phi_deg: f64) -> Position {
+    let (theta, phi) = (theta_deg.to_radians(), phi_deg.to_radians());
+    [theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()]
+}
+
+// "1=Fp1" (BrainVision) or "EEG Fp1-Ref" (EDF) -> "Fp1"
+pub fn clean_label(name: &str) -> String {
+    let name = name.rsplit('=').next().unwrap_or(name).trim();
+    let name = name.strip_prefix("EEG ").unwrap_or(name);
+    name.split('-').next().unwrap_or(name).trim().to_owned()
+}
+
+pub fn standard_position(name: &str) -> Option<Position> {
+    let label = clean_label(name);
+    STANDARD_1010
+        .iter()
+        .find(|(standard, _, _)| standard.eq_ignore_ascii_case(&label))
+        .map(|&(_, theta, phi)| spherical_to_cartesian(theta, phi))
+}
+
+// Position of each of the first `n_channels` channels: from the header if given, else the 10-10 table
+pub fn channel_positions(eeg_info: &EEGInfo, n_channels: usize) -> Vec<Option<Position>> {
+    (0..n_channels)
+        .map(|ch| {
+            eeg_info.ch_pos.get(ch).copied().flatten().or_else(|| {
+                eeg_info.ch_names.get(ch).and_then(|name| standard_position(name))
+            })
+        })
+        .collect()
+}
+
+// Straight-line distance between two electrodes
+pub fn distance(a: &Position, b: &Position) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum::<f64>().sqrt()
+}
+
+// Azimuthal equidistant projection seen from above; the equator (T7, Fpz, T8, Oz) lands on the unit circle
+pub fn project(pos: &Position) -> [f64; 2] {
+    let norm = pos.iter().map(|v| v * v).sum::<f64>().sqrt().max(f64::EPSILON);
+    let polar = (pos[2] / norm).clamp(-1.0, 1.0).acos();
+    let azimuth = pos[1].atan2(pos[0]);
+    let radius = polar / FRAC_PI_2;
+    [radius * azimuth.cos(), radius * azimuth.sin()]
+}
+
+// Inverse of `project`
+pub fn unproject(point: [f64; 2]) -> Position {
+    let radius = point[0].hypot(point[1]);
+    let polar = radius * FRAC_PI_2;
+    let azimuth = point[1].atan2(point[0]);
+    [polar.sin() * azimuth.cos(), polar.sin() * azimuth.sin(), polar.cos()]
+}
@@ -1,47 +1,163 @@
+use std::f64::consts::PI;
 
+use nalgebra::{DMatrix, DVector};
+use ndarray::{s, Array2};
 
-pub fn compute_average_reference_f32(data: &Vec<Vec<f32>>) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
-    if data.is_empty() {
-        return Ok(Vec::new());
-    }
-    let num_channels = data.len();
-    let num_time_points = data[0].len();
-    let mut average_ref = vec![vec![0.0; num_time_points]; num_channels];
-
-    for t in 0..num_time_points {
-        let mut average = 0.0;
-        for ch in 0..num_channels {
-            average += data[ch][t];
+use crate::{EEGInfo, EpochsData, groups, positions};
+use crate::signal::Sample;
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Debug)]
+pub enum Reference {
+    // Average of all channels that are not marked bad
+    Average,
+    // Mean of the listed channels: a single channel, linked mastoids or any subset
+    Channels(Vec<String>),
+    // Reference electrode standardization technique (Yao, 2001) on a homogeneous spherical head
+    Rest,
+}
+
+// channels x channels matrix taking the data as recorded to the new reference
+pub fn reference_matrix(reference: &Reference, eeg_info: &EEGInfo, n_channels: usize) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    let good: Vec<usize> = (0..n_channels).filter(|ch| !eeg_info.bad_channels.contains(ch)).collect();
+    match reference {
+        Reference::Average => {
+            if good.is_empty() {
+                return Err("No good channels to average".into());
+            }
+            Ok(subtract_mean_of(n_channels, &good))
+        }
+        Reference::Channels(labels) => {
+            let ch_names: Vec<String> = eeg_info.ch_names.iter().take(n_channels).cloned().collect();
+            let channels = groups::channel_indices(&ch_names, labels);
+            if channels.is_empty() {
+                return Err(format!("Reference channels {} not found", labels.join(", ")).into());
+            }
+            Ok(subtract_mean_of(n_channels, &channels))
         }
-        average /= num_channels as f32;
+        Reference::Rest => rest_matrix(eeg_info, n_channels, &good),
+    }
+}
 
-        for ch in 0..num_channels {
-            average_ref[ch][t] = data[ch][t] - average;
+// I - 1 w' with w the mean over `channels`
+fn subtract_mean_of(n_channels: usize, channels: &[usize]) -> DMatrix<f64> {
+    let mut matrix = DMatrix::identity(n_channels, n_channels);
+    let weight = 1.0 / channels.len() as f64;
+    for row in 0..n_channels {
+        for &ch in channels {
+            matrix[(row, ch)] -= weight;
         }
     }
+    matrix
+}
 
-    Ok(average_ref)
+// Surface potential of a current dipole in a homogeneous sphere (Frank, 1952), unit conductivity
+fn sphere_potential(electrode: &[f64; 3], source: &[f64; 3], moment: &[f64; 3]) -> f64 {
+    let dot = |a: &[f64; 3], b: &[f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let d = [electrode[0] - source[0], electrode[1] - source[1], electrode[2] - source[2]];
+    let (d_norm, r_norm) = (dot(&d, &d).sqrt(), dot(electrode, electrode).sqrt());
+    let denominator = r_norm * d_norm + dot(electrode, &d);
+    let field: Vec<f64> = (0..3)
+        .map(|i| 2.0 * d[i] / d_norm.powi(3) + (electrode[i] * d_norm + r_norm * d[i]) / (r_norm * d_norm * denominator))
+        .collect();
+    (0..3).map(|i| moment[i] * field[i]).sum::<f64>() / (4.0 * PI)
 }
 
-pub fn compute_average_reference_i16(data: &Vec<Vec<i16>>) -> Result<Vec<Vec<i16>>, Box<dyn std::error::Error>> {
-    if data.is_empty() {
-        return Ok(Vec::new());
+// Roughly uniform points on a sphere of the given radius
+fn fibonacci_sphere(n_points: usize, radius: f64) -> Vec<[f64; 3]> {
+    let golden = PI * (3.0 - 5.0_f64.sqrt());
+    (0..n_points)
+        .map(|i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / n_points as f64;
+            let ring = (1.0 - z * z).sqrt();
+            let angle = golden * i as f64;
+            [radius * ring * angle.cos(), radius * ring * angle.sin(), radius * z]
+        })
+        .collect()
+}
+
+// REST: V_rest = G (H G)^+ H V for the good channels, with G the lead field of a layer of equivalent dipoles
+// and H the average reference. Bad channels are averaged referenced and shifted by the same REST offset.
+fn rest_matrix(eeg_info: &EEGInfo, n_channels: usize, good: &[usize]) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    let all_positions = positions::channel_positions(eeg_info, n_channels);
+    let electrodes: Vec<[f64; 3]> = good
+        .iter()
+        .map(|&ch| all_positions[ch].ok_or_else(|| format!("REST needs a position for channel {}", ch + 1)))
+        .collect::<Result<_, _>>()?;
+    let k = electrodes.len();
+    if k < 3 {
+        return Err("REST needs at least three good channels".into());
     }
-    let num_channels = data.len();
-    let num_time_points = data[0].len();
-    let mut average_ref = vec![vec![0; num_time_points]; num_channels];
-
-    for t in 0..num_time_points {
-        let mut average = 0.0;
-        for ch in 0..num_channels {
-            average += data[ch][t] as f64;
-        }
-        average /= num_channels as f64;
 
-        for ch in 0..num_channels {
-            average_ref[ch][t] = (data[ch][t] as f64 - average).round() as i16;
+    let moments = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    let sources = fibonacci_sphere(1000, 0.85);
+    let lead_field = DMatrix::from_fn(k, sources.len() * 3, |e, s| {
+        sphere_potential(&electrodes[e], &sources[s / 3], &moments[s % 3])
+    });
+    let average = subtract_mean_of(k, &(0..k).collect::<Vec<usize>>());
+    let svd = (&average * &lead_field).svd(true, true);
+    let tolerance = svd.singular_values.max() * 1e-8;
+    let rest = &lead_field * svd.pseudo_inverse(tolerance)? * &average;
+
+    let offset: Vec<f64> = (0..k).map(|j| rest.column(j).mean()).collect();
+    let mut matrix = DMatrix::identity(n_channels, n_channels);
+    for row in 0..n_channels {
+        match good.iter().position(|&ch| ch == row) {
+            Some(i) => {
+                for (j, &ch) in good.iter().enumerate() {
+                    matrix[(row, ch)] = rest[(i, j)];
+                }
+            }
+            None => {
+                for (j, &ch) in good.iter().enumerate() {
+                    matrix[(row, ch)] += offset[j] - 1.0 / k as f64;
+                }
+            }
         }
     }
+    Ok(matrix)
+}
+
+pub fn rereference<T: Sample>(reference: &Reference, eeg_info: &EEGInfo, eeg_data: &Array2<T>) -> Result<Array2<T>, Box<dyn std::error::Error>> {
+    let matrix = reference_matrix(reference, eeg_info, eeg_data.nrows())?;
+    Ok(apply_matrix(&matrix, eeg_data))
+}
 
-    Ok(average_ref)
+// Multiply every sample vector by `matrix`, in blocks so the f64 copy stays small
+pub fn apply_matrix<T: Sample>(matrix: &DMatrix<f64>, eeg_data: &Array2<T>) -> Array2<T> {
+    let (n_out, n_samples) = (matrix.nrows(), eeg_data.ncols());
+    let operator = Array2::from_shape_fn((n_out, matrix.ncols()), |(i, j)| matrix[(i, j)]);
+    let mut output = Array2::from_elem((n_out, n_samples), T::default());
+    let block = 10_000;
+    for start in (0..n_samples).step_by(block) {
+        let end = (start + block).min(n_samples);
+        let chunk = eeg_data.slice(s![.., start..end]).mapv(|sample| sample.into());
+        let result = operator.dot(&chunk);
+        output.slice_mut(s![.., start..end]).zip_mut_with(&result, |out, &value| *out = T::from_f64(value));
+    }
+    output
+}
+
+// Multiply the channel vector at every sample of every epoch by `matrix`
+pub fn apply_matrix_epochs(matrix: &DMatrix<f64>, epochs: &mut EpochsData) {
+    epochs.map_channel_vectors(|samples| {
+        let transformed = matrix * DVector::from_column_slice(samples);
+        samples.copy_from_slice(transformed.as_slice());
+    });
+}
+
+// Append the reference electrode as a flat channel, so re-referencing recovers its signal
+pub fn add_flat_channel<T: Sample>(name: &str, eeg_info: &mut EEGInfo, data: &mut Vec<Vec<T>>) {
+    let n_samples = data.first().map_or(0, Vec::len);
+    let position = data.len().min(eeg_info.ch_names.len());
+    eeg_info.ch_names.insert(position, name.to_owned());
+    if eeg_info.ch_pos.len() >= position {
+        eeg_info.ch_pos.insert(position, None);
+    }
+    // In the unit of the recorded channels; for EDF this lands before the annotations signal's entry
+    if !eeg_info.resolutions.is_empty() && eeg_info.resolutions.len() >= position {
+        let resolution = position.checked_sub(1).map_or(1.0, |ch| eeg_info.resolution(ch));
+        eeg_info.resolutions.insert(position, resolution);
+    }
+    eeg_info.num_ch += 1;
+    data.push(vec![T::default(); n_samples]);
 }
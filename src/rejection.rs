@@ -0,0 +1,250 @@
+use ndarray::{Array2, ArrayView1, Axis};
+
+use crate::{EEGInfo, EpochsData, Markers, DroppedEpoch, DropReason};
+
+// Per-channel thresholds in µV (gradient per sample); kurtosis is a z-score across epochs. None disables a criterion.
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug, Default)]
+pub struct RejectCriteria {
+    pub peak_to_peak: Option<f64>,
+    pub gradient: Option<f64>,
+    pub flat: Option<f64>,
+    pub kurtosis: Option<f64>,
+}
+
+fn kurtosis(trace: ArrayView1<'_, f64>) -> f64 {
+    let n = trace.len() as f64;
+    if n < 4.0 {
+        return 0.0;
+    }
+    let mean = trace.sum() / n;
+    let var = trace.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+    if var <= f64::EPSILON {
+        return 0.0;
+    }
+    trace.iter().map(|x| (x - mean).powi(4)).sum::<f64>() / n / var.powi(2) - 3.0
+}
+
+fn channel_name(epochs: &EpochsData, ch_idx: usize) -> String {
+    epochs
+        .ch_names
+        .get(ch_idx)
+        .cloned()
+        .unwrap_or_else(|| format!("Ch{}", ch_idx + 1))
+}
+
+fn kept_by_user(epochs: &EpochsData, marker_idx: usize) -> bool {
+    epochs.drop_log.iter().any(|d| d.reason == DropReason::KeptByUser && d.event.marker_idx == marker_idx)
+}
+
+// Flag epochs failing any criterion on a good channel as bad and log every failing channel and reason.
+// Earlier criterion decisions are replaced; user marks and overrides, bad segment and edge drops are kept.
+// Returns the number of bad epochs.
+pub fn reject_epochs(criteria: &RejectCriteria, eeg_info: &EEGInfo, epochs: &mut EpochsData) -> usize {
+    epochs.drop_log.retain(|d| {
+        matches!(d.reason, DropReason::OutsideRecording | DropReason::BadSegment | DropReason::User | DropReason::KeptByUser)
+    });
+    let user_marked: Vec<usize> = epochs
+        .drop_log
+        .iter()
+        .filter(|d| matches!(d.reason, DropReason::BadSegment | DropReason::User))
+        .map(|d| d.event.marker_idx)
+        .collect();
+    epochs.bad = epochs
+        .events
+        .iter()
+        .map(|event| user_marked.contains(&event.marker_idx))
+        .collect();
+
+    let data = epochs.data_f64();
+    let (n_epochs, n_channels) = (data.len_of(Axis(0)), data.len_of(Axis(1)));
+    let mut kurtoses = Array2::<f64>::zeros((n_epochs, n_channels));
+    let mut failures = Vec::new();
+    for (epoch_idx, epoch) in data.outer_iter().enumerate() {
+        for (ch_idx, trace) in epoch.outer_iter().enumerate() {
+            if eeg_info.bad_channels.contains(&ch_idx) {
+                continue;
+            }
+            let resolution = eeg_info.resolution(ch_idx);
+            let (min, max) = trace
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &x| (min.min(x), max.max(x)));
+            let peak_to_peak = (max - min) * resolution;
+            let gradient = trace
+                .iter()
+                .zip(trace.iter().skip(1))
+                .map(|(a, b)| (b - a).abs())
+                .fold(0.0, f64::max)
+                * resolution;
+
+            if criteria.peak_to_peak.is_some_and(|limit| peak_to_peak > limit) {
+                failures.push((epoch_idx, ch_idx, DropReason::PeakToPeak));
+            }
+            if criteria.gradient.is_some_and(|limit| gradient > limit) {
+                failures.push((epoch_idx, ch_idx, DropReason::Gradient));
+            }
+            if criteria.flat.is_some_and(|limit| peak_to_peak < limit) {
+                failures.push((epoch_idx, ch_idx, DropReason::Flat));
+            }
+            kurtoses[[epoch_idx, ch_idx]] = kurtosis(trace);
+        }
+    }
+
+    if let Some(limit) = criteria.kurtosis {
+        for (ch_idx, values) in kurtoses.axis_iter(Axis(1)).enumerate() {
+            if eeg_info.bad_channels.contains(&ch_idx) {
+                continue;
+            }
+            let mean = values.mean().unwrap_or(0.0);
+            let std = values.std(0.0);
+            if std <= f64::EPSILON {
+                continue;
+            }
+            for (epoch_idx, value) in values.iter().enumerate() {
+                if ((value - mean) / std).abs() > limit {
+                    failures.push((epoch_idx, ch_idx, DropReason::Kurtosis));
+                }
+            }
+        }
+    }
+
+    for (epoch_idx, ch_idx, reason) in failures {
+        if kept_by_user(epochs, epochs.events[epoch_idx].marker_idx) {
+            continue;
+        }
+        epochs.bad[epoch_idx] = true;
+        epochs.drop_log.push(DroppedEpoch {
+            event: epochs.events[epoch_idx].clone(),
+            reason,
+            channel: Some(channel_name(epochs, ch_idx)),
+        });
+    }
+    epochs.bad.iter().filter(|&&bad| bad).count()
+}
+
+// Flag epochs overlapping a BAD_* annotation as bad. Returns the number flagged.
+pub fn reject_by_annotation(epochs: &mut EpochsData, markers: &Markers) -> usize {
+    let (pre, post) = (epochs.tmin * epochs.sfreq, epochs.tmax * epochs.sfreq);
+    let mut n_flagged = 0;
+    for epoch_idx in 0..epochs.n_epochs() {
+        let event = epochs.events[epoch_idx].clone();
+        if !markers.overlaps_bad(event.sample + pre, event.sample + post) || kept_by_user(epochs, event.marker_idx) {
+            continue;
+        }
+        epochs.bad[epoch_idx] = true;
+        if !epochs.drop_log.iter().any(|d| d.reason == DropReason::BadSegment && d.event.marker_idx == event.marker_idx) {
+            epochs.drop_log.push(DroppedEpoch { event, reason: DropReason::BadSegment, channel: None });
+        }
+        n_flagged += 1;
+    }
+    n_flagged
+}
+
+// Manual decision for one epoch; overrides whatever the criteria decided. Keeping an epoch clears its
+// criteria and BAD segment entries and records the override, so applying the criteria again keeps it.
+pub fn set_epoch_bad(epochs: &mut EpochsData, epoch_idx: usize, bad: bool) {
+    let Some(event) = epochs.events.get(epoch_idx).cloned() else {
+        return;
+    };
+    if epochs.bad.len() != epochs.n_epochs() {
+        epochs.bad.resize(epochs.n_epochs(), false);
+    }
+    epochs.bad[epoch_idx] = bad;
+    let marker_idx = event.marker_idx;
+    if bad {
+        epochs
+            .drop_log
+            .retain(|d| !(matches!(d.reason, DropReason::User | DropReason::KeptByUser) && d.event.marker_idx == marker_idx));
+        // Also next to criterion entries, so the mark survives the criteria being applied again
+        epochs.drop_log.push(DroppedEpoch { event, reason: DropReason::User, channel: None });
+    } else {
+        epochs
+            .drop_log
+            .retain(|d| d.event.marker_idx != marker_idx || d.reason == DropReason::OutsideRecording);
+        epochs.drop_log.push(DroppedEpoch { event, reason: DropReason::KeptByUser, channel: None });
+    }
+}
+
+// Remove the bad epochs from the data; their drop log entries are kept. Returns the number removed.
+pub fn drop_bad_epochs(epochs: &mut EpochsData) -> usize {
+    let good = epochs.good_epochs();
+    let n_dropped = epochs.n_epochs() - good.len();
+    if n_dropped == 0 {
+        return 0;
+    }
+    if !epochs.bv_epochs.is_empty() {
+        epochs.bv_epochs = epochs.bv_epochs.select(Axis(0), &good);
+    }
+    if !epochs.edf_epochs_data.is_empty() {
+        epochs.edf_epochs_data = epochs.edf_epochs_data.select(Axis(0), &good);
+    }
+    epochs.events = good.iter().map(|&i| epochs.events[i].clone()).collect();
+    epochs.bad = vec![false; good.len()];
+    n_dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array3;
+
+    use super::*;
+    use crate::EpochEvent;
+
+    // Three flat BrainVision epochs of two channels at 0.1 µV per unit; epoch 1 swings 30 µV on channel 1,
+    // epoch 2 swings 15 µV there
+    fn epochs() -> (EEGInfo, EpochsData) {
+        let mut data = Array3::<i16>::zeros((3, 2, 10));
+        data[[1, 1, 5]] = 300;
+        data[[2, 1, 5]] = 150;
+        let events = (0..3).map(|i| EpochEvent { marker_idx: i, sample: 100.0 * i as f64, description: "S  1".to_owned() }).collect();
+        let info = EEGInfo { ch_names: vec!["Cz".to_owned(), "Pz".to_owned()], resolutions: vec![0.1, 0.1], ..Default::default() };
+        let epochs = EpochsData {
+            bv_epochs: data,
+            ch_names: info.ch_names.clone(),
+            sfreq: 1000.0,
+            bad: vec![false; 3],
+            events,
+            ..Default::default()
+        };
+        (info, epochs)
+    }
+
+    fn reasons(epochs: &EpochsData, marker_idx: usize) -> Vec<DropReason> {
+        epochs.drop_log.iter().filter(|d| d.event.marker_idx == marker_idx).map(|d| d.reason).collect()
+    }
+
+    #[test]
+    fn peak_to_peak_in_microvolts() {
+        let (info, mut epochs) = epochs();
+        let criteria = RejectCriteria { peak_to_peak: Some(20.0), ..Default::default() };
+        assert_eq!(reject_epochs(&criteria, &info, &mut epochs), 1);
+        assert_eq!(epochs.bad, vec![false, true, false]);
+        assert_eq!(epochs.drop_log.len(), 1);
+        assert_eq!(epochs.drop_log[0].reason, DropReason::PeakToPeak);
+        assert_eq!(epochs.drop_log[0].channel.as_deref(), Some("Pz"));
+    }
+
+    #[test]
+    fn manual_decisions_survive_the_criteria() {
+        let (info, mut epochs) = epochs();
+        let strict = RejectCriteria { peak_to_peak: Some(10.0), ..Default::default() };
+        assert_eq!(reject_epochs(&strict, &info, &mut epochs), 2);
+
+        // Rejected by the criteria and by hand, then the criteria are loosened: the manual mark stays
+        set_epoch_bad(&mut epochs, 1, true);
+        assert_eq!(reasons(&epochs, 1), vec![DropReason::PeakToPeak, DropReason::User]);
+        let loose = RejectCriteria { peak_to_peak: Some(50.0), ..Default::default() };
+        assert_eq!(reject_epochs(&loose, &info, &mut epochs), 1);
+        assert_eq!(epochs.bad, vec![false, true, false]);
+        assert_eq!(reasons(&epochs, 1), vec![DropReason::User]);
+
+        // Kept by hand despite failing: applying the criteria again keeps it
+        set_epoch_bad(&mut epochs, 2, false);
+        assert_eq!(reject_epochs(&strict, &info, &mut epochs), 1);
+        assert_eq!(epochs.bad, vec![false, true, false]);
+        assert_eq!(reasons(&epochs, 2), vec![DropReason::KeptByUser]);
+
+        // Un-marking the manual rejection lets the epoch back in
+        set_epoch_bad(&mut epochs, 1, false);
+        assert_eq!(reject_epochs(&loose, &info, &mut epochs), 0);
+    }
+}
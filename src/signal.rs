@@ -10,6 +10,59 @@ use cubic_spline::SplineOpts;
 use crate::Markers;
 use crate::EEGInfo;
 
+// Sample types we process (BrainVision i16 and EDF f32, f64 for windows already converted), converted through f64
+pub trait Sample: Copy + Send + Sync + Default + PartialOrd + Into<f64> {
+    // Rounding step of stored values in data units, 0 for floats
+    const STEP: f64 = 0.0;
+
+    fn from_f64(value: f64) -> Self;
+}
+
+impl Sample for i16 {
+    const STEP: f64 = 1.0;
+
+    fn from_f64(value: f64) -> Self {
+        value.round() as Self
+    }
+}
+
+impl Sample for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as Self
+    }
+}
+
+impl Sample for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ArtefactMethod {
+    Zero,
+    Interpolate,
+}
+
+// Artefact interval relative to each marker: cut from offset - pre to offset + post (seconds)
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct ArtefactWindow {
+    pub offset: f64,
+    pub pre: f64,
+    pub post: f64,
+    pub method: ArtefactMethod,
+}
+
+// µV in one unit of a header's physical dimension ("uV", "mV", ...); unknown units count as µV
+pub fn microvolts_per_unit(unit: &str) -> f64 {
+    match unit.trim() {
+        "nV" => 1e-3,
+        "mV" => 1e3,
+        "V" => 1e6,
+        _ => 1.0,
+    }
+}
+
 // Helper functions
 pub fn vec_to_ndarray<T: Clone>(v: &Vec<Vec<T>>) -> Array2<T> {
     if v.is_empty() {
@@ -228,6 +281,144 @@ pub fn rm_interp_tms_pulse_f32(
     Ok(data_copy)
 }
 
+// Straight line between the samples on either side of the gap, zeros at the recording edges
+fn interpolate_gap(trace: &mut [f64], start: usize, end: usize) {
+    if start == 0 || end >= trace.len() {
+        trace[start..end].fill(0.0);
+        return;
+    }
+    let y_before = trace[start - 1];
+    let y_after = trace[end];
+    let span = (end - start + 1) as f64;
+    for (i, sample) in trace[start..end].iter_mut().enumerate() {
+        *sample = y_before + (y_after - y_before) * (i + 1) as f64 / span;
+    }
+}
+
+// Zero or interpolate several windows around every marker (e.g. the pulse and the capacitor recharge)
+pub fn remove_artefact_windows<T: Sample>(
+    windows: &[ArtefactWindow],
+    markers: &Markers,
+    eeg_info: &EEGInfo,
+    eeg_data: &Array2<T>,
+) -> Result<Array2<T>, Box<dyn std::error::Error>> {
+    if eeg_data.is_empty() || windows.is_empty() {
+        return Ok(eeg_data.clone());
+    }
+
+    let sfreq = eeg_info.sfreq as f64;
+    let n_samples = eeg_data.ncols() as isize;
+    let spans: Vec<(isize, isize, ArtefactMethod)> = windows
+        .iter()
+        .map(|w| {
+            let start = ((w.offset - w.pre) * sfreq).round() as isize;
+            let end = ((w.offset + w.post) * sfreq).round() as isize;
+            (start, end, w.method)
+        })
+        .collect();
+
+    let data_vec_vec: Vec<Vec<T>> = (0..eeg_data.nrows())
+        .into_par_iter()
+        .map(|ch_idx| {
+            let mut trace: Vec<f64> = eeg_data.row(ch_idx).iter().map(|&sample| sample.into()).collect();
+            for &marker_pos in &markers.markers {
+                let marker_idx = marker_pos.round() as isize;
+                for &(start, end, method) in &spans {
+                    let start_cut = (marker_idx + start).clamp(0, n_samples) as usize;
+                    let end_cut = (marker_idx + end).clamp(0, n_samples) as usize;
+                    if start_cut >= end_cut {
+                        continue;
+                    }
+                    match method {
+                        ArtefactMethod::Zero => trace[start_cut..end_cut].fill(0.0),
+                        ArtefactMethod::Interpolate => interpolate_gap(&mut trace, start_cut, end_cut),
+                    }
+                }
+            }
+            trace.into_iter().map(T::from_f64).collect()
+        })
+        .collect();
+
+    Ok(vec_to_ndarray(&data_vec_vec))
+}
+
+// Delay (s) of the largest mean sample-to-sample jump between search_start and search_end after the markers
+pub fn estimate_recharge_delay<T: Sample>(
+    search_start: f64,
+    search_end: f64,
+    markers: &Markers,
+    eeg_info: &EEGInfo,
+    eeg_data: &Array2<T>,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    if eeg_data.is_empty() || markers.markers.is_empty() {
+        return Err("No data or markers to estimate the recharge delay from".into());
+    }
+
+    let sfreq = eeg_info.sfreq as f64;
+    let first_lag = (search_start * sfreq).round().max(0.0) as usize;
+    let last_lag = (search_end * sfreq).round().max(0.0) as usize;
+    if first_lag >= last_lag {
+        return Err("Recharge search window is empty".into());
+    }
+
+    let n_samples = eeg_data.ncols();
+    let mut jumps = vec![0.0; last_lag - first_lag];
+    let mut counts = vec![0usize; last_lag - first_lag];
+    for &marker_pos in &markers.markers {
+        let marker_idx = marker_pos.round().max(0.0) as usize;
+        for (k, lag) in (first_lag..last_lag).enumerate() {
+            let idx = marker_idx + lag;
+            if idx + 1 >= n_samples {
+                break;
+            }
+            jumps[k] += eeg_data
+                .column(idx)
+                .iter()
+                .zip(eeg_data.column(idx + 1).iter())
+                .map(|(&a, &b)| {
+                    let (a, b): (f64, f64) = (a.into(), b.into());
+                    (b - a).abs()
+                })
+                .sum::<f64>();
+            counts[k] += 1;
+        }
+    }
+
+    let best = jumps
+        .iter()
+        .zip(&counts)
+        .enumerate()
+        .filter(|(_, (_, count))| **count > 0)
+        .map(|(k, (jump, count))| (k, jump / *count as f64))
+        .reduce(|best, current| if current.1 > best.1 { current } else { best })
+        .map(|(k, _)| k)
+        .ok_or("No markers have data inside the recharge search window")?;
+
+    Ok((first_lag + best + 1) as f64 / sfreq)
+}
+
+
+// Run `process` on each stretch of data between the `skip` spans separately, so filter edges do not
+// smear artefacts from skipped data into good data. Skipped spans and stretches shorter than `min_len`
+// samples are left as they are.
+pub fn apply_between_spans<T: Sample>(
+    eeg_data: &Array2<T>,
+    skip: &[(usize, usize)],
+    min_len: usize,
+    process: impl Fn(&Array2<T>) -> Result<Array2<T>, Box<dyn std::error::Error>>,
+) -> Result<Array2<T>, Box<dyn std::error::Error>> {
+    let mut output = eeg_data.clone();
+    let mut start = 0;
+    let ends = skip.iter().copied().chain(std::iter::once((eeg_data.ncols(), eeg_data.ncols())));
+    for (skip_start, skip_end) in ends {
+        if skip_start.saturating_sub(start) >= min_len.max(1) {
+            let processed = process(&eeg_data.slice(s![.., start..skip_start]).to_owned())?;
+            output.slice_mut(s![.., start..skip_start]).assign(&processed);
+        }
+        start = start.max(skip_end);
+    }
+    Ok(output)
+}
 
 pub fn design_butter_lp<F>(order: usize, lowcut: F, fs: F) -> Vec<Sos<F>>
 where
@@ -0,0 +1,323 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+use ndarray::Array2;
+use rayon::prelude::*;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::{EEGInfo, EpochsData};
+use crate::signal::Sample;
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Taper {
+    Hann,
+    Hamming,
+    Rectangular,
+}
+
+// Welch's method: tapered, mean-removed segments of `segment_seconds` overlapping by `overlap` (fraction),
+// each zero-padded to `n_fft` samples (None: the segment length)
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct WelchParams {
+    pub taper: Taper,
+    pub segment_seconds: f64,
+    pub overlap: f64,
+    pub n_fft: Option<usize>,
+}
+
+impl Default for WelchParams {
+    fn default() -> Self {
+        Self { taper: Taper::Hann, segment_seconds: 2.0, overlap: 0.5, n_fft: None }
+    }
+}
+
+// One-sided power spectral density, channels x frequencies in µV²/Hz
+#[derive(Debug, Clone, Default)]
+pub struct Psd {
+    pub freqs: Vec<f64>,
+    pub power: Vec<Vec<f64>>,
+    pub ch_names: Vec<String>,
+    // Segments averaged per channel
+    pub n_segments: usize,
+}
+
+// Mean and percentiles over channels of 10 log10(power) at every frequency
+#[derive(Debug, Clone, Default)]
+pub struct PsdSummary {
+    pub mean: Vec<f64>,
+    pub p5: Vec<f64>,
+    pub p25: Vec<f64>,
+    pub p75: Vec<f64>,
+    pub p95: Vec<f64>,
+}
+
+// Samples per stretch in psd_streamed
+const STREAM_SAMPLES: usize = 50_000;
+
+fn taper(kind: Taper, n: usize) -> Vec<f64> {
+    let phase = |i: usize| 2.0 * std::f64::consts::PI * i as f64 / n as f64;
+    (0..n)
+        .map(|i| match kind {
+            Taper::Hann => 0.5 - 0.5 * phase(i).cos(),
+            Taper::Hamming => 0.54 - 0.46 * phase(i).cos(),
+            Taper::Rectangular => 1.0,
+        })
+        .collect()
+}
+
+// Segment geometry, taper and FFT shared by every channel
+struct Welch {
+    n_segment: usize,
+    step: usize,
+    n_fft: usize,
+    window: Vec<f64>,
+    // 1 / (sfreq * sum of the squared taper)
+    scale: f64,
+    fft: Arc<dyn Fft<f64>>,
+}
+
+impl Welch {
+    fn new(params: &WelchParams, sfreq: f64) -> Result<Self, Box<dyn std::error::Error>> {
+        let n_segment = (params.segment_seconds * sfreq).round() as usize;
+        if n_segment < 2 {
+            return Err("Welch segments need at least two samples".into());
+        }
+        if !(0.0..1.0).contains(&params.overlap) {
+            return Err("Overlap must be at least 0 and below 1".into());
+        }
+        let n_fft = params.n_fft.unwrap_or(n_segment);
+        if n_fft < n_segment {
+            return Err(format!("FFT length {n_fft} is shorter than the segment ({n_segment} samples)").into());
+        }
+        let window = taper(params.taper, n_segment);
+        let scale = 1.0 / (sfreq * window.iter().map(|w| w * w).sum::<f64>());
+        let step = ((n_segment as f64 * (1.0 - params.overlap)).round() as usize).max(1);
+        let fft = FftPlanner::new().plan_fft_forward(n_fft);
+        Ok(Self { n_segment, step, n_fft, window, scale, fft })
+    }
+
+    fn freqs(&self, sfreq: f64) -> Vec<f64> {
+        (0..=self.n_fft / 2).map(|k| k as f64 * sfreq / self.n_fft as f64).collect()
+    }
+
+    // Adds the one-sided periodogram of one segment of `n_segment` samples to `sum`
+    fn add_periodogram(&self, segment: &[f64], buffer: &mut [Complex<f64>], sum: &mut [f64]) {
+        let mean = segment.iter().sum::<f64>() / self.n_segment as f64;
+        buffer.iter_mut().for_each(|c| *c = Complex::new(0.0, 0.0));
+        for ((c, &x), &w) in buffer.iter_mut().zip(segment).zip(&self.window) {
+            c.re = (x - mean) * w;
+        }
+        self.fft.process(buffer);
+        for (k, value) in sum.iter_mut().enumerate() {
+            // Every bin but DC and Nyquist carries the power of its negative twin
+            let doubled = if k == 0 || 2 * k == self.n_fft { 1.0 } else { 2.0 };
+            *value += doubled * buffer[k].norm_sqr() * self.scale;
+        }
+    }
+
+    // Adds the periodograms of every whole segment of `samples` to `sum`, returns the number of segments
+    fn accumulate(&self, samples: &[f64], sum: &mut [f64]) -> usize {
+        if samples.len() < self.n_segment {
+            return 0;
+        }
+        let mut buffer = vec![Complex::new(0.0, 0.0); self.n_fft];
+        let mut count = 0;
+        for start in (0..=samples.len() - self.n_segment).step_by(self.step) {
+            self.add_periodogram(&samples[start..start + self.n_segment], &mut buffer, sum);
+            count += 1;
+        }
+        count
+    }
+}
+
+fn average(sums: Vec<Vec<f64>>, n_segments: usize) -> Vec<Vec<f64>> {
+    sums.into_iter().map(|sum| sum.into_iter().map(|v| v / n_segments as f64).collect()).collect()
+}
+
+// PSD of continuous data over the given stretches of samples, e.g. Markers::good_spans
+pub fn psd_continuous<T: Sample>(
+    params: &WelchParams,
+    eeg_info: &EEGInfo,
+    data: &[Vec<T>],
+    spans: &[(usize, usize)],
+) -> Result<Psd, Box<dyn std::error::Error>> {
+    let sfreq = f64::from(eeg_info.sfreq);
+    let welch = Welch::new(params, sfreq)?;
+    let n_samples = data.iter().map(Vec::len).min().unwrap_or(0);
+    let n_freqs = welch.n_fft / 2 + 1;
+    let results: Vec<(Vec<f64>, usize)> = data
+        .par_iter()
+        .enumerate()
+        .map(|(ch, channel)| {
+            let resolution = eeg_info.resolution(ch);
+            let mut sum = vec![0.0; n_freqs];
+            let mut count = 0;
+            for &(start, end) in spans {
+                let (start, end) = (start.min(n_samples), end.min(n_samples));
+                let samples: Vec<f64> = channel[start..end].iter().map(|&x| x.into() * resolution).collect();
+                count += welch.accumulate(&samples, &mut sum);
+            }
+            (sum, count)
+        })
+        .collect();
+    let n_segments = results.first().map_or(0, |(_, count)| *count);
+    if n_segments == 0 {
+        return Err("No stretch of good data is as long as one segment".into());
+    }
+    Ok(Psd {
+        freqs: welch.freqs(sfreq),
+        power: average(results.into_iter().map(|(sum, _)| sum).collect(), n_segments),
+        ch_names: eeg_info.ch_names.iter().take(data.len()).cloned().collect(),
+        n_segments,
+    })
+}
+
+// PSD over the epochs not marked bad, segments taken within each epoch. Channel resolutions come from `eeg_info`.
+pub fn psd_epochs(params: &WelchParams, eeg_info: &EEGInfo, epochs: &EpochsData) -> Result<Psd, Box<dyn std::error::Error>> {
+    let welch = Welch::new(params, epochs.sfreq)?;
+    let good: Vec<usize> = (0..epochs.n_epochs()).filter(|&idx| !epochs.bad.get(idx).copied().unwrap_or(false)).collect();
+    let n_freqs = welch.n_fft / 2 + 1;
+    let results: Vec<(Vec<f64>, usize)> = (0..epochs.n_channels())
+        .into_par_iter()
+        .map(|ch| {
+            let resolution = eeg_info.resolution(ch);
+            let mut sum = vec![0.0; n_freqs];
+            let mut count = 0;
+            for &idx in &good {
+                let samples: Vec<f64> = epochs.trace(idx, ch).into_iter().map(|x| x * resolution).collect();
+                count += welch.accumulate(&samples, &mut sum);
+            }
+            (sum, count)
+        })
+        .collect();
+    let n_segments = results.first().map_or(0, |(_, count)| *count);
+    if n_segments == 0 {
+        return Err("No good epoch is as long as one segment".into());
+    }
+    Ok(Psd {
+        freqs: welch.freqs(epochs.sfreq),
+        power: average(results.into_iter().map(|(sum, _)| sum).collect(), n_segments),
+        ch_names: epochs.ch_names.iter().take(epochs.n_channels()).cloned().collect(),
+        n_segments,
+    })
+}
+
+// PSD of signals that are computed a stretch at a time, so a long recording never has every signal in
+// memory at once: `stretch(start, end)` returns signals x samples in µV. Segments are the same as
+// psd_continuous takes from `spans`.
+pub fn psd_streamed(
+    params: &WelchParams,
+    sfreq: f64,
+    names: Vec<String>,
+    spans: &[(usize, usize)],
+    stretch: impl Fn(usize, usize) -> Array2<f64>,
+) -> Result<Psd, Box<dyn std::error::Error>> {
+    let welch = Welch::new(params, sfreq)?;
+    let mut sums = vec![vec![0.0; welch.n_fft / 2 + 1]; names.len()];
+    let mut n_segments = 0;
+    // Stretches overlap by one segment less one step, so every segment lies whole in one of them
+    let per_stretch = (STREAM_SAMPLES / welch.step).max(1);
+    for &(start, end) in spans {
+        let mut stretch_start = start;
+        while stretch_start + welch.n_segment <= end {
+            let stretch_end = (stretch_start + welch.n_segment + (per_stretch - 1) * welch.step).min(end);
+            let signals = stretch(stretch_start, stretch_end);
+            let mut count = 0;
+            for (sum, row) in sums.iter_mut().zip(signals.rows()) {
+                count = welch.accumulate(&row.to_vec(), sum);
+            }
+            n_segments += count;
+            stretch_start += per_stretch * welch.step;
+        }
+    }
+    if n_segments == 0 {
+        return Err("No stretch of good data is as long as one segment".into());
+    }
+    Ok(Psd { freqs: welch.freqs(sfreq), power: average(sums, n_segments), ch_names: names, n_segments })
+}
+
+// Short-time spectrum, frequencies x segments in µV²/Hz; `times` are segment centres (s)
+#[derive(Debug, Clone, Default)]
+pub struct Spectrogram {
+    pub times: Vec<f64>,
+    pub freqs: Vec<f64>,
+    pub power: Array2<f64>,
+}
+
+// Spectrogram of one channel with segments centred between samples `start` and `end`, stepping as in Welch's method
+pub fn spectrogram<T: Sample>(
+    params: &WelchParams,
+    sfreq: f64,
+    resolution: f64,
+    channel: &[T],
+    start: usize,
+    end: usize,
+) -> Result<Spectrogram, Box<dyn std::error::Error>> {
+    let welch = Welch::new(params, sfreq)?;
+    let half = welch.n_segment / 2;
+    let first = start.saturating_sub(half);
+    let last = end.min(channel.len()).saturating_sub(welch.n_segment.saturating_sub(half));
+    let starts: Vec<usize> = (first..=last).step_by(welch.step).filter(|&s| s + welch.n_segment <= channel.len()).collect();
+    let n_freqs = welch.n_fft / 2 + 1;
+    let mut power = Array2::zeros((n_freqs, starts.len()));
+    let mut buffer = vec![Complex::new(0.0, 0.0); welch.n_fft];
+    let mut column = vec![0.0; n_freqs];
+    for (idx, &segment_start) in starts.iter().enumerate() {
+        let segment: Vec<f64> = channel[segment_start..segment_start + welch.n_segment].iter().map(|&x| x.into() * resolution).collect();
+        column.iter_mut().for_each(|v| *v = 0.0);
+        welch.add_periodogram(&segment, &mut buffer, &mut column);
+        power.column_mut(idx).assign(&ndarray::ArrayView1::from(&column));
+    }
+    Ok(Spectrogram {
+        times: starts.iter().map(|&s| (s as f64 + welch.n_segment as f64 / 2.0) / sfreq).collect(),
+        freqs: welch.freqs(sfreq),
+        power,
+    })
+}
+
+impl Psd {
+    pub fn summary(&self, channels: &[usize]) -> PsdSummary {
+        let mut summary = PsdSummary::default();
+        for f in 0..self.freqs.len() {
+            let mut db: Vec<f64> = channels
+                .iter()
+                .filter_map(|&ch| self.power.get(ch))
+                .map(|power| 10.0 * power[f].max(f64::MIN_POSITIVE).log10())
+                .collect();
+            if db.is_empty() {
+                continue;
+            }
+            db.sort_by(f64::total_cmp);
+            let percentile = |q: f64| db[((db.len() - 1) as f64 * q).round() as usize];
+            summary.mean.push(db.iter().sum::<f64>() / db.len() as f64);
+            summary.p5.push(percentile(0.05));
+            summary.p25.push(percentile(0.25));
+            summary.p75.push(percentile(0.75));
+            summary.p95.push(percentile(0.95));
+        }
+        summary
+    }
+}
+
+// One row per frequency, one column of µV²/Hz per channel
+pub fn psd_to_csv(psd: &Psd) -> String {
+    let mut csv = String::from("frequency_hz");
+    for name in &psd.ch_names {
+        write!(csv, ",{name}").ok();
+    }
+    csv.push('\n');
+    for (f, freq) in psd.freqs.iter().enumerate() {
+        write!(csv, "{freq:.4}").ok();
+        for power in &psd.power {
+            write!(csv, ",{:.6e}", power[f]).ok();
+        }
+        csv.push('\n');
+    }
+    csv
+}
+
+pub fn write_psd_csv(path: &str, psd: &Psd) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, psd_to_csv(psd))?;
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::f64::consts::PI;
+
+use nalgebra::DMatrix;
+
+use crate::positions::Position;
+
+// Sum of coefficients[n - 1] * P_n(x) for n = 1..=coefficients.len(), Legendre polynomials by recurrence
+fn legendre_series(x: f64, coefficients: &[f64]) -> f64 {
+    let (mut previous, mut current) = (1.0, x);
+    let mut sum = 0.0;
+    for (idx, coefficient) in coefficients.iter().enumerate() {
+        let n = (idx + 1) as f64;
+        sum += coefficient * current;
+        let next = ((2.0 * n + 1.0) * x * current - n * previous) / (n + 1.0);
+        previous = current;
+        current = next;
+    }
+    sum
+}
+
+// Perrin et al. (1989) g_m kernel coefficients
+fn g_coefficients(stiffness: i32, n_terms: usize) -> Vec<f64> {
+    (1..=n_terms)
+        .map(|n| {
+            let n = n as f64;
+            (2.0 * n + 1.0) / (n * (n + 1.0)).powi(stiffness) / (4.0 * PI)
+        })
+        .collect()
+}
+
+// h_m kernel coefficients, the surface Laplacian of g_m up to sign
+fn h_coefficients(stiffness: i32, n_terms: usize) -> Vec<f64> {
+    (1..=n_terms)
+        .map(|n| {
+            let n = n as f64;
+            (2.0 * n + 1.0) / (n * (n + 1.0)).powi(stiffness - 1) / (4.0 * PI)
+        })
+        .collect()
+}
+
+fn cos_angle(a: &Position, b: &Position) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |p: &Position| p.iter().map(|v| v * v).sum::<f64>().sqrt().max(f64::EPSILON);
+    (dot / (norm(a) * norm(b))).clamp(-1.0, 1.0)
+}
+
+// Spherical spline through values at the given electrodes (Perrin et al., 1989)
+pub struct SphericalSpline {
+    positions: Vec<Position>,
+    coefficients: Vec<f64>,
+    // Inverse of [G + lambda I, 1; 1', 0]
+    inverse: DMatrix<f64>,
+}
+
+impl SphericalSpline {
+    // Common choices: stiffness 4, 7 terms for interpolation; lambda regularises noisy data
+    pub fn new(positions: &[Position], stiffness: i32, n_terms: usize, lambda: f64) -> Result<Self, Box<dyn std::error::Error>> {
+        let n = positions.len();
+        if n < 3 {
+            return Err("At least three electrode positions are needed for spherical splines".into());
+        }
+        let coefficients = g_coefficients(stiffness, n_terms);
+        let mut system = DMatrix::zeros(n + 1, n + 1);
+        for i in 0..n {
+            for j in 0..n {
+                system[(i, j)] = legendre_series(cos_angle(&positions[i], &positions[j]), &coefficients);
+            }
+            system[(i, i)] += lambda;
+            system[(i, n)] = 1.0;
+            system[(n, i)] = 1.0;
+        }
+        let inverse = system.pseudo_inverse(1e-12)?;
+        Ok(Self { positions: positions.to_vec(), coefficients, inverse })
+    }
+
+    // targets x electrodes matrix taking the electrode values to the interpolated values at `targets`
+    pub fn interpolation_matrix(&self, targets: &[Position]) -> DMatrix<f64> {
+        let n = self.positions.len();
+        let kernel = DMatrix::from_fn(targets.len(), n + 1, |t, j| {
+            if j == n {
+                1.0
+            } else {
+                legendre_series(cos_angle(&targets[t], &self.positions[j]), &self.coefficients)
+            }
+        });
+        kernel * self.inverse.columns(0, n)
+    }
+}
+
+// electrodes x electrodes matrix taking potentials to current source density on the unit sphere
+// (Perrin et al., 1989; Kayser & Tenke, 2006). lambda smooths the spline fit.
+pub fn csd_matrix(positions: &[Position], stiffness: i32, n_terms: usize, lambda: f64) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    let n = positions.len();
+    if n < 3 {
+        return Err("At least three electrode positions are needed for CSD".into());
+    }
+    let (g_coeffs, h_coeffs) = (g_coefficients(stiffness, n_terms), h_coefficients(stiffness, n_terms));
+    let cosines = DMatrix::from_fn(n, n, |i, j| cos_angle(&positions[i], &positions[j]));
+    let g = cosines.map(|x| legendre_series(x, &g_coeffs)) + DMatrix::identity(n, n) * lambda;
+    let h = cosines.map(|x| legendre_series(x, &h_coeffs));
+    let g_inv = g.try_inverse().ok_or("Spline matrix is singular, check for duplicate positions")?;
+
+    // Spline weights c = G^-1 v - (1' G^-1 v / 1' G^-1 1) G^-1 1 make the weights sum to zero
+    let column_sums = g_inv.row_sum();
+    let total = column_sums.sum();
+    let projector = &g_inv - &g_inv * DMatrix::from_element(n, 1, 1.0) * &column_sums / total;
+    Ok(h * projector)
+}
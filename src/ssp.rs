@@ -0,0 +1,156 @@
+use nalgebra::{DMatrix, SymmetricEigen};
+use ndarray::Array2;
+
+use crate::{EEGInfo, EpochsData, reference};
+use crate::signal::Sample;
+
+// One signal-space projection vector: a unit spatial pattern over the good channels in µV, which the
+// projection removes from every sample
+#[derive(Debug, Clone, PartialEq)]
+pub struct Projector {
+    pub name: String,
+    pub channels: Vec<usize>,
+    pub vector: Vec<f64>,
+    // Share of the artefact data's power along the vector
+    pub explained: f64,
+    pub active: bool,
+}
+
+// Samples kept for the PCA, evenly spaced over the artefact data
+const MAX_SSP_SAMPLES: usize = 200_000;
+
+fn good_channels(eeg_info: &EEGInfo, n_channels: usize) -> Vec<usize> {
+    (0..n_channels).filter(|ch| !eeg_info.bad_channels.contains(ch)).collect()
+}
+
+// Leading eigenvectors of the (uncentred) second moment of channels x samples
+fn leading_vectors(name: &str, channels: &[usize], x: &DMatrix<f64>, n_vectors: usize) -> Result<Vec<Projector>, Box<dyn std::error::Error>> {
+    if x.ncols() < channels.len() {
+        return Err(format!("{} samples of artefact data are too few for {} channels", x.ncols(), channels.len()).into());
+    }
+    let eigen = SymmetricEigen::new(x * x.transpose());
+    let total: f64 = eigen.eigenvalues.iter().map(|v| v.max(0.0)).sum();
+    if total <= 0.0 {
+        return Err("The artefact data is flat".into());
+    }
+    let mut order: Vec<usize> = (0..eigen.eigenvalues.len()).collect();
+    order.sort_by(|&a, &b| eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a]));
+    Ok(order
+        .iter()
+        .take(n_vectors)
+        .enumerate()
+        .map(|(i, &k)| Projector {
+            name: format!("{name} PC{}", i + 1),
+            channels: channels.to_vec(),
+            vector: eigen.eigenvectors.column(k).iter().copied().collect(),
+            explained: eigen.eigenvalues[k].max(0.0) / total,
+            active: true,
+        })
+        .collect())
+}
+
+// Projectors from the given stretches of continuous data, e.g. Markers::labelled_spans of blink segments.
+// Each stretch has its channel means removed first, so offsets do not count as artefact.
+pub fn projectors_continuous<T: Sample>(
+    name: &str,
+    n_vectors: usize,
+    eeg_info: &EEGInfo,
+    data: &[Vec<T>],
+    spans: &[(usize, usize)],
+) -> Result<Vec<Projector>, Box<dyn std::error::Error>> {
+    let n_samples = data.iter().map(Vec::len).min().unwrap_or(0);
+    let spans: Vec<(usize, usize)> = spans.iter().map(|&(start, end)| (start.min(n_samples), end.min(n_samples))).filter(|(start, end)| end > start).collect();
+    let n_kept: usize = spans.iter().map(|(start, end)| end - start).sum();
+    if n_kept == 0 {
+        return Err("No samples in the artefact segments".into());
+    }
+    let step = n_kept.div_ceil(MAX_SSP_SAMPLES).max(1);
+    let channels = good_channels(eeg_info, data.len());
+    let mut columns: Vec<Vec<f64>> = Vec::new();
+    for &(start, end) in &spans {
+        let means: Vec<f64> = channels.iter().map(|&ch| data[ch][start..end].iter().map(|&x| x.into()).sum::<f64>() / (end - start) as f64).collect();
+        for t in (start..end).step_by(step) {
+            columns.push(channels.iter().zip(&means).map(|(&ch, mean)| (data[ch][t].into() - mean) * eeg_info.resolution(ch)).collect());
+        }
+    }
+    let x = DMatrix::from_fn(channels.len(), columns.len(), |i, j| columns[j][i]);
+    leading_vectors(name, &channels, &x, n_vectors)
+}
+
+// Projectors from the samples between `tmin` and `tmax` (s) of the good epochs, e.g. the TMS-evoked decay.
+// Epochs are taken as baseline corrected, so the window is not centred.
+pub fn projectors_epochs(
+    name: &str,
+    n_vectors: usize,
+    eeg_info: &EEGInfo,
+    epochs: &EpochsData,
+    tmin: f64,
+    tmax: f64,
+) -> Result<Vec<Projector>, Box<dyn std::error::Error>> {
+    let first = ((tmin - epochs.tmin) * epochs.sfreq).round().max(0.0) as usize;
+    let last = (((tmax - epochs.tmin) * epochs.sfreq).round().max(0.0) as usize + 1).min(epochs.n_times());
+    if last <= first {
+        return Err("The window lies outside the epochs".into());
+    }
+    let good: Vec<usize> = (0..epochs.n_epochs()).filter(|&idx| !epochs.bad.get(idx).copied().unwrap_or(false)).collect();
+    let channels = good_channels(eeg_info, epochs.n_channels());
+    let step = (good.len() * (last - first)).div_ceil(MAX_SSP_SAMPLES).max(1);
+    let traces: Vec<Vec<f64>> = channels
+        .iter()
+        .map(|&ch| {
+            let resolution = eeg_info.resolution(ch);
+            good.iter().flat_map(|&idx| epochs.trace(idx, ch)[first..last].to_vec()).step_by(step).map(|x| x * resolution).collect()
+        })
+        .collect();
+    let n_samples = traces.first().map_or(0, Vec::len);
+    let x = DMatrix::from_fn(channels.len(), n_samples, |i, j| traces[i][j]);
+    leading_vectors(name, &channels, &x, n_vectors)
+}
+
+// channels x channels matrix in data units projecting the active vectors out, I - U Uᵀ in µV with U an
+// orthonormal basis of the vectors. Vectors from different runs need not be orthogonal.
+pub fn projection_matrix(projectors: &[Projector], eeg_info: &EEGInfo, n_channels: usize) -> DMatrix<f64> {
+    let mut basis: Vec<DMatrix<f64>> = Vec::new();
+    for projector in projectors.iter().filter(|projector| projector.active) {
+        let mut v = DMatrix::zeros(n_channels, 1);
+        for (&ch, &weight) in projector.channels.iter().zip(&projector.vector).filter(|(ch, _)| **ch < n_channels) {
+            v[(ch, 0)] = weight;
+        }
+        for u in &basis {
+            let overlap = u.dot(&v);
+            v -= u * overlap;
+        }
+        let norm = v.norm();
+        // Nearly a combination of vectors already in the basis
+        if norm > 1e-6 {
+            basis.push(v / norm);
+        }
+    }
+    let mut matrix = DMatrix::identity(n_channels, n_channels);
+    for u in &basis {
+        for row in 0..n_channels {
+            for ch in 0..n_channels {
+                matrix[(row, ch)] -= u[(row, 0)] * u[(ch, 0)] * eeg_info.resolution(ch) / eeg_info.resolution(row);
+            }
+        }
+    }
+    matrix
+}
+
+pub fn apply_projectors<T: Sample>(projectors: &[Projector], eeg_info: &EEGInfo, eeg_data: &Array2<T>) -> Array2<T> {
+    reference::apply_matrix(&projection_matrix(projectors, eeg_info, eeg_data.nrows()), eeg_data)
+}
+
+pub fn apply_projectors_epochs(projectors: &[Projector], eeg_info: &EEGInfo, epochs: &mut EpochsData) {
+    let matrix = projection_matrix(projectors, eeg_info, epochs.n_channels());
+    reference::apply_matrix_epochs(&matrix, epochs);
+}
+
+// Every channel between `start` and `end` in data units with the active vectors projected out
+pub fn project_window<T: Sample>(matrix: &DMatrix<f64>, data: &[Vec<T>], start: usize, end: usize) -> Vec<Vec<f64>> {
+    let end = data.iter().map(Vec::len).fold(end, usize::min);
+    let start = start.min(end);
+    let window = DMatrix::from_fn(data.len(), end - start, |ch, t| data[ch][start + t].into());
+    let projected = matrix * window;
+    projected.row_iter().map(|row| row.iter().copied().collect()).collect()
+}
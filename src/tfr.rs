@@ -0,0 +1,317 @@
+use std::f64::consts::PI;
+
+use nalgebra::DMatrix;
+use ndarray::{Array2, Array3, ArrayView2};
+use rayon::prelude::*;
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+
+use crate::{EEGInfo, EpochsData};
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TfrMethod {
+    // Gaussian-windowed complex sinusoids, n_cycles / (2 pi f) standard deviation in time
+    Morlet,
+    // DPSS tapers over n_cycles / f seconds, smoothing over time_bandwidth / (n_cycles / f) Hz
+    Multitaper,
+}
+
+// Total: mean single-trial power. Induced: the evoked response is subtracted from every trial first.
+// Evoked: power of the average.
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum PowerKind {
+    Total,
+    Induced,
+    Evoked,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum PowerBaseline {
+    // 10 log10(P / mean baseline)
+    Decibel,
+    // 100 (P - mean baseline) / mean baseline
+    Percent,
+    // (P - mean baseline) / std baseline
+    ZScore,
+}
+
+// Linearly spaced frequencies fmin..=fmax (Hz); every `decim`-th time point is kept
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy, Debug)]
+pub struct TfrParams {
+    pub method: TfrMethod,
+    pub fmin: f64,
+    pub fmax: f64,
+    pub n_freqs: usize,
+    pub n_cycles: f64,
+    pub time_bandwidth: f64,
+    pub decim: usize,
+    pub power: PowerKind,
+}
+
+impl Default for TfrParams {
+    fn default() -> Self {
+        Self {
+            method: TfrMethod::Morlet,
+            fmin: 4.0,
+            fmax: 45.0,
+            n_freqs: 42,
+            n_cycles: 7.0,
+            time_bandwidth: 4.0,
+            decim: 4,
+            power: PowerKind::Total,
+        }
+    }
+}
+
+// Power (µV²) and inter-trial phase coherence, channels x frequencies x times
+#[derive(Debug, Clone, Default)]
+pub struct Tfr {
+    pub freqs: Vec<f64>,
+    pub times: Vec<f64>,
+    pub power: Array3<f64>,
+    pub itc: Array3<f64>,
+    // Epoch channels the rows belong to
+    pub channels: Vec<usize>,
+    pub ch_names: Vec<String>,
+    pub n_epochs: usize,
+}
+
+impl TfrParams {
+    pub fn freqs(&self) -> Vec<f64> {
+        match self.n_freqs {
+            0 => Vec::new(),
+            1 => vec![self.fmin],
+            n => (0..n).map(|i| self.fmin + (self.fmax - self.fmin) * i as f64 / (n - 1) as f64).collect(),
+        }
+    }
+}
+
+// Discrete prolate spheroidal sequences with half bandwidth `half_bandwidth` / n, unit energy.
+// Long windows are interpolated from 512 points, the sequences change little with length at fixed bandwidth.
+fn dpss(n: usize, half_bandwidth: f64, n_tapers: usize) -> Vec<Vec<f64>> {
+    let m = n.min(512);
+    let w = half_bandwidth / m as f64;
+    let mut tridiagonal = DMatrix::zeros(m, m);
+    for i in 0..m {
+        let centre = (m as f64 - 1.0 - 2.0 * i as f64) / 2.0;
+        tridiagonal[(i, i)] = centre * centre * (2.0 * PI * w).cos();
+        if i + 1 < m {
+            let off = (i + 1) as f64 * (m - i - 1) as f64 / 2.0;
+            tridiagonal[(i, i + 1)] = off;
+            tridiagonal[(i + 1, i)] = off;
+        }
+    }
+    let eigen = tridiagonal.symmetric_eigen();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|&a, &b| eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a]));
+    order
+        .into_iter()
+        .take(n_tapers)
+        .map(|k| {
+            let short: Vec<f64> = eigen.eigenvectors.column(k).iter().copied().collect();
+            let mut taper: Vec<f64> = if m == n {
+                short
+            } else {
+                (0..n)
+                    .map(|i| {
+                        let x = i as f64 * (m - 1) as f64 / (n - 1).max(1) as f64;
+                        let (lo, frac) = (x.floor() as usize, x.fract());
+                        short[lo] * (1.0 - frac) + short[(lo + 1).min(m - 1)] * frac
+                    })
+                    .collect()
+            };
+            // Symmetric tapers positive in the middle, antisymmetric ones rising first
+            let sign = if taper.iter().sum::<f64>().abs() > 1e-6 * n as f64 {
+                taper.iter().sum::<f64>().signum()
+            } else {
+                taper.iter().take(n / 2).sum::<f64>().signum()
+            };
+            let norm = taper.iter().map(|v| v * v).sum::<f64>().sqrt().max(f64::EPSILON);
+            taper.iter_mut().for_each(|v| *v *= sign / norm);
+            taper
+        })
+        .collect()
+}
+
+// Complex kernels for one frequency (one per taper), centred, scaled so that sqrt(0.5) ||w|| = 1
+fn kernels(params: &TfrParams, freq: f64, sfreq: f64) -> Vec<Vec<Complex<f64>>> {
+    let envelopes: Vec<Vec<f64>> = match params.method {
+        TfrMethod::Morlet => {
+            let sigma = params.n_cycles / (2.0 * PI * freq);
+            let half = (5.0 * sigma * sfreq).round() as usize;
+            let envelope = (0..=2 * half)
+                .map(|i| {
+                    let t = (i as f64 - half as f64) / sfreq;
+                    (-t * t / (2.0 * sigma * sigma)).exp()
+                })
+                .collect();
+            vec![envelope]
+        }
+        TfrMethod::Multitaper => {
+            let n = ((params.n_cycles / freq * sfreq).round() as usize).max(2);
+            let n_tapers = ((params.time_bandwidth - 1.0).floor() as usize).max(1);
+            dpss(n, params.time_bandwidth / 2.0, n_tapers)
+        }
+    };
+    envelopes
+        .into_iter()
+        .map(|envelope| {
+            let centre = (envelope.len() - 1) as f64 / 2.0;
+            let kernel: Vec<Complex<f64>> = envelope
+                .iter()
+                .enumerate()
+                .map(|(i, &a)| Complex::from_polar(a, 2.0 * PI * freq * (i as f64 - centre) / sfreq))
+                .collect();
+            let norm = (0.5f64).sqrt() * kernel.iter().map(Complex::norm_sqr).sum::<f64>().sqrt();
+            kernel.into_iter().map(|c| c / norm.max(f64::EPSILON)).collect()
+        })
+        .collect()
+}
+
+// Spectra of the kernels zero-padded to `n_fft`, with the shift that centres the convolution
+struct KernelBank {
+    n_fft: usize,
+    spectra: Vec<Vec<(Vec<Complex<f64>>, usize)>>,
+}
+
+impl KernelBank {
+    fn new(params: &TfrParams, freqs: &[f64], sfreq: f64, n_times: usize) -> Self {
+        let all: Vec<Vec<Vec<Complex<f64>>>> = freqs.iter().map(|&f| kernels(params, f, sfreq)).collect();
+        let longest = all.iter().flatten().map(Vec::len).max().unwrap_or(1);
+        let n_fft = (n_times + longest - 1).next_power_of_two();
+        let fft = FftPlanner::new().plan_fft_forward(n_fft);
+        let spectra = all
+            .into_iter()
+            .map(|tapers| {
+                tapers
+                    .into_iter()
+                    .map(|kernel| {
+                        let shift = (kernel.len() - 1) / 2;
+                        let mut buffer = kernel;
+                        buffer.resize(n_fft, Complex::new(0.0, 0.0));
+                        fft.process(&mut buffer);
+                        (buffer, shift)
+                    })
+                    .collect()
+            })
+            .collect();
+        Self { n_fft, spectra }
+    }
+}
+
+// Power and phase-coherence of one channel, frequencies x decimated times
+fn channel_tfr(bank: &KernelBank, trials: &[Vec<f64>], params: &TfrParams, n_out: usize) -> (Array2<f64>, Array2<f64>) {
+    let n_freqs = bank.spectra.len();
+    let n_times = trials.first().map_or(0, Vec::len);
+    let mut planner = FftPlanner::new();
+    let (forward, inverse) = (planner.plan_fft_forward(bank.n_fft), planner.plan_fft_inverse(bank.n_fft));
+    let spectrum = |signal: &[f64]| {
+        let mut buffer: Vec<Complex<f64>> = signal.iter().map(|&x| Complex::new(x, 0.0)).collect();
+        buffer.resize(bank.n_fft, Complex::new(0.0, 0.0));
+        forward.process(&mut buffer);
+        buffer
+    };
+    // Convolve with every kernel and pass each decimated coefficient to `visit(freq, taper, time, value)`
+    let transform = |signal: &[f64], visit: &mut dyn FnMut(usize, usize, usize, Complex<f64>)| {
+        let x = spectrum(signal);
+        for (f, tapers) in bank.spectra.iter().enumerate() {
+            for (k, (kernel, shift)) in tapers.iter().enumerate() {
+                let mut product: Vec<Complex<f64>> = x.iter().zip(kernel).map(|(a, b)| a * b).collect();
+                inverse.process(&mut product);
+                for (out, t) in (0..n_times).step_by(params.decim.max(1)).enumerate() {
+                    visit(f, k, out, product[t + shift] / bank.n_fft as f64);
+                }
+            }
+        }
+    };
+
+    let mean: Vec<f64> = (0..n_times).map(|t| trials.iter().map(|trial| trial[t]).sum::<f64>() / trials.len().max(1) as f64).collect();
+    let n_tapers = bank.spectra.first().map_or(1, Vec::len);
+    let mut power = Array2::zeros((n_freqs, n_out));
+    // Sum of unit phasors per frequency and taper
+    let mut phase = vec![vec![Complex::new(0.0, 0.0); n_out]; n_freqs * n_tapers];
+    for trial in trials {
+        transform(trial, &mut |f, k, t, c| {
+            phase[f * n_tapers + k][t] += c / c.norm().max(f64::MIN_POSITIVE);
+            if params.power == PowerKind::Total {
+                power[[f, t]] += c.norm_sqr();
+            }
+        });
+        if params.power == PowerKind::Induced {
+            let residual: Vec<f64> = trial.iter().zip(&mean).map(|(x, m)| x - m).collect();
+            transform(&residual, &mut |f, _, t, c| power[[f, t]] += c.norm_sqr());
+        }
+    }
+    let n_trials = trials.len().max(1) as f64;
+    if params.power == PowerKind::Evoked {
+        transform(&mean, &mut |f, _, t, c| power[[f, t]] += c.norm_sqr() * n_trials);
+    }
+    power.mapv_inplace(|p| p / (n_trials * n_tapers as f64));
+    let itc = Array2::from_shape_fn((n_freqs, n_out), |(f, t)| {
+        (0..n_tapers).map(|k| phase[f * n_tapers + k][t].norm() / n_trials).sum::<f64>() / n_tapers as f64
+    });
+    (power, itc)
+}
+
+// Time-frequency decomposition of the good epochs at the given channels; resolutions come from `eeg_info`
+pub fn tfr_epochs(params: &TfrParams, eeg_info: &EEGInfo, epochs: &EpochsData, channels: &[usize]) -> Result<Tfr, Box<dyn std::error::Error>> {
+    let freqs = params.freqs();
+    if freqs.is_empty() || freqs.iter().any(|&f| f <= 0.0 || f >= epochs.sfreq / 2.0) {
+        return Err("Frequencies must lie between 0 and the Nyquist frequency".into());
+    }
+    if params.n_cycles <= 0.0 || (params.method == TfrMethod::Multitaper && params.time_bandwidth < 2.0) {
+        return Err("Need positive cycles and a time-bandwidth product of at least 2".into());
+    }
+    let good = epochs.good_epochs();
+    if good.is_empty() {
+        return Err("No good epochs".into());
+    }
+    let channels: Vec<usize> = channels.iter().copied().filter(|&ch| ch < epochs.n_channels()).collect();
+    let times: Vec<f64> = epochs.times().into_iter().step_by(params.decim.max(1)).collect();
+    let bank = KernelBank::new(params, &freqs, epochs.sfreq, epochs.n_times());
+
+    let results: Vec<(Array2<f64>, Array2<f64>)> = channels
+        .par_iter()
+        .map(|&ch| {
+            let resolution = eeg_info.resolution(ch);
+            let trials: Vec<Vec<f64>> = good.iter().map(|&idx| epochs.trace(idx, ch).into_iter().map(|x| x * resolution).collect()).collect();
+            channel_tfr(&bank, &trials, params, times.len())
+        })
+        .collect();
+    let shape = (channels.len(), freqs.len(), times.len());
+    let mut power = Array3::zeros(shape);
+    let mut itc = Array3::zeros(shape);
+    for (row, (channel_power, channel_itc)) in results.into_iter().enumerate() {
+        power.index_axis_mut(ndarray::Axis(0), row).assign(&channel_power);
+        itc.index_axis_mut(ndarray::Axis(0), row).assign(&channel_itc);
+    }
+    Ok(Tfr {
+        ch_names: channels.iter().map(|&ch| epochs.ch_names.get(ch).cloned().unwrap_or_default()).collect(),
+        freqs,
+        times,
+        power,
+        itc,
+        channels,
+        n_epochs: good.len(),
+    })
+}
+
+// Power (frequencies x times) relative to its mean between bmin and bmax (s), per frequency
+pub fn baseline_power(power: ArrayView2<'_, f64>, times: &[f64], bmin: f64, bmax: f64, mode: PowerBaseline) -> Result<Array2<f64>, Box<dyn std::error::Error>> {
+    let window: Vec<usize> = (0..times.len()).filter(|&t| times[t] >= bmin && times[t] <= bmax).collect();
+    if window.is_empty() {
+        return Err(format!("No time points between {bmin} and {bmax} s").into());
+    }
+    let mut output = power.to_owned();
+    for mut row in output.outer_iter_mut() {
+        let values: Vec<f64> = window.iter().map(|&t| row[t]).collect();
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64).sqrt();
+        row.mapv_inplace(|p| match mode {
+            PowerBaseline::Decibel => 10.0 * (p / mean.max(f64::MIN_POSITIVE)).max(f64::MIN_POSITIVE).log10(),
+            PowerBaseline::Percent => 100.0 * (p - mean) / mean.max(f64::MIN_POSITIVE),
+            PowerBaseline::ZScore => (p - mean) / std.max(f64::MIN_POSITIVE),
+        });
+    }
+    Ok(output)
+}
@@ -0,0 +1,148 @@
+use nalgebra::DMatrix;
+use ndarray::Array2;
+
+use crate::positions::{self, Position};
+use crate::spherical::SphericalSpline;
+
+#[derive(serde::Deserialize, serde::Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Interpolation {
+    SphericalSpline,
+    InverseDistance,
+}
+
+pub type Segment = [[f64; 2]; 2];
+
+// Interpolation of electrode values onto a square grid over the projected head.
+// The grid spans -extent..extent on both axes, row 0 at the front (top).
+pub struct Topomap {
+    pub resolution: usize,
+    pub extent: f64,
+    pub points: Vec<[f64; 2]>,
+    weights: DMatrix<f64>,
+    inside: Vec<usize>,
+}
+
+impl Topomap {
+    pub fn new(electrodes: &[Position], method: Interpolation, resolution: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let points: Vec<[f64; 2]> = electrodes.iter().map(positions::project).collect();
+        let radius = points.iter().map(|p| p[0].hypot(p[1])).fold(1.0, f64::max);
+        let extent = radius * 1.05;
+        let mut topomap = Self { resolution, extent, points, weights: DMatrix::zeros(0, 0), inside: Vec::new() };
+
+        let mut targets = Vec::new();
+        for row in 0..resolution {
+            for col in 0..resolution {
+                let point = topomap.pixel_point(row, col);
+                if point[0].hypot(point[1]) <= radius {
+                    topomap.inside.push(row * resolution + col);
+                    targets.push(point);
+                }
+            }
+        }
+        topomap.weights = match method {
+            Interpolation::SphericalSpline => {
+                let spline = SphericalSpline::new(electrodes, 4, 7, 1e-5)?;
+                let targets: Vec<Position> = targets.into_iter().map(positions::unproject).collect();
+                spline.interpolation_matrix(&targets)
+            }
+            Interpolation::InverseDistance => inverse_distance_weights(&topomap.points, &targets)?,
+        };
+        Ok(topomap)
+    }
+
+    // Projected coordinates of the centre of a grid pixel
+    pub fn pixel_point(&self, row: usize, col: usize) -> [f64; 2] {
+        let step = 2.0 * self.extent / self.resolution as f64;
+        [-self.extent + (col as f64 + 0.5) * step, self.extent - (row as f64 + 0.5) * step]
+    }
+
+    // Interpolated map of one value per electrode; NaN outside the head
+    pub fn grid(&self, values: &[f64]) -> Array2<f64> {
+        let mut grid = Array2::from_elem((self.resolution, self.resolution), f64::NAN);
+        if values.len() != self.weights.ncols() {
+            return grid;
+        }
+        let values = nalgebra::DVector::from_column_slice(values);
+        let interpolated = &self.weights * values;
+        if let Some(flat) = grid.as_slice_mut() {
+            for (&pixel, value) in self.inside.iter().zip(interpolated.iter()) {
+                flat[pixel] = *value;
+            }
+        }
+        grid
+    }
+}
+
+// Weights 1 / d^2 on the projected plane, normalised per target
+fn inverse_distance_weights(points: &[[f64; 2]], targets: &[[f64; 2]]) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+    if points.is_empty() {
+        return Err("No electrode positions to interpolate from".into());
+    }
+    let mut weights = DMatrix::zeros(targets.len(), points.len());
+    for (t, target) in targets.iter().enumerate() {
+        let distances: Vec<f64> = points.iter().map(|p| (p[0] - target[0]).hypot(p[1] - target[1])).collect();
+        if let Some(exact) = distances.iter().position(|&d| d < 1e-9) {
+            weights[(t, exact)] = 1.0;
+            continue;
+        }
+        let total: f64 = distances.iter().map(|d| d.powi(-2)).sum();
+        for (e, d) in distances.iter().enumerate() {
+            weights[(t, e)] = d.powi(-2) / total;
+        }
+    }
+    Ok(weights)
+}
+
+// Evenly spaced levels between -limit and limit, zero excluded
+pub fn contour_levels(limit: f64, n_per_side: usize) -> Vec<f64> {
+    let step = limit / (n_per_side + 1) as f64;
+    (1..=n_per_side)
+        .flat_map(|k| [-(k as f64) * step, k as f64 * step])
+        .collect()
+}
+
+// Marching squares over a topomap grid; segments are in projected coordinates
+pub fn contour_segments(topomap: &Topomap, grid: &Array2<f64>, levels: &[f64]) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let (nrows, ncols) = grid.dim();
+    for row in 0..nrows.saturating_sub(1) {
+        for col in 0..ncols.saturating_sub(1) {
+            // Corners clockwise from the top left
+            let corners = [(row, col), (row, col + 1), (row + 1, col + 1), (row + 1, col)];
+            let values = corners.map(|corner| grid[corner]);
+            if values.iter().any(|v| !v.is_finite()) {
+                continue;
+            }
+            let points = corners.map(|(r, c)| topomap.pixel_point(r, c));
+            let centre = values.iter().sum::<f64>() / 4.0;
+            for &level in levels {
+                let mut crossings = Vec::with_capacity(4);
+                for edge in 0..4 {
+                    let (a, b) = (edge, (edge + 1) % 4);
+                    if (values[a] > level) != (values[b] > level) {
+                        let t = (level - values[a]) / (values[b] - values[a]);
+                        crossings.push([
+                            points[a][0] + t * (points[b][0] - points[a][0]),
+                            points[a][1] + t * (points[b][1] - points[a][1]),
+                        ]);
+                    }
+                }
+                match crossings[..] {
+                    [p, q] => segments.push([p, q]),
+                    // Saddle: the centre decides which corners are cut off
+                    [top, right, bottom, left] => {
+                        if (centre > level) == (values[0] > level) {
+                            segments.push([top, right]);
+                            segments.push([bottom, left]);
+                        } else {
+                            segments.push([left, top]);
+                            segments.push([right, bottom]);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    segments
+}